@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+
+// Where the library gets the current time from: loan due dates and reminders, book,
+// member, and list timestamps, ETags and Last-Modified, stats, and scheduled jobs. Tests
+// and demos can then move time instead of waiting for it to pass
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    // Moves a settable clock to `now`; false for clocks that follow real time
+    fn set(&self, _now: DateTime<Utc>) -> bool {
+        false
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Stands still until it is set, so the same requests always see the same time
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn set(&self, now: DateTime<Utc>) -> bool {
+        *self.now.lock().unwrap() = now;
+        true
+    }
+}
+
+// `CLOCK=manual` selects the settable clock, starting at the time of the call; anything
+// else follows real time
+pub fn from_env() -> Box<dyn Clock> {
+    match std::env::var("CLOCK").as_deref() {
+        Ok("manual") => Box::new(ManualClock::new(Utc::now())),
+        _ => Box::new(SystemClock),
+    }
+}
@@ -0,0 +1,152 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+// Starts every encrypted data file and names its format: the nonce follows, then the
+// ChaCha20-Poly1305 ciphertext with its tag. The marker is authenticated along with the
+// data, so a file can't be passed off as another format. A JSON file can't start with it.
+const MARKER: &[u8] = b"BOOKLIB-ENCRYPTED-V1\n";
+const NONCE_LEN: usize = 12;
+
+// The 256-bit key of an encrypted data file
+#[derive(Clone)]
+pub struct Key(chacha20poly1305::Key);
+
+impl Key {
+    // A key written as 64 hex digits, as `openssl rand -hex 32` prints one. Surrounding
+    // whitespace is ignored, so a key file may end with a newline.
+    pub fn from_hex(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let invalid = || "the key must be 64 hex digits (32 bytes)".to_string();
+        if text.len() != 64 || !text.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = chacha20poly1305::Key::default();
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Key(key))
+    }
+
+    // Encrypts `plaintext` under a fresh random nonce; saving the same data twice gives two
+    // different files
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: MARKER,
+                },
+            )
+            .map_err(|_| "encryption failed".to_string())?;
+        let mut sealed = Vec::with_capacity(MARKER.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MARKER);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // The plaintext of what `seal` wrote. A wrong key and a changed file look the same to
+    // the tag check, so the error can't tell them apart.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        let failed = || {
+            "decryption failed: the key is wrong, or the file was changed or damaged".to_string()
+        };
+        let body = sealed
+            .strip_prefix(MARKER)
+            .ok_or("the file is not encrypted")?;
+        if body.len() < NONCE_LEN {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: MARKER,
+                },
+            )
+            .map_err(|_| failed())
+    }
+}
+
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MARKER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key() -> Key {
+        Key::from_hex(HEX).unwrap()
+    }
+
+    #[test]
+    fn what_is_sealed_opens_with_the_same_key() {
+        let sealed = key().seal(b"{\"books\":[]}").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(5).any(|window| window == b"books"));
+        assert_eq!(key().open(&sealed).unwrap(), b"{\"books\":[]}");
+    }
+
+    #[test]
+    fn each_seal_uses_a_fresh_nonce() {
+        assert_ne!(key().seal(b"same").unwrap(), key().seal(b"same").unwrap());
+    }
+
+    #[test]
+    fn a_wrong_key_is_refused() {
+        let sealed = key().seal(b"secret").unwrap();
+        let other = Key::from_hex(&HEX.replace("1f", "20")).unwrap();
+        let err = other.open(&sealed).unwrap_err();
+        assert!(err.starts_with("decryption failed"), "{}", err);
+    }
+
+    #[test]
+    fn a_changed_byte_anywhere_is_refused() {
+        let sealed = key().seal(b"secret").unwrap();
+        // In the nonce, the ciphertext, and the tag. The marker is left alone, since a file
+        // without it isn't taken for an encrypted one at all.
+        for index in [MARKER.len(), MARKER.len() + NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            let err = key().open(&tampered).unwrap_err();
+            assert!(
+                err.starts_with("decryption failed"),
+                "byte {}: {}",
+                index,
+                err
+            );
+        }
+        let cut = &sealed[..sealed.len() - 1];
+        assert!(key().open(cut).is_err());
+        assert!(key().open(&sealed[..MARKER.len() + 4]).is_err());
+    }
+
+    #[test]
+    fn plain_json_is_not_taken_for_sealed() {
+        assert!(!is_sealed(b"{\"books\":[]}"));
+        assert_eq!(key().open(b"{}").unwrap_err(), "the file is not encrypted");
+    }
+
+    #[test]
+    fn a_key_must_be_64_hex_digits() {
+        assert!(Key::from_hex(&format!("{}\n", HEX)).is_ok());
+        assert!(Key::from_hex(&HEX.to_uppercase()).is_ok());
+        for bad in [
+            "",
+            &HEX[..62],
+            &format!("{}00", HEX),
+            &HEX.replace('0', "g"),
+        ] {
+            assert!(Key::from_hex(bad).is_err(), "{:?}", bad);
+        }
+        // 64 bytes, but not all of them ASCII
+        assert!(Key::from_hex(&format!("é{}", &HEX[2..])).is_err());
+    }
+}
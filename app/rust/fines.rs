@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+// Started days from `due_at` to `at`, so a minute late is one day late. Zero up to and
+// including `due_at` itself.
+pub fn days_late(due_at: DateTime<Utc>, at: DateTime<Utc>) -> u32 {
+    let late = (at - due_at).num_seconds();
+    if late <= 0 {
+        return 0;
+    }
+    (late as u64).div_ceil(SECONDS_PER_DAY) as u32
+}
+
+// `per_day_cents` for every started day late, never more than `max_cents`
+pub fn fine_cents(
+    due_at: DateTime<Utc>,
+    returned_at: DateTime<Utc>,
+    per_day_cents: u32,
+    max_cents: u32,
+) -> u32 {
+    let fine = days_late(due_at, returned_at) as u64 * per_day_cents as u64;
+    fine.min(max_cents as u64) as u32
+}
+
+// Splits a payment over outstanding amounts in order, oldest first: each gets as much of
+// what's left as it needs. The caller checks that `amount` covers no more than the total.
+pub fn allocate(amount: u32, outstanding: &[u32]) -> Vec<u32> {
+    let mut left = amount;
+    outstanding
+        .iter()
+        .map(|&owed| {
+            let paid = owed.min(left);
+            left -= paid;
+            paid
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn due() -> DateTime<Utc> {
+        "2026-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn after(seconds: i64) -> DateTime<Utc> {
+        due() + chrono::Duration::seconds(seconds)
+    }
+
+    #[test]
+    fn nothing_is_owed_up_to_and_including_the_due_time() {
+        assert_eq!(fine_cents(due(), after(-86_400), 25, 1000), 0);
+        assert_eq!(fine_cents(due(), due(), 25, 1000), 0);
+    }
+
+    #[test]
+    fn every_started_day_late_costs_the_daily_rate() {
+        assert_eq!(days_late(due(), after(1)), 1);
+        assert_eq!(fine_cents(due(), after(1), 25, 1000), 25);
+        assert_eq!(fine_cents(due(), after(86_400), 25, 1000), 25);
+        assert_eq!(fine_cents(due(), after(86_401), 25, 1000), 50);
+        assert_eq!(fine_cents(due(), after(10 * 86_400), 25, 1000), 250);
+    }
+
+    #[test]
+    fn the_fine_stops_at_the_cap() {
+        assert_eq!(fine_cents(due(), after(39 * 86_400), 25, 1000), 975);
+        assert_eq!(fine_cents(due(), after(40 * 86_400), 25, 1000), 1000);
+        assert_eq!(fine_cents(due(), after(400 * 86_400), 25, 1000), 1000);
+        // Days times the rate would overflow a u32
+        assert_eq!(fine_cents(due(), after(2 * 86_400), u32::MAX, 1000), 1000);
+    }
+
+    #[test]
+    fn a_payment_goes_to_the_oldest_amounts_first() {
+        assert_eq!(allocate(300, &[100, 250, 50]), [100, 200, 0]);
+        assert_eq!(allocate(400, &[100, 250, 50]), [100, 250, 50]);
+        assert_eq!(allocate(0, &[100]), [0]);
+    }
+}
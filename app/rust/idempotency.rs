@@ -0,0 +1,107 @@
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Everything needed to send a stored response again byte for byte
+#[derive(Clone)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Bytes,
+}
+
+struct Entry {
+    // Canonical form of the request, compared when the key is reused
+    request: serde_json::Value,
+    response: StoredResponse,
+    created_at: Instant,
+}
+
+pub enum Lookup {
+    // First use of the key (or the old entry expired): process the request and `record` it
+    Miss,
+    Replay(StoredResponse),
+    // The key was already used for a different request
+    Mismatch,
+}
+
+// Responses remembered per Idempotency-Key. Keys are forgotten `ttl` after first use,
+// which bounds memory; expired entries are swept whenever the store is looked up.
+pub struct Store {
+    entries: HashMap<String, Entry>,
+    ttl: Duration,
+}
+
+impl Store {
+    pub fn new(ttl: Duration) -> Self {
+        Store {
+            entries: HashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn lookup(&mut self, key: &str, request: &serde_json::Value) -> Lookup {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.created_at.elapsed() < ttl);
+
+        match self.entries.get(key) {
+            None => Lookup::Miss,
+            Some(entry) if entry.request != *request => Lookup::Mismatch,
+            Some(entry) => Lookup::Replay(entry.response.clone()),
+        }
+    }
+
+    pub fn record(&mut self, key: String, request: serde_json::Value, response: StoredResponse) {
+        self.entries.insert(
+            key,
+            Entry {
+                request,
+                response,
+                created_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(body: &'static str) -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::CREATED,
+            headers: vec![(HeaderName::from_static("location"), HeaderValue::from_static("/x"))],
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn the_same_request_is_replayed_and_another_is_a_mismatch() {
+        let mut store = Store::new(Duration::from_secs(60));
+        let request = json!({ "body": { "title": "Dune" } });
+        assert!(matches!(store.lookup("k", &request), Lookup::Miss));
+        store.record("k".to_string(), request.clone(), response("first"));
+
+        let Lookup::Replay(replayed) = store.lookup("k", &request) else {
+            panic!("expected a replay");
+        };
+        assert_eq!(replayed.status, StatusCode::CREATED);
+        assert_eq!(replayed.headers, response("first").headers);
+        assert_eq!(replayed.body, "first");
+        let other = json!({ "body": { "title": "Emma" } });
+        assert!(matches!(store.lookup("k", &other), Lookup::Mismatch));
+        assert!(matches!(store.lookup("other-key", &other), Lookup::Miss));
+    }
+
+    #[test]
+    fn a_key_is_forgotten_once_its_time_is_up() {
+        let mut store = Store::new(Duration::ZERO);
+        let request = json!({ "body": {} });
+        store.record("k".to_string(), request.clone(), response("first"));
+        assert!(matches!(store.lookup("k", &request), Lookup::Miss));
+        assert!(store.entries.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+// Canonical ISBN form used for storage and duplicate detection:
+// hyphens and whitespace removed, check digit `x` uppercased
+pub fn normalize(raw: &str) -> String {
+    raw.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// Checks the length and check digit of a normalized ISBN-10 or ISBN-13
+pub fn validate(isbn: &str) -> Result<(), String> {
+    match isbn.chars().count() {
+        10 => validate_isbn10(isbn),
+        13 => validate_isbn13(isbn),
+        len => Err(format!(
+            "ISBN must have 10 or 13 characters after removing hyphens and spaces, got {}",
+            len
+        )),
+    }
+}
+
+fn digits(chars: &[char]) -> Option<Vec<u32>> {
+    chars.iter().map(|c| c.to_digit(10)).collect()
+}
+
+// Mod 11 over weights 10 down to 2; a remainder of 10 is written as 'X'
+fn isbn10_check_digit(first_nine: &[u32]) -> char {
+    let sum: u32 = first_nine
+        .iter()
+        .zip((2..=10).rev())
+        .map(|(digit, weight)| digit * weight)
+        .sum();
+    match (11 - sum % 11) % 11 {
+        10 => 'X',
+        digit => char::from_digit(digit, 10).unwrap_or('0'),
+    }
+}
+
+// Mod 10 over alternating weights 1 and 3
+fn isbn13_check_digit(first_twelve: &[u32]) -> char {
+    let sum: u32 = first_twelve
+        .iter()
+        .zip([1, 3].iter().cycle())
+        .map(|(digit, weight)| digit * weight)
+        .sum();
+    char::from_digit((10 - sum % 10) % 10, 10).unwrap_or('0')
+}
+
+fn validate_isbn10(isbn: &str) -> Result<(), String> {
+    let chars: Vec<char> = isbn.chars().collect();
+    let body = digits(&chars[..9]).ok_or("ISBN-10 may only contain digits and a final 'X'")?;
+    let check = chars[9];
+    if !check.is_ascii_digit() && check != 'X' {
+        return Err("ISBN-10 may only contain digits and a final 'X'".to_string());
+    }
+    let expected = isbn10_check_digit(&body);
+    if check != expected {
+        return Err(format!(
+            "Invalid ISBN-10 check digit: expected '{}', got '{}'",
+            expected, check
+        ));
+    }
+    Ok(())
+}
+
+fn validate_isbn13(isbn: &str) -> Result<(), String> {
+    let chars: Vec<char> = isbn.chars().collect();
+    let all = digits(&chars).ok_or("ISBN-13 may only contain digits")?;
+    let expected = isbn13_check_digit(&all[..12]);
+    let check = char::from_digit(all[12], 10).unwrap_or('0');
+    if check != expected {
+        return Err(format!(
+            "Invalid ISBN-13 check digit: expected '{}', got '{}'",
+            expected, check
+        ));
+    }
+    Ok(())
+}
+
+// The functions below expect an ISBN that has already passed `validate`
+
+// ISBN-10s gain the 978 prefix and a recomputed check digit; ISBN-13s are returned as is
+pub fn to_isbn13(isbn: &str) -> String {
+    if isbn.len() == 13 {
+        return isbn.to_string();
+    }
+    let body = format!("978{}", &isbn[..9]);
+    let chars: Vec<char> = body.chars().collect();
+    let check = isbn13_check_digit(&digits(&chars).unwrap_or_default());
+    format!("{}{}", body, check)
+}
+
+// Only 978-prefixed ISBN-13s have an ISBN-10 form; 979 ones return None
+pub fn to_isbn10(isbn: &str) -> Option<String> {
+    if isbn.len() == 10 {
+        return Some(isbn.to_string());
+    }
+    let body = isbn.strip_prefix("978")?.get(..9)?;
+    let chars: Vec<char> = body.chars().collect();
+    let check = isbn10_check_digit(&digits(&chars)?);
+    Some(format!("{}{}", body, check))
+}
+
+// Whether two normalized ISBNs name the same book, treating an ISBN-10 and its ISBN-13 as equal.
+// Anything that is not a valid ISBN only equals itself.
+pub fn equivalent(a: &str, b: &str) -> bool {
+    a == b || (validate(a).is_ok() && validate(b).is_ok() && to_isbn13(a) == to_isbn13(b))
+}
+
+// The form equivalent ISBNs share, for keying them: a valid ISBN becomes its ISBN-13, and
+// anything else stays as it is, so it only matches itself
+pub fn canonical(isbn: &str) -> String {
+    match validate(isbn) {
+        Ok(()) => to_isbn13(isbn),
+        Err(_) => isbn.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizing_drops_hyphens_and_spaces_and_uppercases_x() {
+        assert_eq!(normalize(" 0-8044-2957-x "), "080442957X");
+        assert_eq!(normalize("978 0 306 40615 7"), "9780306406157");
+    }
+
+    #[test]
+    fn valid_check_digits_pass() {
+        for isbn in ["0306406152", "080442957X", "9780306406157", "9791032305690"] {
+            assert_eq!(validate(isbn), Ok(()), "{}", isbn);
+        }
+    }
+
+    #[test]
+    fn a_wrong_check_digit_names_the_expected_one() {
+        assert_eq!(
+            validate("0306406153"),
+            Err("Invalid ISBN-10 check digit: expected '2', got '3'".to_string())
+        );
+        assert_eq!(
+            validate("0804429570"),
+            Err("Invalid ISBN-10 check digit: expected 'X', got '0'".to_string())
+        );
+        assert_eq!(
+            validate("9780306406158"),
+            Err("Invalid ISBN-13 check digit: expected '7', got '8'".to_string())
+        );
+    }
+
+    #[test]
+    fn x_is_only_a_check_digit_and_only_in_an_isbn_10() {
+        assert!(validate("0306406152").is_ok());
+        assert!(validate("03064061X2").is_err());
+        assert!(validate("978030640615X").is_err());
+        // An X where the check digit should be a digit
+        assert!(validate("030640615X").is_err());
+    }
+
+    #[test]
+    fn only_10_or_13_characters_are_isbns() {
+        for (isbn, len) in [
+            ("", 0),
+            ("030640615", 9),
+            ("03064061522", 11),
+            ("97803064061570", 14),
+        ] {
+            let err = validate(isbn).unwrap_err();
+            assert!(err.ends_with(&format!("got {}", len)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn isbn_10s_and_978_isbn_13s_convert_both_ways() {
+        for (isbn10, isbn13) in [
+            ("0306406152", "9780306406157"),
+            ("080442957X", "9780804429573"),
+        ] {
+            assert_eq!(to_isbn13(isbn10), isbn13);
+            assert_eq!(to_isbn10(isbn13).as_deref(), Some(isbn10));
+            assert_eq!(to_isbn13(isbn13), isbn13);
+            assert_eq!(to_isbn10(isbn10).as_deref(), Some(isbn10));
+        }
+        assert_eq!(to_isbn10("9791032305690"), None);
+    }
+
+    #[test]
+    fn an_isbn_10_and_its_isbn_13_are_the_same_book() {
+        assert!(equivalent("0306406152", "9780306406157"));
+        assert!(!equivalent("0306406152", "9780804429573"));
+        assert!(equivalent("not-an-isbn", "not-an-isbn"));
+        assert_eq!(canonical("080442957X"), "9780804429573");
+        assert_eq!(canonical("12345"), "12345");
+    }
+}
@@ -1,251 +1,9593 @@
+mod clock;
+mod encryption;
+mod fines;
+mod idempotency;
+mod isbn;
+mod language;
+mod notify;
+mod oplog;
+mod random;
+mod recommend;
+#[cfg(feature = "postgres")]
+mod postgresql;
+mod repository;
+mod row;
+mod search;
+mod similar;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod text;
+mod validation;
+
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use chrono::Datelike;
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use repository::{
+    BookFilter, BookPage, BookRepository, InMemoryBooks, PageRequest, RepositoryError,
+};
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+const DEFAULT_PAGE_LIMIT: usize = 20;
+const DEFAULT_MAX_PAGE_LIMIT: usize = 100;
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+// Batch bodies get their own, larger limit: a full batch doesn't fit in 64 KB
+const DEFAULT_MAX_BATCH_BODY_BYTES: usize = 4 * 1024 * 1024;
+const MAX_BATCH_SIZE: usize = 1000;
+const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_REMINDER_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_SCHEDULE_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HOLD_EXPIRY_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 30_000;
+const DEFAULT_OPERATION_LOG_COMPACT_BYTES: u64 = 64 * 1024 * 1024;
+// Raised whenever a backup's fields change in a way an older restore would misread
+const BACKUP_FORMAT_VERSION: u32 = 1;
+#[cfg(feature = "postgres")]
+const DEFAULT_DATABASE_POOL_SIZE: usize = 8;
+const DEFAULT_FUZZY_THRESHOLD: f64 = 0.7;
+const DEFAULT_SUGGESTION_LIMIT: usize = 10;
+const MAX_SUGGESTION_LIMIT: usize = 50;
+const DEFAULT_RECOMMENDATION_LIMIT: usize = 10;
+const MAX_RECOMMENDATION_LIMIT: usize = 50;
+const DEFAULT_SIMILAR_LIMIT: usize = 5;
+const MAX_SIMILAR_LIMIT: usize = 50;
+const DEFAULT_POPULAR_LIMIT: usize = 20;
+const MAX_POPULAR_LIMIT: usize = 100;
+// Longest `period` the popular report accepts, in days
+const MAX_REPORT_PERIOD_DAYS: i64 = 3650;
+const MIN_REPORT_YEAR: i32 = 1970;
+const MAX_REPORT_YEAR: i32 = 9999;
+const DEFAULT_WEEDING_MAX_LOANS: u32 = 1;
+const DEFAULT_WEEDING_AGE_DAYS: i64 = 365;
+// Minimum title and author similarity (see search::similarity) for two books to be
+// reported as probable duplicates
+const DUPLICATE_SIMILARITY: f64 = 0.85;
+
+// Pre-pagination match count on list responses
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+// Client-chosen key that makes a retried POST /books safe; see `create_book`
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// Set on responses replayed from an earlier request with the same key
+const IDEMPOTENT_REPLAY_HEADER: &str = "Idempotent-Replayed";
+
+// Every API route is registered under this scope; resource URLs are built from it too
+const API_PREFIX: &str = "/api";
+
+// Relevance weights per field for full-text `q` searches
+const TITLE_WEIGHT: f64 = 4.0;
+const AUTHOR_WEIGHT: f64 = 2.0;
+const ISBN_WEIGHT: f64 = 1.0;
+const LANGUAGE_WEIGHT: f64 = 1.0;
+const DESCRIPTION_WEIGHT: f64 = 1.0;
+
+// Earliest accepted publication year (the Gutenberg press)
+const MIN_PUBLISHED_YEAR: u16 = 1450;
+
+// Upper bound on page_count; real books top out well below this
+const MAX_PAGE_COUNT: u32 = 20_000;
+
+const MAX_DESCRIPTION_LEN: usize = 5000;
+const MAX_NOTES_LEN: usize = 5000;
+// Marks a request as coming from staff until real authentication lands
+const STAFF_HEADER: &str = "X-Library-Staff";
+// List responses cut descriptions to this many characters; single-book lookups return them whole
+const DESCRIPTION_PREVIEW_LEN: usize = 200;
+
+const MAX_TITLE_LEN: usize = 500;
+const MAX_AUTHOR_LEN: usize = 500;
+// Applied after normalization, before ISBN-10/13 validation
+const MAX_ISBN_LEN: usize = 20;
+
+const MAX_AUTHORS: usize = 10;
+// Joins authors into the single `author` string of compat=v1 responses
+const AUTHOR_SEPARATOR: &str = ", ";
+
+const MAX_SHELF_LOCATION_LEN: usize = 50;
+
+const MAX_COVER_URL_LEN: usize = 2048;
+
+const MAX_EDITION_LEN: usize = 50;
+
+const MAX_TAG_LEN: usize = 32;
+const MAX_TAGS_PER_BOOK: usize = 20;
+
+const MIN_RATING: u8 = 1;
+const MAX_RATING: u8 = 5;
+const MAX_REVIEW_COMMENT_LEN: usize = 2000;
+const MAX_REVIEWER_LEN: usize = 100;
+
+const MAX_MEMBER_NAME_LEN: usize = 100;
+// The longest address SMTP can deliver to
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_LIST_NAME_LEN: usize = 100;
+const MAX_BRANCH_NAME_LEN: usize = 100;
+const MAX_ADDRESS_LEN: usize = 200;
+const MAX_LISTS_PER_MEMBER: usize = 20;
+const DEFAULT_LOAN_PERIOD_DAYS: i64 = 14;
+const DEFAULT_MAX_RENEWALS: u32 = 2;
+const DEFAULT_MAX_OPEN_LOANS: usize = 5;
+const DEFAULT_FINE_PER_DAY_CENTS: u32 = 25;
+const DEFAULT_MAX_FINE_CENTS: u32 = 1000;
+const DEFAULT_HOLD_PICKUP_DAYS: i64 = 7;
+// What counts as recently added in the catalog stats
+const RECENT_DAYS: i64 = 30;
+// Oldest events are dropped past this many
+const MAX_EVENTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Book {
     id: u32,
     title: String,
-    author: String,
+    authors: Vec<String>,
+    isbn: String,
+    // Derived from available_copies (> 0); kept in the payload for older clients
+    available: bool,
+    total_copies: u32,
+    available_copies: u32,
+    published_year: Option<u16>,
+    genre: Option<String>,
+    publisher: Option<String>,
+    page_count: Option<u32>,
+    // ISO 639-1 code, stored lowercase
+    language: Option<String>,
+    description: Option<String>,
+    // Where the book is filed, e.g. "A3-top"; stored trimmed
+    shelf_location: Option<String>,
+    // The branch holding the book; must name an existing branch
+    #[serde(default)]
+    branch_id: Option<u32>,
+    condition: BookCondition,
+    cover_url: Option<String>,
+    series: Option<String>,
+    // Position within `series`; never set without it
+    series_index: Option<u32>,
+    // Free-form, e.g. "2nd" or "Revised"
+    edition: Option<String>,
+    format: Option<BookFormat>,
+    // Normalized (lowercase, deduplicated) in insertion order
+    #[serde(default)]
+    tags: Vec<String>,
+    // Open loans of the book, kept in step by checkout and return. Copies on loan are off
+    // the shelf, so available_copies plus these never exceeds total_copies.
+    #[serde(default)]
+    checked_out_copies: u32,
+    // Members waiting in the book's hold queue, kept in step with `AppState::holds`
+    #[serde(default)]
+    hold_count: u32,
+    // With HOLDS_RESERVE_COPIES, shelf copies up to hold_count are set aside for the
+    // queue and don't make the book `available`
+    #[serde(skip)]
+    holds_reserve: bool,
+    // Derived from the book's reviews and recomputed whenever one is added or deleted.
+    // Reviews aren't edits to the book, so they leave `updated_at` and `version` alone.
+    #[serde(default)]
+    review_count: u32,
+    // Mean rating rounded to two decimals; null until the book has a review
+    #[serde(default)]
+    average_rating: Option<f64>,
+    // Server-controlled; the request structs have no such fields, so client values are ignored
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+    // Starts at 1 and goes up by one with every change, for If-Match optimistic locking
+    version: u64,
+    // Set while the book is in the trash, and left out of the payload otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    // When the review summary last changed. Internal: it only feeds Last-Modified, which
+    // has to move with the summary even though `updated_at` doesn't.
+    #[serde(skip)]
+    reviews_changed_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Librarians' internal notes. Never serialized with the book, so no public response
+    // (or ETag) can include them; `staff_view` adds them for staff.
+    #[serde(skip)]
+    notes: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CreateBookRequest {
+    title: String,
+    // Legacy single-author input; mutually exclusive with `authors`
+    author: Option<String>,
+    authors: Option<Vec<String>>,
     isbn: String,
+    // Both default to 1; available_copies defaults to total_copies
+    total_copies: Option<u32>,
+    available_copies: Option<u32>,
+    published_year: Option<u16>,
+    genre: Option<String>,
+    publisher: Option<String>,
+    page_count: Option<u32>,
+    language: Option<String>,
+    description: Option<String>,
+    shelf_location: Option<String>,
+    branch_id: Option<u32>,
+    // Defaults to good
+    condition: Option<BookCondition>,
+    cover_url: Option<String>,
+    series: Option<String>,
+    // Requires `series`
+    series_index: Option<u32>,
+    edition: Option<String>,
+    format: Option<BookFormat>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UpdateBookRequest {
+    title: Option<String>,
+    author: Option<String>,
+    authors: Option<Vec<String>>,
+    isbn: Option<String>,
+    // Legacy flag: false sets available_copies to 0, true makes every copy available
+    available: Option<bool>,
+    total_copies: Option<u32>,
+    available_copies: Option<u32>,
+    published_year: Option<u16>,
+    genre: Option<String>,
+    publisher: Option<String>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    page_count: Option<Option<u32>>,
+    language: Option<String>,
+    description: Option<String>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    shelf_location: Option<Option<String>>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    branch_id: Option<Option<u32>>,
+    condition: Option<BookCondition>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    cover_url: Option<Option<String>>,
+    // Absent leaves the value unchanged, `null` clears it. The resulting series_index
+    // requires a resulting series, so clearing series alone fails while an index is set.
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    series: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    series_index: Option<Option<u32>>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    edition: Option<Option<String>>,
+    // Absent leaves the value unchanged, `null` clears it
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    format: Option<Option<BookFormat>>,
+    // Replaces the whole tag list; use the tag endpoints to add or remove single tags
+    tags: Option<Vec<String>>,
+    // Same precondition as an If-Match header, for clients that can't set headers
+    version: Option<u64>,
+}
+
+// Full-replacement body for PUT: every create field plus the legacy `available` flag.
+// Presence of each field is checked on the raw JSON first (`REPLACE_FIELDS`), since a
+// missing optional field would otherwise read as null and silently clear the value.
+#[derive(Deserialize)]
+struct ReplaceBookRequest {
+    #[serde(flatten)]
+    book: CreateBookRequest,
+    available: bool,
+    // Same precondition as an If-Match header, for clients that can't set headers
+    version: Option<u64>,
+}
+
+// Fields a PUT body must contain (`null` is fine for optional ones). `authors` may be sent
+// as the legacy `author` instead.
+const REPLACE_FIELDS: &[&str] = &[
+    "title",
+    "authors",
+    "isbn",
+    "available",
+    "total_copies",
+    "available_copies",
+    "published_year",
+    "genre",
+    "publisher",
+    "page_count",
+    "language",
+    "description",
+    "shelf_location",
+    "branch_id",
+    "condition",
+    "cover_url",
+    "series",
+    "series_index",
+    "edition",
+    "format",
+    "tags",
+];
+
+// Distinguishes an explicit `null` (Some(None)) from a missing field (None, via serde default)
+fn deserialize_nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+struct AddTagsRequest {
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RenameAuthorRequest {
+    name: String,
+}
+
+// One checkout of one copy. Loans are kept after the return, as the lending history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Loan {
+    id: u32,
+    book_id: u32,
+    // Cleared once the loan is closed, for members who don't keep a reading history
+    member_id: Option<u32>,
+    checked_out_at: chrono::DateTime<chrono::Utc>,
+    due_at: chrono::DateTime<chrono::Utc>,
+    // Set by the return; a loan without it is open
+    returned_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Each renewal pushes `due_at` out by one loan period, up to MAX_RENEWALS times
+    renewal_count: u32,
+    // Charged when the loan is returned late; 0 while open
+    fine_cents: u32,
+    // How much of the fine the member has paid so far
+    fine_paid_cents: u32,
+    // The due date the last reminder was sent for, so a renewed loan is reminded again
+    #[serde(skip)]
+    reminded_for: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Loan {
+    fn is_open(&self) -> bool {
+        self.returned_at.is_none()
+    }
+    
+    fn fine_outstanding_cents(&self) -> u32 {
+        self.fine_cents - self.fine_paid_cents
+    }
+    
+    // Drops the link to the borrower. Open loans and unpaid fines keep it, until the
+    // book is back and the fine is paid.
+    fn anonymize(&mut self) {
+        if !self.is_open() && self.fine_outstanding_cents() == 0 {
+            self.member_id = None;
+        }
+    }
+    
+    // Overdue loans are still open, so they match both `open` and `overdue`
+    fn status(&self, now: chrono::DateTime<chrono::Utc>) -> LoanStatus {
+        match self.returned_at {
+            Some(_) => LoanStatus::Returned,
+            None if self.due_at < now => LoanStatus::Overdue,
+            None => LoanStatus::Open,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LoanStatus {
+    Open,
+    Returned,
+    Overdue,
+}
+
+impl LoanStatus {
+    fn matches(self, loan: &Loan, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match self {
+            LoanStatus::Open => loan.is_open(),
+            status => loan.status(now) == status,
+        }
+    }
+}
+
+// Every loan in the order it was made (so in id order), indexed by book so per-book
+// lookups only touch that book's loans. Loans are read and updated through the slice it
+// derefs to, but `book_id` may only change through `move_book`, which keeps the index.
+#[derive(Default)]
+struct LoanLedger {
+    loans: Vec<Loan>,
+    // Positions in `loans` of each book's loans, oldest first
+    by_book: HashMap<u32, Vec<usize>>,
+}
+
+impl LoanLedger {
+    fn push(&mut self, loan: Loan) {
+        self.by_book
+            .entry(loan.book_id)
+            .or_default()
+            .push(self.loans.len());
+        self.loans.push(loan);
+    }
+    
+    // Positions of the book's loans, oldest first
+    fn positions(&self, book_id: u32) -> &[usize] {
+        self.by_book.get(&book_id).map_or(&[], Vec::as_slice)
+    }
+    
+    fn of_book(&self, book_id: u32) -> impl DoubleEndedIterator<Item = &Loan> {
+        self.positions(book_id).iter().map(|&i| &self.loans[i])
+    }
+    
+    fn retain(&mut self, keep: impl FnMut(&Loan) -> bool) {
+        self.loans.retain(keep);
+        self.by_book.clear();
+        for (i, loan) in self.loans.iter().enumerate() {
+            self.by_book.entry(loan.book_id).or_default().push(i);
+        }
+    }
+    
+    // Hands all of one book's loans to another, as a merge does
+    fn move_book(&mut self, from: u32, to: u32) {
+        let Some(moved) = self.by_book.remove(&from) else {
+            return;
+        };
+        for &i in &moved {
+            self.loans[i].book_id = to;
+        }
+        let positions = self.by_book.entry(to).or_default();
+        positions.extend(moved);
+        positions.sort_unstable();
+    }
+}
+
+impl std::ops::Deref for LoanLedger {
+    type Target = [Loan];
+    
+    fn deref(&self) -> &[Loan] {
+        &self.loans
+    }
+}
+
+impl std::ops::DerefMut for LoanLedger {
+    fn deref_mut(&mut self) -> &mut [Loan] {
+        &mut self.loans
+    }
+}
+
+#[derive(Serialize)]
+struct LoanResponse<'a> {
+    #[serde(flatten)]
+    loan: &'a Loan,
+    status: LoanStatus,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PageQuery {
+    page: Option<String>,
+    limit: Option<String>,
+}
+
+// Where a book stands in circulation: overdue while any copy on loan is past due,
+// checked out while any other copy is on loan
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CirculationStatus {
+    Available,
+    CheckedOut,
+    Overdue,
+}
+
+// All zero (and null) for a book that has never been lent
+#[derive(Serialize)]
+struct CirculationSummary {
+    total_loans: usize,
+    open_loans: usize,
+    last_checked_out_at: Option<chrono::DateTime<chrono::Utc>>,
+    status: CirculationStatus,
+}
+
+#[derive(Serialize)]
+struct LoanHistory<'a> {
+    summary: CirculationSummary,
+    #[serde(flatten)]
+    loans: PaginatedResponse<LoanResponse<'a>>,
+}
+
+// A single loan, with the names a client would otherwise look up. Either is null once
+// the book or member is gone.
+#[derive(Serialize)]
+struct LoanDetail<'a> {
+    #[serde(flatten)]
+    loan: LoanResponse<'a>,
+    book_title: Option<String>,
+    member_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverdueQuery {
+    // Leaves out loans fewer days overdue than this
+    min_days: Option<u32>,
+    member_id: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PopularQuery {
+    period: Option<String>,
+    limit: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PopularBook {
+    book_id: u32,
+    title: String,
+    authors: Vec<String>,
+    // Loans started within the period
+    loan_count: usize,
+    last_checked_out_at: chrono::DateTime<chrono::Utc>,
     available: bool,
+    available_copies: u32,
+    total_copies: u32,
+}
+
+#[derive(Serialize)]
+struct PopularReport {
+    period: String,
+    // Start of the period; null for `all`
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    items: Vec<PopularBook>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WeedingQuery {
+    max_loans: Option<u32>,
+    older_than_days: Option<i64>,
+}
+
+// One row per book with scalar fields only, so the report converts to CSV as-is
+#[derive(Serialize)]
+struct WeedingCandidate {
+    book_id: u32,
+    title: String,
+    author: String,
+    isbn: String,
+    shelf_location: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    loan_count: usize,
+    last_loan_at: Option<chrono::DateTime<chrono::Utc>>,
+    total_copies: u32,
+}
+
+#[derive(Serialize)]
+struct WeedingReport {
+    max_loans: u32,
+    older_than_days: i64,
+    // Only books created strictly before this are included
+    cutoff: chrono::DateTime<chrono::Utc>,
+    items: Vec<WeedingCandidate>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActivityQuery {
+    year: Option<String>,
+    format: Option<String>,
+}
+
+// Totals for one calendar month (UTC)
+#[derive(Serialize)]
+struct ActivityMonth {
+    // "2024-01"
+    month: String,
+    books_added: usize,
+    books_deleted: usize,
+    loans_started: usize,
+    loans_returned: usize,
+    // Sum of the fines charged at returns in the month, paid or not
+    fines_assessed_cents: u64,
+}
+
+impl ActivityMonth {
+    const CSV_HEADER: &'static str =
+        "month,books_added,books_deleted,loans_started,loans_returned,fines_assessed_cents";
+    
+    fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.month,
+            self.books_added,
+            self.books_deleted,
+            self.loans_started,
+            self.loans_returned,
+            self.fines_assessed_cents
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ActivityReport {
+    year: i32,
+    months: Vec<ActivityMonth>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuditQuery {
+    branch_id: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct AuditSummary {
+    // Every scan in the body, repeats included
+    scanned: usize,
+    matched: usize,
+    missing: usize,
+    unknown: usize,
+}
+
+#[derive(Serialize)]
+struct AuditedBook {
+    book_id: u32,
+    title: String,
+    isbn: String,
+    branch_id: Option<u32>,
+    shelf_location: Option<String>,
+    // How many times the book's barcode was scanned; 0 for missing books
+    scan_count: usize,
+    total_copies: u32,
+    checked_out_copies: u32,
+}
+
+#[derive(Serialize)]
+struct AuditReport {
+    summary: AuditSummary,
+    matched: Vec<AuditedBook>,
+    missing: Vec<AuditedBook>,
+    // Scans that match no catalog book, as sent, each listed once in scan order
+    unknown: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MemberContact {
+    name: String,
+    email: String,
+}
+
+#[derive(Serialize)]
+struct OverdueLoan<'a> {
+    #[serde(flatten)]
+    loan: LoanResponse<'a>,
+    days_overdue: u32,
+    book_title: Option<String>,
+    // Null once the member has been deleted
+    member: Option<MemberContact>,
+}
+
+// A member's place in a book's hold queue. The queue is first come, first served: holds
+// are kept in the order they were placed, under one lock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Hold {
+    book_id: u32,
+    member_id: u32,
+    placed_at: chrono::DateTime<chrono::Utc>,
+    // Set once a returned copy is waiting for the member; the hold expires if they haven't
+    // checked the book out by then
+    pickup_by: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    HoldExpired,
+}
+
+// Something that happened without a request asking for it, for clients to poll
+#[derive(Debug, Clone, Serialize)]
+struct Event {
+    id: u32,
+    kind: EventKind,
+    at: chrono::DateTime<chrono::Utc>,
+    book_id: u32,
+    member_id: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventsQuery {
+    // Only events with a higher id
+    since: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct HoldResponse<'a> {
+    #[serde(flatten)]
+    hold: &'a Hold,
+    // 1 for the first in line
+    position: usize,
+    member_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HoldRequest {
+    member_id: u32,
+}
+
+#[derive(Serialize)]
+struct HeldBook {
+    loan_id: u32,
+    book_id: u32,
+    title: Option<String>,
+    due_at: chrono::DateTime<chrono::Utc>,
+}
+
+// 409 for a member who already has MAX_OPEN_LOANS books out
+#[derive(Serialize)]
+struct LoanLimitResponse {
+    error: String,
+    loans: Vec<HeldBook>,
+}
+
+#[derive(Serialize)]
+struct ReturnResponse<'a> {
+    book: serde_json::Value,
+    loan: LoanResponse<'a>,
+    // First in the book's hold queue, to be told the copy is back; null without holds
+    next_hold: Option<HoldResponse<'a>>,
+}
+
+#[derive(Serialize)]
+struct FineItem {
+    loan_id: u32,
+    book_id: u32,
+    returned_at: Option<chrono::DateTime<chrono::Utc>>,
+    fine_cents: u32,
+    fine_paid_cents: u32,
+    outstanding_cents: u32,
+}
+
+#[derive(Serialize)]
+struct FinesSummary {
+    member_id: u32,
+    outstanding_cents: u32,
+    // Loans with an unpaid fine, oldest first
+    fines: Vec<FineItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PayFinesRequest {
+    // Everything outstanding when left out
+    amount_cents: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetClockRequest {
+    now: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoansQuery {
+    member_id: Option<u32>,
+    book_id: Option<u32>,
+    status: Option<LoanStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckoutRequest {
+    member_id: u32,
+    // Defaults to LOAN_PERIOD_DAYS from now
+    due_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReturnQuery {
+    // Only needed when more than one copy is checked out
+    member_id: Option<u32>,
+    compat: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Member {
+    id: u32,
+    name: String,
+    // Stored lowercase; unique across members
+    email: String,
+    joined_at: chrono::DateTime<chrono::Utc>,
+    // Deactivated members keep their record but can't borrow
+    active: bool,
+    // When false, returned loans are anonymized rather than kept as the member's history
+    retain_history: bool,
+}
+
+// A library building. Every book may belong to one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Branch {
+    id: u32,
+    // Unique across branches, ignoring case
+    name: String,
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BranchRequest {
+    name: String,
+    address: String,
+}
+
+// 409 for deleting a branch that still has books
+#[derive(Serialize)]
+struct BranchInUseResponse {
+    error: String,
+    book_count: usize,
+}
+
+// One move of a book between branches. Kept after the book or either branch is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Transfer {
+    id: u32,
+    book_id: u32,
+    // None for a book that wasn't at any branch
+    from_branch: Option<u32>,
+    to_branch: u32,
+    transferred_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransferRequest {
+    to_branch: u32,
+}
+
+#[derive(Serialize)]
+struct TransferResponse {
+    // True when the book was already at `to_branch`; nothing changed and no transfer was
+    // recorded
+    unchanged: bool,
+    transfer: Option<Transfer>,
+    book: serde_json::Value,
+}
+
+// Body of both create and update. On update, an omitted `active` or `retain_history`
+// keeps the current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberRequest {
+    name: String,
+    email: String,
+    active: Option<bool>,
+    retain_history: Option<bool>,
+}
+
+// A member's named list of books saved for later, in the order they were added. Books
+// leave every list when they leave the catalog.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReadingList {
+    id: u32,
+    member_id: u32,
+    name: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    book_ids: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReadingListRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListBookRequest {
+    book_id: u32,
+}
+
+// A list with its books in full, in list order
+#[derive(Serialize)]
+struct ReadingListDetail<'a> {
+    #[serde(flatten)]
+    list: &'a ReadingList,
+    books: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimilarQuery {
+    limit: Option<String>,
+    compat: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SimilarBook {
+    book: serde_json::Value,
+    scores: similar::Scores,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecommendationsQuery {
+    limit: Option<String>,
+    // Leaves out books with no copy to take from the shelf
+    available_only: Option<bool>,
+    compat: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RecommendationResponse {
+    book: serde_json::Value,
+    score: f64,
+    reason: recommend::Reason,
+}
+
+// One returned loan in a member's reading history. The title is null once the book is
+// gone, and the authors empty.
+#[derive(Serialize)]
+struct HistoryEntry {
+    loan_id: u32,
+    book_id: u32,
+    title: Option<String>,
+    authors: Vec<String>,
+    checked_out_at: chrono::DateTime<chrono::Utc>,
+    due_at: chrono::DateTime<chrono::Utc>,
+    returned_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Review {
+    id: u32,
+    book_id: u32,
+    rating: u8,
+    reviewer: String,
+    comment: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+struct CreateReviewRequest {
+    rating: u8,
+    reviewer: String,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListQuery {
+    page: Option<String>,
+    limit: Option<String>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    fields: Option<String>,
+    compat: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldsQuery {
+    fields: Option<String>,
+    compat: Option<String>,
+    // Only `notes`, for staff
+    include: Option<String>,
+}
+
+// `null` clears the notes
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotesRequest {
+    notes: Option<String>,
+}
+
+#[derive(Serialize)]
+struct NotesResponse<'a> {
+    book_id: u32,
+    notes: &'a Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompatQuery {
+    compat: Option<String>,
+}
+
+// Either `ids` or search filter parameters, given as JSON values (`"available": false`,
+// `"author": ["a", "b"]`); the filter keys are checked against SearchQuery
+#[derive(Deserialize)]
+struct BatchDeleteRequest {
+    ids: Option<Vec<u32>>,
+    #[serde(default)]
+    dry_run: bool,
+    // Skip the trash, as for DELETE ?permanent=true
+    #[serde(default)]
+    permanent: bool,
+    #[serde(flatten)]
+    filter: serde_json::Map<String, serde_json::Value>,
+}
+
+// The filter is nested here, unlike batch delete, because filter and update keys overlap
+// (`title`, `genre`, ...)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchUpdateRequest {
+    filter: serde_json::Map<String, serde_json::Value>,
+    update: UpdateBookRequest,
+}
+
+// One book of a whole catalog, in the shape GET returns it, so a listing can be sent
+// back as-is. Keys such as `available`, `url`, and the review summary are ignored; the
+// server-controlled fields are optional and default as on create.
+#[derive(Deserialize)]
+struct CatalogRecord {
+    // Assigned after the highest given id when left out
+    id: Option<u32>,
+    #[serde(flatten)]
+    book: CreateBookRequest,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    version: Option<u64>,
+    // Set by list responses, whose descriptions can't be sent back without losing text
+    #[serde(default)]
+    description_truncated: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MergeBooksRequest {
+    keep_id: u32,
+    merge_ids: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteQuery {
+    #[serde(default, deserialize_with = "deserialize_permanent")]
+    permanent: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RestoreQuery {
+    #[serde(default, deserialize_with = "deserialize_merge")]
+    merge: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchCreateQuery {
+    #[serde(default, deserialize_with = "deserialize_atomic")]
+    atomic: Option<bool>,
+    compat: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SearchQuery {
+    q: Option<String>,
+    title: Option<String>,
+    // Raw values of every `author` parameter, collected by parse_search_query
+    #[serde(skip)]
+    author: Vec<String>,
+    title_not: Option<String>,
+    author_not: Option<String>,
+    year_from: Option<String>,
+    year_to: Option<String>,
+    genre: Option<String>,
+    publisher: Option<String>,
+    min_pages: Option<String>,
+    max_pages: Option<String>,
+    language: Option<String>,
+    shelf: Option<String>,
+    branch: Option<String>,
+    series: Option<String>,
+    condition: Option<String>,
+    format: Option<String>,
+    // Raw values of every `tag` parameter, collected by parse_search_query
+    #[serde(skip)]
+    tag: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_available")]
+    available: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_fuzzy")]
+    fuzzy: Option<bool>,
+    #[serde(rename = "match")]
+    match_mode: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_highlight")]
+    highlight: Option<bool>,
+    page: Option<String>,
+    limit: Option<String>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    fields: Option<String>,
+    compat: Option<String>,
+}
+
+// Boolean query flags accept true/false (any case) and 1/0
+fn parse_bool_param(name: &str, raw: &str) -> Result<bool, String> {
+    match raw.to_lowercase().as_str() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(format!(
+            "{} must be 'true' or 'false' (case-insensitive, '1' and '0' also accepted), got '{}'",
+            name, raw
+        )),
+    }
+}
+
+fn deserialize_bool_param<'de, D>(deserializer: D, name: &str) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(raw) => parse_bool_param(name, &raw)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_available<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "available")
+}
+
+fn deserialize_highlight<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "highlight")
+}
+
+fn deserialize_atomic<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "atomic")
+}
+
+fn deserialize_merge<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "merge")
+}
+
+fn deserialize_permanent<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "permanent")
+}
+
+fn deserialize_fuzzy<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bool_param(deserializer, "fuzzy")
+}
+
+// Search parameters that may be repeated (`?author=a&author=b`)
+const REPEATABLE_SEARCH_PARAMS: &[&str] = &["author", "tag"];
+
+// Search parameters that shape the response rather than select books
+const NON_FILTER_SEARCH_PARAMS: &[&str] =
+    &["page", "limit", "cursor", "sort", "fields", "compat", "highlight"];
+
+fn parse_search_query(req: &actix_web::HttpRequest) -> Result<SearchQuery, actix_web::Error> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|err| query_error_handler(err, req))?
+        .into_inner();
+    
+    search_query_from_pairs(pairs).map_err(|err| {
+        query_error_handler(actix_web::error::QueryPayloadError::Deserialize(err), req)
+    })
+}
+
+// serde_urlencoded rejects repeated keys, so repeatable parameters are collected as raw
+// values before the remaining parameters are deserialized into the typed SearchQuery
+fn search_query_from_pairs(
+    pairs: Vec<(String, String)>,
+) -> Result<SearchQuery, serde_urlencoded::de::Error> {
+    let (repeatable, rest): (Vec<_>, Vec<_>) = pairs
+        .into_iter()
+        .partition(|(key, _)| REPEATABLE_SEARCH_PARAMS.contains(&key.as_str()));
+    
+    let encoded = serde_urlencoded::to_string(&rest).unwrap_or_default();
+    let mut query: SearchQuery = serde_urlencoded::from_str(&encoded)?;
+    
+    for (key, value) in repeatable {
+        match key.as_str() {
+            "author" => query.author.push(value),
+            "tag" => query.tag.push(value),
+            _ => {}
+        }
+    }
+    
+    Ok(query)
+}
+
+fn parse_number_param<T: std::str::FromStr>(
+    name: &str,
+    raw: &Option<String>,
+) -> Result<Option<T>, String> {
+    match raw {
+        Some(raw) => raw
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| format!("{} must be a non-negative integer, got '{}'", name, raw)),
+        None => Ok(None),
+    }
+}
+
+// Physical state of a book, serialized lowercase
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BookCondition {
+    New,
+    Good,
+    Worn,
+    Damaged,
+    Lost,
+}
+
+// Every condition in report order, best to worst
+const BOOK_CONDITIONS: &[(&str, BookCondition)] = &[
+    ("new", BookCondition::New),
+    ("good", BookCondition::Good),
+    ("worn", BookCondition::Worn),
+    ("damaged", BookCondition::Damaged),
+    ("lost", BookCondition::Lost),
+];
+
+impl std::str::FromStr for BookCondition {
+    type Err = String;
+    
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let name = raw.to_lowercase();
+        match BOOK_CONDITIONS.iter().find(|(condition_name, _)| *condition_name == name) {
+            Some((_, condition)) => Ok(*condition),
+            None => Err(format!(
+                "Unknown condition '{}'. Valid options: {}",
+                raw,
+                BOOK_CONDITIONS
+                    .iter()
+                    .map(|(condition_name, _)| *condition_name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+// Parsed by hand rather than derived so an unknown value reports the valid options
+impl<'de> Deserialize<'de> for BookCondition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BookFormat {
+    Hardcover,
+    Paperback,
+    Ebook,
+    Audiobook,
+}
+
+const BOOK_FORMATS: &[(&str, BookFormat)] = &[
+    ("hardcover", BookFormat::Hardcover),
+    ("paperback", BookFormat::Paperback),
+    ("ebook", BookFormat::Ebook),
+    ("audiobook", BookFormat::Audiobook),
+];
+
+impl std::str::FromStr for BookFormat {
+    type Err = String;
+    
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let name = raw.to_lowercase();
+        match BOOK_FORMATS.iter().find(|(format_name, _)| *format_name == name) {
+            Some((_, format)) => Ok(*format),
+            None => Err(format!(
+                "Unknown format '{}'. Valid options: {}",
+                raw,
+                BOOK_FORMATS
+                    .iter()
+                    .map(|(format_name, _)| *format_name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+// Parsed by hand for the same reason as BookCondition
+impl<'de> Deserialize<'de> for BookFormat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Book {
+    fn author_line(&self) -> String {
+        self.authors.join(AUTHOR_SEPARATOR)
+    }
+    
+    // Records that the book changed at `now`
+    fn touch(&mut self, now: chrono::DateTime<chrono::Utc>) {
+        self.updated_at = now;
+        self.version += 1;
+    }
+    
+    // Keeps the derived `available` flag in step with the copy counts
+    fn set_copies(&mut self, total: u32, available: u32) {
+        self.total_copies = total;
+        self.available_copies = available;
+        let reserved = if self.holds_reserve { self.hold_count } else { 0 };
+        self.available = available > reserved;
+    }
+    
+    fn set_holds(&mut self, count: u32, reserve: bool) {
+        self.hold_count = count;
+        self.holds_reserve = reserve;
+        self.set_copies(self.total_copies, self.available_copies);
+    }
+    
+    // Recomputes the rating summary from the full review list
+    fn refresh_rating(&mut self, reviews: &[Review], now: chrono::DateTime<chrono::Utc>) {
+        let ratings: Vec<f64> = reviews
+            .iter()
+            .filter(|r| r.book_id == self.id)
+            .map(|r| f64::from(r.rating))
+            .collect();
+        self.review_count = ratings.len() as u32;
+        self.average_rating = if ratings.is_empty() {
+            None
+        } else {
+            let mean = ratings.iter().sum::<f64>() / ratings.len() as f64;
+            Some((mean * 100.0).round() / 100.0)
+        };
+        self.reviews_changed_at = Some(now);
+    }
+    
+    // Latest change to anything in the payload, for Last-Modified
+    fn last_modified(&self) -> chrono::DateTime<chrono::Utc> {
+        self.reviews_changed_at
+            .map_or(self.updated_at, |at| at.max(self.updated_at))
+    }
+    
+    // Fills fields this book leaves empty from `other` and adds its tags; used by merge.
+    // Series and series_index travel together, so the position always belongs to the series.
+    fn fill_missing_from(&mut self, other: &Book) -> Result<(), String> {
+        self.published_year = self.published_year.or(other.published_year);
+        self.page_count = self.page_count.or(other.page_count);
+        self.format = self.format.or(other.format);
+        self.branch_id = self.branch_id.or(other.branch_id);
+        if self.notes.is_none() {
+            self.notes = other.notes.clone();
+        }
+        for (field, value) in [
+            (&mut self.genre, &other.genre),
+            (&mut self.publisher, &other.publisher),
+            (&mut self.language, &other.language),
+            (&mut self.description, &other.description),
+            (&mut self.shelf_location, &other.shelf_location),
+            (&mut self.cover_url, &other.cover_url),
+            (&mut self.edition, &other.edition),
+        ] {
+            if field.is_none() {
+                field.clone_from(value);
+            }
+        }
+        if self.series.is_none() {
+            self.series.clone_from(&other.series);
+            self.series_index = other.series_index;
+        }
+        self.tags = merge_tags(&self.tags, &other.tags)?;
+        Ok(())
+    }
+}
+
+impl SearchQuery {
+    fn list_query(&self) -> ListQuery {
+        ListQuery {
+            page: self.page.clone(),
+            limit: self.limit.clone(),
+            cursor: self.cursor.clone(),
+            sort: self.sort.clone(),
+            fields: self.fields.clone(),
+            compat: self.compat.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IsbnConvertQuery {
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SuggestQuery {
+    prefix: Option<String>,
+    limit: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+// 422 body: every failed field check, plus the first one as `error` for clients that only
+// read ErrorResponse
+#[derive(Serialize)]
+struct ValidationErrorResponse {
+    error: String,
+    errors: Vec<validation::FieldError>,
+}
+
+// ErrorResponse plus whatever the JSON parser could tell about where the body went wrong
+#[derive(Serialize)]
+struct BodyErrorResponse {
+    error: String,
+    // Set when serde names the field (missing, unknown, or duplicate)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+// Outcome of one entry of a batch create, identified by its position in the request
+#[derive(Serialize)]
+struct BatchItemResult {
+    index: usize,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchItemResult {
+    fn failed(index: usize, status: actix_web::http::StatusCode, error: String) -> Self {
+        BatchItemResult {
+            index,
+            status: status.as_u16(),
+            book: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchCreateResponse {
+    created: usize,
+    failed: usize,
+    results: Vec<BatchItemResult>,
+}
+
+// An atomic batch with any failing entry; only the failures are listed
+#[derive(Serialize)]
+struct BatchRejectedResponse {
+    error: String,
+    errors: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize)]
+struct BatchDeleteResponse {
+    // With `dry_run`, the books that would have been deleted
+    deleted: usize,
+    ids: Vec<u32>,
+    // Requested ids with no book
+    not_found: Vec<u32>,
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct BatchUpdateResponse {
+    matched: usize,
+    // Books the update actually changed; the others already had the values
+    modified: usize,
+    ids: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct CatalogReplaceResponse {
+    count: usize,
+    next_id: u32,
+}
+
+// A book of a backup that a merge left out, and why
+#[derive(Serialize)]
+struct RestoreConflict {
+    id: u32,
+    isbn: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct RemappedId {
+    from: u32,
+    to: u32,
+}
+
+#[derive(Serialize)]
+struct RestoreResponse {
+    merge: bool,
+    books_restored: usize,
+    conflicts_skipped: Vec<RestoreConflict>,
+    ids_remapped: Vec<RemappedId>,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: usize,
+    ids: Vec<u32>,
+}
+
+// A pending change to a book's availability, applied once `at` has passed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduledChange {
+    id: u32,
+    book_id: u32,
+    // false takes every copy off the shelf, true puts back every copy that isn't on loan
+    available: bool,
+    at: chrono::DateTime<chrono::Utc>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleRequest {
+    available: bool,
+    at: chrono::DateTime<chrono::Utc>,
+}
+
+// Ids of the schedules a run applied, and of those dropped because their book was gone
+#[derive(Serialize)]
+struct ScheduleRunResponse {
+    applied: Vec<u32>,
+    dropped: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct PreconditionFailedResponse {
+    error: String,
+    current_version: u64,
+    // Send this as If-Match after refetching to retry
+    current_etag: String,
+}
+
+#[derive(Serialize)]
+struct PaginatedResponse<T> {
+    items: Vec<T>,
+    page: Option<usize>,
+    limit: usize,
+    total: usize,
+    next_cursor: Option<String>,
+}
+
+impl<T> PaginatedResponse<T> {
+    fn map<U>(self, f: impl FnMut(T) -> U) -> PaginatedResponse<U> {
+        PaginatedResponse {
+            items: self.items.into_iter().map(f).collect(),
+            page: self.page,
+            limit: self.limit,
+            total: self.total,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+struct Config {
+    // Where the library is saved after every change and loaded from at startup; None keeps
+    // everything in memory
+    data_file: Option<std::path::PathBuf>,
+    // `sqlite://<path>` or `postgres://...` keeps the catalog in that database instead of
+    // in memory; the data file, if there is one, then keeps everything else
+    database_url: Option<String>,
+    // Connections the PostgreSQL store keeps open
+    #[cfg(feature = "postgres")]
+    database_pool_size: usize,
+    // Keeps the catalog in memory and appends every change to this file, which is
+    // replayed at startup; the data file then keeps everything else, as with a database
+    operation_log: Option<std::path::PathBuf>,
+    // Log size in bytes past which the operation log is compacted by itself; zero never
+    // compacts it unasked
+    operation_log_compact_bytes: u64,
+    // Books to start an empty catalog with, in place of the two built-in ones
+    seed_file: Option<std::path::PathBuf>,
+    // Adds the seed file's books to a catalog that already holds some, less those whose
+    // ISBN it holds
+    seed_force: bool,
+    // How often pending changes are written to the data file; zero writes each change
+    // before its response is sent
+    flush_interval: std::time::Duration,
+    max_page_limit: usize,
+    fuzzy_threshold: f64,
+    // Largest accepted JSON request body; larger ones get a 413
+    max_body_bytes: usize,
+    // How long an Idempotency-Key is remembered after its first use
+    idempotency_ttl: std::time::Duration,
+    // Body limit for batch endpoints
+    max_batch_body_bytes: usize,
+    // Trashed books older than this are purged; zero purges them on the next sweep
+    trash_retention: chrono::Duration,
+    // How often the background task sweeps the trash
+    purge_interval: std::time::Duration,
+    // How often loans due within a day are looked for and their borrowers reminded
+    reminder_interval: std::time::Duration,
+    // How often scheduled availability changes that have come due are applied
+    schedule_interval: std::time::Duration,
+    // How often holds past their pickup window are looked for and expired
+    hold_expiry_interval: std::time::Duration,
+    // How long the first member in line has to collect a returned copy
+    hold_pickup_window: chrono::Duration,
+    // Refuse PUT, PATCH, and DELETE without If-Match (or a body version) with 428
+    require_if_match: bool,
+    // Due date of a checkout that doesn't name one, and what a renewal adds
+    loan_period: chrono::Duration,
+    max_renewals: u32,
+    // Open loans one member may have at a time
+    max_open_loans: usize,
+    // Whether a loan that is already past due can still be renewed
+    renew_overdue: bool,
+    // Charged per started day a loan is returned late, up to max_fine_cents per loan
+    fine_per_day_cents: u32,
+    max_fine_cents: u32,
+    // Copies on the shelf go to the hold queue first, and don't count as available
+    holds_reserve_copies: bool,
+}
+
+// Reads an env var, falling back to the default when it is unset, unparsable, or rejected
+fn env_or<T: std::str::FromStr>(name: &str, default: T, valid: impl Fn(&T) -> bool) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(valid)
+        .unwrap_or(default)
+}
+
+// The value of `--name value` or `--name=value` on the command line
+fn cli_option(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+// Whether `--name` is on the command line
+fn cli_flag(name: &str) -> bool {
+    let flag = format!("--{}", name);
+    std::env::args().skip(1).any(|arg| arg == flag)
+}
+
+impl Config {
+    // Whether the catalog is saved somewhere of its own rather than in the data file
+    fn catalog_kept_apart(&self) -> bool {
+        self.database_url.is_some() || self.operation_log.is_some()
+    }
+    
+    fn from_env() -> Self {
+        Config {
+            data_file: cli_option("data-file")
+                .or_else(|| std::env::var("DATA_FILE").ok())
+                .filter(|path| !path.is_empty())
+                .map(std::path::PathBuf::from),
+            database_url: std::env::var("DATABASE_URL").ok().filter(|url| !url.is_empty()),
+            operation_log: cli_option("operation-log")
+                .or_else(|| std::env::var("OPERATION_LOG").ok())
+                .filter(|path| !path.is_empty())
+                .map(std::path::PathBuf::from),
+            operation_log_compact_bytes: env_or(
+                "OPERATION_LOG_COMPACT_BYTES",
+                DEFAULT_OPERATION_LOG_COMPACT_BYTES,
+                |_| true,
+            ),
+            seed_file: cli_option("seed")
+                .or_else(|| std::env::var("SEED_FILE").ok())
+                .filter(|path| !path.is_empty())
+                .map(std::path::PathBuf::from),
+            seed_force: cli_flag("seed-force") || env_or("SEED_FORCE", false, |_| true),
+            #[cfg(feature = "postgres")]
+            database_pool_size: env_or(
+                "DATABASE_POOL_SIZE",
+                DEFAULT_DATABASE_POOL_SIZE,
+                |v| (1..=100).contains(v),
+            ),
+            max_page_limit: env_or("MAX_PAGE_LIMIT", DEFAULT_MAX_PAGE_LIMIT, |v| *v > 0),
+            fuzzy_threshold: env_or("FUZZY_THRESHOLD", DEFAULT_FUZZY_THRESHOLD, |v| {
+                (0.0..=1.0).contains(v)
+            }),
+            max_body_bytes: env_or("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES, |v| *v > 0),
+            idempotency_ttl: std::time::Duration::from_secs(env_or(
+                "IDEMPOTENCY_TTL_SECS",
+                DEFAULT_IDEMPOTENCY_TTL_SECS,
+                |v| *v > 0,
+            )),
+            max_batch_body_bytes: env_or(
+                "MAX_BATCH_BODY_BYTES",
+                DEFAULT_MAX_BATCH_BODY_BYTES,
+                |v| *v > 0,
+            ),
+            trash_retention: chrono::Duration::days(env_or(
+                "TRASH_RETENTION_DAYS",
+                DEFAULT_TRASH_RETENTION_DAYS,
+                |v| (0..=36_500).contains(v),
+            )),
+            purge_interval: std::time::Duration::from_secs(env_or(
+                "PURGE_INTERVAL_SECS",
+                DEFAULT_PURGE_INTERVAL_SECS,
+                |v| *v > 0,
+            )),
+            flush_interval: std::time::Duration::from_millis(env_or(
+                "FLUSH_INTERVAL_MS",
+                DEFAULT_FLUSH_INTERVAL_MS,
+                |_| true,
+            )),
+            reminder_interval: std::time::Duration::from_secs(env_or(
+                "REMINDER_INTERVAL_SECS",
+                DEFAULT_REMINDER_INTERVAL_SECS,
+                |v| *v > 0,
+            )),
+            schedule_interval: std::time::Duration::from_secs(env_or(
+                "SCHEDULE_INTERVAL_SECS",
+                DEFAULT_SCHEDULE_INTERVAL_SECS,
+                |v| *v > 0,
+            )),
+            hold_expiry_interval: std::time::Duration::from_secs(env_or(
+                "HOLD_EXPIRY_INTERVAL_SECS",
+                DEFAULT_HOLD_EXPIRY_INTERVAL_SECS,
+                |v| *v > 0,
+            )),
+            hold_pickup_window: chrono::Duration::days(env_or(
+                "HOLD_PICKUP_DAYS",
+                DEFAULT_HOLD_PICKUP_DAYS,
+                |v| (1..=365).contains(v),
+            )),
+            require_if_match: env_or("REQUIRE_IF_MATCH", false, |_| true),
+            loan_period: chrono::Duration::days(env_or(
+                "LOAN_PERIOD_DAYS",
+                DEFAULT_LOAN_PERIOD_DAYS,
+                |v| (1..=365).contains(v),
+            )),
+            max_renewals: env_or("MAX_RENEWALS", DEFAULT_MAX_RENEWALS, |v| *v <= 100),
+            renew_overdue: env_or("RENEW_OVERDUE", false, |_| true),
+            max_open_loans: env_or("MAX_OPEN_LOANS", DEFAULT_MAX_OPEN_LOANS, |v| *v > 0),
+            fine_per_day_cents: env_or("FINE_PER_DAY_CENTS", DEFAULT_FINE_PER_DAY_CENTS, |_| true),
+            max_fine_cents: env_or("MAX_FINE_CENTS", DEFAULT_MAX_FINE_CENTS, |_| true),
+            holds_reserve_copies: env_or("HOLDS_RESERVE_COPIES", true, |_| true),
+        }
+    }
+}
+
+// Lock order: idempotency, then books (the repository's own lock), then trash, then
+// next_id, then suggestions, then similar, then catalog_version, then catalog_modified
+struct AppState {
+    books: Arc<dyn BookRepository>,
+    next_id: Mutex<u32>,
+    // Soft-deleted books, in id order. Kept apart from `books` so nothing that reads the
+    // catalog can see them; locked after `books` whenever both are needed.
+    trash: Mutex<Vec<Book>>,
+    // Locked after `books` and `trash`, so a book can't be placed at a branch while it is
+    // being deleted
+    branches: Mutex<Vec<Branch>>,
+    next_branch_id: Mutex<u32>,
+    // Locked after `branches`
+    transfers: Mutex<Vec<Transfer>>,
+    next_transfer_id: Mutex<u32>,
+    // Locked after `books`, so a change can't be applied to a book while it is deleted
+    schedules: Mutex<Vec<ScheduledChange>>,
+    next_schedule_id: Mutex<u32>,
+    // Locked last; only ever appended to while other locks are held
+    events: Mutex<Vec<Event>>,
+    next_event_id: Mutex<u32>,
+    // Locked after `books` whenever both are needed, so the two can't deadlock
+    reviews: Mutex<Vec<Review>>,
+    next_review_id: Mutex<u32>,
+    // Typeahead index over titles and authors, rebuilt after every mutation
+    suggestions: Mutex<search::SuggestionIndex>,
+    // Token sets for similar-book lookups. Dropped by every mutation and built again by
+    // the next lookup, so a run of writes doesn't pay for rebuilds nobody reads.
+    similar: Mutex<Option<similar::Index>>,
+    // Bumped by `catalog_changed`; the collection ETag is derived from it
+    catalog_version: Mutex<u64>,
+    // Goes in the collection ETag next to `catalog_version`, which starts again at 1 on
+    // every run, so a tag from an earlier run never matches
+    boot_id: String,
+    // Time of the last `catalog_changed`, sent as the collection Last-Modified. Unlike the
+    // newest `updated_at`, it also moves when a book is deleted.
+    catalog_modified: Mutex<chrono::DateTime<chrono::Utc>>,
+    // Responses to keyed POST /books requests, held for replay
+    idempotency: Mutex<idempotency::Store>,
+    // Every loan, open or returned, in id order. Locked after `books` and `reviews`.
+    loans: Mutex<LoanLedger>,
+    next_loan_id: Mutex<u32>,
+    // Hold queues of all books, each in FIFO order. Locked after `loans`, before `members`.
+    holds: Mutex<Vec<Hold>>,
+    // Locked after `loans` when both are needed
+    members: Mutex<Vec<Member>>,
+    next_member_id: Mutex<u32>,
+    // Members' reading lists. Locked last.
+    lists: Mutex<Vec<ReadingList>>,
+    next_list_id: Mutex<u32>,
+    // "Now" for loans and for every book timestamp, so tests can move it with CLOCK=manual
+    clock: Box<dyn clock::Clock>,
+    // Picks the random book; seeded with RANDOM_SEED for repeatable tests
+    rng: Box<dyn random::Rng>,
+    // The data file, when one is configured. Its write lock is taken before every other
+    // lock.
+    store: Option<storage::FileStore>,
+    // Tells patrons about held books coming back and loans coming due
+    notifier: Box<dyn notify::Notifier>,
+    notifications: notify::Counters,
+    config: Config,
+}
+
+// A book as saved in the data file and in backups: the public record plus what it leaves
+// out, the same fields `row::StoredRow` keeps for the other stores. Files written before
+// the last two were kept load them as unset.
+#[derive(Serialize, Deserialize)]
+struct StoredBook {
+    #[serde(flatten)]
+    book: Book,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    #[serde(default)]
+    holds_reserve: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reviews_changed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl StoredBook {
+    fn new(book: &Book) -> Self {
+        StoredBook {
+            book: book.clone(),
+            notes: book.notes.clone(),
+            holds_reserve: book.holds_reserve,
+            reviews_changed_at: book.reviews_changed_at,
+        }
+    }
+    
+    fn into_book(self) -> Book {
+        Book {
+            notes: self.notes,
+            holds_reserve: self.holds_reserve,
+            reviews_changed_at: self.reviews_changed_at,
+            ..self.book
+        }
+    }
+}
+
+// Everything the server keeps between restarts, in the shape of the data file. Events,
+// idempotency keys, and sent reminders are not kept.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    // DATA_FILE_VERSION when written; older files are upgraded before they are read
+    schema_version: u64,
+    next_id: u32,
+    books: Vec<StoredBook>,
+    #[serde(default)]
+    trash: Vec<StoredBook>,
+    #[serde(default)]
+    next_review_id: u32,
+    #[serde(default)]
+    reviews: Vec<Review>,
+    #[serde(default)]
+    next_loan_id: u32,
+    #[serde(default)]
+    loans: Vec<Loan>,
+    #[serde(default)]
+    holds: Vec<Hold>,
+    #[serde(default)]
+    next_member_id: u32,
+    #[serde(default)]
+    members: Vec<Member>,
+    #[serde(default)]
+    next_branch_id: u32,
+    #[serde(default)]
+    branches: Vec<Branch>,
+    #[serde(default)]
+    next_transfer_id: u32,
+    #[serde(default)]
+    transfers: Vec<Transfer>,
+    #[serde(default)]
+    next_schedule_id: u32,
+    #[serde(default)]
+    schedules: Vec<ScheduledChange>,
+    #[serde(default)]
+    next_list_id: u32,
+    #[serde(default)]
+    lists: Vec<ReadingList>,
+}
+
+// Takes every lock at once, in the usual order, so the snapshot is consistent. `catalog`
+// leaves the books out when false, for a data file whose catalog is saved elsewhere.
+fn take_snapshot(data: &AppState, catalog: bool) -> Result<Snapshot, String> {
+    let snapshot = data.books.read_with(|books| {
+        let trash = data.trash.lock().unwrap();
+        let next_id = data.next_id.lock().unwrap();
+        let reviews = data.reviews.lock().unwrap();
+        let next_review_id = data.next_review_id.lock().unwrap();
+        let loans = data.loans.lock().unwrap();
+        let next_loan_id = data.next_loan_id.lock().unwrap();
+        let holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let next_member_id = data.next_member_id.lock().unwrap();
+        let branches = data.branches.lock().unwrap();
+        let next_branch_id = data.next_branch_id.lock().unwrap();
+        let transfers = data.transfers.lock().unwrap();
+        let next_transfer_id = data.next_transfer_id.lock().unwrap();
+        let schedules = data.schedules.lock().unwrap();
+        let next_schedule_id = data.next_schedule_id.lock().unwrap();
+        let lists = data.lists.lock().unwrap();
+        let next_list_id = data.next_list_id.lock().unwrap();
+        Snapshot {
+            schema_version: DATA_FILE_VERSION,
+            next_id: *next_id,
+            books: if catalog {
+                books.iter().map(StoredBook::new).collect()
+            } else {
+                Vec::new()
+            },
+            trash: trash.iter().map(StoredBook::new).collect(),
+            next_review_id: *next_review_id,
+            reviews: reviews.clone(),
+            next_loan_id: *next_loan_id,
+            loans: loans.to_vec(),
+            holds: holds.clone(),
+            next_member_id: *next_member_id,
+            members: members.clone(),
+            next_branch_id: *next_branch_id,
+            branches: branches.clone(),
+            next_transfer_id: *next_transfer_id,
+            transfers: transfers.clone(),
+            next_schedule_id: *next_schedule_id,
+            schedules: schedules.clone(),
+            next_list_id: *next_list_id,
+            lists: lists.clone(),
+        }
+    });
+    snapshot.map_err(|err| format!("could not read the catalog: {}", err))
+}
+
+// The steps that bring an older data file, or the snapshot in an older backup, up to
+// the current shape; see storage::Migration. Add one, and never change one, whenever a
+// field of the snapshot is added without a default, renamed, or reshaped.
+const DATA_FILE_MIGRATIONS: &[storage::Migration] = &[data_file_v1_to_v2];
+const DATA_FILE_VERSION: u64 = storage::current_version(DATA_FILE_MIGRATIONS);
+
+// Version 2 is version 1 with `schema_version` recorded, and nothing else. Version 1 is
+// every file written before versions were, and each field added to those files over time
+// came with a serde default, so any of them reads as version 2 as it is. The step has
+// nothing to transform; it is here so that the next change of shape is step 2 to 3.
+// testdata/data_file_v1.json is such a file, as the last unversioned server wrote it.
+fn data_file_v1_to_v2(
+    _fields: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<(), String> {
+    Ok(())
+}
+
+// A backup is the snapshot with the catalog always in it, whatever store holds it, and
+// what a restore needs to check it
+#[derive(Serialize)]
+struct Backup {
+    format_version: u32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    snapshot: Snapshot,
+}
+
+// The SHA-256 of the backup's other fields, in the order they come and as compact JSON,
+// so whitespace added to the file doesn't matter but any change to a value does
+fn backup_checksum(fields: &serde_json::Map<String, serde_json::Value>) -> String {
+    use sha2::Digest;
+    let json = serde_json::to_vec(fields).expect("a JSON value always serializes");
+    let digest = sha2::Sha256::digest(&json);
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256:{}", hex)
+}
+
+// Checks a backup document as GET /api/admin/backup wrote it, and reads the snapshot in
+// it. A document that fails any check is never half used.
+fn read_backup(document: serde_json::Value) -> Result<Snapshot, String> {
+    let serde_json::Value::Object(mut fields) = document else {
+        return Err("A backup must be a JSON object".to_string());
+    };
+    let version = match fields.get("format_version") {
+        None => {
+            return Err(
+                "The backup has no format_version; send a file from GET /api/admin/backup"
+                    .to_string(),
+            )
+        }
+        Some(version) => version
+            .as_u64()
+            .filter(|&version| version > 0)
+            .ok_or("format_version must be a whole number of at least 1")?,
+    };
+    if version > u64::from(BACKUP_FORMAT_VERSION) {
+        return Err(format!(
+            "The backup is format version {}, but this server reads version {} at most; \
+             restore it on the newer server that wrote it",
+            version, BACKUP_FORMAT_VERSION
+        ));
+    }
+    let checksum = match fields.remove("checksum") {
+        Some(serde_json::Value::String(checksum)) => checksum,
+        Some(_) => return Err("checksum must be a string".to_string()),
+        None => return Err("The backup has no checksum".to_string()),
+    };
+    if backup_checksum(&fields) != checksum {
+        return Err(
+            "The backup's checksum doesn't match its contents: it was changed or damaged \
+             after it was taken"
+                .to_string(),
+        );
+    }
+    let upgraded = storage::upgrade(serde_json::Value::Object(fields), DATA_FILE_MIGRATIONS)
+        .map_err(|err| format!("Invalid backup: {}", err))?;
+    let snapshot: Snapshot =
+        serde_json::from_value(upgraded).map_err(|err| format!("Invalid backup: {}", err))?;
+    let mut ids = HashSet::new();
+    for stored in snapshot.books.iter().chain(&snapshot.trash) {
+        if !ids.insert(stored.book.id) {
+            let id = stored.book.id;
+            return Err(format!("Invalid backup: book id {} appears more than once", id));
+        }
+    }
+    for (index, stored) in snapshot.books.iter().enumerate() {
+        let book = &stored.book;
+        if let Some(other) = snapshot.books[..index]
+            .iter()
+            .find(|other| isbn::equivalent(&other.book.isbn, &book.isbn))
+        {
+            return Err(format!(
+                "Invalid backup: books {} and {} have the same ISBN",
+                other.book.id, book.id
+            ));
+        }
+    }
+    Ok(snapshot)
+}
+
+// Puts the books of a backup into the catalog by ISBN, leaving the rest of the library as
+// it is. A book whose ISBN is in the catalog takes the place of that book under its id,
+// with its loans, holds, and reviews; the others are added, under their own id if it is
+// free. Copies the backup had on the shelf or on loan are on the shelf now, less those
+// on loan to the book it replaces.
+fn merge_backup(
+    data: &AppState,
+    backup: Vec<StoredBook>,
+) -> Result<RestoreResponse, RepositoryError> {
+    data.books.write_with(|books| {
+        let trash = data.trash.lock().unwrap();
+        let mut next_id = data.next_id.lock().unwrap();
+        let mut summary = RestoreResponse {
+            merge: true,
+            books_restored: 0,
+            conflicts_skipped: Vec::new(),
+            ids_remapped: Vec::new(),
+        };
+        for stored in backup {
+            let mut book = stored.into_book();
+            let copies = book.available_copies + book.checked_out_copies;
+            let skipped = |reason: String| RestoreConflict {
+                id: book.id,
+                isbn: book.isbn.clone(),
+                reason,
+            };
+            if let Err(reason) = check_branch(data, book.branch_id) {
+                summary.conflicts_skipped.push(skipped(reason));
+                continue;
+            }
+            let same_isbn = |b: &&Book| isbn::equivalent(&b.isbn, &book.isbn);
+            if let Some(index) = books.iter().position(|b| same_isbn(&b)) {
+                let current = &books[index];
+                if current.checked_out_copies > copies {
+                    let reason = format!(
+                        "book {} has {} copies checked out, more than the backup's {}",
+                        current.id, current.checked_out_copies, copies
+                    );
+                    summary.conflicts_skipped.push(skipped(reason));
+                    continue;
+                }
+                book.id = current.id;
+                book.version = current.version;
+                book.checked_out_copies = current.checked_out_copies;
+                book.review_count = current.review_count;
+                book.average_rating = current.average_rating;
+                book.reviews_changed_at = current.reviews_changed_at;
+                book.hold_count = current.hold_count;
+                book.touch(data.clock.now());
+                book.available_copies = copies - book.checked_out_copies;
+                books[index] = book;
+            } else if let Some(trashed) = trash.iter().find(same_isbn) {
+                let reason = format!("the ISBN belongs to book {} in the trash", trashed.id);
+                summary.conflicts_skipped.push(skipped(reason));
+                continue;
+            } else {
+                if books.iter().chain(trash.iter()).any(|b| b.id == book.id) {
+                    summary.ids_remapped.push(RemappedId {
+                        from: book.id,
+                        to: *next_id,
+                    });
+                    book.id = *next_id;
+                }
+                *next_id = (*next_id).max(book.id + 1);
+                book.checked_out_copies = 0;
+                book.review_count = 0;
+                book.average_rating = None;
+                book.reviews_changed_at = None;
+                book.hold_count = 0;
+                book.available_copies = copies;
+                repository::insert_sorted(books, book);
+            }
+            summary.books_restored += 1;
+        }
+        for book in books.iter_mut() {
+            book.set_holds(book.hold_count, data.config.holds_reserve_copies);
+        }
+        drop((trash, next_id));
+        if summary.books_restored > 0 {
+            catalog_changed(data, books);
+        }
+        summary
+    })
+}
+
+// The next id of a sequence: the saved one, unless the file holds a record at or past it
+fn next_after(saved: u32, ids: impl Iterator<Item = u32>) -> u32 {
+    ids.map(|id| id + 1).fold(saved.max(1), u32::max)
+}
+
+// Replaces everything in `data` with the snapshot. Hold counts are recounted from the
+// holds, and every counter is moved past the highest id in the snapshot but never back,
+// so ids handed out since a backup was taken aren't handed out again. `catalog`
+// false leaves the books as they are, for a data file whose catalog is saved elsewhere.
+fn restore_snapshot(data: &AppState, snapshot: Snapshot, catalog: bool) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for stored in snapshot.books.iter().chain(&snapshot.trash) {
+        if !ids.insert(stored.book.id) {
+            return Err(format!("book id {} appears more than once", stored.book.id));
+        }
+    }
+    
+    let restored = data.books.write_with(|books| {
+        // The catalog stays as its own store has it; the file only supplies the trash
+        if !catalog {
+            let clash = snapshot.trash.iter().find(|t| books.iter().any(|b| b.id == t.book.id));
+            if let Some(clash) = clash {
+                return Err(format!(
+                    "book id {} is both in the catalog and in the trash",
+                    clash.book.id
+                ));
+            }
+        }
+        let mut trash = data.trash.lock().unwrap();
+        let mut next_id = data.next_id.lock().unwrap();
+        let mut reviews = data.reviews.lock().unwrap();
+        let mut next_review_id = data.next_review_id.lock().unwrap();
+        let mut loans = data.loans.lock().unwrap();
+        let mut next_loan_id = data.next_loan_id.lock().unwrap();
+        let mut holds = data.holds.lock().unwrap();
+        let mut members = data.members.lock().unwrap();
+        let mut next_member_id = data.next_member_id.lock().unwrap();
+        let mut branches = data.branches.lock().unwrap();
+        let mut next_branch_id = data.next_branch_id.lock().unwrap();
+        let mut transfers = data.transfers.lock().unwrap();
+        let mut next_transfer_id = data.next_transfer_id.lock().unwrap();
+        let mut schedules = data.schedules.lock().unwrap();
+        let mut next_schedule_id = data.next_schedule_id.lock().unwrap();
+        let mut lists = data.lists.lock().unwrap();
+        let mut next_list_id = data.next_list_id.lock().unwrap();
+    
+        if catalog {
+            *books = snapshot.books.into_iter().map(StoredBook::into_book).collect();
+        }
+        *trash = snapshot.trash.into_iter().map(StoredBook::into_book).collect();
+        for book in books.iter_mut().chain(trash.iter_mut()) {
+            let count = snapshot.holds.iter().filter(|h| h.book_id == book.id).count() as u32;
+            book.set_holds(count, data.config.holds_reserve_copies);
+        }
+        let book_ids = ids.into_iter().chain(books.iter().map(|b| b.id));
+        *next_id = next_after(snapshot.next_id.max(*next_id), book_ids);
+        *next_review_id = next_after(
+            snapshot.next_review_id.max(*next_review_id),
+            snapshot.reviews.iter().map(|r| r.id),
+        );
+        *reviews = snapshot.reviews;
+        *next_loan_id = next_after(
+            snapshot.next_loan_id.max(*next_loan_id),
+            snapshot.loans.iter().map(|l| l.id),
+        );
+        *loans = LoanLedger::default();
+        for loan in snapshot.loans {
+            loans.push(loan);
+        }
+        *holds = snapshot.holds;
+        *next_member_id = next_after(
+            snapshot.next_member_id.max(*next_member_id),
+            snapshot.members.iter().map(|m| m.id),
+        );
+        *members = snapshot.members;
+        *next_branch_id = next_after(
+            snapshot.next_branch_id.max(*next_branch_id),
+            snapshot.branches.iter().map(|b| b.id),
+        );
+        *branches = snapshot.branches;
+        *next_transfer_id = next_after(
+            snapshot.next_transfer_id.max(*next_transfer_id),
+            snapshot.transfers.iter().map(|t| t.id),
+        );
+        *transfers = snapshot.transfers;
+        *next_schedule_id = next_after(
+            snapshot.next_schedule_id.max(*next_schedule_id),
+            snapshot.schedules.iter().map(|c| c.id),
+        );
+        *schedules = snapshot.schedules;
+        *next_list_id = next_after(
+            snapshot.next_list_id.max(*next_list_id),
+            snapshot.lists.iter().map(|l| l.id),
+        );
+        *lists = snapshot.lists;
+    
+        drop((trash, next_id, reviews, next_review_id, loans, next_loan_id, holds, members));
+        drop((next_member_id, branches, next_branch_id, transfers, next_transfer_id));
+        drop((schedules, next_schedule_id, lists, next_list_id));
+        catalog_changed(data, books);
+        Ok(())
+    });
+    restored.map_err(|err| err.to_string()).and_then(|restored| restored)
+}
+
+// Whether to encrypt the data file, from DATA_FILE_KEY, or DATA_FILE_KEY_FILE naming a file
+// that holds the key, and DATA_FILE_CONVERT to switch an existing file over. The key is
+// never taken from the command line, where other users of the machine could see it.
+fn data_file_encryption() -> Result<storage::Encryption, String> {
+    let key = std::env::var("DATA_FILE_KEY").ok().filter(|key| !key.is_empty());
+    let key_file = cli_option("data-file-key-file")
+        .or_else(|| std::env::var("DATA_FILE_KEY_FILE").ok())
+        .filter(|path| !path.is_empty());
+    let key = match (key, key_file) {
+        (Some(_), Some(_)) => {
+            return Err("set DATA_FILE_KEY or DATA_FILE_KEY_FILE, not both".to_string())
+        }
+        (Some(key), None) => {
+            Some(encryption::Key::from_hex(&key).map_err(|err| format!("DATA_FILE_KEY: {}", err))?)
+        }
+        (None, Some(path)) => {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|err| format!("could not read the key file {}: {}", path, err))?;
+            Some(encryption::Key::from_hex(&text).map_err(|err| format!("{}: {}", path, err))?)
+        }
+        (None, None) => None,
+    };
+    let convert = std::env::var("DATA_FILE_CONVERT").ok().filter(|value| !value.is_empty());
+    match (convert.as_deref(), key) {
+        (None, None) => Ok(storage::Encryption::Off),
+        (None, Some(key)) => Ok(storage::Encryption::On(key)),
+        (Some("encrypt"), Some(key)) => Ok(storage::Encryption::Encrypting(key)),
+        (Some("decrypt"), Some(key)) => Ok(storage::Encryption::Decrypting(key)),
+        (Some("encrypt" | "decrypt"), None) => Err(
+            "DATA_FILE_CONVERT needs the key, in DATA_FILE_KEY or DATA_FILE_KEY_FILE".to_string(),
+        ),
+        (Some(_), _) => Err("DATA_FILE_CONVERT must be encrypt or decrypt".to_string()),
+    }
+}
+
+// Adds the books of the seed file, an array of create bodies, as POST /api/books would:
+// each entry is validated, and checked for a branch and for an ISBN taken by an earlier
+// entry, before any book is added, and any failure names the entry. The books go into an
+// empty catalog only, unless `force`, which skips those whose ISBN the catalog holds.
+// Returns how many books were added and how many skipped, or None for a catalog that
+// already held books.
+fn load_seed_file(
+    data: &AppState,
+    path: &std::path::Path,
+    force: bool,
+) -> Result<Option<(usize, usize)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("could not read the seed file {}: {}", path.display(), err))?;
+    let entries: Vec<CreateBookRequest> = serde_json::from_str(&text)
+        .map_err(|err| format!("{} is not a valid seed file: {}", path.display(), err))?;
+    let mut seeds: Vec<Book> = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let failed = |error: String| format!("{} entry {}: {}", path.display(), index, error);
+        let book = book_from_request(entry, data.clock.now()).map_err(|errors| failed(errors.into()))?;
+        check_branch(data, book.branch_id).map_err(failed)?;
+        if let Some(earlier) = seeds.iter().position(|b| isbn::equivalent(&b.isbn, &book.isbn)) {
+            let error = format!("Duplicate ISBN within the seed file (same as entry {})", earlier);
+            return Err(failed(error));
+        }
+        seeds.push(book);
+    }
+    let added = data.books.write_with(|books| {
+        if !force && !books.is_empty() {
+            return None;
+        }
+        let mut next_id = data.next_id.lock().unwrap();
+        let (mut added, mut skipped) = (0, 0);
+        for mut book in seeds {
+            if books.iter().any(|b| isbn::equivalent(&b.isbn, &book.isbn)) {
+                skipped += 1;
+                continue;
+            }
+            book.id = *next_id;
+            *next_id += 1;
+            books.push(book);
+            added += 1;
+        }
+        catalog_changed(data, books);
+        Some((added, skipped))
+    });
+    added.map_err(|err| format!("could not add the seed books: {}", err))
+}
+
+// The catalog store DATABASE_URL or OPERATION_LOG names, or the in-memory one holding
+// `seed` without either. The URL may carry a password, so errors name only its scheme.
+fn open_books(config: &Config, seed: Vec<Book>) -> Result<Arc<dyn BookRepository>, String> {
+    let url = match (config.database_url.as_deref(), &config.operation_log) {
+        (Some(_), Some(_)) => {
+            return Err("set DATABASE_URL or OPERATION_LOG for the catalog, not both".to_string())
+        }
+        (None, Some(path)) => {
+            let compact_at = Some(config.operation_log_compact_bytes).filter(|&bytes| bytes > 0);
+            return Ok(oplog::LoggedBooks::open(path, seed, compact_at)?);
+        }
+        (None, None) => return Ok(Arc::new(InMemoryBooks::new(seed))),
+        (Some(url), None) => url,
+    };
+    match url.split_once("://") {
+        Some(("sqlite", path)) => open_sqlite(path, seed),
+        Some(("postgres" | "postgresql", _)) => open_postgres(url, config, seed),
+        _ => Err("DATABASE_URL must start with sqlite:// or postgres://".to_string()),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &str, seed: Vec<Book>) -> Result<Arc<dyn BookRepository>, String> {
+    Ok(Arc::new(sqlite::SqliteBooks::open(path, seed)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_path: &str, _seed: Vec<Book>) -> Result<Arc<dyn BookRepository>, String> {
+    Err(without_feature("an SQLite", "sqlite"))
+}
+
+#[cfg(feature = "postgres")]
+fn open_postgres(
+    url: &str,
+    config: &Config,
+    seed: Vec<Book>,
+) -> Result<Arc<dyn BookRepository>, String> {
+    let books = postgresql::PostgresBooks::open(url, config.database_pool_size, seed)?;
+    Ok(Arc::new(books))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn open_postgres(
+    _url: &str,
+    _config: &Config,
+    _seed: Vec<Book>,
+) -> Result<Arc<dyn BookRepository>, String> {
+    Err(without_feature("a PostgreSQL", "postgres"))
+}
+
+#[cfg(not(all(feature = "sqlite", feature = "postgres")))]
+fn without_feature(database: &str, feature: &str) -> String {
+    format!(
+        "DATABASE_URL names {} database, but this server was built without the {} feature; \
+         rebuild it with --features {}",
+        database, feature, feature
+    )
+}
+
+// Writes the library to the data file if anything changed since the last write
+fn flush(data: &AppState) -> Result<(), String> {
+    match &data.store {
+        // With a database or an operation log the catalog is saved there, change by change
+        Some(store) => store.flush(|| take_snapshot(data, !data.config.catalog_kept_apart())),
+        None => Ok(()),
+    }
+}
+
+// Notes that the library changed. With a flush interval the write is left to the flusher,
+// and the error is that of the last write, if it failed; without one the change is written
+// now.
+fn persist(data: &AppState) -> Result<(), String> {
+    let Some(store) = &data.store else {
+        return Ok(());
+    };
+    store.mark_dirty();
+    if data.config.flush_interval.is_zero() {
+        flush(data)
+    } else {
+        store.failure().map_or(Ok(()), Err)
+    }
+}
+
+// For background tasks, which have no response to report a failure in
+fn persist_or_log(data: &AppState) {
+    if let Err(cause) = persist(data) {
+        eprintln!("Persistence failed: {}", cause);
+    }
+}
+
+fn persistence_failed(cause: String) -> HttpResponse {
+    eprintln!("Persistence failed: {}", cause);
+    HttpResponse::InternalServerError().json(ErrorResponse {
+        error: format!(
+            "The change was made but could not be saved, and will be lost if the server stops \
+             before a later save succeeds: {}",
+            cause
+        ),
+    })
+}
+
+fn build_suggestions(books: &[Book]) -> search::SuggestionIndex {
+    search::SuggestionIndex::build(books.iter().flat_map(|b| {
+        std::iter::once((b.title.as_str(), search::SuggestionKind::Title)).chain(
+            b.authors
+                .iter()
+                .map(|a| (a.as_str(), search::SuggestionKind::Author)),
+        )
+    }))
+}
+
+fn build_similar(books: &[Book]) -> similar::Index {
+    similar::Index::build(books.iter().map(|b| similar::BookText {
+        id: b.id,
+        title: &b.title,
+        authors: &b.authors,
+        genre: b.genre.as_deref(),
+        tags: &b.tags,
+    }))
+}
+
+// Called by every handler that changes the catalog, with the books lock still held so
+// neither the index nor the collection ETag can miss a concurrent mutation
+fn catalog_changed(data: &AppState, books: &[Book]) {
+    *data.suggestions.lock().unwrap() = build_suggestions(books);
+    *data.similar.lock().unwrap() = None;
+    *data.catalog_version.lock().unwrap() += 1;
+    *data.catalog_modified.lock().unwrap() = data.clock.now();
+}
+
+// catalog_changed for a change made through the single-book repository calls, which
+// can't run it themselves. The catalog is already changed, so readers may briefly see it
+// under the old ETag; never the old catalog under the new one.
+fn refresh_catalog(data: &AppState) {
+    if let Err(err) = data.books.read_all(&mut |books| catalog_changed(data, books)) {
+        eprintln!("Could not refresh the catalog caches: {}", err);
+    }
+}
+
+// Strong ETag for the book list and search results; read with the books lock held, or
+// before the books are read
+fn catalog_etag(data: &AppState) -> String {
+    format!("\"catalog-{}-{}\"", data.boot_id, *data.catalog_version.lock().unwrap())
+}
+
+// Nanoseconds of the system time at startup, in hex; never the test clock, which may
+// start at the same time on every run
+fn boot_id() -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    format!("{:x}", now.map_or(0, |elapsed| elapsed.as_nanos()))
+}
+
+enum PageSelector {
+    Offset(usize),
+    After(u32),
+}
+
+struct Pagination {
+    selector: PageSelector,
+    limit: usize,
+    // Cursors encode an id, so they only make sense while results are in id order
+    cursor_enabled: bool,
+}
+
+const CURSOR_PREFIX: &str = "after:";
+
+// Cursors are the hex-encoded last seen id so clients treat them as opaque tokens
+fn encode_cursor(last_id: u32) -> String {
+    format!("{}{}", CURSOR_PREFIX, last_id)
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<u32> {
+    if cursor.is_empty() || !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let decoded = String::from_utf8(bytes).ok()?;
+    decoded.strip_prefix(CURSOR_PREFIX)?.parse().ok()
+}
+
+fn parse_pagination(
+    query: &ListQuery,
+    config: &Config,
+    id_ordered: bool,
+) -> Result<Pagination, String> {
+    if query.cursor.is_some() && !id_ordered {
+        return Err("cursor pagination only supports the default id ordering".to_string());
+    }
+    
+    let selector = match (&query.page, &query.cursor) {
+        (Some(_), Some(_)) => return Err("page and cursor cannot be combined".to_string()),
+        (Some(raw), None) => PageSelector::Offset(parse_page(raw)?),
+        (None, Some(raw)) => match decode_cursor(raw) {
+            Some(last_id) => PageSelector::After(last_id),
+            None => return Err(format!("Invalid or malformed cursor '{}'", raw)),
+        },
+        (None, None) => PageSelector::Offset(1),
+    };
+    
+    Ok(Pagination {
+        selector,
+        limit: parse_limit(query.limit.as_deref(), config)?,
+        cursor_enabled: id_ordered,
+    })
+}
+
+fn parse_page(raw: &str) -> Result<usize, String> {
+    match raw.parse::<usize>() {
+        Ok(page) if page >= 1 => Ok(page),
+        _ => Err(format!("page must be a positive integer, got '{}'", raw)),
+    }
+}
+
+fn parse_limit(raw: Option<&str>, config: &Config) -> Result<usize, String> {
+    let Some(raw) = raw else {
+        return Ok(DEFAULT_PAGE_LIMIT.min(config.max_page_limit));
+    };
+    match raw.parse::<usize>() {
+        Ok(limit) if limit >= 1 && limit <= config.max_page_limit => Ok(limit),
+        _ => Err(format!(
+            "limit must be an integer between 1 and {}, got '{}'",
+            config.max_page_limit, raw
+        )),
+    }
+}
+
+fn paginate(books: Vec<Book>, pagination: &Pagination) -> PaginatedResponse<Book> {
+    let page = repository::page_of(books, &BookFilter::default(), &page_request(pagination));
+    paged(page, pagination)
+}
+
+// The books to ask for: one more than the limit, which tells whether another page follows
+fn page_request(pagination: &Pagination) -> PageRequest {
+    let limit = Some(pagination.limit.saturating_add(1));
+    match pagination.selector {
+        PageSelector::Offset(page) => PageRequest {
+            after: None,
+            offset: (page - 1).saturating_mul(pagination.limit),
+            limit,
+        },
+        // Ids are monotonically increasing, so resuming after the last seen id is
+        // stable even when books are created or deleted between requests
+        PageSelector::After(last_id) => PageRequest {
+            after: Some(last_id),
+            offset: 0,
+            limit,
+        },
+    }
+}
+
+// The response for a page fetched with `page_request`
+fn paged(fetched: BookPage, pagination: &Pagination) -> PaginatedResponse<Book> {
+    let total = fetched.total;
+    let page = match pagination.selector {
+        PageSelector::Offset(page) => Some(page),
+        PageSelector::After(_) => None,
+    };
+    let has_more = fetched.books.len() > pagination.limit;
+    let items: Vec<Book> = fetched.books.into_iter().take(pagination.limit).collect();
+    let next_cursor = if has_more && pagination.cursor_enabled {
+        items.last().map(|b| encode_cursor(b.id))
+    } else {
+        None
+    };
+    
+    PaginatedResponse {
+        items,
+        page,
+        limit: pagination.limit,
+        total,
+        next_cursor,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortField {
+    Id,
+    Title,
+    Author,
+    Isbn,
+    Available,
+}
+
+const SORT_FIELDS: &[(&str, SortField)] = &[
+    ("id", SortField::Id),
+    ("title", SortField::Title),
+    ("author", SortField::Author),
+    ("isbn", SortField::Isbn),
+    ("available", SortField::Available),
+];
+
+struct SortKey {
+    field: SortField,
+    descending: bool,
+}
+
+impl SortKey {
+    fn is_id_ascending(&self) -> bool {
+        self.field == SortField::Id && !self.descending
+    }
+}
+
+// Parses a comma-separated list of sort keys, applied left-to-right
+fn parse_sort(raw: Option<&str>) -> Result<Option<Vec<SortKey>>, String> {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    
+    let mut keys: Vec<SortKey> = Vec::new();
+    for part in raw.split(',') {
+        let (name, descending) = match part.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (part, false),
+        };
+        
+        let field = match SORT_FIELDS.iter().find(|(field_name, _)| *field_name == name) {
+            Some((_, field)) => *field,
+            None => {
+                return Err(format!(
+                    "Unknown sort field '{}'. Valid options: {}",
+                    name,
+                    SORT_FIELDS
+                        .iter()
+                        .map(|(field_name, _)| *field_name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        };
+        
+        if keys.iter().any(|k| k.field == field) {
+            return Err(format!("Duplicate sort field '{}'", name));
+        }
+        keys.push(SortKey { field, descending });
+    }
+    
+    Ok(Some(keys))
+}
+
+fn is_id_ordered(sort: &Option<Vec<SortKey>>) -> bool {
+    match sort {
+        Some(keys) => keys.first().is_none_or(SortKey::is_id_ascending),
+        None => true,
+    }
+}
+
+fn compare_books(a: &Book, b: &Book, field: SortField) -> std::cmp::Ordering {
+    match field {
+        SortField::Id => a.id.cmp(&b.id),
+        SortField::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        SortField::Author => a
+            .author_line()
+            .to_lowercase()
+            .cmp(&b.author_line().to_lowercase()),
+        SortField::Isbn => a.isbn.to_lowercase().cmp(&b.isbn.to_lowercase()),
+        SortField::Available => a.available.cmp(&b.available),
+    }
+}
+
+// Ties on every requested key fall back to ascending id so ordering is deterministic
+fn sort_books(books: &mut [Book], keys: &[SortKey]) {
+    books.sort_by(|a, b| {
+        keys.iter()
+            .map(|key| {
+                let ordering = compare_books(a, b, key.field);
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.id.cmp(&b.id))
+    });
+}
+
+// Field names accepted by ?fields=, in serialization order
+const BOOK_FIELDS: &[&str] = &[
+    "id",
+    "title",
+    "authors",
+    "isbn",
+    "available",
+    "total_copies",
+    "available_copies",
+    "published_year",
+    "genre",
+    "publisher",
+    "page_count",
+    "language",
+    "description",
+    "shelf_location",
+    "branch_id",
+    "condition",
+    "cover_url",
+    "series",
+    "series_index",
+    "edition",
+    "format",
+    "tags",
+    "checked_out_copies",
+    "hold_count",
+    "review_count",
+    "average_rating",
+    "created_at",
+    "updated_at",
+    "version",
+    "url",
+];
+
+fn parse_fields(raw: Option<&str>) -> Result<Option<Vec<String>>, String> {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    
+    let mut fields = Vec::new();
+    for name in raw.split(',').map(str::trim) {
+        // Pre-`authors` field name, still accepted for older clients
+        let name = if name == "author" { "authors" } else { name };
+        if !BOOK_FIELDS.contains(&name) {
+            return Err(format!(
+                "Unknown field '{}'. Valid options: {}",
+                name,
+                BOOK_FIELDS.join(", ")
+            ));
+        }
+        fields.push(name.to_string());
+    }
+    
+    Ok(Some(fields))
+}
+
+struct ListOptions {
+    sort: Option<Vec<SortKey>>,
+    pagination: Pagination,
+    fields: Option<Vec<String>>,
+    compat: Compat,
+}
+
+// `default_id_ordered` is false when results without an explicit sort come back in
+// some other order (such as best match first), which disables cursor pagination
+fn parse_list_options(
+    query: &ListQuery,
+    config: &Config,
+    default_id_ordered: bool,
+) -> Result<ListOptions, String> {
+    let sort = parse_sort(query.sort.as_deref())?;
+    let id_ordered = match &sort {
+        Some(_) => is_id_ordered(&sort),
+        None => default_id_ordered,
+    };
+    let pagination = parse_pagination(query, config, id_ordered)?;
+    let fields = parse_fields(query.fields.as_deref())?;
+    let compat = parse_compat(query.compat.as_deref())?;
+    Ok(ListOptions {
+        sort,
+        pagination,
+        fields,
+        compat,
+    })
+}
+
+// Sorts, paginates, and projects the matching books into the list response body.
+// `decorate` can attach extra per-item keys (such as a match score) after projection.
+fn list_response(
+    mut books: Vec<Book>,
+    options: &ListOptions,
+    decorate: impl Fn(&Book, &mut serde_json::Map<String, serde_json::Value>),
+) -> PaginatedResponse<serde_json::Value> {
+    if let Some(sort) = &options.sort {
+        sort_books(&mut books, sort);
+    }
+    project_page(paginate(books, &options.pagination), options, decorate)
+}
+
+fn project_page(
+    page: PaginatedResponse<Book>,
+    options: &ListOptions,
+    decorate: impl Fn(&Book, &mut serde_json::Map<String, serde_json::Value>),
+) -> PaginatedResponse<serde_json::Value> {
+    page.map(|b| {
+        let mut value = project_book(&b, &options.fields, options.compat);
+        if let Some(map) = value.as_object_mut() {
+            truncate_description(map);
+            decorate(&b, map);
+        }
+        value
+    })
+}
+
+// Keeps list payloads small; `description_truncated` tells clients to fetch the full book
+fn truncate_description(item: &mut serde_json::Map<String, serde_json::Value>) {
+    let truncated = match item.get_mut("description") {
+        Some(serde_json::Value::String(description)) => {
+            match description.char_indices().nth(DESCRIPTION_PREVIEW_LEN) {
+                Some((cut, _)) => {
+                    description.truncate(cut);
+                    true
+                }
+                None => false,
+            }
+        }
+        _ => return,
+    };
+    item.insert(
+        "description_truncated".to_string(),
+        serde_json::Value::Bool(truncated),
+    );
+}
+
+// serde_json only names the field for these errors; type mismatches carry just a position
+fn failed_field(message: &str) -> Option<String> {
+    ["missing field `", "unknown field `", "duplicate field `"]
+        .iter()
+        .find_map(|prefix| message.split_once(prefix))
+        .and_then(|(_, rest)| rest.split_once('`'))
+        .map(|(field, _)| field.to_string())
+}
+
+// JSON counterpart of query_error_handler, so malformed bodies also get a JSON error.
+// The status stays actix's own: 413 for an oversized body, 400 for everything else.
+fn json_error_handler(
+    err: actix_web::error::JsonPayloadError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    use actix_web::error::JsonPayloadError;
+    use actix_web::ResponseError;
+    
+    let body = match &err {
+        JsonPayloadError::Deserialize(e) if e.is_eof() && e.line() == 1 && e.column() == 0 => {
+            BodyErrorResponse {
+                error: "Request body is empty; expected a JSON object".to_string(),
+                field: None,
+                line: None,
+                column: None,
+            }
+        }
+        JsonPayloadError::Deserialize(e) => BodyErrorResponse {
+            error: format!("Invalid request body: {}", e),
+            field: failed_field(&e.to_string()),
+            line: Some(e.line()),
+            column: Some(e.column()),
+        },
+        JsonPayloadError::OverflowKnownLength { limit, .. } | JsonPayloadError::Overflow { limit } => {
+            BodyErrorResponse {
+                error: format!("Request body exceeds the limit of {} bytes", limit),
+                field: None,
+                line: None,
+                column: None,
+            }
+        }
+        JsonPayloadError::ContentType => BodyErrorResponse {
+            error: "Content-Type must be application/json".to_string(),
+            field: None,
+            line: None,
+            column: None,
+        },
+        other => BodyErrorResponse {
+            error: format!("Invalid request body: {}", other),
+            field: None,
+            line: None,
+            column: None,
+        },
+    };
+    let response = HttpResponse::build(err.status_code()).json(body);
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+fn query_error_handler(
+    err: actix_web::error::QueryPayloadError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    let detail = match &err {
+        actix_web::error::QueryPayloadError::Deserialize(e) => e.to_string(),
+        other => other.to_string(),
+    };
+    let response = HttpResponse::BadRequest().json(ErrorResponse {
+        error: format!("Invalid query parameter: {}", detail),
+    });
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+// A path parameter that doesn't parse, like the id in `/api/books/abc`, names nothing
+// there is, so it is a JSON 404 like any other missing resource
+fn path_error_handler(
+    err: actix_web::error::PathError,
+    req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    let detail = match &err {
+        actix_web::error::PathError::Deserialize(e) => e.to_string(),
+        other => other.to_string(),
+    };
+    let response = HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("No resource at {}: {}", req.path(), detail),
+    });
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+// Answers every path that no route matches
+async fn no_route(req: actix_web::HttpRequest) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("No resource at {}", req.path()),
+    })
+}
+
+// Registers `path` for `methods` (which must list every method routed on it, in the same
+// spelling as the Allow header) and answers any other method with a JSON 405
+fn resource(path: &str, methods: &'static [&'static str]) -> actix_web::Resource {
+    web::resource(path).default_service(web::to(move |req: actix_web::HttpRequest| async move {
+        let allowed = methods.join(", ");
+        HttpResponse::MethodNotAllowed()
+            .insert_header((actix_web::http::header::ALLOW, allowed.clone()))
+            .json(ErrorResponse {
+                error: format!(
+                    "Method {} is not allowed on {}; allowed methods: {}",
+                    req.method(),
+                    req.path(),
+                    allowed
+                ),
+            })
+    }))
+}
+
+// Response shape selected with ?compat=. V1 is the pre-`authors` shape, with the
+// author list emitted as a single joined `author` string.
+#[derive(Clone, Copy, PartialEq)]
+enum Compat {
+    Current,
+    V1,
+}
+
+fn parse_compat(raw: Option<&str>) -> Result<Compat, String> {
+    match raw {
+        None => Ok(Compat::Current),
+        Some("v1") => Ok(Compat::V1),
+        Some(other) => Err(format!("compat must be 'v1', got '{}'", other)),
+    }
+}
+
+// Canonical path of a book, as sent in `Location` and the `url` field
+fn book_path(id: u32) -> String {
+    format!("{}/books/{}", API_PREFIX, id)
+}
+
+// Serializes a book keeping only the selected fields; keys stay in struct order
+fn project_book(book: &Book, fields: &Option<Vec<String>>, compat: Compat) -> serde_json::Value {
+    let mut value = serde_json::to_value(book).unwrap_or(serde_json::Value::Null);
+    if let Some(map) = value.as_object_mut() {
+        map.insert("url".to_string(), serde_json::json!(book_path(book.id)));
+        if let Some(fields) = fields {
+            map.retain(|key, _| fields.iter().any(|f| f == key));
+        }
+        if compat == Compat::V1 {
+            // Rebuilt rather than renamed in place so `author` keeps the position of `authors`
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, value)| match key.as_str() {
+                    "authors" => ("author".to_string(), serde_json::json!(book.author_line())),
+                    _ => (key, value),
+                })
+                .collect();
+        }
+    }
+    value
+}
+
+// Also reports request limits, so integrators can discover them
+// With a database behind the catalog, also reports whether it answers, and a 503 when not
+async fn health_check(data: web::Data<AppState>) -> impl Responder {
+    let database = data.books.health().map(|checked| {
+        checked
+            .map_err(|err| eprintln!("Database health check failed: {}", err))
+            .is_ok()
+    });
+    let healthy = database != Some(false);
+    let mut body = serde_json::json!({
+        "status": if healthy { "healthy" } else { "unhealthy" },
+        "service": "book-library-api",
+        "limits": {
+            "max_body_bytes": data.config.max_body_bytes,
+            "max_batch_body_bytes": data.config.max_batch_body_bytes,
+            "max_batch_size": MAX_BATCH_SIZE,
+            "max_page_limit": data.config.max_page_limit
+        }
+    });
+    if let Some(connected) = database {
+        body["database"] = serde_json::json!(if connected { "connected" } else { "unreachable" });
+    }
+    // How far the data file lags behind: changes since `last_saved_at` are only in memory
+    if let Some(store) = &data.store {
+        body["persistence"] = serde_json::json!({
+            "dirty": store.is_dirty(),
+            "last_saved_at": store.last_saved_at()
+        });
+    }
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// Counters for monitoring that don't belong in the health check
+async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "notifications": {
+            "sent": data.notifications.sent(),
+            "failed": data.notifications.failed()
+        }
+    }))
+}
+
+// Sends in the background, so the request that triggered it neither waits for delivery
+// nor fails with it
+fn notify_later(data: &web::Data<AppState>, recipient: String, subject: String, body: String) {
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        notify::send(&*data.notifier, &data.notifications, &recipient, &subject, &body).await;
+    });
+}
+
+// Reminds the borrower of every open loan due within the next 24 hours, once per due
+// date. Returns how many reminders were sent; run by the background reminder task.
+async fn send_due_reminders(data: &web::Data<AppState>) -> usize {
+    let now = data.clock.now();
+    let mut reminders = Vec::new();
+    let collected = data.books.read_with(|books| {
+        let mut loans = data.loans.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        for loan in loans.iter_mut() {
+            let due_soon = loan.is_open()
+                && loan.due_at > now
+                && loan.due_at <= now + chrono::Duration::hours(24);
+            if !due_soon || loan.reminded_for == Some(loan.due_at) {
+                continue;
+            }
+            let Some(member) = members.iter().find(|m| Some(m.id) == loan.member_id) else {
+                continue;
+            };
+            let title = books
+                .iter()
+                .find(|b| b.id == loan.book_id)
+                .map_or_else(|| format!("book {}", loan.book_id), |b| b.title.clone());
+            loan.reminded_for = Some(loan.due_at);
+            reminders.push((
+                member.email.clone(),
+                format!("\"{}\" is due tomorrow", title),
+                format!(
+                    "Hi {}, your loan of \"{}\" is due back by {}.",
+                    member.name,
+                    title,
+                    loan.due_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                ),
+            ));
+        }
+    });
+    if let Err(err) = collected {
+        eprintln!("Could not look for due loans: {}", err);
+        return 0;
+    }
+    for (recipient, subject, body) in &reminders {
+        notify::send(&*data.notifier, &data.notifications, recipient, subject, body).await;
+    }
+    reminders.len()
+}
+
+async fn get_books(
+    req: actix_web::HttpRequest,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let options = match parse_list_options(&query, &data.config, true) {
+        Ok(options) => options,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    // Read before the books: a change lands in the catalog before its new ETag, so this
+    // way the ETag sent is never newer than the books
+    let tag = catalog_etag(&data);
+    let modified = *data.catalog_modified.lock().unwrap();
+    let now = data.clock.now();
+    if none_match(&req, &tag) || not_modified_since(&req, modified, now) {
+        return not_modified(tag, modified, now);
+    }
+    // In id order the store can cut out the page itself, which a database does in its query
+    let response = if is_id_ordered(&options.sort) {
+        let request = page_request(&options.pagination);
+        data.books.page(&BookFilter::default(), &request).map(|page| {
+            project_page(paged(page, &options.pagination), &options, |_, _| {})
+        })
+    } else {
+        data.books.list().map(|books| list_response(books, &options, |_, _| {}))
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => return repository_error(err),
+    };
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header((actix_web::http::header::ETAG, tag))
+        .insert_header((TOTAL_COUNT_HEADER, response.total));
+    if let Some(header) = last_modified(modified, now) {
+        builder.insert_header(header);
+    }
+    builder.json(response)
+}
+
+async fn get_book_by_id(
+    req: actix_web::HttpRequest,
+    path: web::Path<u32>,
+    query: web::Query<FieldsQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    let fields = match parse_fields(query.fields.as_deref()) {
+        Ok(fields) => fields,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let include_notes = match query.include.as_deref() {
+        None => false,
+        Some("notes") if is_staff(&req) => true,
+        Some("notes") => return staff_only(),
+        Some(other) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("include must be 'notes', got '{}'", other),
+            })
+        }
+    };
+    
+    let now = data.clock.now();
+    match data.books.get(book_id) {
+        // The ETag doesn't cover notes, so a staff view is never answered with 304
+        Ok(book)
+            if !include_notes
+                && (none_match(&req, &book_etag(&book))
+                    || not_modified_since(&req, book.last_modified(), now)) =>
+        {
+            not_modified(book_etag(&book), book.last_modified(), now)
+        }
+        Ok(book) => {
+            let mut builder = HttpResponse::Ok();
+            builder.insert_header(etag(&book));
+            if let Some(header) = last_modified(book.last_modified(), now) {
+                builder.insert_header(header);
+            }
+            let mut body = project_book(&book, &fields, compat);
+            if include_notes {
+                staff_view(&mut body, &book);
+            }
+            builder.json(body)
+        }
+        Err(err) => repository_error(err),
+    }
+}
+
+fn is_staff(req: &actix_web::HttpRequest) -> bool {
+    req.headers()
+        .get(STAFF_HEADER)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"))
+}
+
+fn staff_only() -> HttpResponse {
+    HttpResponse::Forbidden().json(ErrorResponse {
+        error: format!("Notes are only available to staff ({}: true)", STAFF_HEADER),
+    })
+}
+
+// Adds what only staff may see to a book's public projection
+fn staff_view(body: &mut serde_json::Value, book: &Book) {
+    if let Some(map) = body.as_object_mut() {
+        map.insert("notes".to_string(), serde_json::json!(book.notes));
+    }
+}
+
+fn validate_notes(raw: &str) -> Result<String, String> {
+    let notes = text::clean_multiline("Notes", raw)?;
+    if notes.is_empty() {
+        return Err("Notes cannot be empty; send null to clear them".to_string());
+    }
+    validate_length("Notes", &notes, MAX_NOTES_LEN)?;
+    Ok(notes)
+}
+
+// Notes are internal, so setting them doesn't change the book's `version`, `updated_at`,
+// or ETag
+async fn put_notes(
+    req: actix_web::HttpRequest,
+    path: web::Path<u32>,
+    notes_req: web::Json<NotesRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_staff(&req) {
+        return staff_only();
+    }
+    let notes = match notes_req.notes.as_deref().map(validate_notes).transpose() {
+        Ok(notes) => notes,
+        Err(message) => return validation_failed(validation::Errors::new("notes", message)),
+    };
+    let book_id = path.into_inner();
+    let mut notes = Some(notes);
+    match data.books.update(book_id, &mut |book| book.notes = notes.take().flatten()) {
+        Ok(book) => HttpResponse::Ok().json(NotesResponse {
+            book_id,
+            notes: &book.notes,
+        }),
+        Err(err) => repository_error(err),
+    }
+}
+
+async fn get_book_by_isbn(
+    path: web::Path<String>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let raw_isbn = path.into_inner();
+    let normalized_isbn = isbn::normalize(&raw_isbn);
+    match data.books.find_by_isbn(&normalized_isbn) {
+        Ok(book) => HttpResponse::Ok().json(project_book(&book, &None, compat)),
+        // Reported as the client wrote it, not normalized
+        Err(RepositoryError::IsbnNotFound(_)) => {
+            repository_error(RepositoryError::IsbnNotFound(raw_isbn))
+        }
+        Err(err) => repository_error(err),
+    }
+}
+
+#[derive(Serialize)]
+struct IsbnConversion {
+    isbn10: Option<String>,
+    isbn13: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+async fn convert_isbn(query: web::Query<IsbnConvertQuery>) -> impl Responder {
+    let normalized = match &query.value {
+        Some(raw) => isbn::normalize(raw),
+        None => String::new(),
+    };
+    if normalized.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "value is required".to_string(),
+        });
+    }
+    if let Err(error) = isbn::validate(&normalized) {
+        return HttpResponse::BadRequest().json(ErrorResponse { error });
+    }
+    
+    let isbn10 = isbn::to_isbn10(&normalized);
+    let note = match isbn10 {
+        Some(_) => None,
+        None => Some("ISBN-13s with the 979 prefix have no ISBN-10 equivalent".to_string()),
+    };
+    HttpResponse::Ok().json(IsbnConversion {
+        isbn10,
+        isbn13: isbn::to_isbn13(&normalized),
+        note,
+    })
+}
+
+// Years before printing or more than a year ahead are almost certainly typos
+fn validate_title(raw: &str) -> Result<String, String> {
+    let title = text::clean("Title", raw)?;
+    if title.is_empty() {
+        return Err("Title cannot be empty".to_string());
+    }
+    validate_length("Title", &title, MAX_TITLE_LEN)?;
+    Ok(title)
+}
+
+// Returns the ISBN normalized
+fn validate_isbn(raw: &str) -> Result<String, String> {
+    let normalized = isbn::normalize(&text::sanitize("ISBN", raw)?);
+    if normalized.is_empty() {
+        return Err("ISBN cannot be empty".to_string());
+    }
+    validate_length("ISBN", &normalized, MAX_ISBN_LEN)?;
+    isbn::validate(&normalized)?;
+    Ok(normalized)
+}
+
+fn validate_genre(raw: &str) -> Result<String, String> {
+    let genre = text::clean("Genre", raw)?;
+    if genre.is_empty() {
+        return Err("Genre cannot be empty".to_string());
+    }
+    Ok(genre)
+}
+
+fn validate_publisher(raw: &str) -> Result<String, String> {
+    let publisher = text::clean("Publisher", raw)?;
+    if publisher.is_empty() {
+        return Err("Publisher cannot be empty".to_string());
+    }
+    Ok(publisher)
+}
+
+fn validate_published_year(year: u16) -> Result<(), String> {
+    let max_year = chrono::Utc::now().year() + 1;
+    if year < MIN_PUBLISHED_YEAR || i32::from(year) > max_year {
+        return Err(format!(
+            "published_year must be between {} and {}",
+            MIN_PUBLISHED_YEAR, max_year
+        ));
+    }
+    Ok(())
+}
+
+// Tags are cleaned and lowercased so "Staff-Pick" and "staff-pick" are the same tag
+fn normalize_tag(raw: &str) -> Result<String, String> {
+    let tag = text::clean("Tag", raw)?.to_lowercase();
+    if tag.is_empty() {
+        return Err("Tags cannot be empty".to_string());
+    }
+    if tag.chars().count() > MAX_TAG_LEN {
+        return Err(format!(
+            "Tag '{}' exceeds the maximum length of {} characters",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    Ok(tag)
+}
+
+// Appends normalized tags that aren't already present, keeping insertion order
+fn merge_tags(existing: &[String], raw: &[String]) -> Result<Vec<String>, String> {
+    let mut tags = existing.to_vec();
+    for raw_tag in raw {
+        let tag = normalize_tag(raw_tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS_PER_BOOK {
+        return Err(format!("A book cannot have more than {} tags", MAX_TAGS_PER_BOOK));
+    }
+    Ok(tags)
+}
+
+fn validate_page_count(page_count: u32) -> Result<(), String> {
+    if !(1..=MAX_PAGE_COUNT).contains(&page_count) {
+        return Err(format!("page_count must be between 1 and {}", MAX_PAGE_COUNT));
+    }
+    Ok(())
+}
+
+// Returns the code lowercased, so "EN" is stored as "en"
+fn validate_language(raw: &str) -> Result<String, String> {
+    let code = text::clean("language", raw)?.to_lowercase();
+    if !language::is_iso_639_1(&code) {
+        return Err(format!(
+            "language must be a two-letter ISO 639-1 code such as 'en' or 'de', got '{}'",
+            raw
+        ));
+    }
+    Ok(code)
+}
+
+// Returns the description cleaned; line breaks inside it are kept
+fn validate_description(raw: &str) -> Result<String, String> {
+    let description = text::clean_multiline("Description", raw)?;
+    if description.is_empty() {
+        return Err("Description cannot be empty".to_string());
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(format!(
+            "Description cannot be longer than {} characters",
+            MAX_DESCRIPTION_LEN
+        ));
+    }
+    Ok(description)
+}
+
+// Lengths count Unicode scalar values (chars), not bytes
+fn validate_length(field: &str, value: &str, max_len: usize) -> Result<(), String> {
+    if value.chars().count() > max_len {
+        return Err(format!(
+            "{} cannot be longer than {} characters",
+            field, max_len
+        ));
+    }
+    Ok(())
+}
+
+// The key author errors are reported under: whichever of the two the client sent
+fn author_field(author: &Option<String>, authors: &Option<Vec<String>>) -> &'static str {
+    if author.is_some() && authors.is_none() {
+        "author"
+    } else {
+        "authors"
+    }
+}
+
+// Accepts either the legacy single `author` string or an `authors` list, not both.
+// Returns the cleaned names, or None when neither is supplied.
+fn resolve_authors(
+    author: &Option<String>,
+    authors: &Option<Vec<String>>,
+) -> Result<Option<Vec<String>>, String> {
+    let authors = match (author, authors) {
+        (Some(_), Some(_)) => {
+            return Err("Provide either author or authors, not both".to_string())
+        }
+        (Some(author), None) => vec![text::clean("Author", author)?],
+        (None, Some(authors)) => authors
+            .iter()
+            .map(|a| text::clean("Author", a))
+            .collect::<Result<_, _>>()?,
+        (None, None) => return Ok(None),
+    };
+    
+    if authors.is_empty() {
+        return Err("At least one author is required".to_string());
+    }
+    if authors.iter().any(|a| a.is_empty()) {
+        return Err("Author cannot be empty".to_string());
+    }
+    for author in &authors {
+        validate_length("Author", author, MAX_AUTHOR_LEN)?;
+    }
+    if authors.len() > MAX_AUTHORS {
+        return Err(format!("A book cannot have more than {} authors", MAX_AUTHORS));
+    }
+    Ok(Some(authors))
+}
+
+// Returns the location cleaned
+// Called with the books lock held, so the branch can't be deleted before the book is stored
+fn check_branch(data: &AppState, branch_id: Option<u32>) -> Result<(), String> {
+    match branch_id {
+        Some(id) if !data.branches.lock().unwrap().iter().any(|b| b.id == id) => {
+            Err(format!("Branch with id {} not found", id))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_shelf_location(raw: &str) -> Result<String, String> {
+    let location = text::clean("shelf_location", raw)?;
+    if location.is_empty() {
+        return Err("shelf_location cannot be empty".to_string());
+    }
+    if location.chars().count() > MAX_SHELF_LOCATION_LEN {
+        return Err(format!(
+            "shelf_location cannot be longer than {} characters",
+            MAX_SHELF_LOCATION_LEN
+        ));
+    }
+    Ok(location)
+}
+
+// Only absolute http(s) URLs with a host, so clients can put the value straight into <img src>
+// Returns the URL sanitized; the url crate would otherwise silently drop embedded tabs
+// and newlines while parsing, accepting a value that differs from what gets stored
+fn validate_cover_url(raw: &str) -> Result<String, String> {
+    let raw = text::sanitize("cover_url", raw)?;
+    if raw.chars().count() > MAX_COVER_URL_LEN {
+        return Err(format!(
+            "cover_url cannot be longer than {} characters",
+            MAX_COVER_URL_LEN
+        ));
+    }
+    let parsed = url::Url::parse(&raw)
+        .map_err(|e| format!("cover_url must be an absolute http(s) URL: {}", e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!(
+            "cover_url must use http or https, got '{}'",
+            parsed.scheme()
+        ));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("cover_url must include a host".to_string());
+    }
+    Ok(raw)
+}
+
+fn validate_series(raw: &str) -> Result<String, String> {
+    let series = text::clean("Series", raw)?;
+    if series.is_empty() {
+        return Err("Series cannot be empty".to_string());
+    }
+    validate_length("Series", &series, MAX_TITLE_LEN)?;
+    Ok(series)
+}
+
+fn validate_edition(raw: &str) -> Result<String, String> {
+    let edition = text::clean("Edition", raw)?;
+    if edition.is_empty() {
+        return Err("Edition cannot be empty".to_string());
+    }
+    validate_length("Edition", &edition, MAX_EDITION_LEN)?;
+    Ok(edition)
+}
+
+// Books with a different ISBN but the same title and the same set of authors, compared
+// cleaned and case-insensitively; often another edition, sometimes a mistyped ISBN
+fn possible_duplicates(books: &[Book], title: &str, authors: &[String]) -> Vec<u32> {
+    let author_key = |authors: &[String]| {
+        let mut slugs: Vec<String> = authors.iter().map(|a| text::slug(a)).collect();
+        slugs.sort();
+        slugs
+    };
+    let title = title.to_lowercase();
+    let authors = author_key(authors);
+    books
+        .iter()
+        .filter(|b| b.title.to_lowercase() == title && author_key(&b.authors) == authors)
+        .map(|b| b.id)
+        .collect()
+}
+
+// Copies on loan are off the shelf, so they count against total_copies as well
+fn validate_loaned_copies(total: u32, available: u32, on_loan: usize) -> Result<(), String> {
+    if available as usize + on_loan > total as usize {
+        return Err(format!(
+            "available_copies ({}) plus the {} copies checked out cannot exceed total_copies ({})",
+            available, on_loan, total
+        ));
+    }
+    Ok(())
+}
+
+// A deliberately loose check: one `@`, something before it, and a dotted domain after
+// it. Whether the address exists is only known by writing to it.
+fn validate_email(raw: &str) -> Result<String, String> {
+    let email = text::clean("Email", raw)?.to_lowercase();
+    if email.is_empty() {
+        return Err("Email cannot be empty".to_string());
+    }
+    validate_length("Email", &email, MAX_EMAIL_LEN)?;
+    let invalid = || format!("Email must look like name@example.com, got '{}'", email);
+    let Some((local, domain)) = email.split_once('@') else {
+        return Err(invalid());
+    };
+    let labels: Vec<&str> = domain.split('.').collect();
+    if local.is_empty()
+        || email.contains(' ')
+        || domain.contains('@')
+        || labels.len() < 2
+        || labels.iter().any(|label| label.is_empty())
+    {
+        return Err(invalid());
+    }
+    Ok(email)
+}
+
+fn validate_member_name(raw: &str) -> Result<String, String> {
+    let name = text::clean("Name", raw)?;
+    if name.is_empty() {
+        return Err("Name cannot be empty".to_string());
+    }
+    validate_length("Name", &name, MAX_MEMBER_NAME_LEN)?;
+    Ok(name)
+}
+
+fn validate_copies(total: u32, available: u32) -> Result<(), String> {
+    if available > total {
+        return Err(format!(
+            "available_copies ({}) cannot exceed total_copies ({})",
+            available, total
+        ));
+    }
+    Ok(())
+}
+
+// What an If-Match header or a body `version` field requires of the current book
+enum Precondition {
+    // Neither was sent, or `If-Match: *`: last write wins
+    Unconditional,
+    Version(u64),
+    // A full ETag from this API, which must equal the current one
+    ETag(String),
+}
+
+impl Precondition {
+    fn holds(&self, book: &Book) -> bool {
+        match self {
+            Precondition::Unconditional => true,
+            Precondition::Version(version) => *version == book.version,
+            Precondition::ETag(tag) => *tag == book_etag(book),
+        }
+    }
+}
+
+// Reads the precondition for a PUT, PATCH, or DELETE. The header takes an ETag
+// (`"<version>-<digest>"`), which only matches the exact current representation, or a
+// bare `"<version>"`, which only compares versions. With REQUIRE_IF_MATCH set, a request
+// that would be unconditional is refused with 428 instead.
+fn parse_precondition(
+    req: &actix_web::HttpRequest,
+    body_version: Option<u64>,
+    config: &Config,
+) -> Result<Precondition, (actix_web::http::StatusCode, String)> {
+    use actix_web::http::StatusCode;
+    
+    let header = match req.headers().get(actix_web::http::header::IF_MATCH) {
+        Some(value) => {
+            let raw = value.to_str().unwrap_or_default().trim();
+            if raw == "*" {
+                None
+            } else {
+                let tag = raw.strip_prefix("W/").unwrap_or(raw).trim_matches('"');
+                let (version, has_digest) = match tag.split_once('-') {
+                    Some((version, _)) => (version, true),
+                    None => (tag, false),
+                };
+                match version.parse::<u64>() {
+                    Ok(version) if has_digest => {
+                        Some((version, Precondition::ETag(format!("\"{}\"", tag))))
+                    }
+                    Ok(version) => Some((version, Precondition::Version(version))),
+                    Err(_) => {
+                        return Err((
+                            StatusCode::BAD_REQUEST,
+                            format!(
+                                "If-Match must be an ETag or a quoted version number such as \"3\", got '{}'",
+                                raw
+                            ),
+                        ))
+                    }
+                }
+            }
+        }
+        None => None,
+    };
+    
+    let precondition = match (header, body_version) {
+        (Some((header, _)), Some(body)) if header != body => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "If-Match version {} does not match body version {}",
+                    header, body
+                ),
+            ))
+        }
+        (Some((_, precondition)), _) => precondition,
+        (None, Some(body)) => Precondition::Version(body),
+        (None, None) => Precondition::Unconditional,
+    };
+    
+    if config.require_if_match && matches!(precondition, Precondition::Unconditional) {
+        return Err((
+            StatusCode::PRECONDITION_REQUIRED,
+            "This server requires If-Match with the book's current ETag (or a body version) on PUT, PATCH, and DELETE".to_string(),
+        ));
+    }
+    Ok(precondition)
+}
+
+fn validation_failed(errors: validation::Errors) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(ValidationErrorResponse {
+        error: errors.to_string(),
+        errors: errors.into_vec(),
+    })
+}
+
+// The one place repository errors become responses
+fn repository_error(err: RepositoryError) -> HttpResponse {
+    match err {
+        RepositoryError::NotFound(id) => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("Book with id {} not found", id),
+        }),
+        RepositoryError::IsbnNotFound(isbn) => HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("Book with ISBN {} not found", isbn),
+        }),
+        RepositoryError::DuplicateIsbn { .. } => HttpResponse::Conflict().json(ErrorResponse {
+            error: "Book with this ISBN already exists".to_string(),
+        }),
+        RepositoryError::Changed { id, version } => {
+            HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Book with id {} changed while the request was handled (now version {}); \
+                     try again",
+                    id, version
+                ),
+            })
+        }
+        RepositoryError::Unavailable(cause) => {
+            eprintln!("Book repository failed: {}", cause);
+            HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                error: "The catalog is unavailable; try again later".to_string(),
+            })
+        }
+    }
+}
+
+fn precondition_failed(book: &Book) -> HttpResponse {
+    HttpResponse::PreconditionFailed().json(PreconditionFailedResponse {
+        error: format!(
+            "Book with id {} has been modified; current version is {}",
+            book.id, book.version
+        ),
+        current_version: book.version,
+        current_etag: book_etag(book),
+    })
+}
+
+fn etag(book: &Book) -> (actix_web::http::header::HeaderName, String) {
+    (actix_web::http::header::ETAG, book_etag(book))
+}
+
+// `"<version>-<digest>"`. The digest covers the whole record, so the tag also changes when
+// the review summary does, which leaves `version` alone; the version prefix keeps the tag
+// usable in If-Match. The digest is the first 8 bytes of the record's SHA-256, which is the
+// same on every build and every run, so a tag a client cached before an upgrade or a
+// restart still matches; std's hasher makes no such promise.
+fn book_etag(book: &Book) -> String {
+    use sha2::Digest;
+    let json = serde_json::to_vec(book).unwrap_or_default();
+    let digest = sha2::Sha256::digest(&json);
+    let hex: String = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}-{}\"", book.version, hex)
+}
+
+// Whether If-None-Match lists `tag` (or is `*`). Compared weakly, as RFC 9110 requires
+// for this header, so `W/` prefixes are ignored.
+fn none_match(req: &actix_web::HttpRequest, tag: &str) -> bool {
+    req.headers()
+        .get_all(actix_web::http::header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == tag)
+}
+
+// HTTP dates only have whole seconds, so a second that hasn't ended yet could still see
+// another change that the date can't tell apart. Last-Modified is only sent, and
+// If-Modified-Since only trusted, once `modified` lies in a second that is over by `now`.
+fn settled(modified: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>) -> bool {
+    modified.timestamp() < now.timestamp()
+}
+
+fn last_modified(
+    modified: chrono::DateTime<chrono::Utc>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<(actix_web::http::header::HeaderName, String)> {
+    settled(modified, now).then(|| {
+        let date = actix_web::http::header::HttpDate::from(std::time::SystemTime::from(modified));
+        (actix_web::http::header::LAST_MODIFIED, date.to_string())
+    })
+}
+
+// Whether If-Modified-Since shows the client already has the version changed at
+// `modified`. Ignored when If-None-Match is present (RFC 9110 13.1.3) or unparseable.
+fn not_modified_since(
+    req: &actix_web::HttpRequest,
+    modified: chrono::DateTime<chrono::Utc>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    use actix_web::http::header;
+    
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+    let since = req
+        .headers()
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<header::HttpDate>().ok())
+        .map(|date| chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::from(date)));
+    match since {
+        Some(since) => settled(modified, now) && modified.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+fn not_modified(
+    tag: String,
+    modified: chrono::DateTime<chrono::Utc>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> HttpResponse {
+    let mut response = HttpResponse::NotModified();
+    response.insert_header((actix_web::http::header::ETAG, tag));
+    if let Some(header) = last_modified(modified, now) {
+        response.insert_header(header);
+    }
+    response.finish()
+}
+
+// Validates a create (or full replace) body and builds the book it describes, with text
+// fields in their cleaned form. The caller assigns the id and checks for ISBN conflicts
+// under the books lock.
+fn book_from_request(
+    book_req: &CreateBookRequest,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Book, validation::Errors> {
+    let mut v = validation::Validator::new();
+    
+    let title = v.check("title", validate_title(&book_req.title));
+    let authors = v.check(
+        author_field(&book_req.author, &book_req.authors),
+        resolve_authors(&book_req.author, &book_req.authors)
+            .and_then(|authors| authors.ok_or_else(|| "At least one author is required".to_string())),
+    );
+    let normalized_isbn = v.check("isbn", validate_isbn(&book_req.isbn));
+    
+    let total_copies = book_req.total_copies.unwrap_or(1);
+    let available_copies = book_req.available_copies.unwrap_or(total_copies);
+    v.check("available_copies", validate_copies(total_copies, available_copies));
+    
+    if let Some(year) = book_req.published_year {
+        v.check("published_year", validate_published_year(year));
+    }
+    let genre = v.check("genre", book_req.genre.as_deref().map(validate_genre).transpose());
+    let publisher = v.check(
+        "publisher",
+        book_req.publisher.as_deref().map(validate_publisher).transpose(),
+    );
+    if let Some(page_count) = book_req.page_count {
+        v.check("page_count", validate_page_count(page_count));
+    }
+    let language = v.check(
+        "language",
+        book_req.language.as_deref().map(validate_language).transpose(),
+    );
+    let description = v.check(
+        "description",
+        book_req.description.as_deref().map(validate_description).transpose(),
+    );
+    let shelf_location = v.check(
+        "shelf_location",
+        book_req.shelf_location.as_deref().map(validate_shelf_location).transpose(),
+    );
+    let cover_url = v.check(
+        "cover_url",
+        book_req.cover_url.as_deref().map(validate_cover_url).transpose(),
+    );
+    
+    let series = v.check("series", book_req.series.as_deref().map(validate_series).transpose());
+    // Skipped when series itself failed, since it's unknown whether there will be one
+    if book_req.series_index.is_some() && matches!(series, Some(None)) {
+        v.fail("series_index", "series_index requires series".to_string());
+    }
+    
+    let edition = v.check(
+        "edition",
+        book_req.edition.as_deref().map(validate_edition).transpose(),
+    );
+    let tags = v.check(
+        "tags",
+        merge_tags(&[], book_req.tags.as_deref().unwrap_or_default()),
+    );
+    
+    v.finish()?;
+    // Every check passed, so each value above is Some
+    let (title, authors, normalized_isbn, tags) = (
+        title.unwrap_or_default(),
+        authors.unwrap_or_default(),
+        normalized_isbn.unwrap_or_default(),
+        tags.unwrap_or_default(),
+    );
+    let (genre, publisher, language) = (genre.flatten(), publisher.flatten(), language.flatten());
+    let (description, shelf_location, cover_url) =
+        (description.flatten(), shelf_location.flatten(), cover_url.flatten());
+    let (series, edition) = (series.flatten(), edition.flatten());
+    
+    Ok(Book {
+        id: 0,
+        title,
+        authors,
+        isbn: normalized_isbn,
+        available: available_copies > 0,
+        total_copies,
+        available_copies,
+        published_year: book_req.published_year,
+        genre,
+        publisher,
+        page_count: book_req.page_count,
+        language,
+        description,
+        shelf_location,
+        branch_id: book_req.branch_id,
+        condition: book_req.condition.unwrap_or(BookCondition::Good),
+        cover_url,
+        series,
+        series_index: book_req.series_index,
+        edition,
+        format: book_req.format,
+        tags,
+        checked_out_copies: 0,
+        hold_count: 0,
+        holds_reserve: false,
+        review_count: 0,
+        average_rating: None,
+        created_at: now,
+        updated_at: now,
+        version: 1,
+        deleted_at: None,
+        reviews_changed_at: None,
+        notes: None,
+    })
+}
+
+// With an Idempotency-Key header the first request is processed and its response kept;
+// a retry with the same key and the same body and query gets that response again instead
+// of creating (or conflicting with) a second book, and the same key with anything else is
+// a 409. Whatever the first request got is replayed, errors included. The store stays
+// locked while the book is created, so concurrent retries can't both get through.
+async fn create_book(
+    req: actix_web::HttpRequest,
+    book_req: web::Json<CreateBookRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let key = match idempotency_key(&req) {
+        Ok(Some(key)) => key,
+        Ok(None) => return create_book_response(&book_req, &query, &data),
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let request = serde_json::json!({ "body": &*book_req, "compat": &query.compat });
+    
+    let mut store = data.idempotency.lock().unwrap();
+    match store.lookup(&key, &request) {
+        idempotency::Lookup::Replay(stored) => {
+            let mut response = HttpResponse::build(stored.status);
+            for header in stored.headers {
+                response.append_header(header);
+            }
+            return response
+                .insert_header((IDEMPOTENT_REPLAY_HEADER, "true"))
+                .body(stored.body);
+        }
+        idempotency::Lookup::Mismatch => {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "{} '{}' was already used for a different request",
+                    IDEMPOTENCY_KEY_HEADER, key
+                ),
+            })
+        }
+        idempotency::Lookup::Miss => {}
+    }
+    
+    let (head, body) = create_book_response(&book_req, &query, &data).into_parts();
+    // JSON bodies are always in memory; anything else is passed through uncached
+    let body = match actix_web::body::MessageBody::try_into_bytes(body) {
+        Ok(bytes) => bytes,
+        Err(body) => return head.set_body(body),
+    };
+    let stored = idempotency::StoredResponse {
+        status: head.status(),
+        headers: head
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        body: body.clone(),
+    };
+    store.record(key, request, stored);
+    head.set_body(body).map_into_boxed_body()
+}
+
+// The Idempotency-Key header, if sent: 1 to 255 visible ASCII characters
+fn idempotency_key(req: &actix_web::HttpRequest) -> Result<Option<String>, String> {
+    let value = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let key = value.to_str().unwrap_or_default();
+    if key.is_empty()
+        || key.len() > MAX_IDEMPOTENCY_KEY_LEN
+        || !key.chars().all(|c| c.is_ascii_graphic())
+    {
+        return Err(format!(
+            "{} must be 1 to {} visible ASCII characters",
+            IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LEN
+        ));
+    }
+    Ok(Some(key.to_string()))
+}
+
+fn create_book_response(
+    book_req: &CreateBookRequest,
+    query: &CompatQuery,
+    data: &AppState,
+) -> HttpResponse {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let mut new_book = match book_from_request(book_req, data.clock.now()) {
+        Ok(book) => book,
+        Err(errors) => return validation_failed(errors),
+    };
+    
+    let response = data.books.write_with(|books| {
+        let mut next_id = data.next_id.lock().unwrap();
+    
+        if let Err(error) = check_branch(data, new_book.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+        // Check for duplicate ISBN
+        if let Some(holder) = books.iter().find(|b| isbn::equivalent(&b.isbn, &new_book.isbn)) {
+            return repository_error(RepositoryError::DuplicateIsbn {
+                isbn: holder.isbn.clone(),
+                existing_id: holder.id,
+            });
+        }
+        new_book.id = *next_id;
+    
+        // Checked after the ISBN conflict, so every match has a different ISBN
+        let duplicates = possible_duplicates(books, &new_book.title, &new_book.authors);
+    
+        *next_id += 1;
+        books.push(new_book.clone());
+        catalog_changed(data, books);
+    
+        HttpResponse::Created()
+            .insert_header(etag(&new_book))
+            .insert_header((actix_web::http::header::LOCATION, book_path(new_book.id)))
+            .json(created_book_body(&new_book, &duplicates, compat))
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// A newly created book as returned to the client, with `possible_duplicate_of` when needed
+fn created_book_body(book: &Book, duplicates: &[u32], compat: Compat) -> serde_json::Value {
+    let mut body = project_book(book, &None, compat);
+    if let Some(map) = body.as_object_mut().filter(|_| !duplicates.is_empty()) {
+        map.insert("possible_duplicate_of".to_string(), serde_json::json!(duplicates));
+    }
+    body
+}
+
+// Every entry is validated, and checked for ISBN conflicts with the catalog and with
+// earlier entries, before anything is inserted; the books lock is taken once for the
+// conflict checks and all the inserts. By default the valid entries are created and the
+// rest reported (207 unless all succeed); with `atomic=true` one failure rejects the batch.
+async fn create_books_batch(
+    book_reqs: web::Json<Vec<CreateBookRequest>>,
+    query: web::Query<BatchCreateQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    use actix_web::http::StatusCode;
+    
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    if book_reqs.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Batch cannot be empty".to_string(),
+        });
+    }
+    if book_reqs.len() > MAX_BATCH_SIZE {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "Batch cannot contain more than {} books (got {})",
+                MAX_BATCH_SIZE,
+                book_reqs.len()
+            ),
+        });
+    }
+    
+    let mut outcomes: Vec<Result<Book, BatchItemResult>> = book_reqs
+        .iter()
+        .enumerate()
+        .map(|(index, book_req)| {
+            book_from_request(book_req, data.clock.now())
+                .map_err(|error| BatchItemResult::failed(index, StatusCode::BAD_REQUEST, error.into()))
+        })
+        .collect();
+    
+    let response = data.books.write_with(|books| {
+        let mut next_id = data.next_id.lock().unwrap();
+    
+        for index in 0..outcomes.len() {
+            let book = match &outcomes[index] {
+                Ok(book) => book,
+                Err(_) => continue,
+            };
+            if let Err(error) = check_branch(&data, book.branch_id) {
+                outcomes[index] =
+                    Err(BatchItemResult::failed(index, StatusCode::BAD_REQUEST, error));
+                continue;
+            }
+            let conflict = if books.iter().any(|b| isbn::equivalent(&b.isbn, &book.isbn)) {
+                Some("Book with this ISBN already exists".to_string())
+            } else {
+                // The first valid entry with an ISBN wins; later ones conflict with it
+                outcomes[..index]
+                    .iter()
+                    .position(|earlier| matches!(earlier, Ok(e) if isbn::equivalent(&e.isbn, &book.isbn)))
+                    .map(|earlier| format!("Duplicate ISBN within the batch (same as index {})", earlier))
+            };
+            if let Some(error) = conflict {
+                outcomes[index] = Err(BatchItemResult::failed(index, StatusCode::CONFLICT, error));
+            }
+        }
+    
+        let failed = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+        if failed > 0 && query.atomic.unwrap_or(false) {
+            let errors: Vec<BatchItemResult> =
+                outcomes.into_iter().filter_map(Result::err).collect();
+            let status = if errors.iter().all(|e| e.status == StatusCode::CONFLICT.as_u16()) {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            return HttpResponse::build(status).json(BatchRejectedResponse {
+                error: format!(
+                    "Batch rejected: {} of {} books failed; nothing was created",
+                    failed,
+                    book_reqs.len()
+                ),
+                errors,
+            });
+        }
+    
+        let mut results = Vec::with_capacity(outcomes.len());
+        for (index, outcome) in outcomes.into_iter().enumerate() {
+            let mut book = match outcome {
+                Ok(book) => book,
+                Err(result) => {
+                    results.push(result);
+                    continue;
+                }
+            };
+            book.id = *next_id;
+            *next_id += 1;
+            let duplicates = possible_duplicates(books, &book.title, &book.authors);
+            results.push(BatchItemResult {
+                index,
+                status: StatusCode::CREATED.as_u16(),
+                book: Some(created_book_body(&book, &duplicates, compat)),
+                error: None,
+            });
+            books.push(book);
+        }
+    
+        let created = results.len() - failed;
+        if created > 0 {
+            catalog_changed(&data, books);
+        }
+        let status = if failed == 0 {
+            StatusCode::CREATED
+        } else {
+            StatusCode::MULTI_STATUS
+        };
+        HttpResponse::build(status).json(BatchCreateResponse {
+            created,
+            failed,
+            results,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Validates one record of a catalog replacement into the book it describes
+fn book_from_record(
+    record: serde_json::Value,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Book, String> {
+    if !record.is_object() {
+        return Err("Invalid book: expected a JSON object".to_string());
+    }
+    let record: CatalogRecord =
+        serde_json::from_value(record).map_err(|e| format!("Invalid book: {}", e))?;
+    if record.description_truncated {
+        return Err(
+            "description was truncated by a list response; send the full text from GET /api/books/{id}"
+                .to_string(),
+        );
+    }
+    let mut book = book_from_request(&record.book, now)?;
+    if record.id == Some(0) {
+        return Err("id must be at least 1".to_string());
+    }
+    book.id = record.id.unwrap_or(0);
+    if let Some(created_at) = record.created_at {
+        book.created_at = created_at;
+        book.updated_at = created_at;
+    }
+    if let Some(updated_at) = record.updated_at {
+        if updated_at < book.created_at {
+            return Err("updated_at cannot be before created_at".to_string());
+        }
+        book.updated_at = updated_at;
+    }
+    match record.version {
+        Some(0) => return Err("version must be at least 1".to_string()),
+        Some(version) => book.version = version,
+        None => {}
+    }
+    Ok(book)
+}
+
+// Swaps in a whole new catalog, for test setups and restores. Every record is validated
+// first, and one bad record rejects the lot with all the problems listed. Reviews stay
+// with the ids they belong to, so they survive a round trip; those of ids that are gone
+// are deleted along with the trash, and so is the loan history. Open loans have to stay
+// with their books, so every book with copies checked out must be in the new catalog
+// with room for them. Not authenticated yet: it belongs behind admin auth.
+async fn replace_catalog(
+    records: web::Json<Vec<serde_json::Value>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    use actix_web::http::StatusCode;
+    
+    let records = records.into_inner();
+    let total = records.len();
+    let now = data.clock.now();
+    let mut outcomes: Vec<Result<Book, BatchItemResult>> = records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            book_from_record(record, now)
+                .map_err(|error| BatchItemResult::failed(index, StatusCode::BAD_REQUEST, error))
+        })
+        .collect();
+    
+    for index in 0..outcomes.len() {
+        let Ok(book) = &outcomes[index] else { continue };
+        let earlier = &outcomes[..index];
+        let conflict = if let Some(earlier) = earlier
+            .iter()
+            .position(|e| matches!(e, Ok(e) if book.id != 0 && e.id == book.id))
+        {
+            Some(format!("Duplicate id within the catalog (same as index {})", earlier))
+        } else {
+            earlier
+                .iter()
+                .position(|e| matches!(e, Ok(e) if isbn::equivalent(&e.isbn, &book.isbn)))
+                .map(|earlier| format!("Duplicate ISBN within the catalog (same as index {})", earlier))
+        };
+        if let Some(error) = conflict {
+            outcomes[index] = Err(BatchItemResult::failed(index, StatusCode::BAD_REQUEST, error));
+        }
+    }
+    
+    let mut catalog = Vec::with_capacity(total);
+    let mut errors = Vec::new();
+    for outcome in outcomes {
+        match outcome {
+            Ok(book) => catalog.push(book),
+            Err(result) => errors.push(result),
+        }
+    }
+    if !errors.is_empty() {
+        return HttpResponse::BadRequest().json(BatchRejectedResponse {
+            error: format!(
+                "Catalog rejected: {} of {} books are invalid; nothing was changed",
+                errors.len(),
+                total
+            ),
+            errors,
+        });
+    }
+    
+    let mut next_id = catalog.iter().map(|b| b.id).max().unwrap_or(0) + 1;
+    for book in catalog.iter_mut().filter(|b| b.id == 0) {
+        book.id = next_id;
+        next_id += 1;
+    }
+    catalog.sort_by_key(|b| b.id);
+    
+    let response = data.books.write_with(|books| {
+        let mut trash = data.trash.lock().unwrap();
+        let mut stored_next_id = data.next_id.lock().unwrap();
+        let mut reviews = data.reviews.lock().unwrap();
+        let mut loans = data.loans.lock().unwrap();
+    
+        let misplaced: Vec<u32> = catalog
+            .iter()
+            .filter(|b| check_branch(&data, b.branch_id).is_err())
+            .map(|b| b.id)
+            .collect();
+        if !misplaced.is_empty() {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!(
+                    "Catalog rejected: books {:?} are at branches that don't exist; nothing was changed",
+                    misplaced
+                ),
+            });
+        }
+        let mut stranded: Vec<u32> = books
+            .iter()
+            .filter(|current| current.checked_out_copies > 0)
+            .filter(|current| {
+                catalog.iter().find(|b| b.id == current.id).is_none_or(|b| {
+                    validate_loaned_copies(
+                        b.total_copies,
+                        b.available_copies,
+                        current.checked_out_copies as usize,
+                    )
+                    .is_err()
+                })
+            })
+            .map(|current| current.id)
+            .collect();
+        if !stranded.is_empty() {
+            stranded.sort_unstable();
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Catalog rejected: books {:?} have copies checked out that the new catalog has no room for; nothing was changed",
+                    stranded
+                ),
+            });
+        }
+        for book in catalog.iter_mut() {
+            let current = books.iter().find(|current| current.id == book.id);
+            book.checked_out_copies = current.map_or(0, |current| current.checked_out_copies);
+            // Listings never carry notes, so a catalog built from one would otherwise lose them
+            book.notes = current.and_then(|current| current.notes.clone());
+        }
+        loans.retain(|l| catalog.iter().any(|b| b.id == l.book_id));
+        let mut holds = data.holds.lock().unwrap();
+        holds.retain(|h| catalog.iter().any(|b| b.id == h.book_id));
+        for book in catalog.iter_mut() {
+            let count = holds.iter().filter(|h| h.book_id == book.id).count() as u32;
+            book.set_holds(count, data.config.holds_reserve_copies);
+        }
+        drop(holds);
+        let removed: Vec<u32> = books
+            .iter()
+            .map(|b| b.id)
+            .filter(|id| !catalog.iter().any(|b| b.id == *id))
+            .collect();
+        unlist_books(&data, &removed);
+    
+        reviews.retain(|r| catalog.iter().any(|b| b.id == r.book_id));
+        for book in catalog.iter_mut() {
+            if reviews.iter().any(|r| r.book_id == book.id) {
+                book.refresh_rating(&reviews, data.clock.now());
+            }
+        }
+        trash.clear();
+        *stored_next_id = next_id;
+        *books = catalog;
+        catalog_changed(&data, books);
+    
+        HttpResponse::Ok().json(CatalogReplaceResponse {
+            count: books.len(),
+            next_id,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// `current` with a partial update applied, touched if anything changed. Every supplied
+// field is validated before the result is built, so a rejected request never leaves the
+// book (or the derived indexes) half-updated. ISBN uniqueness is left to the caller.
+fn apply_update(
+    current: &Book,
+    update_req: &UpdateBookRequest,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Book, validation::Errors> {
+    let mut v = validation::Validator::new();
+    
+    let title = v
+        .check("title", update_req.title.as_deref().map(validate_title).transpose())
+        .flatten();
+    let authors = v
+        .check(
+            author_field(&update_req.author, &update_req.authors),
+            resolve_authors(&update_req.author, &update_req.authors),
+        )
+        .flatten();
+    let normalized_isbn = v
+        .check("isbn", update_req.isbn.as_deref().map(validate_isbn).transpose())
+        .flatten();
+    
+    let total_copies = update_req.total_copies.unwrap_or(current.total_copies);
+    let on_loan = current.checked_out_copies as usize;
+    let available_copies = match (update_req.available_copies, update_req.available) {
+        (Some(_), Some(_)) => {
+            v.fail(
+                "available",
+                "available and available_copies cannot be combined".to_string(),
+            );
+            None
+        }
+        (Some(copies), None) => Some(copies),
+        (None, Some(false)) => Some(0),
+        // Every copy that isn't on loan
+        (None, Some(true)) => Some(total_copies.saturating_sub(on_loan as u32)),
+        (None, None) => Some(current.available_copies),
+    };
+    // Checked against the resulting counts, so lowering total_copies alone can fail too
+    if let Some(available_copies) = available_copies {
+        v.check(
+            "available_copies",
+            validate_copies(total_copies, available_copies)
+                .and_then(|_| validate_loaned_copies(total_copies, available_copies, on_loan)),
+        );
+    }
+    
+    if let Some(year) = update_req.published_year {
+        v.check("published_year", validate_published_year(year));
+    }
+    let genre = v
+        .check("genre", update_req.genre.as_deref().map(validate_genre).transpose())
+        .flatten();
+    let publisher = v
+        .check(
+            "publisher",
+            update_req.publisher.as_deref().map(validate_publisher).transpose(),
+        )
+        .flatten();
+    if let Some(Some(page_count)) = update_req.page_count {
+        v.check("page_count", validate_page_count(page_count));
+    }
+    let language = v
+        .check(
+            "language",
+            update_req.language.as_deref().map(validate_language).transpose(),
+        )
+        .flatten();
+    let description = v
+        .check(
+            "description",
+            update_req.description.as_deref().map(validate_description).transpose(),
+        )
+        .flatten();
+    
+    // For the nullable fields, None is "leave unchanged" and Some(None) is "clear"
+    let shelf_location = v
+        .check(
+            "shelf_location",
+            update_req
+                .shelf_location
+                .as_ref()
+                .map(|raw| raw.as_deref().map(validate_shelf_location).transpose())
+                .transpose(),
+        )
+        .flatten();
+    let cover_url = v
+        .check(
+            "cover_url",
+            update_req
+                .cover_url
+                .as_ref()
+                .map(|raw| raw.as_deref().map(validate_cover_url).transpose())
+                .transpose(),
+        )
+        .flatten();
+    let series_check = v.check(
+        "series",
+        update_req
+            .series
+            .as_ref()
+            .map(|raw| raw.as_deref().map(validate_series).transpose())
+            .transpose(),
+    );
+    // Checked against the resulting values, like the copy counts, unless series failed
+    if let Some(series) = &series_check {
+        let has_series = series.as_ref().map_or(current.series.is_some(), Option::is_some);
+        let has_index = update_req
+            .series_index
+            .map_or(current.series_index.is_some(), |index| index.is_some());
+        if has_index && !has_series {
+            v.fail("series_index", "series_index requires series".to_string());
+        }
+    }
+    let series = series_check.flatten();
+    
+    let edition = v
+        .check(
+            "edition",
+            update_req
+                .edition
+                .as_ref()
+                .map(|raw| raw.as_deref().map(validate_edition).transpose())
+                .transpose(),
+        )
+        .flatten();
+    let tags = v
+        .check(
+            "tags",
+            update_req.tags.as_deref().map(|raw| merge_tags(&[], raw)).transpose(),
+        )
+        .flatten();
+    
+    v.finish()?;
+    let available_copies = available_copies.unwrap_or(current.available_copies);
+    
+    let mut book = current.clone();
+    
+    if let Some(title) = title {
+        book.title = title;
+    }
+    
+    if let Some(authors) = authors {
+        book.authors = authors;
+    }
+    
+    if let Some(isbn) = normalized_isbn {
+        book.isbn = isbn;
+    }
+    
+    book.set_copies(total_copies, available_copies);
+    
+    if let Some(year) = update_req.published_year {
+        book.published_year = Some(year);
+    }
+    
+    if let Some(genre) = genre {
+        book.genre = Some(genre);
+    }
+    
+    if let Some(publisher) = publisher {
+        book.publisher = Some(publisher);
+    }
+    
+    if let Some(page_count) = update_req.page_count {
+        book.page_count = page_count;
+    }
+    
+    if let Some(code) = language {
+        book.language = Some(code);
+    }
+    
+    if let Some(description) = description {
+        book.description = Some(description);
+    }
+    
+    if let Some(location) = shelf_location {
+        book.shelf_location = location;
+    }
+    
+    if let Some(branch_id) = update_req.branch_id {
+        book.branch_id = branch_id;
+    }
+    
+    if let Some(condition) = update_req.condition {
+        book.condition = condition;
+    }
+    
+    if let Some(cover_url) = cover_url {
+        book.cover_url = cover_url;
+    }
+    
+    if let Some(series) = series {
+        book.series = series;
+    }
+    
+    if let Some(series_index) = update_req.series_index {
+        book.series_index = series_index;
+    }
+    
+    if let Some(edition) = edition {
+        book.edition = edition;
+    }
+    
+    if let Some(format) = update_req.format {
+        book.format = format;
+    }
+    
+    if let Some(tags) = tags {
+        book.tags = tags;
+    }
+    
+    // A request that only restates current values isn't a change
+    if book != *current {
+        book.touch(now);
+    }
+    
+    Ok(book)
+}
+
+async fn update_book(
+    req: actix_web::HttpRequest,
+    path: web::Path<u32>,
+    update_req: web::Json<UpdateBookRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let precondition = match parse_precondition(&req, update_req.version, &data.config) {
+        Ok(precondition) => precondition,
+        Err((status, error)) => return HttpResponse::build(status).json(ErrorResponse { error }),
+    };
+    
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+    
+        let book_index = match books.iter().position(|b| b.id == book_id) {
+            Some(index) => index,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        // Without a precondition the update is last-write-wins, as for clients predating versions
+        if !precondition.holds(&books[book_index]) {
+            return precondition_failed(&books[book_index]);
+        }
+    
+        let updated = match apply_update(&books[book_index], &update_req, data.clock.now()) {
+            Ok(book) => book,
+            Err(errors) => return validation_failed(errors),
+        };
+        if let Err(error) = check_branch(&data, updated.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    
+        // Check for duplicate ISBN (excluding current book)
+        let holder = update_req.isbn.as_ref().and_then(|_| {
+            books
+                .iter()
+                .find(|b| isbn::equivalent(&b.isbn, &updated.isbn) && b.id != book_id)
+        });
+        if let Some(holder) = holder {
+            return repository_error(RepositoryError::DuplicateIsbn {
+                isbn: holder.isbn.clone(),
+                existing_id: holder.id,
+            });
+        }
+    
+        books[book_index] = updated;
+        let book = &books[book_index];
+        let header = etag(book);
+        let updated = project_book(book, &None, compat);
+        catalog_changed(&data, books);
+        HttpResponse::Ok().insert_header(header).json(updated)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// A new book starting from an existing one. The body takes the same fields as PATCH, which
+// override the copy, and must include an isbn since two books can't share one. The copy gets
+// a new id and fresh timestamps and starts at version 1 without reviews.
+async fn clone_book(
+    path: web::Path<u32>,
+    clone_req: web::Json<UpdateBookRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    if clone_req.isbn.is_none() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "isbn is required to clone a book, since two books can't share one".to_string(),
+        });
+    }
+    if clone_req.version.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "version cannot be used when cloning a book".to_string(),
+        });
+    }
+    
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+        let mut next_id = data.next_id.lock().unwrap();
+    
+        let Some(source) = books.iter().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        // Loans stay with the source, so the copies it has lent out are on the shelf in the copy
+        let mut template = source.clone();
+        template.set_copies(
+            template.total_copies,
+            template.available_copies + template.checked_out_copies,
+        );
+        template.checked_out_copies = 0;
+        template.set_holds(0, false);
+        let mut new_book = match apply_update(&template, &clone_req, data.clock.now()) {
+            Ok(book) => book,
+            Err(errors) => return validation_failed(errors),
+        };
+        if let Err(error) = check_branch(&data, new_book.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+        if let Some(holder) = books.iter().find(|b| isbn::equivalent(&b.isbn, &new_book.isbn)) {
+            return repository_error(RepositoryError::DuplicateIsbn {
+                isbn: holder.isbn.clone(),
+                existing_id: holder.id,
+            });
+        }
+    
+        let now = data.clock.now();
+        new_book.id = *next_id;
+        new_book.review_count = 0;
+        new_book.average_rating = None;
+        new_book.reviews_changed_at = None;
+        new_book.notes = None;
+        new_book.created_at = now;
+        new_book.updated_at = now;
+        new_book.version = 1;
+    
+        *next_id += 1;
+        books.push(new_book.clone());
+        catalog_changed(&data, books);
+    
+        HttpResponse::Created()
+            .insert_header(etag(&new_book))
+            .insert_header((actix_web::http::header::LOCATION, book_path(new_book.id)))
+            .json(project_book(&new_book, &None, compat))
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn parse_replace_request(body: serde_json::Value) -> Result<ReplaceBookRequest, String> {
+    let map = body
+        .as_object()
+        .ok_or_else(|| "Invalid request body: expected a JSON object".to_string())?;
+    let missing: Vec<&str> = REPLACE_FIELDS
+        .iter()
+        .copied()
+        .filter(|field| match *field {
+            "authors" => !map.contains_key("authors") && !map.contains_key("author"),
+            field => !map.contains_key(field),
+        })
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "PUT replaces the whole book and requires every field (use PATCH for partial updates); missing: {}",
+            missing.join(", ")
+        ));
+    }
+    serde_json::from_value(body).map_err(|e| format!("Invalid request body: {}", e))
+}
+
+// Full replacement: the book keeps its id, created_at, and review summary, and everything
+// else comes from the body. Validation matches create; PATCH is the partial update.
+async fn replace_book(
+    req: actix_web::HttpRequest,
+    path: web::Path<u32>,
+    body: web::Json<serde_json::Value>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let replace_req = match parse_replace_request(body.into_inner()) {
+        Ok(replace_req) => replace_req,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let precondition = match parse_precondition(&req, replace_req.version, &data.config) {
+        Ok(precondition) => precondition,
+        Err((status, error)) => return HttpResponse::build(status).json(ErrorResponse { error }),
+    };
+    
+    let mut replacement = match book_from_request(&replace_req.book, data.clock.now()) {
+        Ok(book) => book,
+        Err(errors) => return validation_failed(errors),
+    };
+    // `available` is derived, so it may only restate what available_copies implies
+    if replace_req.available != replacement.available {
+        return validation_failed(validation::Errors::new(
+            "available",
+            format!(
+                "available ({}) contradicts available_copies ({})",
+                replace_req.available, replacement.available_copies
+            ),
+        ));
+    }
+    
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+    
+        let book_index = match books.iter().position(|b| b.id == book_id) {
+            Some(index) => index,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        if !precondition.holds(&books[book_index]) {
+            return precondition_failed(&books[book_index]);
+        }
+        if let Err(error) = check_branch(&data, replacement.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    
+        if let Some(holder) = books
+            .iter()
+            .find(|b| isbn::equivalent(&b.isbn, &replacement.isbn) && b.id != book_id)
+        {
+            return repository_error(RepositoryError::DuplicateIsbn {
+                isbn: holder.isbn.clone(),
+                existing_id: holder.id,
+            });
+        }
+    
+        let current = &books[book_index];
+        if let Err(message) = validate_loaned_copies(
+            replacement.total_copies,
+            replacement.available_copies,
+            current.checked_out_copies as usize,
+        ) {
+            return validation_failed(validation::Errors::new("available_copies", message));
+        }
+        replacement.id = current.id;
+        replacement.checked_out_copies = current.checked_out_copies;
+        replacement.set_holds(current.hold_count, current.holds_reserve);
+        replacement.review_count = current.review_count;
+        replacement.average_rating = current.average_rating;
+        replacement.reviews_changed_at = current.reviews_changed_at;
+        replacement.notes = current.notes.clone();
+        replacement.created_at = current.created_at;
+        replacement.updated_at = current.updated_at;
+        replacement.version = current.version;
+        // Replacing a book with identical content isn't a change
+        if replacement != *current {
+            replacement.touch(data.clock.now());
+        }
+    
+        let header = etag(&replacement);
+        let replaced = project_book(&replacement, &None, compat);
+        books[book_index] = replacement;
+        catalog_changed(&data, books);
+        HttpResponse::Ok().insert_header(header).json(replaced)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Creates the book with this ISBN (201) or replaces the one that has it (200), so a sync
+// that only knows ISBNs can send the same payload every time. The body is a create body;
+// its isbn may be left out, and otherwise has to name the same book as the path. A replace
+// keeps the id, review summary, and copy counts, which belong to this library rather than
+// the catalog the data comes from. If-Match is honored but never required.
+async fn upsert_book_by_isbn(
+    req: actix_web::HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<serde_json::Value>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let precondition = match parse_precondition(&req, None, &data.config) {
+        Ok(precondition) => precondition,
+        // Repeating an upsert is harmless, so REQUIRE_IF_MATCH leaves it alone
+        Err((actix_web::http::StatusCode::PRECONDITION_REQUIRED, _)) => Precondition::Unconditional,
+        Err((status, error)) => return HttpResponse::build(status).json(ErrorResponse { error }),
+    };
+    
+    let raw_isbn = path.into_inner();
+    let path_isbn = isbn::normalize(&raw_isbn);
+    let mut body = body.into_inner();
+    let Some(map) = body.as_object_mut() else {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Invalid request body: expected a JSON object".to_string(),
+        });
+    };
+    match map.get("isbn") {
+        None => {
+            map.insert("isbn".to_string(), serde_json::json!(raw_isbn));
+        }
+        Some(serde_json::Value::String(body_isbn))
+            if isbn::equivalent(&isbn::normalize(body_isbn), &path_isbn) => {}
+        Some(body_isbn) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!(
+                    "ISBN in the body ({}) does not match the ISBN in the path ({})",
+                    body_isbn.as_str().map_or_else(|| body_isbn.to_string(), str::to_string),
+                    raw_isbn
+                ),
+            })
+        }
+    }
+    let book_req: CreateBookRequest = match serde_json::from_value(body) {
+        Ok(book_req) => book_req,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("Invalid request body: {}", e),
+            })
+        }
+    };
+    let mut book = match book_from_request(&book_req, data.clock.now()) {
+        Ok(book) => book,
+        Err(errors) => return validation_failed(errors),
+    };
+    
+    let response = data.books.write_with(|books| {
+        let mut next_id = data.next_id.lock().unwrap();
+        if let Err(error) = check_branch(&data, book.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    
+        let Some(book_index) = books
+            .iter()
+            .position(|b| isbn::equivalent(&b.isbn, &path_isbn))
+        else {
+            if !matches!(precondition, Precondition::Unconditional) {
+                return HttpResponse::PreconditionFailed().json(ErrorResponse {
+                    error: format!("Book with ISBN {} does not exist, so If-Match cannot match", raw_isbn),
+                });
+            }
+            book.id = *next_id;
+            let duplicates = possible_duplicates(books, &book.title, &book.authors);
+            *next_id += 1;
+            books.push(book.clone());
+            catalog_changed(&data, books);
+        
+            return HttpResponse::Created()
+                .insert_header(etag(&book))
+                .insert_header((actix_web::http::header::LOCATION, book_path(book.id)))
+                .json(created_book_body(&book, &duplicates, compat));
+        };
+    
+        let current = &books[book_index];
+        if !precondition.holds(current) {
+            return precondition_failed(current);
+        }
+        book.id = current.id;
+        book.set_copies(current.total_copies, current.available_copies);
+        book.checked_out_copies = current.checked_out_copies;
+        book.set_holds(current.hold_count, current.holds_reserve);
+        book.review_count = current.review_count;
+        book.average_rating = current.average_rating;
+        book.reviews_changed_at = current.reviews_changed_at;
+        book.notes = current.notes.clone();
+        book.created_at = current.created_at;
+        book.updated_at = current.updated_at;
+        book.version = current.version;
+        // Sending the same payload again isn't a change
+        if book != *current {
+            book.touch(data.clock.now());
+        }
+    
+        let header = etag(&book);
+        let replaced = project_book(&book, &None, compat);
+        books[book_index] = book;
+        catalog_changed(&data, books);
+        HttpResponse::Ok().insert_header(header).json(replaced)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Moves the book to the trash, where it keeps its reviews and can be restored. With
+// `permanent=true` the book (in the catalog or already in the trash) and its reviews are
+// removed for good.
+async fn delete_book(
+    req: actix_web::HttpRequest,
+    path: web::Path<u32>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let precondition = match parse_precondition(&req, None, &data.config) {
+        Ok(precondition) => precondition,
+        Err((status, error)) => return HttpResponse::build(status).json(ErrorResponse { error }),
+    };
+    let permanent = query.permanent.unwrap_or(false);
+    
+    let book_id = path.into_inner();
+    let book = match data.books.get(book_id) {
+        Ok(book) => {
+            if !precondition.holds(&book) {
+                return precondition_failed(&book);
+            }
+            if book.checked_out_copies > 0 {
+                return HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!(
+                        "Book with id {} has {} copies checked out; they must be returned before it can be deleted",
+                        book_id, book.checked_out_copies
+                    ),
+                });
+            }
+            // Only the version checked above is deleted, so a checkout that got in first
+            // makes this a conflict rather than taking a book on loan out of the catalog
+            let book = match data.books.delete(book_id, book.version) {
+                Ok(book) => book,
+                Err(err) => return repository_error(err),
+            };
+            refresh_catalog(&data);
+            book
+        }
+        Err(RepositoryError::NotFound(_)) => {
+            let mut trash = data.trash.lock().unwrap();
+            match trash.iter().position(|b| b.id == book_id).filter(|_| permanent) {
+                Some(index) if !precondition.holds(&trash[index]) => {
+                    return precondition_failed(&trash[index])
+                }
+                Some(index) => trash.remove(index),
+                None => return repository_error(RepositoryError::NotFound(book_id)),
+            }
+        }
+        Err(err) => return repository_error(err),
+    };
+    
+    if permanent {
+        data.reviews.lock().unwrap().retain(|r| r.book_id != book_id);
+        data.holds.lock().unwrap().retain(|h| h.book_id != book_id);
+    } else {
+        move_to_trash(&mut data.trash.lock().unwrap(), vec![book], data.clock.now());
+    }
+    unlist_books(&data, &[book_id]);
+    HttpResponse::NoContent().finish()
+}
+
+// Takes books out of every reading list; restoring a book from the trash doesn't put it back
+fn unlist_books(data: &AppState, ids: &[u32]) {
+    for list in data.lists.lock().unwrap().iter_mut() {
+        list.book_ids.retain(|id| !ids.contains(id));
+    }
+}
+
+fn move_to_trash(trash: &mut Vec<Book>, removed: Vec<Book>, now: chrono::DateTime<chrono::Utc>) {
+    trash.extend(removed.into_iter().map(|mut book| {
+        book.deleted_at = Some(now);
+        book
+    }));
+    trash.sort_by_key(|b| b.id);
+}
+
+// Permanently removes trashed books (and their reviews) deleted longer ago than the
+// retention period, returning their ids. Called by the background sweep and the admin
+// endpoint; the trash lock is held only for the sweep itself.
+fn purge_trash(data: &AppState) -> Vec<u32> {
+    let cutoff = data.clock.now() - data.config.trash_retention;
+    let mut trash = data.trash.lock().unwrap();
+    let (expired, kept): (Vec<Book>, Vec<Book>) = std::mem::take(&mut *trash)
+        .into_iter()
+        .partition(|b| b.deleted_at.is_some_and(|deleted_at| deleted_at <= cutoff));
+    *trash = kept;
+    
+    let ids: Vec<u32> = expired.iter().map(|b| b.id).collect();
+    if !ids.is_empty() {
+        data.reviews.lock().unwrap().retain(|r| !ids.contains(&r.book_id));
+        data.holds.lock().unwrap().retain(|h| !ids.contains(&h.book_id));
+        println!("Purged {} book(s) from the trash: {:?}", ids.len(), ids);
+    }
+    ids
+}
+
+// Applies every scheduled change whose time has come, earliest first, as an update
+// setting `available` would. A change for a book no longer in the catalog (deleted or in
+// the trash) is logged and dropped. Run by the background task and the admin endpoint.
+fn apply_due_schedules(data: &AppState) -> ScheduleRunResponse {
+    let now = data.clock.now();
+    let run = data.books.write_with(|books| {
+        let mut schedules = data.schedules.lock().unwrap();
+        let (mut due, pending): (Vec<ScheduledChange>, Vec<ScheduledChange>) =
+            std::mem::take(&mut *schedules)
+                .into_iter()
+                .partition(|change| change.at <= now);
+        *schedules = pending;
+        drop(schedules);
+        due.sort_by_key(|change| (change.at, change.id));
+    
+        let mut run = ScheduleRunResponse {
+            applied: Vec::new(),
+            dropped: Vec::new(),
+        };
+        let mut changed = false;
+        for change in due {
+            let Some(book) = books.iter_mut().find(|b| b.id == change.book_id) else {
+                println!(
+                    "Dropped scheduled change {}: book {} no longer exists",
+                    change.id, change.book_id
+                );
+                run.dropped.push(change.id);
+                continue;
+            };
+            let copies = if change.available {
+                book.total_copies - book.checked_out_copies
+            } else {
+                0
+            };
+            if copies != book.available_copies {
+                book.set_copies(book.total_copies, copies);
+                book.touch(data.clock.now());
+                changed = true;
+            }
+            run.applied.push(change.id);
+        }
+        if changed {
+            catalog_changed(data, books);
+        }
+        run
+    });
+    run.unwrap_or_else(|err| {
+        eprintln!("Could not apply scheduled changes: {}", err);
+        ScheduleRunResponse {
+            applied: Vec::new(),
+            dropped: Vec::new(),
+        }
+    })
+}
+
+async fn apply_schedules_now(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(apply_due_schedules(&data))
+}
+
+async fn get_clock(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "now": data.clock.now() }))
+}
+
+// Only the manual clock (CLOCK=manual) can be moved, for tests of due dates
+async fn set_clock(
+    clock_req: web::Json<SetClockRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !data.clock.set(clock_req.now) {
+        return HttpResponse::Conflict().json(ErrorResponse {
+            error: "The clock follows the system time; start the server with CLOCK=manual to set it"
+                .to_string(),
+        });
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "now": data.clock.now() }))
+}
+
+// Rewrites the operation log down to the books in the catalog; only a log has anything to
+// compact
+async fn compact_log_now(data: web::Data<AppState>) -> impl Responder {
+    match data.books.compact() {
+        None => HttpResponse::Conflict().json(ErrorResponse {
+            error: "The catalog has no operation log; start the server with OPERATION_LOG to \
+                    keep one"
+                .to_string(),
+        }),
+        Some(Err(err)) => repository_error(err),
+        Some(Ok(done)) => HttpResponse::Ok().json(serde_json::json!({
+            "entries_before": done.entries_before,
+            "entries_after": done.entries_after,
+            "bytes_before": done.bytes_before,
+            "bytes_after": done.bytes_after,
+        })),
+    }
+}
+
+// The whole library in one document, for offsite backup. It is copied under every lock
+// at once, as for the data file, so it never holds half a change, and serialized once
+// they are released.
+async fn download_backup(data: web::Data<AppState>) -> impl Responder {
+    let snapshot = match take_snapshot(&data, true) {
+        Ok(snapshot) => snapshot,
+        Err(cause) => {
+            eprintln!("Backup failed: {}", cause);
+            return HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                error: "The catalog is unavailable; try again later".to_string(),
+            });
+        }
+    };
+    let created_at = chrono::Utc::now();
+    let backup = Backup {
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at,
+        snapshot,
+    };
+    let mut fields = match serde_json::to_value(&backup) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => {
+            eprintln!("Backup failed: the snapshot didn't serialize to a JSON object");
+            return HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "The backup could not be written".to_string(),
+            });
+        }
+    };
+    let checksum = backup_checksum(&fields);
+    fields.insert("checksum".to_string(), checksum.into());
+    let filename = format!("library-backup-{}.json", created_at.format("%Y%m%dT%H%M%SZ"));
+    HttpResponse::Ok()
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .json(fields)
+}
+
+// Replaces the whole library with a backup from GET /api/admin/backup, or with
+// `merge=true` puts its books into the catalog by ISBN. The backup is checked in full
+// first, so one that fails a check leaves everything as it was.
+async fn restore_backup(
+    query: web::Query<RestoreQuery>,
+    document: web::Json<serde_json::Value>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let snapshot = match read_backup(document.into_inner()) {
+        Ok(snapshot) => snapshot,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("{}; nothing was changed", error),
+            })
+        }
+    };
+    if query.merge.unwrap_or(false) {
+        return match merge_backup(&data, snapshot.books) {
+            Ok(summary) => HttpResponse::Ok().json(summary),
+            Err(err) => repository_error(err),
+        };
+    }
+    let books_restored = snapshot.books.len();
+    match restore_snapshot(&data, snapshot, true) {
+        Ok(()) => HttpResponse::Ok().json(RestoreResponse {
+            merge: false,
+            books_restored,
+            conflicts_skipped: Vec::new(),
+            ids_remapped: Vec::new(),
+        }),
+        Err(cause) => {
+            eprintln!("Restore failed: {}", cause);
+            HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                error: "The catalog is unavailable; try again later".to_string(),
+            })
+        }
+    }
+}
+
+async fn purge_trash_now(data: web::Data<AppState>) -> impl Responder {
+    let ids = purge_trash(&data);
+    HttpResponse::Ok().json(PurgeResponse {
+        purged: ids.len(),
+        ids,
+    })
+}
+
+// Lists soft-deleted books, with their `deleted_at`, paged like the catalog
+async fn get_trash(query: web::Query<ListQuery>, data: web::Data<AppState>) -> impl Responder {
+    let options = match parse_list_options(&query, &data.config, true) {
+        Ok(options) => options,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let trash = data.trash.lock().unwrap().clone();
+    let response = list_response(trash, &options, |_, _| {});
+    HttpResponse::Ok()
+        .insert_header((TOTAL_COUNT_HEADER, response.total))
+        .json(response)
+}
+
+// Puts a soft-deleted book back in the catalog, unless another book has taken its ISBN
+// in the meantime
+async fn restore_book(
+    path: web::Path<u32>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let book_id = path.into_inner();
+    // Taken out of the trash first, as the trash is locked after the catalog; put back if
+    // the catalog won't have it
+    let trashed = {
+        let mut trash = data.trash.lock().unwrap();
+        match trash.iter().position(|b| b.id == book_id) {
+            Some(index) => trash.remove(index),
+            None => {
+                return HttpResponse::NotFound().json(ErrorResponse {
+                    error: format!("Book with id {} is not in the trash", book_id),
+                })
+            }
+        }
+    };
+    let mut book = trashed.clone();
+    book.deleted_at = None;
+    book.touch(data.clock.now());
+    if let Err(err) = data.books.insert(book.clone()) {
+        let mut trash = data.trash.lock().unwrap();
+        let position = trash.partition_point(|b| b.id < book_id);
+        trash.insert(position, trashed);
+        return match err {
+            RepositoryError::DuplicateIsbn { isbn, existing_id } => {
+                HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!(
+                        "Cannot restore book {}: its ISBN {} now belongs to book {}",
+                        book_id, isbn, existing_id
+                    ),
+                })
+            }
+            err => repository_error(err),
+        };
+    }
+    refresh_catalog(&data);
+    
+    HttpResponse::Ok()
+        .insert_header(etag(&book))
+        .json(project_book(&book, &None, compat))
+}
+
+// Search filters sent as a JSON object, validated exactly like search query parameters.
+// None when no filter parameter was given.
+fn filter_from_body(
+    body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Option<SearchFilter>, String> {
+    use serde_json::Value;
+    
+    let mut pairs = Vec::new();
+    for (key, value) in body {
+        if NON_FILTER_SEARCH_PARAMS.contains(&key.as_str()) {
+            return Err(format!("'{}' is not a filter parameter", key));
+        }
+        let values: Vec<&Value> = match value {
+            Value::Array(items) if REPEATABLE_SEARCH_PARAMS.contains(&key.as_str()) => {
+                items.iter().collect()
+            }
+            other => vec![other],
+        };
+        for value in values {
+            let raw = match value {
+                Value::String(raw) => raw.clone(),
+                Value::Number(number) => number.to_string(),
+                Value::Bool(flag) => flag.to_string(),
+                Value::Null => continue,
+                _ => return Err(format!("{} must be a string, number, or boolean", key)),
+            };
+            pairs.push((key.clone(), raw));
+        }
+    }
+    if pairs.is_empty() {
+        return Ok(None);
+    }
+    
+    let query = search_query_from_pairs(pairs).map_err(|err| format!("Invalid filter: {}", err))?;
+    SearchFilter::from_query(&query).map(Some)
+}
+
+// Deletes the listed books, or every book matching a search filter, under one lock; like
+// a single delete they go to the trash unless `permanent` is set. Only books in the
+// catalog are matched. An empty selection is refused so a missing filter can't wipe it.
+async fn batch_delete_books(
+    delete_req: web::Json<BatchDeleteRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let filter = match filter_from_body(&delete_req.filter) {
+        Ok(filter) => filter,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let ids = delete_req.ids.as_ref().filter(|ids| !ids.is_empty());
+    if ids.is_some() && filter.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Send either ids or filter parameters, not both".to_string(),
+        });
+    }
+    
+    let response = data.books.write_with(|books| {
+    
+        let (mut matched, not_found) = match (ids, filter) {
+            (Some(ids), _) => {
+                let mut requested = ids.clone();
+                requested.sort_unstable();
+                requested.dedup();
+                requested
+                    .into_iter()
+                    .partition(|id| books.iter().any(|b| b.id == *id))
+            }
+            (None, Some(filter)) => {
+                let results = filter.apply(books, &data.config);
+                (results.books.iter().map(|b| b.id).collect(), Vec::new())
+            }
+            (None, None) => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Batch delete needs ids or at least one filter parameter".to_string(),
+                })
+            }
+        };
+        matched.sort_unstable();
+    
+        let on_loan: Vec<u32> = books
+            .iter()
+            .filter(|b| b.checked_out_copies > 0 && matched.binary_search(&b.id).is_ok())
+            .map(|b| b.id)
+            .collect();
+        if !on_loan.is_empty() {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Books {:?} have copies checked out; nothing was deleted",
+                    on_loan
+                ),
+            });
+        }
+    
+        if !delete_req.dry_run && !matched.is_empty() {
+            let (removed, kept): (Vec<Book>, Vec<Book>) = std::mem::take(&mut *books)
+                .into_iter()
+                .partition(|b| matched.binary_search(&b.id).is_ok());
+            *books = kept;
+            if delete_req.permanent {
+                data.reviews
+                    .lock()
+                    .unwrap()
+                    .retain(|r| matched.binary_search(&r.book_id).is_err());
+                data.holds
+                    .lock()
+                    .unwrap()
+                    .retain(|h| matched.binary_search(&h.book_id).is_err());
+            } else {
+                move_to_trash(&mut data.trash.lock().unwrap(), removed, data.clock.now());
+            }
+            unlist_books(&data, &matched);
+            catalog_changed(&data, books);
+        }
+    
+        HttpResponse::Ok().json(BatchDeleteResponse {
+            deleted: matched.len(),
+            ids: matched,
+            not_found,
+            dry_run: delete_req.dry_run,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Applies one partial update to every book matching a filter, under one lock. The update
+// is validated against every matching book before any of them is changed.
+async fn batch_update_books(
+    batch_req: web::Json<BatchUpdateRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let update_req = &batch_req.update;
+    if update_req.isbn.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "isbn cannot be set in a batch update, since every book needs its own".to_string(),
+        });
+    }
+    if update_req.version.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "version cannot be used in a batch update".to_string(),
+        });
+    }
+    let filter = match filter_from_body(&batch_req.filter) {
+        Ok(Some(filter)) => filter,
+        Ok(None) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Batch update needs at least one filter parameter".to_string(),
+            })
+        }
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let response = data.books.write_with(|books| {
+        if let Err(error) = check_branch(&data, update_req.branch_id.flatten()) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    
+        let matched: Vec<u32> =
+            filter.apply(books, &data.config).books.iter().map(|b| b.id).collect();
+        let mut updates = Vec::new();
+        for (index, book) in books.iter().enumerate() {
+            if !matched.contains(&book.id) {
+                continue;
+            }
+            match apply_update(book, update_req, data.clock.now()) {
+                Ok(updated) if updated != *book => updates.push((index, updated)),
+                Ok(_) => {}
+                Err(error) => {
+                    return HttpResponse::BadRequest().json(ErrorResponse {
+                        error: format!("Book with id {}: {}", book.id, error),
+                    })
+                }
+            }
+        }
+    
+        let ids: Vec<u32> = updates.iter().map(|(_, book)| book.id).collect();
+        for (index, updated) in updates {
+            books[index] = updated;
+        }
+        if !ids.is_empty() {
+            catalog_changed(&data, books);
+        }
+    
+        HttpResponse::Ok().json(BatchUpdateResponse {
+            matched: matched.len(),
+            modified: ids.len(),
+            ids,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Lends one copy: it leaves the shelf and a loan is opened until it's returned. A member
+// can hold only one copy of a book at a time. Responds with the book, and the new loan
+// in `Location`.
+async fn checkout_book(
+    path: web::Path<u32>,
+    checkout_req: web::Json<CheckoutRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let now = data.clock.now();
+    let due_at = checkout_req.due_at.unwrap_or(now + data.config.loan_period);
+    if due_at <= now {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "due_at must be in the future".to_string(),
+        });
+    }
+    
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+        let Some(book) = books.iter_mut().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        let mut loans = data.loans.lock().unwrap();
+        let mut next_loan_id = data.next_loan_id.lock().unwrap();
+        let mut holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let Some(member) = members.iter().find(|m| m.id == checkout_req.member_id) else {
+            return validation_failed(validation::Errors::new(
+                "member_id",
+                format!("Member with id {} not found", checkout_req.member_id),
+            ));
+        };
+        if !member.active {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!("Member with id {} is deactivated and can't borrow", member.id),
+            });
+        }
+        if loans
+            .of_book(book_id)
+            .any(|l| l.is_open() && l.member_id == Some(member.id))
+        {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Member with id {} already has a copy of book {} checked out",
+                    member.id, book_id
+                ),
+            });
+        }
+        // Checked under the same loans lock the new loan is added with, so concurrent checkouts
+        // can't take a member past the limit. Staff overrides wait for admin auth.
+        let open: Vec<&Loan> = loans
+            .iter()
+            .filter(|l| l.is_open() && l.member_id == Some(member.id))
+            .collect();
+        if open.len() >= data.config.max_open_loans {
+            return HttpResponse::Conflict().json(LoanLimitResponse {
+                error: format!(
+                    "Member with id {} already has {} books checked out, the most allowed",
+                    member.id,
+                    open.len()
+                ),
+                loans: open
+                    .iter()
+                    .map(|l| HeldBook {
+                        loan_id: l.id,
+                        book_id: l.book_id,
+                        title: books.iter().find(|b| b.id == l.book_id).map(|b| b.title.clone()),
+                        due_at: l.due_at,
+                    })
+                    .collect(),
+            });
+        }
+        if book.available_copies == 0 {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Book with id {} has no copy available ({} of {} checked out)",
+                    book_id, book.checked_out_copies, book.total_copies
+                ),
+            });
+        }
+        // Copies set aside for the hold queue go to those first in line
+        let queue: Vec<usize> = (0..holds.len())
+            .filter(|&i| holds[i].book_id == book_id)
+            .collect();
+        let place = queue.iter().position(|&i| holds[i].member_id == member.id);
+        if !book.available && place.is_none_or(|place| place as u32 >= book.available_copies) {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Book with id {} is held for the {} members ahead in its hold queue",
+                    book_id,
+                    place.unwrap_or(queue.len())
+                ),
+            });
+        }
+        // Checking out fulfils the member's hold
+        if let Some(place) = place {
+            holds.remove(queue[place]);
+            book.set_holds(book.hold_count - 1, data.config.holds_reserve_copies);
+        }
+    
+        let loan_id = *next_loan_id;
+        *next_loan_id += 1;
+        loans.push(Loan {
+            id: loan_id,
+            book_id,
+            member_id: Some(member.id),
+            checked_out_at: now,
+            due_at,
+            returned_at: None,
+            renewal_count: 0,
+            fine_cents: 0,
+            fine_paid_cents: 0,
+            reminded_for: None,
+        });
+        book.checked_out_copies += 1;
+        book.set_copies(book.total_copies, book.available_copies - 1);
+        book.touch(data.clock.now());
+    
+        let header = etag(book);
+        let body = project_book(book, &None, compat);
+        drop(members);
+        drop(holds);
+        catalog_changed(&data, books);
+        HttpResponse::Ok()
+            .insert_header(header)
+            .insert_header((
+                actix_web::http::header::LOCATION,
+                format!("{}/loans/{}", API_PREFIX, loan_id),
+            ))
+            .json(body)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Closes an open loan, charging its fine if it's late, and puts the copy back on the
+// shelf. `member_id` picks the loan when several copies are out; with one loan it may be
+// left out. Returns are accepted from members
+// who have since been deactivated or deleted.
+async fn return_book(
+    path: web::Path<u32>,
+    query: web::Query<ReturnQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+        let Some(book) = books.iter_mut().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        let mut loans = data.loans.lock().unwrap();
+        let open: Vec<usize> = loans
+            .positions(book_id)
+            .iter()
+            .copied()
+            .filter(|&i| loans[i].is_open())
+            .collect();
+        let index = match (query.member_id, open.len()) {
+            (_, 0) => {
+                return HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!("Book with id {} is not checked out", book_id),
+                })
+            }
+            (Some(member_id), _) => match open.iter().find(|&&i| loans[i].member_id == Some(member_id)) {
+                Some(&index) => index,
+                None => {
+                    return HttpResponse::Conflict().json(ErrorResponse {
+                        error: format!(
+                            "Member with id {} has no copy of book {} checked out",
+                            member_id, book_id
+                        ),
+                    })
+                }
+            },
+            (None, 1) => open[0],
+            (None, on_loan) => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "Book with id {} has {} copies checked out; pass member_id to say which one is returned",
+                        book_id, on_loan
+                    ),
+                })
+            }
+        };
+    
+        let now = data.clock.now();
+        let loan = &mut loans[index];
+        loan.returned_at = Some(now);
+        loan.fine_cents = fines::fine_cents(
+            loan.due_at,
+            now,
+            data.config.fine_per_day_cents,
+            data.config.max_fine_cents,
+        );
+        book.checked_out_copies -= 1;
+        book.set_copies(book.total_copies, book.available_copies + 1);
+        book.touch(data.clock.now());
+    
+        let mut holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        if members
+            .iter()
+            .any(|m| Some(m.id) == loan.member_id && !m.retain_history)
+        {
+            loan.anonymize();
+        }
+        // The pickup window starts with the first return the member is told about
+        if let Some(hold) = holds.iter_mut().find(|h| h.book_id == book_id) {
+            hold.pickup_by.get_or_insert(now + data.config.hold_pickup_window);
+        }
+        let next_hold = holds
+            .iter()
+            .find(|h| h.book_id == book_id)
+            .map(|hold| hold_response(hold, 1, &members));
+        if let Some(member) = next_hold
+            .as_ref()
+            .and_then(|next| members.iter().find(|m| m.id == next.hold.member_id))
+        {
+            notify_hold_ready(&data, member, &book.title);
+        }
+    
+        let header = etag(book);
+        let body = ReturnResponse {
+            book: project_book(book, &None, compat),
+            loan: LoanResponse {
+                loan,
+                status: loan.status(now),
+            },
+            next_hold,
+        };
+        catalog_changed(&data, books);
+        HttpResponse::Ok().insert_header(header).json(body)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn notify_hold_ready(data: &web::Data<AppState>, member: &Member, title: &str) {
+    notify_later(
+        data,
+        member.email.clone(),
+        format!("\"{}\" is ready for you", title),
+        format!(
+            "Hi {}, a copy of \"{}\" you placed a hold on has been returned.",
+            member.name, title
+        ),
+    );
+}
+
+fn record_event(data: &AppState, kind: EventKind, book_id: u32, member_id: Option<u32>) {
+    let mut events = data.events.lock().unwrap();
+    let mut next_event_id = data.next_event_id.lock().unwrap();
+    events.push(Event {
+        id: *next_event_id,
+        kind,
+        at: data.clock.now(),
+        book_id,
+        member_id,
+    });
+    *next_event_id += 1;
+    if events.len() > MAX_EVENTS {
+        let excess = events.len() - MAX_EVENTS;
+        events.drain(..excess);
+    }
+}
+
+// Cancels every hold whose pickup window has lapsed, recording a `hold_expired` event for
+// each. The next member in line then gets the copy: their window starts and they are
+// notified, as if the book had just been returned. Holds on books in the trash expire too,
+// but nobody is promoted until the book is back. Returns how many holds expired; run by
+// the background task and the admin endpoint.
+fn expire_holds(data: &web::Data<AppState>) -> usize {
+    let now = data.clock.now();
+    let expired = data.books.write_with(|books| {
+        let mut holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let (expired, kept): (Vec<Hold>, Vec<Hold>) = std::mem::take(&mut *holds)
+            .into_iter()
+            .partition(|h| h.pickup_by.is_some_and(|pickup_by| pickup_by <= now));
+        *holds = kept;
+    
+        for hold in &expired {
+            println!(
+                "Hold of member {} on book {} expired uncollected",
+                hold.member_id, hold.book_id
+            );
+            record_event(data, EventKind::HoldExpired, hold.book_id, Some(hold.member_id));
+            let Some(book) = books.iter_mut().find(|b| b.id == hold.book_id) else {
+                continue;
+            };
+            book.set_holds(book.hold_count - 1, data.config.holds_reserve_copies);
+            if book.available_copies == 0 {
+                continue;
+            }
+            let Some(next) = holds.iter_mut().find(|h| h.book_id == book.id) else {
+                continue;
+            };
+            if next.pickup_by.is_none() {
+                next.pickup_by = Some(now + data.config.hold_pickup_window);
+                if let Some(member) = members.iter().find(|m| m.id == next.member_id) {
+                    notify_hold_ready(data, member, &book.title);
+                }
+            }
+        }
+        drop(members);
+        drop(holds);
+        if !expired.is_empty() {
+            catalog_changed(data, books);
+        }
+        expired.len()
+    });
+    expired.unwrap_or_else(|err| {
+        eprintln!("Could not expire holds: {}", err);
+        0
+    })
+}
+
+async fn expire_holds_now(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "expired": expire_holds(&data) }))
+}
+
+// Events in the order they happened; `since` the id of the last one a client has seen
+async fn get_events(query: web::Query<EventsQuery>, data: web::Data<AppState>) -> impl Responder {
+    let since = query.since.unwrap_or(0);
+    let events = data.events.lock().unwrap();
+    let newer: Vec<&Event> = events.iter().filter(|e| e.id > since).collect();
+    HttpResponse::Ok().json(newer)
+}
+
+fn hold_response<'a>(hold: &'a Hold, position: usize, members: &[Member]) -> HoldResponse<'a> {
+    HoldResponse {
+        hold,
+        position,
+        member_name: members
+            .iter()
+            .find(|m| m.id == hold.member_id)
+            .map(|m| m.name.clone()),
+    }
+}
+
+// Lists a book's hold queue, first in line first
+async fn get_holds(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let book_id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return repository_error(RepositoryError::NotFound(book_id));
+        }
+        let holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let queue: Vec<HoldResponse> = holds
+            .iter()
+            .filter(|h| h.book_id == book_id)
+            .enumerate()
+            .map(|(i, hold)| hold_response(hold, i + 1, &members))
+            .collect();
+        HttpResponse::Ok().json(queue)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// "More like this": other books ranked by shared authors, genre and tags, and title
+// words, with the component scores so clients can see why
+async fn get_similar_books(
+    path: web::Path<u32>,
+    query: web::Query<SimilarQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match &query.limit {
+        Some(raw) => match raw.parse::<usize>() {
+            Ok(limit) if (1..=MAX_SIMILAR_LIMIT).contains(&limit) => limit,
+            _ => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "limit must be an integer between 1 and {}, got '{}'",
+                        MAX_SIMILAR_LIMIT, raw
+                    ),
+                })
+            }
+        },
+        None => DEFAULT_SIMILAR_LIMIT,
+    };
+    let book_id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        let mut index = data.similar.lock().unwrap();
+        let Some(similar) = index
+            .get_or_insert_with(|| build_similar(books))
+            .similar(book_id, limit)
+        else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        let body: Vec<SimilarBook> = similar
+            .into_iter()
+            .map(|s| {
+                let book = books.iter().find(|b| b.id == s.book_id).unwrap();
+                SimilarBook {
+                    book: project_book(book, &None, compat),
+                    scores: s.scores,
+                }
+            })
+            .collect();
+        HttpResponse::Ok().json(body)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// A book's whole lending history, newest first, with a summary of its circulation.
+// Unknown books are 404; a book that has never been lent has an empty history.
+async fn get_book_loans(
+    path: web::Path<u32>,
+    query: web::Query<PageQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    let page = match query.page.as_deref().map_or(Ok(1), parse_page) {
+        Ok(page) => page,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match parse_limit(query.limit.as_deref(), &data.config) {
+        Ok(limit) => limit,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return repository_error(RepositoryError::NotFound(book_id));
+        }
+    
+        let now = data.clock.now();
+        let loans = data.loans.lock().unwrap();
+        let history: Vec<&Loan> = loans.of_book(book_id).rev().collect();
+        let open: Vec<&&Loan> = history.iter().filter(|l| l.is_open()).collect();
+        let status = if open.iter().any(|l| l.status(now) == LoanStatus::Overdue) {
+            CirculationStatus::Overdue
+        } else if !open.is_empty() {
+            CirculationStatus::CheckedOut
+        } else {
+            CirculationStatus::Available
+        };
+        let summary = CirculationSummary {
+            total_loans: history.len(),
+            open_loans: open.len(),
+            last_checked_out_at: history.first().map(|l| l.checked_out_at),
+            status,
+        };
+        let items: Vec<LoanResponse> = history
+            .iter()
+            .skip((page - 1).saturating_mul(limit))
+            .take(limit)
+            .map(|&loan| LoanResponse {
+                loan,
+                status: loan.status(now),
+            })
+            .collect();
+    
+        HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, history.len()))
+            .json(LoanHistory {
+                summary,
+                loans: PaginatedResponse {
+                    items,
+                    page: Some(page),
+                    limit,
+                    total: history.len(),
+                    next_cursor: None,
+                },
+            })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Moves a book to another branch. Only a book with every copy on the shelf can move; the
+// check and the move happen under the books lock, so a checkout can't slip in between.
+async fn transfer_book(
+    path: web::Path<u32>,
+    transfer_req: web::Json<TransferRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let book_id = path.into_inner();
+    let to_branch = transfer_req.to_branch;
+    let response = data.books.write_with(|books| {
+        let Some(book) = books.iter_mut().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        if let Err(error) = check_branch(&data, Some(to_branch)) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+        if book.branch_id == Some(to_branch) {
+            return HttpResponse::Ok().insert_header(etag(book)).json(TransferResponse {
+                unchanged: true,
+                transfer: None,
+                book: project_book(book, &None, compat),
+            });
+        }
+        if book.checked_out_copies > 0 {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Book with id {} has {} copies checked out; return them before transferring",
+                    book_id, book.checked_out_copies
+                ),
+            });
+        }
+    
+        let mut next_transfer_id = data.next_transfer_id.lock().unwrap();
+        let transfer = Transfer {
+            id: *next_transfer_id,
+            book_id,
+            from_branch: book.branch_id,
+            to_branch,
+            transferred_at: data.clock.now(),
+        };
+        *next_transfer_id += 1;
+        data.transfers.lock().unwrap().push(transfer.clone());
+        book.branch_id = Some(to_branch);
+        book.touch(data.clock.now());
+    
+        let header = etag(book);
+        let body = project_book(book, &None, compat);
+        catalog_changed(&data, books);
+        HttpResponse::Ok().insert_header(header).json(TransferResponse {
+            unchanged: false,
+            transfer: Some(transfer),
+            book: body,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Newest first
+async fn get_book_transfers(
+    path: web::Path<u32>,
+    query: web::Query<PageQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    let page = match query.page.as_deref().map_or(Ok(1), parse_page) {
+        Ok(page) => page,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match parse_limit(query.limit.as_deref(), &data.config) {
+        Ok(limit) => limit,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return repository_error(RepositoryError::NotFound(book_id));
+        }
+    
+        let transfers = data.transfers.lock().unwrap();
+        let history: Vec<&Transfer> =
+            transfers.iter().filter(|t| t.book_id == book_id).rev().collect();
+        let items: Vec<&Transfer> = history
+            .iter()
+            .skip((page - 1).saturating_mul(limit))
+            .take(limit)
+            .copied()
+            .collect();
+        HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, history.len()))
+            .json(PaginatedResponse {
+                items,
+                page: Some(page),
+                limit,
+                total: history.len(),
+                next_cursor: None,
+            })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn schedule_book_not_found(book_id: u32) -> HttpResponse {
+    repository_error(RepositoryError::NotFound(book_id))
+}
+
+async fn schedule_change(
+    path: web::Path<u32>,
+    schedule_req: web::Json<ScheduleRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    let now = data.clock.now();
+    if schedule_req.at <= now {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "at must be in the future".to_string(),
+        });
+    }
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return schedule_book_not_found(book_id);
+        }
+    
+        let mut schedules = data.schedules.lock().unwrap();
+        let mut next_schedule_id = data.next_schedule_id.lock().unwrap();
+        let change = ScheduledChange {
+            id: *next_schedule_id,
+            book_id,
+            available: schedule_req.available,
+            at: schedule_req.at,
+            created_at: now,
+        };
+        *next_schedule_id += 1;
+        schedules.push(change.clone());
+        HttpResponse::Created().json(change)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// The book's pending changes, the next one first
+async fn get_schedule(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let book_id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return schedule_book_not_found(book_id);
+        }
+    
+        let schedules = data.schedules.lock().unwrap();
+        let mut pending: Vec<&ScheduledChange> =
+            schedules.iter().filter(|c| c.book_id == book_id).collect();
+        pending.sort_by_key(|c| (c.at, c.id));
+        HttpResponse::Ok().json(pending)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn cancel_schedule(path: web::Path<(u32, u32)>, data: web::Data<AppState>) -> impl Responder {
+    let (book_id, schedule_id) = path.into_inner();
+    let mut schedules = data.schedules.lock().unwrap();
+    let Some(index) = schedules
+        .iter()
+        .position(|c| c.id == schedule_id && c.book_id == book_id)
+    else {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: format!(
+                "Book with id {} has no pending change with id {}",
+                book_id, schedule_id
+            ),
+        });
+    };
+    schedules.remove(index);
+    HttpResponse::NoContent().finish()
+}
+
+// Puts a member at the back of a book's hold queue. Holds are for books with no copy to
+// take from the shelf; members already holding or borrowing the book can't queue again.
+async fn place_hold(
+    path: web::Path<u32>,
+    hold_req: web::Json<HoldRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    let member_id = hold_req.member_id;
+    let response = data.books.write_with(|books| {
+        let Some(book) = books.iter_mut().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        let loans = data.loans.lock().unwrap();
+        let mut holds = data.holds.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let Some(member) = members.iter().find(|m| m.id == member_id) else {
+            return validation_failed(validation::Errors::new(
+                "member_id",
+                format!("Member with id {} not found", member_id),
+            ));
+        };
+        if !member.active {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!("Member with id {} is deactivated and can't place holds", member_id),
+            });
+        }
+        if book.available {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Book with id {} has a copy available; check it out instead",
+                    book_id
+                ),
+            });
+        }
+        if holds.iter().any(|h| h.book_id == book_id && h.member_id == member_id) {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Member with id {} is already in the hold queue of book {}",
+                    member_id, book_id
+                ),
+            });
+        }
+        if loans
+            .iter()
+            .any(|l| l.is_open() && l.book_id == book_id && l.member_id == Some(member_id))
+        {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: format!(
+                    "Member with id {} already has a copy of book {} checked out",
+                    member_id, book_id
+                ),
+            });
+        }
+    
+        holds.push(Hold {
+            book_id,
+            member_id,
+            placed_at: data.clock.now(),
+            pickup_by: None,
+        });
+        book.set_holds(book.hold_count + 1, data.config.holds_reserve_copies);
+        let position = book.hold_count as usize;
+        let body = serde_json::to_value(hold_response(holds.last().unwrap(), position, &members))
+            .unwrap();
+        drop(members);
+        drop(holds);
+        catalog_changed(&data, books);
+        HttpResponse::Created().json(body)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn cancel_hold(path: web::Path<(u32, u32)>, data: web::Data<AppState>) -> impl Responder {
+    let (book_id, member_id) = path.into_inner();
+    let response = data.books.write_with(|books| {
+        let Some(book) = books.iter_mut().find(|b| b.id == book_id) else {
+            return repository_error(RepositoryError::NotFound(book_id));
+        };
+        let mut holds = data.holds.lock().unwrap();
+        let Some(index) = holds
+            .iter()
+            .position(|h| h.book_id == book_id && h.member_id == member_id)
+        else {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: format!(
+                    "Member with id {} has no hold on book {}",
+                    member_id, book_id
+                ),
+            });
+        };
+        holds.remove(index);
+        book.set_holds(book.hold_count - 1, data.config.holds_reserve_copies);
+        drop(holds);
+        catalog_changed(&data, books);
+        HttpResponse::NoContent().finish()
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+#[derive(Serialize)]
+struct DuplicateCluster {
+    ids: Vec<u32>,
+    books: Vec<serde_json::Value>,
+}
+
+// Fields listed per book in a duplicate cluster
+const DUPLICATE_BOOK_FIELDS: &[&str] =
+    &["id", "title", "authors", "isbn", "published_year", "edition"];
+
+// Clusters of books whose titles and author lists are both at least DUPLICATE_SIMILARITY
+// alike once slugged, so case, punctuation, and small typos don't hide a duplicate.
+// Similarity chains: if A is like B and B is like C, all three are one cluster. Clusters
+// are ordered by their lowest id, and books within one by id.
+async fn get_duplicates(query: web::Query<CompatQuery>, data: web::Data<AppState>) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let response = data.books.read_with(|books| {
+        let keys: Vec<(String, String)> = books
+            .iter()
+            .map(|b| {
+                let mut authors: Vec<String> = b.authors.iter().map(|a| text::slug(a)).collect();
+                authors.sort();
+                (text::slug(&b.title), authors.join(" "))
+            })
+            .collect();
+    
+        // Cluster label per book, relabelled as clusters join
+        let mut labels: Vec<usize> = (0..books.len()).collect();
+        for i in 0..books.len() {
+            for j in (i + 1)..books.len() {
+                let (title_a, authors_a) = &keys[i];
+                let (title_b, authors_b) = &keys[j];
+                if labels[i] != labels[j]
+                    && search::similarity(title_a, title_b) >= DUPLICATE_SIMILARITY
+                    && search::similarity(authors_a, authors_b) >= DUPLICATE_SIMILARITY
+                {
+                    let (from, to) = (labels[j], labels[i]);
+                    for label in labels.iter_mut().filter(|label| **label == from) {
+                        *label = to;
+                    }
+                }
+            }
+        }
+    
+        let fields = Some(DUPLICATE_BOOK_FIELDS.iter().map(|f| f.to_string()).collect());
+        let mut clusters: Vec<Vec<&Book>> = Vec::new();
+        let mut seen: Vec<usize> = Vec::new();
+        for &label in &labels {
+            if seen.contains(&label) {
+                continue;
+            }
+            seen.push(label);
+            let mut members: Vec<&Book> = books
+                .iter()
+                .zip(&labels)
+                .filter(|(_, l)| **l == label)
+                .map(|(b, _)| b)
+                .collect();
+            if members.len() > 1 {
+                members.sort_by_key(|b| b.id);
+                clusters.push(members);
+            }
+        }
+        clusters.sort_by_key(|members| members[0].id);
+    
+        let clusters: Vec<DuplicateCluster> = clusters
+            .into_iter()
+            .map(|members| DuplicateCluster {
+                ids: members.iter().map(|b| b.id).collect(),
+                books: members
+                    .iter()
+                    .map(|b| project_book(b, &fields, compat))
+                    .collect(),
+            })
+            .collect();
+        HttpResponse::Ok().json(clusters)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Folds the `merge_ids` books into `keep_id` and deletes them for good (they don't go to
+// the trash): empty fields on the kept book are filled from the merged ones in the order
+// given, tags are combined, and their reviews move over. Copy counts and everything the
+// kept book already has stay as they are. All or nothing.
+async fn merge_books(
+    merge_req: web::Json<MergeBooksRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let keep_id = merge_req.keep_id;
+    let mut merge_ids: Vec<u32> = Vec::new();
+    for &id in &merge_req.merge_ids {
+        if !merge_ids.contains(&id) {
+            merge_ids.push(id);
+        }
+    }
+    if merge_ids.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "merge_ids cannot be empty".to_string(),
+        });
+    }
+    if merge_ids.contains(&keep_id) {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("Cannot merge book {} into itself", keep_id),
+        });
+    }
+    
+    let response = data.books.write_with(|books| {
+        let mut reviews = data.reviews.lock().unwrap();
+    
+        let Some(keep_index) = books.iter().position(|b| b.id == keep_id) else {
+            return repository_error(RepositoryError::NotFound(keep_id));
+        };
+        let mut merged = books[keep_index].clone();
+        for &id in &merge_ids {
+            let Some(other) = books.iter().find(|b| b.id == id) else {
+                return repository_error(RepositoryError::NotFound(id));
+            };
+            if other.hold_count > 0 {
+                return HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!(
+                        "Cannot merge book {}: {} members are waiting in its hold queue",
+                        id, other.hold_count
+                    ),
+                });
+            }
+            if other.checked_out_copies > 0 {
+                return HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!(
+                        "Cannot merge book {}: {} of its copies are checked out",
+                        id, other.checked_out_copies
+                    ),
+                });
+            }
+            if let Err(error) = merged.fill_missing_from(other) {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!("Cannot merge book {}: {}", id, error),
+                });
+            }
+        }
+        if merged != books[keep_index] {
+            merged.touch(data.clock.now());
+        }
+    
+        let mut moved_reviews = false;
+        for review in reviews.iter_mut().filter(|r| merge_ids.contains(&r.book_id)) {
+            review.book_id = keep_id;
+            moved_reviews = true;
+        }
+        if moved_reviews {
+            merged.refresh_rating(&reviews, data.clock.now());
+        }
+        // Only returned loans can be left; their history moves with the reviews
+        let mut loans = data.loans.lock().unwrap();
+        for &id in &merge_ids {
+            loans.move_book(id, keep_id);
+        }
+        drop(loans);
+        for transfer in data.transfers.lock().unwrap().iter_mut() {
+            if merge_ids.contains(&transfer.book_id) {
+                transfer.book_id = keep_id;
+            }
+        }
+        // In lists, the merged books become the kept book, listed once where the first of them was
+        for list in data.lists.lock().unwrap().iter_mut() {
+            let mut seen = false;
+            list.book_ids.retain_mut(|id| {
+                if *id == keep_id || merge_ids.contains(id) {
+                    *id = keep_id;
+                    !std::mem::replace(&mut seen, true)
+                } else {
+                    true
+                }
+            });
+        }
+    
+        books[keep_index] = merged.clone();
+        books.retain(|b| !merge_ids.contains(&b.id));
+        catalog_changed(&data, books);
+    
+        HttpResponse::Ok()
+            .insert_header(etag(&merged))
+            .json(project_book(&merged, &None, compat))
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn add_tags(
+    path: web::Path<u32>,
+    tags_req: web::Json<AddTagsRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let book_id = path.into_inner();
+    let response = data.books.write_with(|books| {
+    
+        let book = match books.iter_mut().find(|b| b.id == book_id) {
+            Some(book) => book,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        match merge_tags(&book.tags, &tags_req.tags) {
+            Ok(tags) if tags != book.tags => {
+                book.tags = tags;
+                book.touch(data.clock.now());
+            }
+            Ok(_) => return HttpResponse::Ok().json(project_book(book, &None, compat)),
+            Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+        }
+    
+        let body = project_book(book, &None, compat);
+        catalog_changed(&data, books);
+        HttpResponse::Ok().json(body)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn remove_tag(
+    path: web::Path<(u32, String)>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let (book_id, raw_tag) = path.into_inner();
+    let tag = raw_tag.trim().to_lowercase();
+    let response = data.books.write_with(|books| {
+    
+        let book = match books.iter_mut().find(|b| b.id == book_id) {
+            Some(book) => book,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        match book.tags.iter().position(|t| *t == tag) {
+            Some(index) => {
+                book.tags.remove(index);
+                book.touch(data.clock.now());
+                let body = project_book(book, &None, compat);
+                catalog_changed(&data, books);
+                HttpResponse::Ok().json(body)
+            }
+            None => HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("Book with id {} does not have tag '{}'", book_id, raw_tag),
+            }),
+        }
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn validate_rating(rating: u8) -> Result<(), String> {
+    if !(MIN_RATING..=MAX_RATING).contains(&rating) {
+        return Err(format!(
+            "rating must be between {} and {}",
+            MIN_RATING, MAX_RATING
+        ));
+    }
+    Ok(())
+}
+
+// Oldest first
+async fn get_reviews(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let book_id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        if !books.iter().any(|b| b.id == book_id) {
+            return repository_error(RepositoryError::NotFound(book_id));
+        }
+    
+        let reviews: Vec<Review> = data
+            .reviews
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.book_id == book_id)
+            .cloned()
+            .collect();
+        HttpResponse::Ok().json(reviews)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn create_review(
+    path: web::Path<u32>,
+    review_req: web::Json<CreateReviewRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let book_id = path.into_inner();
+    
+    if let Err(error) = validate_rating(review_req.rating) {
+        return HttpResponse::BadRequest().json(ErrorResponse { error });
+    }
+    
+    let reviewer = match text::clean("Reviewer", &review_req.reviewer) {
+        Ok(reviewer) => reviewer,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    if reviewer.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Reviewer cannot be empty".to_string(),
+        });
+    }
+    if let Err(error) = validate_length("Reviewer", &reviewer, MAX_REVIEWER_LEN) {
+        return HttpResponse::BadRequest().json(ErrorResponse { error });
+    }
+    
+    // An empty or whitespace-only comment is stored as no comment
+    let comment = review_req
+        .comment
+        .as_deref()
+        .map(|raw| text::clean_multiline("Comment", raw));
+    let comment = match comment {
+        Some(Ok(comment)) if comment.is_empty() => None,
+        Some(Ok(comment)) => Some(comment),
+        Some(Err(error)) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+        None => None,
+    };
+    if let Some(comment) = &comment {
+        if let Err(error) = validate_length("Comment", comment, MAX_REVIEW_COMMENT_LEN) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    }
+    
+    let response = data.books.write_with(|books| {
+        let book = match books.iter_mut().find(|b| b.id == book_id) {
+            Some(book) => book,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        let mut reviews = data.reviews.lock().unwrap();
+        let mut next_review_id = data.next_review_id.lock().unwrap();
+        let review = Review {
+            id: *next_review_id,
+            book_id,
+            rating: review_req.rating,
+            reviewer,
+            comment,
+            created_at: data.clock.now(),
+        };
+        *next_review_id += 1;
+        reviews.push(review.clone());
+        book.refresh_rating(&reviews, data.clock.now());
+        catalog_changed(&data, books);
+    
+        HttpResponse::Created().json(review)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn delete_review(path: web::Path<(u32, u32)>, data: web::Data<AppState>) -> impl Responder {
+    let (book_id, review_id) = path.into_inner();
+    let response = data.books.write_with(|books| {
+        let book = match books.iter_mut().find(|b| b.id == book_id) {
+            Some(book) => book,
+            None => {
+                return repository_error(RepositoryError::NotFound(book_id))
+            }
+        };
+    
+        let mut reviews = data.reviews.lock().unwrap();
+        // A review id under the wrong book is reported as missing rather than deleted
+        match reviews
+            .iter()
+            .position(|r| r.id == review_id && r.book_id == book_id)
+        {
+            Some(index) => {
+                reviews.remove(index);
+                book.refresh_rating(&reviews, data.clock.now());
+                catalog_changed(&data, books);
+                HttpResponse::NoContent().finish()
+            }
+            None => HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("Review with id {} not found for book {}", review_id, book_id),
+            }),
+        }
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn suggest_books(
+    query: web::Query<SuggestQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let prefix = match &query.prefix {
+        Some(prefix) if !prefix.trim().is_empty() => prefix,
+        _ => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: "prefix cannot be empty".to_string(),
+            })
+        }
+    };
+    
+    let limit = match &query.limit {
+        Some(raw) => match raw.parse::<usize>() {
+            Ok(limit) if (1..=MAX_SUGGESTION_LIMIT).contains(&limit) => limit,
+            _ => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "limit must be an integer between 1 and {}, got '{}'",
+                        MAX_SUGGESTION_LIMIT, raw
+                    ),
+                })
+            }
+        },
+        None => DEFAULT_SUGGESTION_LIMIT,
+    };
+    
+    let suggestions = data.suggestions.lock().unwrap();
+    HttpResponse::Ok().json(suggestions.lookup(prefix, limit))
+}
+
+// Every token must appear in at least one of the book's text fields.
+// ISBNs are stored normalized, so tokens are normalized before matching them.
+// Language codes are too short to match partially, so they must equal the token.
+fn matches_all_tokens(book: &Book, tokens: &[String]) -> bool {
+    let title = book.title.to_lowercase();
+    let author = book.author_line().to_lowercase();
+    let description = book.description.as_deref().unwrap_or_default().to_lowercase();
+    tokens.iter().all(|token| {
+        let isbn_token = isbn::normalize(token);
+        title.contains(token.as_str())
+            || author.contains(token.as_str())
+            || description.contains(token.as_str())
+            || (!isbn_token.is_empty() && book.isbn.contains(&isbn_token))
+            || book.language.as_ref() == Some(token)
+    })
+}
+
+fn relevance(book: &Book, tokens: &[String]) -> f64 {
+    let title = book.title.to_lowercase();
+    let author = book.author_line().to_lowercase();
+    let description = book.description.as_deref().unwrap_or_default().to_lowercase();
+    let isbn_tokens: Vec<String> = tokens.iter().map(|t| isbn::normalize(t)).collect();
+    let language_matches = tokens
+        .iter()
+        .filter(|t| book.language.as_ref() == Some(*t))
+        .count();
+    let text_fields = [
+        (title.as_str(), TITLE_WEIGHT),
+        (author.as_str(), AUTHOR_WEIGHT),
+        (description.as_str(), DESCRIPTION_WEIGHT),
+    ];
+    search::relevance_score(&text_fields, tokens)
+        + search::relevance_score(&[(&book.isbn, ISBN_WEIGHT)], &isbn_tokens)
+        + language_matches as f64 * LANGUAGE_WEIGHT
+}
+
+// Caps on user-supplied regular expressions. The regex crate matches in linear time, so
+// bounding pattern length and compiled size is enough to keep a pattern from hogging the server.
+const MAX_REGEX_PATTERN_LEN: usize = 256;
+const MAX_REGEX_COMPILED_SIZE: usize = 1 << 20;
+
+// Matches a text field either by case-insensitive substring or by regular expression
+enum TextMatcher {
+    Substring(String),
+    Pattern(regex::Regex),
+}
+
+impl TextMatcher {
+    fn new(raw: &str, use_regex: bool, param: &str) -> Result<Self, String> {
+        if !use_regex {
+            return Ok(TextMatcher::Substring(raw.to_lowercase()));
+        }
+        if raw.chars().count() > MAX_REGEX_PATTERN_LEN {
+            return Err(format!(
+                "{} pattern exceeds the maximum length of {} characters",
+                param, MAX_REGEX_PATTERN_LEN
+            ));
+        }
+        regex::RegexBuilder::new(raw)
+            .case_insensitive(true)
+            .size_limit(MAX_REGEX_COMPILED_SIZE)
+            .dfa_size_limit(MAX_REGEX_COMPILED_SIZE)
+            .build()
+            .map(TextMatcher::Pattern)
+            .map_err(|e| format!("Invalid {} pattern: {}", param, e))
+    }
+    
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            TextMatcher::Substring(needle) => text.to_lowercase().contains(needle.as_str()),
+            TextMatcher::Pattern(regex) => regex.is_match(text),
+        }
+    }
+    
+    // Byte ranges of every match in the original text
+    fn ranges(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            TextMatcher::Substring(needle) => search::find_ranges_ci(text, needle),
+            TextMatcher::Pattern(regex) => regex
+                .find_iter(text)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        }
+    }
+}
+
+// Validated search filters, shared by the search and count endpoints
+struct SearchFilter {
+    tokens: Option<Vec<String>>,
+    title: Option<TextMatcher>,
+    // Raw title query in fuzzy mode, which scores titles instead of matching them
+    fuzzy_title: Option<String>,
+    authors: Vec<TextMatcher>,
+    available: Option<bool>,
+    year_from: Option<u16>,
+    year_to: Option<u16>,
+    genre: Option<String>,
+    publisher: Option<String>,
+    min_pages: Option<u32>,
+    max_pages: Option<u32>,
+    language: Option<String>,
+    shelf: Option<String>,
+    branch: Option<u32>,
+    // Lowercased; matched exactly against the lowercased series
+    series: Option<String>,
+    condition: Option<BookCondition>,
+    format: Option<BookFormat>,
+    // Normalized tags; a book must carry every one of them
+    tags: Vec<String>,
+    title_not: Option<String>,
+    author_not: Option<String>,
+}
+
+struct SearchResults {
+    books: Vec<Book>,
+    // Fuzzy title scores by book id, only populated in fuzzy mode
+    scores: HashMap<u32, f64>,
+}
+
+impl SearchFilter {
+    fn from_query(query: &SearchQuery) -> Result<Self, String> {
+        let fuzzy = query.fuzzy.unwrap_or(false);
+        if fuzzy && query.title.is_none() {
+            return Err("fuzzy search requires a title parameter".to_string());
+        }
+        
+        let use_regex = match query.match_mode.as_deref() {
+            None | Some("substring") => false,
+            Some("regex") => true,
+            Some(other) => {
+                return Err(format!(
+                    "match must be 'substring' or 'regex', got '{}'",
+                    other
+                ))
+            }
+        };
+        if use_regex && fuzzy {
+            return Err("match=regex cannot be combined with fuzzy=true".to_string());
+        }
+        
+        let title = match &query.title {
+            Some(title) if !fuzzy => Some(TextMatcher::new(title, use_regex, "title")?),
+            _ => None,
+        };
+        
+        // Substring mode splits comma-separated authors; regex patterns are kept whole
+        // since commas are meaningful inside them (e.g. `{1,3}`)
+        let author_values: Vec<&str> = if use_regex {
+            query.author.iter().map(String::as_str).collect()
+        } else {
+            query
+                .author
+                .iter()
+                .flat_map(|raw| raw.split(','))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        let authors = author_values
+            .into_iter()
+            .map(|value| TextMatcher::new(value, use_regex, "author"))
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        let year_from = parse_number_param::<u16>("year_from", &query.year_from)?;
+        let year_to = parse_number_param::<u16>("year_to", &query.year_to)?;
+        if let (Some(from), Some(to)) = (year_from, year_to) {
+            if from > to {
+                return Err(format!(
+                    "year_from ({}) cannot be greater than year_to ({})",
+                    from, to
+                ));
+            }
+        }
+        
+        let min_pages = parse_number_param::<u32>("min_pages", &query.min_pages)?;
+        let max_pages = parse_number_param::<u32>("max_pages", &query.max_pages)?;
+        if let (Some(min), Some(max)) = (min_pages, max_pages) {
+            if min > max {
+                return Err(format!(
+                    "min_pages ({}) cannot be greater than max_pages ({})",
+                    min, max
+                ));
+            }
+        }
+        
+        let branch = parse_number_param::<u32>("branch", &query.branch)?;
+        
+        let condition = query
+            .condition
+            .as_deref()
+            .map(str::parse::<BookCondition>)
+            .transpose()?;
+        
+        let format = query
+            .format
+            .as_deref()
+            .map(str::parse::<BookFormat>)
+            .transpose()?;
+        
+        let tags = query
+            .tag
+            .iter()
+            .map(|raw| normalize_tag(raw))
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        Ok(SearchFilter {
+            tokens: query
+                .q
+                .as_ref()
+                .map(|q| q.split_whitespace().map(|t| t.to_lowercase()).collect()),
+            title,
+            fuzzy_title: query.title.as_ref().filter(|_| fuzzy).cloned(),
+            authors,
+            available: query.available,
+            year_from,
+            year_to,
+            genre: query.genre.as_ref().map(|g| g.to_lowercase()),
+            publisher: query.publisher.as_ref().map(|p| p.to_lowercase()),
+            min_pages,
+            max_pages,
+            language: query.language.as_ref().map(|l| l.trim().to_lowercase()),
+            shelf: query.shelf.as_deref().map(text::normalize),
+            branch,
+            series: query.series.as_deref().map(|s| text::normalize(s).to_lowercase()),
+            condition,
+            format,
+            tags,
+            title_not: query.title_not.as_ref().map(|t| t.to_lowercase()),
+            author_not: query.author_not.as_ref().map(|a| a.to_lowercase()),
+        })
+    }
+    
+    // Ranked searches come back best match first rather than in id order
+    fn is_ranked(&self) -> bool {
+        self.fuzzy_title.is_some() || self.tokens.is_some()
+    }
+    
+    // Merged byte ranges of `text` matched by any `q` token or by one of `matchers`
+    fn match_ranges<'a>(
+        &self,
+        text: &str,
+        matchers: impl Iterator<Item = &'a TextMatcher>,
+    ) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        if let Some(tokens) = &self.tokens {
+            for token in tokens {
+                ranges.extend(search::find_ranges_ci(text, token));
+            }
+        }
+        for matcher in matchers {
+            ranges.extend(matcher.ranges(text));
+        }
+        search::merge_ranges(ranges)
+    }
+    
+    // Matched segments per field for ?highlight=true; fields without a match are omitted.
+    // `authors` holds one segment list per author, in the book's author order.
+    fn highlights(&self, book: &Book, compat: Compat) -> serde_json::Map<String, serde_json::Value> {
+        let mut highlights = serde_json::Map::new();
+        
+        let title_ranges = self.match_ranges(&book.title, self.title.iter());
+        if !title_ranges.is_empty() {
+            highlights.insert(
+                "title".to_string(),
+                serde_json::json!(search::segments(&book.title, &title_ranges)),
+            );
+        }
+        
+        match compat {
+            Compat::V1 => {
+                let author = book.author_line();
+                let ranges = self.match_ranges(&author, self.authors.iter());
+                if !ranges.is_empty() {
+                    highlights.insert(
+                        "author".to_string(),
+                        serde_json::json!(search::segments(&author, &ranges)),
+                    );
+                }
+            }
+            Compat::Current => {
+                let ranges: Vec<Vec<(usize, usize)>> = book
+                    .authors
+                    .iter()
+                    .map(|author| self.match_ranges(author, self.authors.iter()))
+                    .collect();
+                if ranges.iter().any(|r| !r.is_empty()) {
+                    let segments: Vec<Vec<search::Segment>> = book
+                        .authors
+                        .iter()
+                        .zip(&ranges)
+                        .map(|(author, ranges)| search::segments(author, ranges))
+                        .collect();
+                    highlights.insert("authors".to_string(), serde_json::json!(segments));
+                }
+            }
+        }
+        highlights
+    }
+    
+    // The filters a store can check itself; `apply` checks them again with the rest
+    fn stored(&self) -> BookFilter {
+        BookFilter {
+            available: self.available,
+            year_from: self.year_from,
+            year_to: self.year_to,
+            min_pages: self.min_pages,
+            max_pages: self.max_pages,
+            language: self.language.clone(),
+            shelf: self.shelf.clone(),
+            branch: self.branch,
+        }
+    }
+    
+    fn apply(&self, books: &[Book], config: &Config) -> SearchResults {
+        let mut filtered: Vec<Book> = books.to_vec();
+        
+        let mut relevance_scores: HashMap<u32, f64> = HashMap::new();
+        if let Some(tokens) = &self.tokens {
+            filtered.retain(|b| matches_all_tokens(b, tokens));
+            for book in &filtered {
+                relevance_scores.insert(book.id, relevance(book, tokens));
+            }
+        }
+        
+        // Fuzzy mode scores titles instead of requiring a substring match
+        let mut scores: HashMap<u32, f64> = HashMap::new();
+        if let Some(title) = &self.fuzzy_title {
+            for book in &filtered {
+                scores.insert(book.id, search::fuzzy_score(&book.title, title));
+            }
+            filtered.retain(|b| scores[&b.id] >= config.fuzzy_threshold);
+        }
+        
+        if let Some(title) = &self.title {
+            filtered.retain(|b| title.is_match(&b.title));
+        }
+        
+        // Several author values are OR-ed together, and any of a book's authors can match
+        if !self.authors.is_empty() {
+            filtered.retain(|b| {
+                self.authors
+                    .iter()
+                    .any(|a| b.authors.iter().any(|name| a.is_match(name)))
+            });
+        }
+        
+        if let Some(available) = self.available {
+            filtered.retain(|b| b.available == available);
+        }
+        
+        // Books without a year can't be placed in a range, so any year filter excludes them
+        if self.year_from.is_some() || self.year_to.is_some() {
+            filtered.retain(|b| {
+                b.published_year.is_some_and(|year| {
+                    self.year_from.is_none_or(|from| year >= from)
+                        && self.year_to.is_none_or(|to| year <= to)
+                })
+            });
+        }
+        
+        // As with years, books without a page count are excluded by either bound
+        if self.min_pages.is_some() || self.max_pages.is_some() {
+            filtered.retain(|b| {
+                b.page_count.is_some_and(|pages| {
+                    self.min_pages.is_none_or(|min| pages >= min)
+                        && self.max_pages.is_none_or(|max| pages <= max)
+                })
+            });
+        }
+        
+        if let Some(language) = &self.language {
+            filtered.retain(|b| b.language.as_ref() == Some(language));
+        }
+        
+        if let Some(shelf) = &self.shelf {
+            filtered.retain(|b| b.shelf_location.as_ref() == Some(shelf));
+        }
+        
+        if let Some(branch) = self.branch {
+            filtered.retain(|b| b.branch_id == Some(branch));
+        }
+        
+        if let Some(series) = &self.series {
+            filtered.retain(|b| b.series.as_ref().is_some_and(|s| s.to_lowercase() == *series));
+        }
+        
+        if let Some(condition) = self.condition {
+            filtered.retain(|b| b.condition == condition);
+        }
+        
+        if let Some(format) = self.format {
+            filtered.retain(|b| b.format == Some(format));
+        }
+        
+        if let Some(genre) = &self.genre {
+            filtered.retain(|b| {
+                b.genre
+                    .as_ref()
+                    .is_some_and(|g| g.to_lowercase() == *genre)
+            });
+        }
+        
+        if let Some(publisher) = &self.publisher {
+            filtered.retain(|b| {
+                b.publisher
+                    .as_ref()
+                    .is_some_and(|p| p.to_lowercase().contains(publisher.as_str()))
+            });
+        }
+        
+        // Several tag values are AND-ed together
+        if !self.tags.is_empty() {
+            filtered.retain(|b| self.tags.iter().all(|t| b.tags.contains(t)));
+        }
+        
+        // Exclusions run after the positive filters; contradicting filters simply match nothing
+        if let Some(title_not) = &self.title_not {
+            filtered.retain(|b| !b.title.to_lowercase().contains(title_not.as_str()));
+        }
+        
+        // Excluded if any of the book's authors matches
+        if let Some(author_not) = &self.author_not {
+            filtered.retain(|b| {
+                !b.authors
+                    .iter()
+                    .any(|name| name.to_lowercase().contains(author_not.as_str()))
+            });
+        }
+        
+        // Best match first; the stable sort keeps id order among equal scores.
+        // An explicit ?sort= re-sorts later in list_response.
+        if self.fuzzy_title.is_some() {
+            filtered.sort_by(|a, b| scores[&b.id].total_cmp(&scores[&a.id]));
+        } else if !relevance_scores.is_empty() {
+            filtered.sort_by(|a, b| relevance_scores[&b.id].total_cmp(&relevance_scores[&a.id]));
+        }
+        
+        SearchResults {
+            books: filtered,
+            scores,
+        }
+    }
+}
+
+async fn search_books(req: actix_web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let query = match parse_search_query(&req) {
+        Ok(query) => query,
+        Err(err) => return err.error_response(),
+    };
+    
+    let filter = match SearchFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let options =
+        match parse_list_options(&query.list_query(), &data.config, !filter.is_ranked()) {
+            Ok(options) => options,
+            Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+        };
+    
+    let highlight = query.highlight.unwrap_or(false);
+    if highlight && query.q.is_none() && query.title.is_none() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "highlight requires a q or title parameter".to_string(),
+        });
+    }
+    
+    // As in get_books, the ETag is read before the books. The filters a store can check
+    // itself go to it, and the rest are applied to what it returns.
+    let tag = catalog_etag(&data);
+    let modified = *data.catalog_modified.lock().unwrap();
+    let now = data.clock.now();
+    if none_match(&req, &tag) || not_modified_since(&req, modified, now) {
+        return not_modified(tag, modified, now);
+    }
+    let candidates = match data.books.page(&filter.stored(), &PageRequest::default()) {
+        Ok(candidates) => candidates.books,
+        Err(err) => return repository_error(err),
+    };
+    let results = filter.apply(&candidates, &data.config);
+    let scores = results.scores;
+    let response = list_response(results.books, &options, |book, item| {
+        if let Some(score) = scores.get(&book.id) {
+            item.insert("score".to_string(), serde_json::json!(score));
+        }
+        if highlight {
+            item.insert(
+                "highlights".to_string(),
+                serde_json::Value::Object(filter.highlights(book, options.compat)),
+            );
+        }
+    });
+    
+    HttpResponse::Ok()
+        .insert_header((actix_web::http::header::ETAG, tag))
+        .insert_header((TOTAL_COUNT_HEADER, response.total))
+        .json(response)
+}
+
+// Counts values grouped case-insensitively, reporting each in the spelling first seen
+fn count_distinct<'a>(values: impl Iterator<Item = &'a String>) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for value in values {
+        match counts
+            .iter_mut()
+            .find(|(seen, _)| seen.to_lowercase() == value.to_lowercase())
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value.clone(), 1)),
+        }
+    }
+    counts
+}
+
+#[derive(Serialize)]
+struct GenreCount {
+    genre: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct LoanStats {
+    open_loans: usize,
+    // Open loans past their due date; a subset of `open_loans`
+    overdue_loans: usize,
+}
+
+// The shape of `GET /api/stats`. Fields are only ever added, so clients can rely on it.
+#[derive(Serialize)]
+struct CatalogStats {
+    total_books: usize,
+    total_copies: u32,
+    // Books a member could check out right now, as in the `available` filter
+    available_books: usize,
+    // Books with at least one copy on loan
+    checked_out_books: usize,
+    available_copies: u32,
+    checked_out_copies: u32,
+    // Told apart like `GET /api/authors`
+    distinct_authors: usize,
+    // Books without a genre are left out; sorted like `GET /api/books/genres`
+    books_per_genre: Vec<GenreCount>,
+    // Created within the last RECENT_DAYS days by the loan clock
+    added_recently: usize,
+    // Null until the first loan
+    loans: Option<LoanStats>,
+    // The number of the last change in the operation log; only with OPERATION_LOG
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_sequence: Option<u64>,
+}
+
+// One pass over the books and one over the loans, both locked once for the whole count
+async fn get_stats(data: web::Data<AppState>) -> impl Responder {
+    let now = data.clock.now();
+    let recent = now - chrono::Duration::days(RECENT_DAYS);
+    // Read first: the log's own lock can't be taken inside read_with
+    let log_sequence = data.books.sequence();
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+    
+        let mut stats = CatalogStats {
+            total_books: books.len(),
+            total_copies: 0,
+            available_books: 0,
+            checked_out_books: 0,
+            available_copies: 0,
+            checked_out_copies: 0,
+            distinct_authors: 0,
+            books_per_genre: Vec::new(),
+            added_recently: 0,
+            loans: None,
+            log_sequence,
+        };
+        let mut authors = HashSet::new();
+        let mut genres: HashMap<String, GenreCount> = HashMap::new();
+        for book in books.iter() {
+            stats.total_copies += book.total_copies;
+            stats.available_copies += book.available_copies;
+            stats.checked_out_copies += book.checked_out_copies;
+            if book.available {
+                stats.available_books += 1;
+            }
+            if book.checked_out_copies > 0 {
+                stats.checked_out_books += 1;
+            }
+            if book.created_at >= recent {
+                stats.added_recently += 1;
+            }
+            authors.extend(book.authors.iter().map(|name| text::slug(name)));
+            if let Some(genre) = &book.genre {
+                genres
+                    .entry(genre.to_lowercase())
+                    .or_insert_with(|| GenreCount {
+                        genre: genre.clone(),
+                        count: 0,
+                    })
+                    .count += 1;
+            }
+        }
+        stats.distinct_authors = authors.len();
+        stats.books_per_genre = genres.into_values().collect();
+        stats.books_per_genre.sort_by_key(|c| c.genre.to_lowercase());
+    
+        if !loans.is_empty() {
+            let mut loan_stats = LoanStats {
+                open_loans: 0,
+                overdue_loans: 0,
+            };
+            for loan in loans.iter() {
+                match loan.status(now) {
+                    LoanStatus::Open => loan_stats.open_loans += 1,
+                    LoanStatus::Overdue => {
+                        loan_stats.open_loans += 1;
+                        loan_stats.overdue_loans += 1;
+                    }
+                    LoanStatus::Returned => {}
+                }
+            }
+            stats.loans = Some(loan_stats);
+        }
+        HttpResponse::Ok().json(stats)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Sorted alphabetically
+async fn get_genres(data: web::Data<AppState>) -> impl Responder {
+    let response = data.books.read_with(|books| {
+        let mut counts: Vec<GenreCount> =
+            count_distinct(books.iter().filter_map(|b| b.genre.as_ref()))
+                .into_iter()
+                .map(|(genre, count)| GenreCount { genre, count })
+                .collect();
+        counts.sort_by_key(|c| c.genre.to_lowercase());
+        HttpResponse::Ok().json(counts)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+#[derive(Serialize)]
+struct PublisherCount {
+    publisher: String,
+    count: usize,
+}
+
+// Most books first; ties are broken alphabetically
+async fn get_publishers(data: web::Data<AppState>) -> impl Responder {
+    let response = data.books.read_with(|books| {
+        let mut counts: Vec<PublisherCount> =
+            count_distinct(books.iter().filter_map(|b| b.publisher.as_ref()))
+                .into_iter()
+                .map(|(publisher, count)| PublisherCount { publisher, count })
+                .collect();
+        counts.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.publisher.to_lowercase().cmp(&b.publisher.to_lowercase()))
+        });
+        HttpResponse::Ok().json(counts)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+#[derive(Serialize)]
+struct ConditionCount {
+    condition: BookCondition,
+    count: usize,
+}
+
+// One entry per condition, including those with no books, so the report's shape is fixed
+async fn get_condition_report(data: web::Data<AppState>) -> impl Responder {
+    let response = data.books.read_with(|books| {
+        let report: Vec<ConditionCount> = BOOK_CONDITIONS
+            .iter()
+            .map(|(_, condition)| ConditionCount {
+                condition: *condition,
+                count: books.iter().filter(|b| b.condition == *condition).count(),
+            })
+            .collect();
+        HttpResponse::Ok().json(report)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Fields printed on shelf lists
+const SHELF_BOOK_FIELDS: &[&str] = &["id", "title", "authors", "isbn"];
+
+#[derive(Serialize)]
+struct Shelf {
+    shelf_location: String,
+    books: Vec<serde_json::Value>,
+}
+
+// Shelves sorted by location, books on each shelf by title; unshelved books are left out
+async fn get_shelves(query: web::Query<CompatQuery>, data: web::Data<AppState>) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let mut shelved = match data.books.search(&|b| b.shelf_location.is_some()) {
+        Ok(shelved) => shelved,
+        Err(err) => return repository_error(err),
+    };
+    shelved.sort_by(|a, b| {
+        a.shelf_location
+            .cmp(&b.shelf_location)
+            .then_with(|| compare_books(a, b, SortField::Title))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    
+    let fields = Some(SHELF_BOOK_FIELDS.iter().map(|f| f.to_string()).collect());
+    let mut shelves: Vec<Shelf> = Vec::new();
+    for book in &shelved {
+        let location = book.shelf_location.clone().unwrap_or_default();
+        let entry = project_book(book, &fields, compat);
+        match shelves.last_mut() {
+            Some(shelf) if shelf.shelf_location == location => shelf.books.push(entry),
+            _ => shelves.push(Shelf {
+                shelf_location: location,
+                books: vec![entry],
+            }),
+        }
+    }
+    HttpResponse::Ok().json(shelves)
+}
+
+#[derive(Serialize)]
+struct AuthorSummary {
+    id: String,
+    name: String,
+    book_count: usize,
+}
+
+#[derive(Serialize)]
+struct AuthorBooks {
+    id: String,
+    name: String,
+    books: Vec<serde_json::Value>,
+}
+
+// Distinct authors keyed by slug, in order of first appearance. Spellings that share a
+// slug are one author, shown under the first spelling found.
+fn collect_authors(books: &[Book]) -> Vec<(String, String, Vec<&Book>)> {
+    let mut authors: Vec<(String, String, Vec<&Book>)> = Vec::new();
+    for book in books {
+        for name in &book.authors {
+            let id = text::slug(name);
+            match authors.iter_mut().find(|(seen, _, _)| *seen == id) {
+                Some((_, _, author_books)) => {
+                    if !author_books.iter().any(|b| b.id == book.id) {
+                        author_books.push(book);
+                    }
+                }
+                None => authors.push((id, name.clone(), vec![book])),
+            }
+        }
+    }
+    authors
+}
+
+fn author_view(id: String, name: String, books: &[&Book], compat: Compat) -> AuthorBooks {
+    let mut books = books.to_vec();
+    books.sort_by(|a, b| compare_books(a, b, SortField::Title).then_with(|| a.id.cmp(&b.id)));
+    AuthorBooks {
+        id,
+        name,
+        books: books.iter().map(|b| project_book(b, &None, compat)).collect(),
+    }
+}
+
+// Sorted by name
+async fn get_authors(data: web::Data<AppState>) -> impl Responder {
+    let response = data.books.read_with(|books| {
+        let mut authors: Vec<AuthorSummary> = collect_authors(books)
+            .into_iter()
+            .map(|(id, name, author_books)| AuthorSummary {
+                id,
+                name,
+                book_count: author_books.len(),
+            })
+            .collect();
+        authors.sort_by_key(|a| a.name.to_lowercase());
+        HttpResponse::Ok().json(authors)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn author_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("Author with id '{}' not found", id),
+    })
+}
+
+// Books sorted by title
+async fn get_author_books(
+    path: web::Path<String>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        match collect_authors(books).into_iter().find(|(seen, _, _)| *seen == id) {
+            Some((id, name, author_books)) => {
+                HttpResponse::Ok().json(author_view(id, name, &author_books, compat))
+            }
+            None => author_not_found(&id),
+        }
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Renames the author on every book that credits them, in one pass under the books lock so
+// no reader sees a half-renamed catalog. Renaming to another author's name merges the two.
+async fn rename_author(
+    path: web::Path<String>,
+    rename_req: web::Json<RenameAuthorRequest>,
+    query: web::Query<CompatQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let name = match text::clean("Author", &rename_req.name) {
+        Ok(name) => name,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    if name.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Author cannot be empty".to_string(),
+        });
+    }
+    if let Err(error) = validate_length("Author", &name, MAX_AUTHOR_LEN) {
+        return HttpResponse::BadRequest().json(ErrorResponse { error });
+    }
+    // The new name has to be addressable afterwards
+    if text::slug(&name).is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Author must contain at least one letter or digit".to_string(),
+        });
+    }
+    
+    let id = path.into_inner();
+    let response = data.books.write_with(|books| {
+        if !books
+            .iter()
+            .any(|b| b.authors.iter().any(|a| text::slug(a) == id))
+        {
+            return author_not_found(&id);
+        }
+    
+        for book in books.iter_mut() {
+            if !book.authors.iter().any(|a| text::slug(a) == id) {
+                continue;
+            }
+            let before = book.authors.clone();
+            let mut renamed: Vec<String> = Vec::new();
+            for author in &before {
+                let author = if text::slug(author) == id { &name } else { author };
+                // A book crediting both the old and the new name keeps a single entry
+                if !renamed.iter().any(|a| text::slug(a) == text::slug(author)) {
+                    renamed.push(author.clone());
+                }
+            }
+            if renamed != before {
+                book.authors = renamed;
+                book.touch(data.clock.now());
+            }
+        }
+        catalog_changed(&data, books);
+    
+        let new_id = text::slug(&name);
+        match collect_authors(books).into_iter().find(|(seen, _, _)| *seen == new_id) {
+            Some((id, name, author_books)) => {
+                HttpResponse::Ok().json(author_view(id, name, &author_books, compat))
+            }
+            None => author_not_found(&new_id),
+        }
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Fields listed per book in a series
+const SERIES_BOOK_FIELDS: &[&str] = &["id", "title", "authors", "isbn", "series_index"];
+
+#[derive(Serialize)]
+struct Series {
+    series: String,
+    books: Vec<serde_json::Value>,
+}
+
+// Series sorted by name. Spellings differing only in case are one series, shown under the
+// first spelling found. Books are in series_index order, unnumbered ones last by title.
+async fn get_series(query: web::Query<CompatQuery>, data: web::Data<AppState>) -> impl Responder {
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let response = data.books.read_with(|books| {
+        let mut groups: Vec<(String, Vec<&Book>)> = Vec::new();
+        for book in books.iter() {
+            let Some(name) = &book.series else { continue };
+            match groups
+                .iter_mut()
+                .find(|(seen, _)| seen.to_lowercase() == name.to_lowercase())
+            {
+                Some((_, members)) => members.push(book),
+                None => groups.push((name.clone(), vec![book])),
+            }
+        }
+        groups.sort_by_key(|(name, _)| name.to_lowercase());
+    
+        let fields = Some(SERIES_BOOK_FIELDS.iter().map(|f| f.to_string()).collect());
+        let series: Vec<Series> = groups
+            .into_iter()
+            .map(|(name, mut members)| {
+                // None sorts before Some, so unnumbered books are moved last explicitly
+                members.sort_by(|a, b| {
+                    a.series_index
+                        .is_none()
+                        .cmp(&b.series_index.is_none())
+                        .then(a.series_index.cmp(&b.series_index))
+                        .then_with(|| compare_books(a, b, SortField::Title))
+                        .then_with(|| a.id.cmp(&b.id))
+                });
+                Series {
+                    series: name,
+                    books: members
+                        .iter()
+                        .map(|b| project_book(b, &fields, compat))
+                        .collect(),
+                }
+            })
+            .collect();
+        HttpResponse::Ok().json(series)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Accepts the same filters as search; paging and projection parameters have no effect
+async fn count_books(req: actix_web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let query = match parse_search_query(&req) {
+        Ok(query) => query,
+        Err(err) => return err.error_response(),
+    };
+    
+    let filter = match SearchFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let response = data.books.read_with(|books| {
+        let count = filter.apply(books, &data.config).books.len();
+        HttpResponse::Ok().json(serde_json::json!({ "count": count }))
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// One of the books matching the search filters in the query string, at the index `choose`
+// gives for their count; the books are in id order. Paging and sort parameters have no
+// effect.
+fn pick_book(
+    req: &actix_web::HttpRequest,
+    data: &AppState,
+    choose: impl FnOnce(usize) -> usize,
+) -> HttpResponse {
+    let query = match parse_search_query(req) {
+        Ok(query) => query,
+        Err(err) => return err.error_response(),
+    };
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let filter = match SearchFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    
+    let response = data.books.read_with(|books| {
+        let mut candidates = filter.apply(books, &data.config).books;
+        if candidates.is_empty() {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: "No books match these filters; try fewer of them".to_string(),
+            });
+        }
+        candidates.sort_by_key(|b| b.id);
+        let book = &candidates[choose(candidates.len())];
+        HttpResponse::Ok().json(project_book(book, &None, compat))
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// One matching book, each equally likely
+async fn get_random_book(req: actix_web::HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    pick_book(&req, &data, |count| data.rng.below(count))
+}
+
+// The book of the day: chosen from the date on the loan clock (UTC) alone, so every
+// client gets the same book until midnight, as long as the matching books don't change
+async fn get_featured_book(
+    req: actix_web::HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let today = data.clock.now().date_naive();
+    pick_book(&req, &data, |count| random::pick_for_day(today, count))
+}
+
+async fn get_loans(query: web::Query<LoansQuery>, data: web::Data<AppState>) -> impl Responder {
+    let now = data.clock.now();
+    let loans = data.loans.lock().unwrap();
+    let matching: Vec<LoanResponse> = loans
+        .iter()
+        .filter(|l| query.member_id.is_none_or(|id| l.member_id == Some(id)))
+        .filter(|l| query.book_id.is_none_or(|id| l.book_id == id))
+        .filter(|l| query.status.is_none_or(|status| status.matches(l, now)))
+        .map(|loan| LoanResponse {
+            loan,
+            status: loan.status(now),
+        })
+        .collect();
+    HttpResponse::Ok().json(matching)
+}
+
+async fn get_loan(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        let members = data.members.lock().unwrap();
+        let Some(loan) = loans.iter().find(|l| l.id == id) else {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("Loan with id {} not found", id),
+            });
+        };
+        HttpResponse::Ok().json(LoanDetail {
+            loan: LoanResponse {
+                loan,
+                status: loan.status(data.clock.now()),
+            },
+            book_title: books
+                .iter()
+                .find(|b| b.id == loan.book_id)
+                .map(|b| b.title.clone()),
+            member_name: members
+                .iter()
+                .find(|m| Some(m.id) == loan.member_id)
+                .map(|m| m.name.clone()),
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// `all`, or a number of days such as `30d`; None is everything
+fn parse_period(raw: &str) -> Result<Option<chrono::Duration>, String> {
+    if raw == "all" {
+        return Ok(None);
+    }
+    match raw.strip_suffix('d').and_then(|days| days.parse::<i64>().ok()) {
+        Some(days) if (1..=MAX_REPORT_PERIOD_DAYS).contains(&days) => {
+            Ok(Some(chrono::Duration::days(days)))
+        }
+        _ => Err(format!(
+            "period must be 'all' or a number of days from 1d to {}d, such as 30d; got '{}'",
+            MAX_REPORT_PERIOD_DAYS, raw
+        )),
+    }
+}
+
+// Books ranked by loans started within the period, ties going to the most recently
+// borrowed. Books no longer in the catalog are left out.
+async fn get_popular_report(
+    query: web::Query<PopularQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let period = query.period.clone().unwrap_or_else(|| "30d".to_string());
+    let length = match parse_period(&period) {
+        Ok(length) => length,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match &query.limit {
+        Some(raw) => match raw.parse::<usize>() {
+            Ok(limit) if (1..=MAX_POPULAR_LIMIT).contains(&limit) => limit,
+            _ => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "limit must be an integer between 1 and {}, got '{}'",
+                        MAX_POPULAR_LIMIT, raw
+                    ),
+                })
+            }
+        },
+        None => DEFAULT_POPULAR_LIMIT,
+    };
+    let since = length.map(|length| data.clock.now() - length);
+    
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        let mut items: Vec<PopularBook> = books
+            .iter()
+            .filter_map(|book| {
+                let started: Vec<&Loan> = loans
+                    .of_book(book.id)
+                    .filter(|l| since.is_none_or(|since| l.checked_out_at >= since))
+                    .collect();
+                let last = started.iter().map(|l| l.checked_out_at).max()?;
+                Some(PopularBook {
+                    book_id: book.id,
+                    title: book.title.clone(),
+                    authors: book.authors.clone(),
+                    loan_count: started.len(),
+                    last_checked_out_at: last,
+                    available: book.available,
+                    available_copies: book.available_copies,
+                    total_copies: book.total_copies,
+                })
+            })
+            .collect();
+        items.sort_by(|a, b| {
+            b.loan_count
+                .cmp(&a.loan_count)
+                .then(b.last_checked_out_at.cmp(&a.last_checked_out_at))
+                .then(a.book_id.cmp(&b.book_id))
+        });
+        items.truncate(limit);
+        HttpResponse::Ok().json(PopularReport {
+            period,
+            since,
+            items,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Books that have been in the catalog longer than `older_than_days` and were lent at
+// most `max_loans` times in all, least borrowed first, then oldest first
+async fn get_weeding_report(
+    query: web::Query<WeedingQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let max_loans = query.max_loans.unwrap_or(DEFAULT_WEEDING_MAX_LOANS);
+    let older_than_days = query.older_than_days.unwrap_or(DEFAULT_WEEDING_AGE_DAYS);
+    if !(1..=MAX_REPORT_PERIOD_DAYS).contains(&older_than_days) {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "older_than_days must be between 1 and {}, got {}",
+                MAX_REPORT_PERIOD_DAYS, older_than_days
+            ),
+        });
+    }
+    let cutoff = data.clock.now() - chrono::Duration::days(older_than_days);
+    
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        let mut items: Vec<WeedingCandidate> = books
+            .iter()
+            .filter(|book| book.created_at < cutoff)
+            .filter_map(|book| {
+                let loan_count = loans.of_book(book.id).count();
+                if loan_count > max_loans as usize {
+                    return None;
+                }
+                Some(WeedingCandidate {
+                    book_id: book.id,
+                    title: book.title.clone(),
+                    author: book.author_line(),
+                    isbn: book.isbn.clone(),
+                    shelf_location: book.shelf_location.clone(),
+                    created_at: book.created_at,
+                    loan_count,
+                    last_loan_at: loans.of_book(book.id).map(|l| l.checked_out_at).max(),
+                    total_copies: book.total_copies,
+                })
+            })
+            .collect();
+        items.sort_by(|a, b| {
+            a.loan_count
+                .cmp(&b.loan_count)
+                .then(a.created_at.cmp(&b.created_at))
+                .then(a.book_id.cmp(&b.book_id))
+        });
+        HttpResponse::Ok().json(WeedingReport {
+            max_loans,
+            older_than_days,
+            cutoff,
+            items,
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// Counted from the timestamps books and loans carry, so the report can't drift from the
+// data. Books deleted permanently (or purged from the trash) leave no timestamps behind,
+// so they drop out of both `books_added` and `books_deleted`.
+async fn get_activity_report(
+    query: web::Query<ActivityQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let year = match query.year.as_deref() {
+        None => data.clock.now().year(),
+        Some(raw) => match raw.parse::<i32>() {
+            Ok(year) if (MIN_REPORT_YEAR..=MAX_REPORT_YEAR).contains(&year) => year,
+            _ => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "year must be an integer between {} and {}, got '{}'",
+                        MIN_REPORT_YEAR, MAX_REPORT_YEAR, raw
+                    ),
+                })
+            }
+        },
+    };
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("format must be 'json' or 'csv', got '{}'", other),
+            })
+        }
+    };
+    
+    let mut months: Vec<ActivityMonth> = (1..=12)
+        .map(|month| ActivityMonth {
+            month: format!("{:04}-{:02}", year, month),
+            books_added: 0,
+            books_deleted: 0,
+            loans_started: 0,
+            loans_returned: 0,
+            fines_assessed_cents: 0,
+        })
+        .collect();
+    let in_year =
+        |at: chrono::DateTime<chrono::Utc>| (at.year() == year).then(|| at.month0() as usize);
+    let counted = data.books.read_with(|books| {
+        let trash = data.trash.lock().unwrap();
+        for book in books.iter().chain(trash.iter()) {
+            if let Some(month) = in_year(book.created_at) {
+                months[month].books_added += 1;
+            }
+            if let Some(month) = book.deleted_at.and_then(in_year) {
+                months[month].books_deleted += 1;
+            }
+        }
+        let loans = data.loans.lock().unwrap();
+        for loan in loans.iter() {
+            if let Some(month) = in_year(loan.checked_out_at) {
+                months[month].loans_started += 1;
+            }
+            if let Some(month) = loan.returned_at.and_then(in_year) {
+                months[month].loans_returned += 1;
+                months[month].fines_assessed_cents += u64::from(loan.fine_cents);
+            }
+        }
+    });
+    if let Err(err) = counted {
+        return repository_error(err);
+    }
+    
+    if csv {
+        let mut body = String::from(ActivityMonth::CSV_HEADER);
+        body.push('\n');
+        for month in &months {
+            body.push_str(&month.csv_row());
+            body.push('\n');
+        }
+        return HttpResponse::Ok().content_type("text/csv; charset=utf-8").body(body);
+    }
+    HttpResponse::Ok().json(ActivityReport { year, months })
+}
+
+// The form two scans of the same book share: ISBN-10s become their ISBN-13. Anything
+// that isn't a valid ISBN is only normalized, so it can still match itself.
+fn audit_key(raw: &str) -> String {
+    isbn::canonical(&isbn::normalize(raw))
+}
+
+// Reconciles a stocktake against the catalog. Each scan is looked up once in a map of
+// the catalog's ISBNs, so the cost grows with the number of scans plus books, not their
+// product. With `branch_id`, only that branch's books can be missing; a scanned book from
+// another branch still counts as matched, and its `branch_id` shows where it belongs.
+async fn audit_inventory(
+    scans: web::Json<Vec<String>>,
+    query: web::Query<AuditQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let response = data.books.read_with(|books| {
+        if let Err(error) = check_branch(&data, query.branch_id) {
+            return HttpResponse::BadRequest().json(ErrorResponse { error });
+        }
+    
+        let index: HashMap<String, usize> = books
+            .iter()
+            .enumerate()
+            .map(|(i, book)| (audit_key(&book.isbn), i))
+            .collect();
+        let mut scan_counts = vec![0; books.len()];
+        let mut seen_unknown = HashSet::new();
+        let mut unknown = Vec::new();
+        for scan in scans.iter() {
+            let key = audit_key(scan);
+            match index.get(&key) {
+                Some(&i) => scan_counts[i] += 1,
+                None => {
+                    if seen_unknown.insert(key) {
+                        unknown.push(scan.clone());
+                    }
+                }
+            }
+        }
+    
+        let audited = |i: usize| {
+            let book = &books[i];
+            AuditedBook {
+                book_id: book.id,
+                title: book.title.clone(),
+                isbn: book.isbn.clone(),
+                branch_id: book.branch_id,
+                shelf_location: book.shelf_location.clone(),
+                scan_count: scan_counts[i],
+                total_copies: book.total_copies,
+                checked_out_copies: book.checked_out_copies,
+            }
+        };
+        let matched: Vec<AuditedBook> = (0..books.len())
+            .filter(|&i| scan_counts[i] > 0)
+            .map(audited)
+            .collect();
+        // A book with every copy on loan isn't expected on the shelf
+        let missing: Vec<AuditedBook> = (0..books.len())
+            .filter(|&i| scan_counts[i] == 0)
+            .filter(|&i| query.branch_id.is_none_or(|id| books[i].branch_id == Some(id)))
+            .filter(|&i| books[i].checked_out_copies < books[i].total_copies)
+            .map(audited)
+            .collect();
+        HttpResponse::Ok().json(AuditReport {
+            summary: AuditSummary {
+                scanned: scans.len(),
+                matched: matched.len(),
+                missing: missing.len(),
+                unknown: unknown.len(),
+            },
+            matched,
+            missing,
+            unknown,
+        })
+    });
+    response.unwrap_or_else(repository_error)
 }
 
-#[derive(Serialize, Deserialize)]
-struct CreateBookRequest {
-    title: String,
-    author: String,
-    isbn: String,
+// Open loans past their due date, most overdue first, with what staff need to chase them
+async fn get_overdue_loans(
+    query: web::Query<OverdueQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let now = data.clock.now();
+    let min_days = query.min_days.unwrap_or(0);
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        let members = data.members.lock().unwrap();
+    
+        let mut overdue: Vec<&Loan> = loans
+            .iter()
+            .filter(|l| l.status(now) == LoanStatus::Overdue)
+            .filter(|l| fines::days_late(l.due_at, now) >= min_days)
+            .filter(|l| query.member_id.is_none_or(|id| l.member_id == Some(id)))
+            .collect();
+        overdue.sort_by_key(|l| (l.due_at, l.id));
+    
+        let items: Vec<OverdueLoan> = overdue
+            .into_iter()
+            .map(|loan| OverdueLoan {
+                loan: LoanResponse {
+                    loan,
+                    status: LoanStatus::Overdue,
+                },
+                days_overdue: fines::days_late(loan.due_at, now),
+                book_title: books
+                    .iter()
+                    .find(|b| b.id == loan.book_id)
+                    .map(|b| b.title.clone()),
+                member: members
+                    .iter()
+                    .find(|m| Some(m.id) == loan.member_id)
+                    .map(|m| MemberContact {
+                        name: m.name.clone(),
+                        email: m.email.clone(),
+                    }),
+            })
+            .collect();
+        HttpResponse::Ok().json(items)
+    });
+    response.unwrap_or_else(repository_error)
 }
 
-#[derive(Serialize, Deserialize)]
-struct UpdateBookRequest {
-    title: Option<String>,
-    author: Option<String>,
-    isbn: Option<String>,
-    available: Option<bool>,
+// Pushes an open loan's due date out by one loan period, counted from the current due
+// date rather than from now, so renewing early doesn't shorten the loan
+async fn renew_loan(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let now = data.clock.now();
+    let mut loans = data.loans.lock().unwrap();
+    let Some(loan) = loans.iter_mut().find(|l| l.id == id) else {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("Loan with id {} not found", id),
+        });
+    };
+    
+    let refusal = match loan.status(now) {
+        LoanStatus::Returned => Some(format!("Loan with id {} has already been returned", id)),
+        LoanStatus::Overdue if !data.config.renew_overdue => Some(format!(
+            "Loan with id {} is overdue (due {}) and can't be renewed; return the book instead",
+            id,
+            loan.due_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        )),
+        _ if loan.renewal_count >= data.config.max_renewals => Some(format!(
+            "Loan with id {} has reached the renewal limit ({} of {})",
+            id, loan.renewal_count, data.config.max_renewals
+        )),
+        _ => None,
+    };
+    if let Some(error) = refusal {
+        return HttpResponse::Conflict().json(ErrorResponse { error });
+    }
+    
+    loan.due_at += data.config.loan_period;
+    loan.renewal_count += 1;
+    HttpResponse::Ok().json(LoanResponse {
+        loan,
+        status: loan.status(now),
+    })
 }
 
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+fn fines_summary(member_id: u32, loans: &[Loan]) -> FinesSummary {
+    let fines: Vec<FineItem> = loans
+        .iter()
+        .filter(|l| l.member_id == Some(member_id) && l.fine_outstanding_cents() > 0)
+        .map(|l| FineItem {
+            loan_id: l.id,
+            book_id: l.book_id,
+            returned_at: l.returned_at,
+            fine_cents: l.fine_cents,
+            fine_paid_cents: l.fine_paid_cents,
+            outstanding_cents: l.fine_outstanding_cents(),
+        })
+        .collect();
+    FinesSummary {
+        member_id,
+        outstanding_cents: fines.iter().map(|f| f.outstanding_cents).sum(),
+        fines,
+    }
 }
 
-struct AppState {
-    books: Mutex<Vec<Book>>,
-    next_id: Mutex<u32>,
+async fn get_member_fines(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let loans = data.loans.lock().unwrap();
+    if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+        return member_not_found(id);
+    }
+    HttpResponse::Ok().json(fines_summary(id, &loans))
 }
 
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "healthy",
-        "service": "book-library-api"
-    }))
+// Pays off some or all of a member's fines, oldest fine first
+async fn pay_member_fines(
+    path: web::Path<u32>,
+    pay_req: web::Json<PayFinesRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let mut loans = data.loans.lock().unwrap();
+    let Some(retain_history) = data
+        .members
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|m| m.id == id)
+        .map(|m| m.retain_history)
+    else {
+        return member_not_found(id);
+    };
+    
+    let owing: Vec<usize> = (0..loans.len())
+        .filter(|&i| loans[i].member_id == Some(id) && loans[i].fine_outstanding_cents() > 0)
+        .collect();
+    let outstanding: Vec<u32> = owing.iter().map(|&i| loans[i].fine_outstanding_cents()).collect();
+    let total: u32 = outstanding.iter().sum();
+    if total == 0 {
+        return HttpResponse::Conflict().json(ErrorResponse {
+            error: format!("Member with id {} has no outstanding fines", id),
+        });
+    }
+    let amount = pay_req.amount_cents.unwrap_or(total);
+    if amount == 0 || amount > total {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "amount_cents must be between 1 and the outstanding {} cents, got {}",
+                total, amount
+            ),
+        });
+    }
+    
+    for (&i, paid) in owing.iter().zip(fines::allocate(amount, &outstanding)) {
+        loans[i].fine_paid_cents += paid;
+    }
+    // Paid-off loans were only kept for their fines
+    let summary = fines_summary(id, &loans);
+    if !retain_history {
+        forget_history(&mut loans, id);
+    }
+    HttpResponse::Ok().json(summary)
 }
 
-async fn get_books(data: web::Data<AppState>) -> impl Responder {
-    let books = data.books.lock().unwrap();
-    HttpResponse::Ok().json(&*books)
+// Anonymizes every returned loan of the member that has no fine left to pay
+fn forget_history(loans: &mut [Loan], member_id: u32) {
+    for loan in loans.iter_mut().filter(|l| l.member_id == Some(member_id)) {
+        loan.anonymize();
+    }
 }
 
-async fn get_book_by_id(
+// A member's returned loans, most recently checked out first
+async fn get_member_history(
     path: web::Path<u32>,
+    query: web::Query<PageQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let book_id = path.into_inner();
-    let books = data.books.lock().unwrap();
+    let id = path.into_inner();
+    let page = match query.page.as_deref().map_or(Ok(1), parse_page) {
+        Ok(page) => page,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match parse_limit(query.limit.as_deref(), &data.config) {
+        Ok(limit) => limit,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+            return member_not_found(id);
+        }
     
-    match books.iter().find(|b| b.id == book_id) {
-        Some(book) => HttpResponse::Ok().json(book),
-        None => HttpResponse::NotFound().json(ErrorResponse {
-            error: format!("Book with id {} not found", book_id),
-        }),
-    }
+        let mut returned: Vec<&Loan> = loans
+            .iter()
+            .filter(|l| l.member_id == Some(id) && !l.is_open())
+            .collect();
+        returned.sort_by(|a, b| {
+            b.checked_out_at
+                .cmp(&a.checked_out_at)
+                .then(b.id.cmp(&a.id))
+        });
+        let total = returned.len();
+        let items: Vec<HistoryEntry> = returned
+            .into_iter()
+            .skip((page - 1).saturating_mul(limit))
+            .take(limit)
+            .map(|loan| {
+                let book = books.iter().find(|b| b.id == loan.book_id);
+                HistoryEntry {
+                    loan_id: loan.id,
+                    book_id: loan.book_id,
+                    title: book.map(|b| b.title.clone()),
+                    authors: book.map_or_else(Vec::new, |b| b.authors.clone()),
+                    checked_out_at: loan.checked_out_at,
+                    due_at: loan.due_at,
+                    returned_at: loan.returned_at.unwrap(),
+                }
+            })
+            .collect();
+    
+        HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, total))
+            .json(PaginatedResponse {
+                items,
+                page: Some(page),
+                limit,
+                total,
+                next_cursor: None,
+            })
+    });
+    response.unwrap_or_else(repository_error)
 }
 
-async fn create_book(
-    book_req: web::Json<CreateBookRequest>,
+// Books the member hasn't borrowed yet, best first; see recommend::recommend for the
+// scoring. Anonymized loans have no borrower and don't count.
+async fn get_recommendations(
+    path: web::Path<u32>,
+    query: web::Query<RecommendationsQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    if book_req.title.trim().is_empty() {
-        return HttpResponse::BadRequest().json(ErrorResponse {
-            error: "Title cannot be empty".to_string(),
-        });
-    }
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let limit = match &query.limit {
+        Some(raw) => match raw.parse::<usize>() {
+            Ok(limit) if (1..=MAX_RECOMMENDATION_LIMIT).contains(&limit) => limit,
+            _ => {
+                return HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!(
+                        "limit must be an integer between 1 and {}, got '{}'",
+                        MAX_RECOMMENDATION_LIMIT, raw
+                    ),
+                })
+            }
+        },
+        None => DEFAULT_RECOMMENDATION_LIMIT,
+    };
+    let available_only = query.available_only.unwrap_or(false);
+    let id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        let loans = data.loans.lock().unwrap();
+        if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+            return member_not_found(id);
+        }
     
-    if book_req.author.trim().is_empty() {
-        return HttpResponse::BadRequest().json(ErrorResponse {
-            error: "Author cannot be empty".to_string(),
-        });
+        let borrows: Vec<(u32, u32)> = loans
+            .iter()
+            .filter_map(|l| l.member_id.map(|member_id| (member_id, l.book_id)))
+            .collect();
+        let candidates: Vec<recommend::Candidate> = books
+            .iter()
+            .map(|b| recommend::Candidate {
+                id: b.id,
+                authors: &b.authors,
+                genre: b.genre.as_deref(),
+                eligible: b.available || !available_only,
+            })
+            .collect();
+        let recommendations: Vec<RecommendationResponse> =
+            recommend::recommend(id, &borrows, &candidates, limit)
+                .into_iter()
+                .map(|r| {
+                    let book = books.iter().find(|b| b.id == r.book_id).unwrap();
+                    RecommendationResponse {
+                        book: project_book(book, &None, compat),
+                        score: r.score,
+                        reason: r.reason,
+                    }
+                })
+                .collect();
+        HttpResponse::Ok().json(recommendations)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+fn list_not_found(member_id: u32, list_id: u32) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("Member with id {} has no list with id {}", member_id, list_id),
+    })
+}
+
+fn validate_list_name(raw: &str) -> Result<String, String> {
+    let name = text::clean("name", raw)?;
+    if name.is_empty() {
+        return Err("name cannot be empty".to_string());
+    }
+    validate_length("name", &name, MAX_LIST_NAME_LEN)?;
+    Ok(name)
+}
+
+async fn get_member_lists(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+        return member_not_found(id);
+    }
+    let lists = data.lists.lock().unwrap();
+    let owned: Vec<&ReadingList> = lists.iter().filter(|l| l.member_id == id).collect();
+    HttpResponse::Ok().json(owned)
+}
+
+// Names are unique per member, ignoring case
+async fn create_member_list(
+    path: web::Path<u32>,
+    list_req: web::Json<ReadingListRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let name = match validate_list_name(&list_req.name) {
+        Ok(name) => name,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+        return member_not_found(id);
     }
     
-    if book_req.isbn.trim().is_empty() {
+    let mut lists = data.lists.lock().unwrap();
+    let owned: Vec<&ReadingList> = lists.iter().filter(|l| l.member_id == id).collect();
+    if owned.len() >= MAX_LISTS_PER_MEMBER {
         return HttpResponse::BadRequest().json(ErrorResponse {
-            error: "ISBN cannot be empty".to_string(),
+            error: format!(
+                "Member with id {} already has {} lists, the most allowed",
+                id, MAX_LISTS_PER_MEMBER
+            ),
         });
     }
-    
-    let mut books = data.books.lock().unwrap();
-    let mut next_id = data.next_id.lock().unwrap();
-    
-    // Check for duplicate ISBN
-    if books.iter().any(|b| b.isbn == book_req.isbn) {
+    if let Some(existing) = owned
+        .iter()
+        .find(|l| l.name.to_lowercase() == name.to_lowercase())
+    {
         return HttpResponse::Conflict().json(ErrorResponse {
-            error: "Book with this ISBN already exists".to_string(),
+            error: format!(
+                "Member with id {} already has a list named '{}' (id {})",
+                id, existing.name, existing.id
+            ),
         });
     }
-    
-    let new_book = Book {
-        id: *next_id,
-        title: book_req.title.clone(),
-        author: book_req.author.clone(),
-        isbn: book_req.isbn.clone(),
-        available: true,
+    let mut next_list_id = data.next_list_id.lock().unwrap();
+    let list = ReadingList {
+        id: *next_list_id,
+        member_id: id,
+        name,
+        created_at: data.clock.now(),
+        book_ids: Vec::new(),
     };
+    *next_list_id += 1;
+    lists.push(list.clone());
     
-    *next_id += 1;
-    books.push(new_book.clone());
-    
-    HttpResponse::Created().json(new_book)
+    HttpResponse::Created()
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            format!("{}/members/{}/lists/{}", API_PREFIX, id, list.id),
+        ))
+        .json(list)
 }
 
-async fn update_book(
-    path: web::Path<u32>,
-    update_req: web::Json<UpdateBookRequest>,
+async fn get_member_list(
+    path: web::Path<(u32, u32)>,
+    query: web::Query<CompatQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let book_id = path.into_inner();
-    let mut books = data.books.lock().unwrap();
-    
-    let book_index = match books.iter().position(|b| b.id == book_id) {
-        Some(index) => index,
-        None => {
-            return HttpResponse::NotFound().json(ErrorResponse {
-                error: format!("Book with id {} not found", book_id),
-            })
-        }
+    let compat = match parse_compat(query.compat.as_deref()) {
+        Ok(compat) => compat,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
     };
-    
-    let book = &mut books[book_index];
-    
-    if let Some(title) = &update_req.title {
-        if title.trim().is_empty() {
-            return HttpResponse::BadRequest().json(ErrorResponse {
-                error: "Title cannot be empty".to_string(),
-            });
+    let (id, list_id) = path.into_inner();
+    let response = data.books.read_with(|books| {
+        if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+            return member_not_found(id);
         }
-        book.title = title.clone();
+        let lists = data.lists.lock().unwrap();
+        let Some(list) = lists.iter().find(|l| l.id == list_id && l.member_id == id) else {
+            return list_not_found(id, list_id);
+        };
+        HttpResponse::Ok().json(ReadingListDetail {
+            list,
+            books: list
+                .book_ids
+                .iter()
+                .filter_map(|book_id| books.iter().find(|b| b.id == *book_id))
+                .map(|book| project_book(book, &None, compat))
+                .collect(),
+        })
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn delete_member_list(path: web::Path<(u32, u32)>, data: web::Data<AppState>) -> impl Responder {
+    let (id, list_id) = path.into_inner();
+    if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+        return member_not_found(id);
     }
-    
-    if let Some(author) = &update_req.author {
-        if author.trim().is_empty() {
-            return HttpResponse::BadRequest().json(ErrorResponse {
-                error: "Author cannot be empty".to_string(),
-            });
+    let mut lists = data.lists.lock().unwrap();
+    let Some(index) = lists.iter().position(|l| l.id == list_id && l.member_id == id) else {
+        return list_not_found(id, list_id);
+    };
+    lists.remove(index);
+    HttpResponse::NoContent().finish()
+}
+
+// Adds a book at the end of the list
+async fn add_list_book(
+    path: web::Path<(u32, u32)>,
+    book_req: web::Json<ListBookRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (id, list_id) = path.into_inner();
+    let book_id = book_req.book_id;
+    let response = data.books.read_with(|books| {
+        if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+            return member_not_found(id);
         }
-        book.author = author.clone();
-    }
-    
-    if let Some(isbn) = &update_req.isbn {
-        if isbn.trim().is_empty() {
-            return HttpResponse::BadRequest().json(ErrorResponse {
-                error: "ISBN cannot be empty".to_string(),
-            });
+        let mut lists = data.lists.lock().unwrap();
+        let Some(list) = lists.iter_mut().find(|l| l.id == list_id && l.member_id == id) else {
+            return list_not_found(id, list_id);
+        };
+        if !books.iter().any(|b| b.id == book_id) {
+            return repository_error(RepositoryError::NotFound(book_id));
         }
-        // Check for duplicate ISBN (excluding current book)
-        if books.iter().any(|b| b.isbn == *isbn && b.id != book_id) {
+        if list.book_ids.contains(&book_id) {
             return HttpResponse::Conflict().json(ErrorResponse {
-                error: "Book with this ISBN already exists".to_string(),
+                error: format!("Book with id {} is already on list {}", book_id, list_id),
             });
         }
-        book.isbn = isbn.clone();
+        list.book_ids.push(book_id);
+        HttpResponse::Ok().json(&*list)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+async fn remove_list_book(
+    path: web::Path<(u32, u32, u32)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (id, list_id, book_id) = path.into_inner();
+    if !data.members.lock().unwrap().iter().any(|m| m.id == id) {
+        return member_not_found(id);
+    }
+    let mut lists = data.lists.lock().unwrap();
+    let Some(list) = lists.iter_mut().find(|l| l.id == list_id && l.member_id == id) else {
+        return list_not_found(id, list_id);
+    };
+    let Some(index) = list.book_ids.iter().position(|b| *b == book_id) else {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("Book with id {} is not on list {}", book_id, list_id),
+        });
+    };
+    list.book_ids.remove(index);
+    HttpResponse::NoContent().finish()
+}
+
+fn member_not_found(id: u32) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("Member with id {} not found", id),
+    })
+}
+
+fn validate_member(member_req: &MemberRequest) -> Result<(String, String), validation::Errors> {
+    let mut v = validation::Validator::new();
+    let name = v.check("name", validate_member_name(&member_req.name));
+    let email = v.check("email", validate_email(&member_req.email));
+    v.finish()?;
+    Ok((name.unwrap(), email.unwrap()))
+}
+
+fn email_conflict(members: &[Member], email: &str, except_id: Option<u32>) -> Option<HttpResponse> {
+    members
+        .iter()
+        .find(|m| m.email == email && Some(m.id) != except_id)
+        .map(|m| {
+            HttpResponse::Conflict().json(ErrorResponse {
+                error: format!("Member with email {} already exists (id {})", email, m.id),
+            })
+        })
+}
+
+async fn get_members(data: web::Data<AppState>) -> impl Responder {
+    let members = data.members.lock().unwrap();
+    HttpResponse::Ok().json(&*members)
+}
+
+async fn get_member(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let members = data.members.lock().unwrap();
+    match members.iter().find(|m| m.id == id) {
+        Some(member) => HttpResponse::Ok().json(member),
+        None => member_not_found(id),
     }
+}
+
+async fn create_member(
+    member_req: web::Json<MemberRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (name, email) = match validate_member(&member_req) {
+        Ok(fields) => fields,
+        Err(errors) => return validation_failed(errors),
+    };
     
-    if let Some(available) = update_req.available {
-        book.available = available;
+    let mut members = data.members.lock().unwrap();
+    if let Some(conflict) = email_conflict(&members, &email, None) {
+        return conflict;
     }
+    let mut next_member_id = data.next_member_id.lock().unwrap();
+    let member = Member {
+        id: *next_member_id,
+        name,
+        email,
+        joined_at: data.clock.now(),
+        active: member_req.active.unwrap_or(true),
+        retain_history: member_req.retain_history.unwrap_or(true),
+    };
+    *next_member_id += 1;
+    members.push(member.clone());
     
-    HttpResponse::Ok().json(book.clone())
+    HttpResponse::Created()
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            format!("{}/members/{}", API_PREFIX, member.id),
+        ))
+        .json(member)
 }
 
-async fn delete_book(
+// Replaces name and email; `active: false` deactivates the member, which keeps their
+// record and loans but stops new checkouts. Turning `retain_history` off anonymizes the
+// member's returned loans there and then.
+async fn update_member(
     path: web::Path<u32>,
+    member_req: web::Json<MemberRequest>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let book_id = path.into_inner();
-    let mut books = data.books.lock().unwrap();
+    let id = path.into_inner();
+    let (name, email) = match validate_member(&member_req) {
+        Ok(fields) => fields,
+        Err(errors) => return validation_failed(errors),
+    };
     
-    let book_index = match books.iter().position(|b| b.id == book_id) {
-        Some(index) => index,
-        None => {
-            return HttpResponse::NotFound().json(ErrorResponse {
-                error: format!("Book with id {} not found", book_id),
+    let mut loans = data.loans.lock().unwrap();
+    let mut members = data.members.lock().unwrap();
+    if !members.iter().any(|m| m.id == id) {
+        return member_not_found(id);
+    }
+    if let Some(conflict) = email_conflict(&members, &email, Some(id)) {
+        return conflict;
+    }
+    let member = members.iter_mut().find(|m| m.id == id).unwrap();
+    member.name = name;
+    member.email = email;
+    if let Some(active) = member_req.active {
+        member.active = active;
+    }
+    if let Some(retain_history) = member_req.retain_history {
+        member.retain_history = retain_history;
+    }
+    if !member.retain_history {
+        forget_history(&mut loans, id);
+    }
+    HttpResponse::Ok().json(&*member)
+}
+
+// Members who still have books checked out can only be deactivated
+async fn delete_member(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let loans = data.loans.lock().unwrap();
+    let mut members = data.members.lock().unwrap();
+    let Some(index) = members.iter().position(|m| m.id == id) else {
+        return member_not_found(id);
+    };
+    let on_loan = loans.iter().filter(|l| l.is_open() && l.member_id == Some(id)).count();
+    if on_loan > 0 {
+        return HttpResponse::Conflict().json(ErrorResponse {
+            error: format!(
+                "Member with id {} has {} books checked out; return them or deactivate the member instead",
+                id, on_loan
+            ),
+        });
+    }
+    members.remove(index);
+    data.lists.lock().unwrap().retain(|l| l.member_id != id);
+    HttpResponse::NoContent().finish()
+}
+
+fn branch_not_found(id: u32) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: format!("Branch with id {} not found", id),
+    })
+}
+
+fn validate_branch(branch_req: &BranchRequest) -> Result<(String, String), validation::Errors> {
+    let mut v = validation::Validator::new();
+    let name = v.check("name", required_text("name", &branch_req.name, MAX_BRANCH_NAME_LEN));
+    let address = v.check(
+        "address",
+        required_text("address", &branch_req.address, MAX_ADDRESS_LEN),
+    );
+    v.finish()?;
+    Ok((name.unwrap(), address.unwrap()))
+}
+
+fn required_text(field: &str, raw: &str, max_len: usize) -> Result<String, String> {
+    let value = text::clean(field, raw)?;
+    if value.is_empty() {
+        return Err(format!("{} cannot be empty", field));
+    }
+    validate_length(field, &value, max_len)?;
+    Ok(value)
+}
+
+fn branch_name_conflict(branches: &[Branch], name: &str, except_id: Option<u32>) -> Option<HttpResponse> {
+    branches
+        .iter()
+        .find(|b| b.name.to_lowercase() == name.to_lowercase() && Some(b.id) != except_id)
+        .map(|b| {
+            HttpResponse::Conflict().json(ErrorResponse {
+                error: format!("Branch named '{}' already exists (id {})", b.name, b.id),
             })
-        }
+        })
+}
+
+async fn get_branches(data: web::Data<AppState>) -> impl Responder {
+    let branches = data.branches.lock().unwrap();
+    HttpResponse::Ok().json(&*branches)
+}
+
+async fn get_branch(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let branches = data.branches.lock().unwrap();
+    match branches.iter().find(|b| b.id == id) {
+        Some(branch) => HttpResponse::Ok().json(branch),
+        None => branch_not_found(id),
+    }
+}
+
+async fn create_branch(
+    branch_req: web::Json<BranchRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (name, address) = match validate_branch(&branch_req) {
+        Ok(fields) => fields,
+        Err(errors) => return validation_failed(errors),
     };
     
-    books.remove(book_index);
-    HttpResponse::NoContent().finish()
+    let mut branches = data.branches.lock().unwrap();
+    if let Some(conflict) = branch_name_conflict(&branches, &name, None) {
+        return conflict;
+    }
+    let mut next_branch_id = data.next_branch_id.lock().unwrap();
+    let branch = Branch {
+        id: *next_branch_id,
+        name,
+        address,
+    };
+    *next_branch_id += 1;
+    branches.push(branch.clone());
+    
+    HttpResponse::Created()
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            format!("{}/branches/{}", API_PREFIX, branch.id),
+        ))
+        .json(branch)
 }
 
-async fn search_books(
-    query: web::Query<std::collections::HashMap<String, String>>,
+async fn update_branch(
+    path: web::Path<u32>,
+    branch_req: web::Json<BranchRequest>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let books = data.books.lock().unwrap();
-    let mut filtered: Vec<Book> = books.clone();
+    let id = path.into_inner();
+    let (name, address) = match validate_branch(&branch_req) {
+        Ok(fields) => fields,
+        Err(errors) => return validation_failed(errors),
+    };
     
-    if let Some(author) = query.get("author") {
-        let author_lower = author.to_lowercase();
-        filtered.retain(|b| b.author.to_lowercase().contains(&author_lower));
+    let mut branches = data.branches.lock().unwrap();
+    if !branches.iter().any(|b| b.id == id) {
+        return branch_not_found(id);
     }
+    if let Some(conflict) = branch_name_conflict(&branches, &name, Some(id)) {
+        return conflict;
+    }
+    let branch = branches.iter_mut().find(|b| b.id == id).unwrap();
+    branch.name = name;
+    branch.address = address;
+    HttpResponse::Ok().json(&*branch)
+}
+
+// Only empty branches can be deleted. Books in the trash don't keep a branch alive; they
+// lose their branch_id instead.
+async fn delete_branch(path: web::Path<u32>, data: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        let mut trash = data.trash.lock().unwrap();
+        let mut branches = data.branches.lock().unwrap();
+        let Some(index) = branches.iter().position(|b| b.id == id) else {
+            return branch_not_found(id);
+        };
+        let book_count = books.iter().filter(|b| b.branch_id == Some(id)).count();
+        if book_count > 0 {
+            return HttpResponse::Conflict().json(BranchInUseResponse {
+                error: format!(
+                    "Branch with id {} still holds {} books; move or delete them first",
+                    id, book_count
+                ),
+                book_count,
+            });
+        }
+        for book in trash.iter_mut().filter(|b| b.branch_id == Some(id)) {
+            book.branch_id = None;
+        }
+        branches.remove(index);
+        HttpResponse::NoContent().finish()
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// The branch's holdings, paged, sorted, and projected like the catalog
+async fn get_branch_books(
+    path: web::Path<u32>,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let options = match parse_list_options(&query, &data.config, true) {
+        Ok(options) => options,
+        Err(error) => return HttpResponse::BadRequest().json(ErrorResponse { error }),
+    };
+    let id = path.into_inner();
+    let response = data.books.read_with(|books| {
+        if !data.branches.lock().unwrap().iter().any(|b| b.id == id) {
+            return branch_not_found(id);
+        }
     
-    if let Some(available) = query.get("available") {
-        if let Ok(avail_bool) = available.parse::<bool>() {
-            filtered.retain(|b| b.available == avail_bool);
+        let held: Vec<Book> = books
+            .iter()
+            .filter(|b| b.branch_id == Some(id))
+            .cloned()
+            .collect();
+        let response = list_response(held, &options, |_, _| {});
+        HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, response.total))
+            .json(response)
+    });
+    response.unwrap_or_else(repository_error)
+}
+
+// The books a catalog starts with when no seed file is given
+fn builtin_books(now: chrono::DateTime<chrono::Utc>) -> Vec<Book> {
+    let mut books = vec![
+        Book {
+            id: 1,
+            title: "The Rust Programming Language".to_string(),
+            authors: vec!["Steve Klabnik".to_string(), "Carol Nichols".to_string()],
+            isbn: "978-1718500440".to_string(),
+            available: true,
+            total_copies: 3,
+            available_copies: 2,
+            published_year: Some(2023),
+            genre: Some("Programming".to_string()),
+            publisher: Some("No Starch Press".to_string()),
+            page_count: Some(560),
+            language: Some("en".to_string()),
+            description: None,
+            shelf_location: Some("A3-top".to_string()),
+            branch_id: Some(1),
+            condition: BookCondition::Good,
+            cover_url: None,
+            series: None,
+            series_index: None,
+            edition: None,
+            format: None,
+            tags: vec!["staff-pick".to_string()],
+            checked_out_copies: 0,
+            hold_count: 0,
+            holds_reserve: false,
+            review_count: 0,
+            average_rating: None,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+            deleted_at: None,
+            reviews_changed_at: None,
+            notes: None,
+        },
+        Book {
+            id: 2,
+            title: "Programming Rust".to_string(),
+            authors: vec![
+                "Jim Blandy".to_string(),
+                "Jason Orendorff".to_string(),
+                "Leonora Tindall".to_string(),
+            ],
+            isbn: "978-1492052593".to_string(),
+            available: true,
+            total_copies: 1,
+            available_copies: 1,
+            published_year: Some(2021),
+            genre: Some("Programming".to_string()),
+            publisher: Some("O'Reilly Media".to_string()),
+            page_count: Some(738),
+            language: Some("en".to_string()),
+            description: None,
+            shelf_location: Some("A3-top".to_string()),
+            branch_id: Some(1),
+            condition: BookCondition::Good,
+            cover_url: None,
+            series: None,
+            series_index: None,
+            edition: None,
+            format: None,
+            tags: Vec::new(),
+            checked_out_copies: 0,
+            hold_count: 0,
+            holds_reserve: false,
+            review_count: 0,
+            average_rating: None,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+            deleted_at: None,
+            reviews_changed_at: None,
+            notes: None,
+        },
+    ];
+    for book in &mut books {
+        book.isbn = isbn::normalize(&book.isbn);
+    }
+    books
+}
+
+// The library around `books`, with nothing else in it yet but the first member and branch.
+// New books get ids from `first_id` on, or past the highest one in the catalog.
+fn new_state(
+    config: Config,
+    books: Arc<dyn BookRepository>,
+    first_id: u32,
+    store: Option<storage::FileStore>,
+    started_at: chrono::DateTime<chrono::Utc>,
+) -> Result<AppState, RepositoryError> {
+    // A database or a log may already hold more than the seed books
+    let (suggestions, next_id) = books.read_with(|books| {
+        (build_suggestions(books), next_after(first_id, books.iter().map(|b| b.id)))
+    })?;
+    // A patron to check books out to from the start
+    let members = vec![Member {
+        id: 1,
+        name: "Ann Lee".to_string(),
+        email: "ann.lee@example.com".to_string(),
+        joined_at: started_at,
+        active: true,
+        retain_history: true,
+    }];
+    Ok(AppState {
+        suggestions: Mutex::new(suggestions),
+        similar: Mutex::new(None),
+        catalog_version: Mutex::new(1),
+        boot_id: boot_id(),
+        catalog_modified: Mutex::new(started_at),
+        books,
+        next_id: Mutex::new(next_id),
+        trash: Mutex::new(Vec::new()),
+        reviews: Mutex::new(Vec::new()),
+        next_review_id: Mutex::new(1),
+        idempotency: Mutex::new(idempotency::Store::new(config.idempotency_ttl)),
+        loans: Mutex::new(LoanLedger::default()),
+        next_loan_id: Mutex::new(1),
+        holds: Mutex::new(Vec::new()),
+        members: Mutex::new(members),
+        next_member_id: Mutex::new(2),
+        branches: Mutex::new(vec![Branch {
+            id: 1,
+            name: "Main Library".to_string(),
+            address: "1 Library Square".to_string(),
+        }]),
+        next_branch_id: Mutex::new(2),
+        transfers: Mutex::new(Vec::new()),
+        next_transfer_id: Mutex::new(1),
+        schedules: Mutex::new(Vec::new()),
+        next_schedule_id: Mutex::new(1),
+        events: Mutex::new(Vec::new()),
+        next_event_id: Mutex::new(1),
+        lists: Mutex::new(Vec::new()),
+        next_list_id: Mutex::new(1),
+        clock: clock::from_env(),
+        rng: random::from_env(),
+        store,
+        notifier: notify::from_env(),
+        notifications: notify::Counters::default(),
+        config,
+    })
+}
+
+// The routes and the middleware around them, for the server and for tests
+fn build_app(
+    app_state: web::Data<AppState>,
+) -> App<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let persist_state = app_state.clone();
+    App::new()
+        // Every request that may have changed something and succeeded is followed by a
+        // save; one that can't be saved turns into a 500
+        .wrap_fn(move |req, srv| {
+            use actix_web::dev::Service;
+            use actix_web::http::Method;
+            
+            let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+            let data = persist_state.clone();
+            let response = srv.call(req);
+            async move {
+                let response = response.await?;
+                if mutating && response.status().is_success() {
+                    if let Err(cause) = persist(&data) {
+                        return Ok(response.into_response(persistence_failed(cause)));
+                    }
+                }
+                Ok(response.map_into_boxed_body())
+            }
+        })
+        .app_data(app_state.clone())
+        .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+        .app_data(web::PathConfig::default().error_handler(path_error_handler))
+        .app_data(
+            web::JsonConfig::default()
+                .limit(app_state.config.max_body_bytes)
+                .error_handler(json_error_handler),
+        )
+        .service(
+            resource("/health", &["GET"])
+                .route(web::get().to(health_check)),
+        )
+        .service(
+            resource("/metrics", &["GET"])
+                .route(web::get().to(get_metrics)),
+        )
+        .service(
+            web::scope(API_PREFIX)
+                .service(
+                    // A resource of its own so the whole catalog gets the batch body
+                    // limit; the guard hands every other method to the one below
+                    web::resource("/books")
+                        .guard(actix_web::guard::Put())
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(app_state.config.max_batch_body_bytes)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::put().to(replace_catalog)),
+                )
+                .service(
+                    resource("/books", &["GET", "HEAD", "POST", "PUT"])
+                        .route(web::get().to(get_books))
+                        .route(web::head().to(get_books))
+                        .route(web::post().to(create_book)),
+                )
+                .service(
+                    resource("/books/batch", &["POST"])
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(app_state.config.max_batch_body_bytes)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::post().to(create_books_batch)),
+                )
+                .service(
+                    resource("/books/batch-update", &["POST"])
+                        .route(web::post().to(batch_update_books)),
+                )
+                .service(
+                    resource("/books/batch-delete", &["POST"])
+                        .route(web::post().to(batch_delete_books)),
+                )
+                .service(
+                    resource("/books/duplicates", &["GET"])
+                        .route(web::get().to(get_duplicates)),
+                )
+                .service(
+                    resource("/books/merge", &["POST"])
+                        .route(web::post().to(merge_books)),
+                )
+                .service(
+                    resource("/books/trash", &["GET"])
+                        .route(web::get().to(get_trash)),
+                )
+                .service(
+                    resource("/books/search", &["GET", "HEAD"])
+                        .route(web::get().to(search_books))
+                        .route(web::head().to(search_books)),
+                )
+                .service(
+                    resource("/books/suggest", &["GET"])
+                        .route(web::get().to(suggest_books)),
+                )
+                .service(
+                    resource("/books/count", &["GET"])
+                        .route(web::get().to(count_books)),
+                )
+                .service(
+                    resource("/books/random", &["GET"])
+                        .route(web::get().to(get_random_book)),
+                )
+                .service(
+                    resource("/books/featured", &["GET"])
+                        .route(web::get().to(get_featured_book)),
+                )
+                .service(
+                    resource("/books/genres", &["GET"])
+                        .route(web::get().to(get_genres)),
+                )
+                .service(
+                    resource("/books/publishers", &["GET"])
+                        .route(web::get().to(get_publishers)),
+                )
+                .service(
+                    resource("/books/condition-report", &["GET"])
+                        .route(web::get().to(get_condition_report)),
+                )
+                .service(
+                    resource("/books/isbn/{isbn}", &["GET", "PUT"])
+                        .route(web::get().to(get_book_by_isbn))
+                        .route(web::put().to(upsert_book_by_isbn)),
+                )
+                .service(
+                    resource("/books/{id}", &["GET", "HEAD", "PUT", "PATCH", "DELETE"])
+                        .route(web::get().to(get_book_by_id))
+                        .route(web::head().to(get_book_by_id))
+                        .route(web::put().to(replace_book))
+                        .route(web::patch().to(update_book))
+                        .route(web::delete().to(delete_book)),
+                )
+                .service(
+                    resource("/books/{id}/clone", &["POST"])
+                        .route(web::post().to(clone_book)),
+                )
+                .service(
+                    resource("/books/{id}/checkout", &["POST"])
+                        .route(web::post().to(checkout_book)),
+                )
+                .service(
+                    resource("/books/{id}/return", &["POST"])
+                        .route(web::post().to(return_book)),
+                )
+                .service(
+                    resource("/books/{id}/similar", &["GET"])
+                        .route(web::get().to(get_similar_books)),
+                )
+                .service(
+                    resource("/books/{id}/loans", &["GET"])
+                        .route(web::get().to(get_book_loans)),
+                )
+                .service(
+                    resource("/books/{id}/schedule", &["GET", "POST"])
+                        .route(web::get().to(get_schedule))
+                        .route(web::post().to(schedule_change)),
+                )
+                .service(
+                    resource("/books/{id}/schedule/{schedule_id}", &["DELETE"])
+                        .route(web::delete().to(cancel_schedule)),
+                )
+                .service(
+                    resource("/books/{id}/notes", &["PUT"])
+                        .route(web::put().to(put_notes)),
+                )
+                .service(
+                    resource("/books/{id}/transfer", &["POST"])
+                        .route(web::post().to(transfer_book)),
+                )
+                .service(
+                    resource("/books/{id}/transfers", &["GET"])
+                        .route(web::get().to(get_book_transfers)),
+                )
+                .service(
+                    resource("/books/{id}/holds", &["GET", "POST"])
+                        .route(web::get().to(get_holds))
+                        .route(web::post().to(place_hold)),
+                )
+                .service(
+                    resource("/books/{id}/holds/{member_id}", &["DELETE"])
+                        .route(web::delete().to(cancel_hold)),
+                )
+                .service(
+                    resource("/books/{id}/restore", &["POST"])
+                        .route(web::post().to(restore_book)),
+                )
+                .service(
+                    resource("/books/{id}/tags", &["POST"])
+                        .route(web::post().to(add_tags)),
+                )
+                .service(
+                    resource("/books/{id}/tags/{tag}", &["DELETE"])
+                        .route(web::delete().to(remove_tag)),
+                )
+                .service(
+                    resource("/books/{id}/reviews", &["GET", "POST"])
+                        .route(web::get().to(get_reviews))
+                        .route(web::post().to(create_review)),
+                )
+                .service(
+                    resource("/books/{id}/reviews/{review_id}", &["DELETE"])
+                        .route(web::delete().to(delete_review)),
+                )
+                .service(
+                    resource("/shelves", &["GET"])
+                        .route(web::get().to(get_shelves)),
+                )
+                .service(
+                    resource("/series", &["GET"])
+                        .route(web::get().to(get_series)),
+                )
+                .service(
+                    resource("/authors", &["GET"])
+                        .route(web::get().to(get_authors)),
+                )
+                .service(
+                    resource("/authors/{id}/books", &["GET"])
+                        .route(web::get().to(get_author_books)),
+                )
+                .service(
+                    resource("/authors/{id}", &["PUT"])
+                        .route(web::put().to(rename_author)),
+                )
+                .service(
+                    resource("/loans", &["GET"])
+                        .route(web::get().to(get_loans)),
+                )
+                .service(
+                    resource("/loans/overdue", &["GET"])
+                        .route(web::get().to(get_overdue_loans)),
+                )
+                .service(
+                    resource("/loans/{id}", &["GET"])
+                        .route(web::get().to(get_loan)),
+                )
+                .service(
+                    resource("/loans/{id}/renew", &["POST"])
+                        .route(web::post().to(renew_loan)),
+                )
+                .service(
+                    resource("/members", &["GET", "POST"])
+                        .route(web::get().to(get_members))
+                        .route(web::post().to(create_member)),
+                )
+                .service(
+                    resource("/members/{id}", &["GET", "PUT", "DELETE"])
+                        .route(web::get().to(get_member))
+                        .route(web::put().to(update_member))
+                        .route(web::delete().to(delete_member)),
+                )
+                .service(
+                    resource("/members/{id}/lists", &["GET", "POST"])
+                        .route(web::get().to(get_member_lists))
+                        .route(web::post().to(create_member_list)),
+                )
+                .service(
+                    resource("/members/{id}/lists/{list_id}", &["GET", "DELETE"])
+                        .route(web::get().to(get_member_list))
+                        .route(web::delete().to(delete_member_list)),
+                )
+                .service(
+                    resource("/members/{id}/lists/{list_id}/books", &["POST"])
+                        .route(web::post().to(add_list_book)),
+                )
+                .service(
+                    resource("/members/{id}/lists/{list_id}/books/{book_id}", &["DELETE"])
+                        .route(web::delete().to(remove_list_book)),
+                )
+                .service(
+                    resource("/members/{id}/recommendations", &["GET"])
+                        .route(web::get().to(get_recommendations)),
+                )
+                .service(
+                    resource("/members/{id}/history", &["GET"])
+                        .route(web::get().to(get_member_history)),
+                )
+                .service(
+                    resource("/members/{id}/fines", &["GET"])
+                        .route(web::get().to(get_member_fines)),
+                )
+                .service(
+                    resource("/members/{id}/fines/pay", &["POST"])
+                        .route(web::post().to(pay_member_fines)),
+                )
+                .service(
+                    resource("/isbn/convert", &["GET"])
+                        .route(web::get().to(convert_isbn)),
+                )
+                .service(
+                    resource("/stats", &["GET"])
+                        .route(web::get().to(get_stats)),
+                )
+                .service(
+                    resource("/reports/popular", &["GET"])
+                        .route(web::get().to(get_popular_report)),
+                )
+                .service(
+                    resource("/reports/activity", &["GET"])
+                        .route(web::get().to(get_activity_report)),
+                )
+                .service(
+                    resource("/reports/weeding", &["GET"])
+                        .route(web::get().to(get_weeding_report)),
+                )
+                .service(
+                    // A stocktake sends every scanned barcode at once
+                    resource("/inventory/audit", &["POST"])
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(app_state.config.max_batch_body_bytes)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::post().to(audit_inventory)),
+                )
+                .service(
+                    resource("/branches", &["GET", "POST"])
+                        .route(web::get().to(get_branches))
+                        .route(web::post().to(create_branch)),
+                )
+                .service(
+                    resource("/branches/{id}", &["GET", "PUT", "DELETE"])
+                        .route(web::get().to(get_branch))
+                        .route(web::put().to(update_branch))
+                        .route(web::delete().to(delete_branch)),
+                )
+                .service(
+                    resource("/branches/{id}/books", &["GET"])
+                        .route(web::get().to(get_branch_books)),
+                )
+                .service(
+                    resource("/admin/expire-holds", &["POST"])
+                        .route(web::post().to(expire_holds_now)),
+                )
+                .service(
+                    resource("/events", &["GET"])
+                        .route(web::get().to(get_events)),
+                )
+                .service(
+                    resource("/admin/apply-schedules", &["POST"])
+                        .route(web::post().to(apply_schedules_now)),
+                )
+                .service(
+                    resource("/admin/purge-trash", &["POST"])
+                        .route(web::post().to(purge_trash_now)),
+                )
+                .service(
+                    resource("/admin/backup", &["GET"])
+                        .route(web::get().to(download_backup)),
+                )
+                .service(
+                    // A backup holds the whole library, so it gets the batch body limit
+                    resource("/admin/restore", &["POST"])
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(app_state.config.max_batch_body_bytes)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::post().to(restore_backup)),
+                )
+                .service(
+                    resource("/admin/compact", &["POST"])
+                        .route(web::post().to(compact_log_now)),
+                )
+                .service(
+                    resource("/admin/clock", &["GET", "PUT"])
+                        .route(web::get().to(get_clock))
+                        .route(web::put().to(set_clock)),
+                )
+        )
+        .default_service(web::to(no_route))
+}
+
+// Loads the data file into `data`, true when there was one. A new file is written at once
+// with what `data` holds, so a bad path is found now rather than at the first change.
+fn open_data_file(data: &AppState) -> Result<bool, String> {
+    let Some(store) = &data.store else {
+        return Ok(false);
+    };
+    let snapshot = store
+        .load::<Snapshot>(DATA_FILE_MIGRATIONS)
+        .map_err(|cause| format!("could not load the data file: {}", cause))?;
+    match snapshot {
+        Some(snapshot) => {
+            let catalog = !data.config.catalog_kept_apart();
+            restore_snapshot(data, snapshot, catalog)
+                .map(|()| true)
+                .map_err(|cause| format!("could not load the data file: {}", cause))
         }
+        None => persist(data).and_then(|()| flush(data)).map(|()| false),
     }
-    
-    HttpResponse::Ok().json(filtered)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_state = web::Data::new(AppState {
-        books: Mutex::new(vec![
-            Book {
-                id: 1,
-                title: "The Rust Programming Language".to_string(),
-                author: "Steve Klabnik".to_string(),
-                isbn: "978-1718500440".to_string(),
-                available: true,
-            },
-            Book {
-                id: 2,
-                title: "Programming Rust".to_string(),
-                author: "Jim Blandy".to_string(),
-                isbn: "978-1492052593".to_string(),
-                available: true,
-            },
-        ]),
-        next_id: Mutex::new(3),
+    let started_at = chrono::Utc::now();
+    let config = Config::from_env();
+    let encryption = match data_file_encryption() {
+        Ok(encryption) => encryption,
+        Err(cause) => {
+            eprintln!("Fatal: {}", cause);
+            std::process::exit(1);
+        }
+    };
+    // A seed file takes the place of the built-in books, ids included
+    let (seed_books, first_id) = match config.seed_file {
+        Some(_) => (Vec::new(), 1),
+        None => (builtin_books(started_at), 3),
+    };
+    let books = match open_books(&config, seed_books) {
+        Ok(books) => books,
+        Err(cause) => {
+            eprintln!("Fatal: {}", cause);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &config.operation_log {
+        println!("Replayed {} changes from {}", books.sequence().unwrap_or(0), path.display());
+    }
+    let store = config.data_file.clone().map(|path| storage::FileStore::new(path, encryption));
+    let app_state = match new_state(config, books, first_id, store, started_at) {
+        Ok(state) => web::Data::new(state),
+        Err(err) => {
+            eprintln!("Fatal: could not read the catalog: {}", err);
+            std::process::exit(1);
+        }
+    };
+    
+    if let Some(store) = &app_state.store {
+        let path = store.path();
+        match open_data_file(&app_state) {
+            Ok(true) => println!(
+                "Loaded {} books from {}",
+                app_state.books.read_with(|books| books.len()).unwrap_or(0),
+                path.display()
+            ),
+            Ok(false) => println!("Created data file {}", path.display()),
+            Err(cause) => {
+                eprintln!("Fatal: {}", cause);
+                std::process::exit(1);
+            }
+        }
+        // A conversion is done once the file is saved the new way, so it is saved now, not
+        // at the first change
+        if store.encryption().is_conversion() {
+            let (done, unset) = match store.encryption() {
+                storage::Encryption::Decrypting(_) => ("decrypted", "DATA_FILE_CONVERT and the key"),
+                _ => ("encrypted", "DATA_FILE_CONVERT"),
+            };
+            match persist(&app_state).and_then(|()| flush(&app_state)) {
+                Ok(()) => println!(
+                    "Saved the data file {} {}; unset {} for the next start",
+                    path.display(),
+                    done,
+                    unset
+                ),
+                Err(cause) => {
+                    eprintln!("Fatal: {}", cause);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    
+    // After the data file, so that a catalog loaded from it counts as not empty
+    if let Some(path) = &app_state.config.seed_file {
+        match load_seed_file(&app_state, path, app_state.config.seed_force) {
+            Ok(Some((added, skipped))) => {
+                println!("Seeded {} books from {}", added, path.display());
+                if skipped > 0 {
+                    eprintln!(
+                        "Warning: skipped {} seed books whose ISBN is already in the catalog",
+                        skipped
+                    );
+                }
+                if added > 0 {
+                    if let Err(cause) = persist(&app_state).and_then(|()| flush(&app_state)) {
+                        eprintln!("Fatal: {}", cause);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Ok(None) => println!(
+                "The catalog already holds books, so {} was not loaded; start with --seed-force \
+                 to add its books anyway",
+                path.display()
+            ),
+            Err(cause) => {
+                eprintln!("Fatal: {}", cause);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    if !app_state.config.flush_interval.is_zero() && app_state.store.is_some() {
+        let flush_state = app_state.clone();
+        actix_web::rt::spawn(async move {
+            let mut flushes = actix_web::rt::time::interval(flush_state.config.flush_interval);
+            // Only a change of state is logged, not every retry of the same failure
+            let mut failing = false;
+            loop {
+                flushes.tick().await;
+                match flush(&flush_state) {
+                    Ok(()) if failing => {
+                        failing = false;
+                        println!("Saving to the data file works again");
+                    }
+                    Err(cause) if !failing => {
+                        failing = true;
+                        eprintln!("Persistence failed: {}", cause);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+    
+    let purge_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut sweeps = actix_web::rt::time::interval(purge_state.config.purge_interval);
+        loop {
+            sweeps.tick().await;
+            if !purge_trash(&purge_state).is_empty() {
+                persist_or_log(&purge_state);
+            }
+        }
+    });
+    
+    let reminder_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut scans = actix_web::rt::time::interval(reminder_state.config.reminder_interval);
+        loop {
+            scans.tick().await;
+            send_due_reminders(&reminder_state).await;
+        }
+    });
+    
+    let schedule_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut runs = actix_web::rt::time::interval(schedule_state.config.schedule_interval);
+        loop {
+            runs.tick().await;
+            let run = apply_due_schedules(&schedule_state);
+            if !run.applied.is_empty() || !run.dropped.is_empty() {
+                persist_or_log(&schedule_state);
+            }
+        }
+    });
+    
+    let hold_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut sweeps = actix_web::rt::time::interval(hold_state.config.hold_expiry_interval);
+        loop {
+            sweeps.tick().await;
+            if expire_holds(&hold_state) > 0 {
+                persist_or_log(&hold_state);
+            }
+        }
     });
     
     println!("Starting Book Library API on http://127.0.0.1:8080");
     
-    HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
-            .route("/health", web::get().to(health_check))
-            .route("/api/books", web::get().to(get_books))
-            .route("/api/books/search", web::get().to(search_books))
-            .route("/api/books/{id}", web::get().to(get_book_by_id))
-            .route("/api/books", web::post().to(create_book))
-            .route("/api/books/{id}", web::put().to(update_book))
-            .route("/api/books/{id}", web::delete().to(delete_book))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    let shutdown_state = app_state.clone();
+    HttpServer::new(move || build_app(app_state.clone()))
+        .bind("127.0.0.1:8080")?
+        .run()
+        .await?;
+    
+    // The server has stopped taking requests and finished the ones in flight; whatever the
+    // flusher hadn't written yet goes now
+    if let Err(cause) = flush(&shutdown_state) {
+        eprintln!("Persistence failed on shutdown: {}", cause);
+    }
+    Ok(())
 }
 
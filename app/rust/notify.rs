@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// How long a webhook gets to accept a notification before it counts as failed
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type NotifyFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+// Delivers a message to a patron. Implementations decide what `recipient` means (the
+// server passes the member's email) and how the message travels; an `Err` is logged and
+// counted by `send`, never shown to the client whose request triggered it.
+//
+// Written as a boxed future rather than an `async fn` so it can sit behind `dyn`.
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(&'a self, recipient: &'a str, subject: &'a str, body: &'a str) -> NotifyFuture<'a>;
+}
+
+// Prints each notification to stdout; the default until a real channel is configured
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify<'a>(&'a self, recipient: &'a str, subject: &'a str, body: &'a str) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            println!("Notification to {}: {} - {}", recipient, subject, body);
+            Ok(())
+        })
+    }
+}
+
+// POSTs each notification as JSON (`recipient`, `subject`, `body`) to a plain-HTTP
+// endpoint, which does the actual emailing or texting. Any 2xx status is a success.
+pub struct WebhookNotifier {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(raw: &str) -> Result<Self, String> {
+        let url = url::Url::parse(raw).map_err(|e| format!("invalid webhook URL: {}", e))?;
+        if url.scheme() != "http" {
+            return Err("webhook URL must use http".to_string());
+        }
+        let host = url.host_str().ok_or("webhook URL has no host")?.to_string();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        Ok(WebhookNotifier {
+            host,
+            port: url.port().unwrap_or(80),
+            path,
+        })
+    }
+
+    async fn post(&self, payload: String) -> Result<(), String> {
+        let mut stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("webhook connection failed: {}", e))?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            payload.len(),
+            payload
+        );
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| format!("webhook request failed: {}", e))?;
+
+        // Only the status line matters
+        let mut head = [0u8; 64];
+        let read = stream
+            .read(&mut head)
+            .await
+            .map_err(|e| format!("webhook response failed: {}", e))?;
+        let status_line = String::from_utf8_lossy(&head[..read]);
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => Err(format!("webhook answered {}", status)),
+            None => Err("webhook sent no HTTP response".to_string()),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify<'a>(&'a self, recipient: &'a str, subject: &'a str, body: &'a str) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let payload = serde_json::json!({
+                "recipient": recipient,
+                "subject": subject,
+                "body": body,
+            })
+            .to_string();
+            match tokio::time::timeout(WEBHOOK_TIMEOUT, self.post(payload)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("webhook timed out after {:?}", WEBHOOK_TIMEOUT)),
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Counters {
+    sent: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Counters {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+// Sends one notification, logging and counting a failure instead of returning it
+pub async fn send(
+    notifier: &dyn Notifier,
+    counters: &Counters,
+    recipient: &str,
+    subject: &str,
+    body: &str,
+) {
+    match notifier.notify(recipient, subject, body).await {
+        Ok(()) => {
+            counters.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(error) => {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Notification to {} failed: {}", recipient, error);
+        }
+    }
+}
+
+// `NOTIFIER=webhook` with `NOTIFY_WEBHOOK_URL` selects the webhook; anything else, or a
+// webhook URL that can't be used, logs instead
+pub fn from_env() -> Box<dyn Notifier> {
+    if std::env::var("NOTIFIER").as_deref() != Ok("webhook") {
+        return Box::new(LogNotifier);
+    }
+    match std::env::var("NOTIFY_WEBHOOK_URL")
+        .map_err(|_| "NOTIFY_WEBHOOK_URL is not set".to_string())
+        .and_then(|url| WebhookNotifier::new(&url))
+    {
+        Ok(webhook) => Box::new(webhook),
+        Err(error) => {
+            eprintln!("Not using the webhook notifier ({}); logging notifications instead", error);
+            Box::new(LogNotifier)
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use crate::repository::{
+    self, BookFilter, BookPage, BookRepository, Compaction, PageRequest, RepositoryError,
+};
+use crate::row::{LoadedRow, StoredRow};
+use crate::{isbn, storage, Book};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Create,
+    Update,
+    Delete,
+    // A book as it stood when the log was compacted. A compacted log opens with one per
+    // book, all numbered with the last change the old log held, and nothing else may
+    // come before them; an empty catalog gets a single one with no book and id 0.
+    Snapshot,
+}
+
+// One line of the log: the change numbered `seq`, with the book as the change left it.
+// A delete has no book.
+#[derive(Serialize)]
+struct StoredEntry<'a> {
+    seq: u64,
+    op: Op,
+    id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book: Option<StoredRow<'a>>,
+}
+
+#[derive(Deserialize)]
+struct LoadedEntry {
+    seq: u64,
+    op: Op,
+    id: u32,
+    #[serde(default)]
+    book: Option<LoadedRow>,
+}
+
+enum Change<'a> {
+    Create(&'a Book),
+    Update(&'a Book),
+    Delete(u32),
+    Snapshot(Option<&'a Book>),
+}
+
+impl Change<'_> {
+    fn entry(&self, seq: u64) -> StoredEntry<'_> {
+        let (op, id, book) = match self {
+            Change::Create(book) => (Op::Create, book.id, Some(StoredRow::new(book))),
+            Change::Update(book) => (Op::Update, book.id, Some(StoredRow::new(book))),
+            Change::Delete(id) => (Op::Delete, *id, None),
+            Change::Snapshot(book) => {
+                (Op::Snapshot, book.map_or(0, |b| b.id), book.map(StoredRow::new))
+            }
+        };
+        StoredEntry { seq, op, id, book }
+    }
+}
+
+// Adds the change as one line
+fn push_line(bytes: &mut Vec<u8>, change: &Change, seq: u64) -> Result<(), RepositoryError> {
+    serde_json::to_writer(&mut *bytes, &change.entry(seq)).map_err(|err| {
+        RepositoryError::Unavailable(format!("could not serialize change {}: {}", seq, err))
+    })?;
+    bytes.push(b'\n');
+    Ok(())
+}
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    // Where the last whole entry ends; a failed append is cut back to it
+    len: u64,
+    seq: u64,
+    // Lines in the log
+    entries: u64,
+    // Where the log ended after the last compaction; 0 until there has been one
+    compacted: u64,
+    // Why nothing more can be written: an append failed and couldn't be cut back, so the
+    // next entry would follow a partial line
+    broken: Option<String>,
+}
+
+impl Log {
+    // Writes the changes, numbered on from the last, and syncs them to disk. Either all of
+    // them are in the log afterwards or, with an error, none are.
+    fn append(&mut self, changes: &[Change]) -> Result<(), RepositoryError> {
+        if let Some(cause) = &self.broken {
+            return Err(RepositoryError::Unavailable(cause.clone()));
+        }
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        for (seq, change) in (self.seq + 1..).zip(changes) {
+            push_line(&mut bytes, change, seq)?;
+        }
+        if let Err(err) = self.file.write_all(&bytes).and_then(|()| self.file.sync_data()) {
+            let cause = format!("could not append to {}: {}", self.path.display(), err);
+            if let Err(err) = self.file.set_len(self.len) {
+                self.broken = Some(format!(
+                    "{}, nor cut the partial entry off again ({}); restart the server to drop it",
+                    cause, err
+                ));
+            }
+            return Err(RepositoryError::Unavailable(cause));
+        }
+        self.len += bytes.len() as u64;
+        self.seq += changes.len() as u64;
+        self.entries += changes.len() as u64;
+        Ok(())
+    }
+}
+
+// Rebuilds the catalog from the log's whole lines, applying the changes in order. Returns
+// the books in id order, the number of the last change, and the number of lines. Anything
+// that doesn't follow on from what came before is an error naming the line: the log was
+// edited or damaged, and replaying past it would rebuild some other catalog.
+fn replay(path: &Path, lines: &[u8]) -> Result<(Vec<Book>, u64, u64), String> {
+    let mut books = BTreeMap::new();
+    let mut seq = 0;
+    let mut entries = 0;
+    // Still in the snapshot entries a compacted log opens with
+    let mut opening = true;
+    for (number, line) in (1..).zip(lines.split_inclusive(|&b| b == b'\n')) {
+        entries = number;
+        let at = |problem: String| format!("{} line {}: {}", path.display(), number, problem);
+        let entry: LoadedEntry = serde_json::from_slice(line)
+            .map_err(|err| at(format!("not a log entry: {}", err)))?;
+        if let Op::Snapshot = entry.op {
+            if !opening {
+                return Err(at("a snapshot entry after the log's first change".to_string()));
+            }
+            if number > 1 && entry.seq != seq {
+                return Err(at(format!("a snapshot at change {} after one at {}", entry.seq, seq)));
+            }
+            seq = entry.seq;
+            match entry.book.map(LoadedRow::into_book) {
+                Some(book) if book.id != entry.id => {
+                    return Err(at(format!("the book of the snapshot is not book {}", entry.id)));
+                }
+                Some(_) if books.contains_key(&entry.id) => {
+                    return Err(at(format!("book {} is in the snapshot twice", entry.id)));
+                }
+                Some(book) => {
+                    books.insert(entry.id, book);
+                }
+                None => {}
+            }
+            continue;
+        }
+        opening = false;
+        if entry.seq != seq + 1 {
+            return Err(at(format!("change {} follows change {}", entry.seq, seq)));
+        }
+        seq = entry.seq;
+        let exists = books.contains_key(&entry.id);
+        match (entry.op, entry.book.map(LoadedRow::into_book)) {
+            (Op::Delete, _) if exists => {
+                books.remove(&entry.id);
+            }
+            (Op::Create, Some(book)) if !exists && book.id == entry.id => {
+                books.insert(entry.id, book);
+            }
+            (Op::Update, Some(book)) if exists && book.id == entry.id => {
+                books.insert(entry.id, book);
+            }
+            (Op::Create, Some(_)) if exists => {
+                return Err(at(format!("creates book {}, which already exists", entry.id)));
+            }
+            (Op::Create | Op::Update, Some(book)) if book.id != entry.id => {
+                return Err(at(format!("the book of change {} is not book {}", seq, entry.id)));
+            }
+            (Op::Create | Op::Update, None) => {
+                return Err(at(format!("change {} has no book", seq)));
+            }
+            _ => return Err(at(format!("book {} doesn't exist", entry.id))),
+        }
+    }
+    Ok((books.into_values().collect(), seq, entries))
+}
+
+struct State {
+    // In id order, as in InMemoryBooks
+    books: Vec<Book>,
+    log: Log,
+}
+
+// The catalog in memory, with every change appended to a log file before it is made. At
+// startup the log is replayed to rebuild the catalog, so a write costs one line however
+// large the catalog is.
+pub struct LoggedBooks {
+    state: Mutex<State>,
+    // One compaction at a time
+    compacting: Mutex<()>,
+    // The log size past which a compaction starts by itself; None leaves it to
+    // POST /api/admin/compact
+    compact_at: Option<u64>,
+    // Whether a compaction started by itself is still running
+    compaction_started: AtomicBool,
+    // For the thread a compaction started by itself runs on
+    me: Weak<LoggedBooks>,
+}
+
+impl LoggedBooks {
+    // Replays the log at `path`. A missing or empty log is started with `seed`. Bytes after
+    // the last newline are a line whose write was cut short by a crash: they are cut off
+    // with a warning, and the change they held was never reported as made.
+    pub fn open(path: &Path, seed: Vec<Book>, compact_at: Option<u64>) -> Result<Arc<Self>, String> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(format!("could not read {}: {}", path.display(), err)),
+        };
+        let whole = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |end| end + 1);
+        let (books, seq, entries) = replay(path, &bytes[..whole])?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("could not open {}: {}", path.display(), err))?;
+        if whole < bytes.len() {
+            eprintln!(
+                "Warning: {} ended in a partial entry of {} bytes, left by a write that was \
+                 cut short; it has been dropped",
+                path.display(),
+                bytes.len() - whole
+            );
+            file.set_len(whole as u64)
+                .and_then(|()| file.sync_data())
+                .map_err(|err| {
+                    format!("could not cut the partial entry off {}: {}", path.display(), err)
+                })?;
+        }
+        let mut state = State {
+            books,
+            log: Log {
+                path: path.to_path_buf(),
+                file,
+                len: whole as u64,
+                seq,
+                entries,
+                compacted: 0,
+                broken: None,
+            },
+        };
+        if seq == 0 {
+            let changes: Vec<Change> = seed.iter().map(Change::Create).collect();
+            state.log.append(&changes).map_err(|err| err.to_string())?;
+            for book in seed {
+                repository::insert_sorted(&mut state.books, book);
+            }
+        }
+        Ok(Arc::new_cyclic(|me| LoggedBooks {
+            state: Mutex::new(state),
+            compacting: Mutex::new(()),
+            compact_at,
+            compaction_started: AtomicBool::new(false),
+            me: me.clone(),
+        }))
+    }
+
+    // As with InMemoryBooks, a panic with the lock held poisons it for good
+    fn lock(&self) -> Result<MutexGuard<'_, State>, RepositoryError> {
+        self.state.lock().map_err(|_| {
+            RepositoryError::Unavailable(
+                "a request panicked while changing the catalog".to_string(),
+            )
+        })
+    }
+
+    // Rewrites the log as a snapshot of the catalog: one entry per book, and nothing of
+    // the changes that led there. The catalog's lock is held to copy the catalog and
+    // again to swap the new log in, not while the snapshot is written, so requests go on
+    // meanwhile; changes they make are appended to the old log as usual, and copied after
+    // the snapshot before the swap. The swap is a rename, so a crash leaves the old log
+    // or the new one, and the old one is gone once it is done.
+    fn compact_log(&self) -> Result<Compaction, RepositoryError> {
+        let _compacting = self.compacting.lock().map_err(|_| {
+            RepositoryError::Unavailable("a compaction panicked halfway through".to_string())
+        })?;
+        let (books, path, seq, from, entries_from) = {
+            let state = self.lock()?;
+            let log = &state.log;
+            (state.books.clone(), log.path.clone(), log.seq, log.len, log.entries)
+        };
+        let mut bytes = Vec::new();
+        if books.is_empty() {
+            push_line(&mut bytes, &Change::Snapshot(None), seq)?;
+        }
+        for book in &books {
+            push_line(&mut bytes, &Change::Snapshot(Some(book)), seq)?;
+        }
+        let snapshot_entries = books.len().max(1) as u64;
+        drop(books);
+        let temp = storage::temp_path(&path);
+        let compacted = write_snapshot(&temp, &bytes).and_then(|file| {
+            let mut state = self.lock()?;
+            let tail = read_from(&path, from, state.log.len)?;
+            let swapped = swap_in(file, &tail, &temp, &path)?;
+            let log = &mut state.log;
+            let done = Compaction {
+                entries_before: log.entries,
+                entries_after: snapshot_entries + log.entries - entries_from,
+                bytes_before: log.len,
+                bytes_after: (bytes.len() + tail.len()) as u64,
+            };
+            log.file = swapped;
+            log.len = done.bytes_after;
+            log.entries = done.entries_after;
+            log.compacted = done.bytes_after;
+            // The new log ends in whole entries whatever the old one ended in
+            log.broken = None;
+            Ok(done)
+        });
+        if compacted.is_err() {
+            // Best effort, as for the data file: nothing reads a leftover temporary file
+            let _ = std::fs::remove_file(&temp);
+        }
+        compacted
+    }
+
+    // Starts a compaction on a thread of its own once the log is past `compact_at` and
+    // twice the size it was left at by the last one, so that a catalog that is large by
+    // itself isn't compacted again after every change
+    fn compact_if_due(&self, log: &Log) {
+        let due = self
+            .compact_at
+            .is_some_and(|at| log.len > at && log.len > 2 * log.compacted);
+        if !due || self.compaction_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let Some(books) = self.me.upgrade() else {
+            return;
+        };
+        std::thread::spawn(move || {
+            match books.compact_log() {
+                Ok(done) => println!(
+                    "Compacted the operation log from {} entries to {}",
+                    done.entries_before, done.entries_after
+                ),
+                Err(err) => {
+                    eprintln!("Compacting the operation log failed: {}", err);
+                    // Wait for the log to double again rather than retry on every change
+                    if let Ok(mut state) = books.lock() {
+                        state.log.compacted = state.log.len;
+                    }
+                }
+            }
+            books.compaction_started.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn io_failed(path: &Path, err: std::io::Error) -> RepositoryError {
+    RepositoryError::Unavailable(format!("could not compact into {}: {}", path.display(), err))
+}
+
+// Writes the snapshot to a fresh file at `temp`, open for appending
+fn write_snapshot(temp: &Path, bytes: &[u8]) -> Result<File, RepositoryError> {
+    match std::fs::remove_file(temp) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(io_failed(temp, err)),
+        _ => {}
+    }
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(temp)
+        .map_err(|err| io_failed(temp, err))?;
+    file.write_all(bytes).map_err(|err| io_failed(temp, err))?;
+    Ok(file)
+}
+
+// The bytes of the log from `from` to `to`
+fn read_from(path: &Path, from: u64, to: u64) -> Result<Vec<u8>, RepositoryError> {
+    let read = |err| {
+        RepositoryError::Unavailable(format!("could not read {}: {}", path.display(), err))
+    };
+    let mut file = File::open(path).map_err(read)?;
+    file.seek(SeekFrom::Start(from)).map_err(read)?;
+    let mut tail = vec![0; (to - from) as usize];
+    file.read_exact(&mut tail).map_err(read)?;
+    Ok(tail)
+}
+
+// Appends the changes made since the snapshot, syncs the file, and renames it over the log
+fn swap_in(mut file: File, tail: &[u8], temp: &Path, path: &Path) -> Result<File, RepositoryError> {
+    file.write_all(tail)
+        .and_then(|()| file.sync_data())
+        .map_err(|err| io_failed(temp, err))?;
+    std::fs::rename(temp, path).map_err(|err| io_failed(path, err))?;
+    storage::sync_dir(path).map_err(|err| io_failed(path, err))?;
+    Ok(file)
+}
+
+impl BookRepository for LoggedBooks {
+    fn list(&self) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.lock()?.books.clone())
+    }
+
+    fn get(&self, id: u32) -> Result<Book, RepositoryError> {
+        let state = self.lock()?;
+        Ok(state.books[repository::position(&state.books, id)?].clone())
+    }
+
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError> {
+        self.lock()?
+            .books
+            .iter()
+            .find(|b| isbn::equivalent(&b.isbn, isbn))
+            .cloned()
+            .ok_or_else(|| RepositoryError::IsbnNotFound(isbn.to_string()))
+    }
+
+    fn insert(&self, book: Book) -> Result<(), RepositoryError> {
+        let mut state = self.lock()?;
+        repository::check_isbn(&state.books, &book.isbn)?;
+        state.log.append(&[Change::Create(&book)])?;
+        self.compact_if_due(&state.log);
+        repository::insert_sorted(&mut state.books, book);
+        Ok(())
+    }
+
+    // The change is made to a copy, which replaces the book once it is in the log; a
+    // change that leaves the book as it was isn't logged
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError> {
+        let mut state = self.lock()?;
+        let index = repository::position(&state.books, id)?;
+        let mut changed = state.books[index].clone();
+        change(&mut changed);
+        if changed != state.books[index] {
+            state.log.append(&[Change::Update(&changed)])?;
+            self.compact_if_due(&state.log);
+            state.books[index] = changed.clone();
+        }
+        Ok(changed)
+    }
+
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError> {
+        let mut state = self.lock()?;
+        let index = repository::position(&state.books, id)?;
+        repository::check_version(&state.books[index], version)?;
+        state.log.append(&[Change::Delete(id)])?;
+        self.compact_if_due(&state.log);
+        Ok(state.books.remove(index))
+    }
+
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.lock()?.books.iter().filter(|b| matches(b)).cloned().collect())
+    }
+
+    fn page(
+        &self,
+        filter: &BookFilter,
+        request: &PageRequest,
+    ) -> Result<BookPage, RepositoryError> {
+        let books = self.lock()?.books.iter().filter(|b| filter.matches(b)).cloned().collect();
+        Ok(repository::page_of(books, &BookFilter::default(), request))
+    }
+
+    fn health(&self) -> Option<Result<(), RepositoryError>> {
+        None
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        self.state.lock().ok().map(|state| state.log.seq)
+    }
+
+    fn compact(&self) -> Option<Result<Compaction, RepositoryError>> {
+        Some(self.compact_log())
+    }
+
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError> {
+        read(&self.lock()?.books);
+        Ok(())
+    }
+
+    // The closure changes a copy of the catalog. The books that came out different are
+    // compared with the originals, logged as deletes, creates, and updates, and the copy
+    // takes the catalog's place once they are in the log.
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError> {
+        let mut state = self.lock()?;
+        let State { books: current, log } = &mut *state;
+        let mut books = current.clone();
+        write(&mut books);
+        let before: HashMap<u32, &Book> = current.iter().map(|b| (b.id, b)).collect();
+        let kept: HashSet<u32> = books.iter().map(|b| b.id).collect();
+        let mut changes: Vec<Change> = current
+            .iter()
+            .filter(|b| !kept.contains(&b.id))
+            .map(|b| Change::Delete(b.id))
+            .collect();
+        for book in &books {
+            match before.get(&book.id) {
+                None => changes.push(Change::Create(book)),
+                Some(old) if *old != book => changes.push(Change::Update(book)),
+                Some(_) => {}
+            }
+        }
+        log.append(&changes)?;
+        self.compact_if_due(log);
+        drop((changes, before));
+        *current = books;
+        Ok(())
+    }
+}
@@ -0,0 +1,602 @@
+use crate::repository::{
+    BookFilter, BookPage, BookRepository, Compaction, PageRequest, RepositoryError,
+};
+use crate::row::{decode, encode};
+use crate::{isbn, Book};
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{Client, NoTls};
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Condvar, Mutex};
+
+// Schema changes in the order they were made. Each runs once, in the transaction that
+// records it in schema_migrations; new ones go at the end, and applied ones never change.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE books (
+        id BIGINT PRIMARY KEY,
+        isbn_key TEXT NOT NULL,
+        available BOOLEAN NOT NULL,
+        published_year BIGINT,
+        page_count BIGINT,
+        language TEXT,
+        shelf_location TEXT,
+        branch_id BIGINT,
+        book JSONB NOT NULL
+    );
+    CREATE UNIQUE INDEX books_isbn_key ON books (isbn_key);
+    CREATE INDEX books_available ON books (available);
+    CREATE INDEX books_published_year ON books (published_year);
+"];
+
+// Taken while migrating, so servers starting together don't both apply a change. Any
+// number works as long as nothing else on the database uses it.
+const MIGRATION_LOCK: i64 = 0x0062_6f6f_6b73;
+
+// The unique index whose refusals are duplicate ISBNs rather than failures
+const ISBN_INDEX: &str = "books_isbn_key";
+
+const SELECT_BOOKS: &str = "SELECT book::text FROM books";
+
+// A book's row. The whole book is the JSON; the fields filters use are copied into
+// columns of their own, and `isbn_key` is the ISBN-13 form when the ISBN is valid, so the
+// unique index also refuses an ISBN-10 and the ISBN-13 of the same book.
+struct Row {
+    id: i64,
+    isbn_key: String,
+    available: bool,
+    published_year: Option<i64>,
+    page_count: Option<i64>,
+    language: Option<String>,
+    shelf_location: Option<String>,
+    branch_id: Option<i64>,
+    json: String,
+}
+
+impl Row {
+    fn new(book: &Book) -> Result<Self, RepositoryError> {
+        Ok(Row {
+            id: book.id.into(),
+            isbn_key: isbn::canonical(&book.isbn),
+            available: book.available,
+            published_year: book.published_year.map(i64::from),
+            page_count: book.page_count.map(i64::from),
+            language: book.language.clone(),
+            shelf_location: book.shelf_location.clone(),
+            branch_id: book.branch_id.map(i64::from),
+            json: encode(book)?,
+        })
+    }
+
+    fn params(&self) -> [&(dyn ToSql + Sync); 9] {
+        [
+            &self.id,
+            &self.isbn_key,
+            &self.available,
+            &self.published_year,
+            &self.page_count,
+            &self.language,
+            &self.shelf_location,
+            &self.branch_id,
+            &self.json,
+        ]
+    }
+
+    fn insert(&self, client: &mut Client) -> Result<u64, postgres::Error> {
+        client.execute(
+            "INSERT INTO books (id, isbn_key, available, published_year, page_count, language, \
+             shelf_location, branch_id, book) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::jsonb)",
+            &self.params(),
+        )
+    }
+
+    fn update(&self, client: &mut Client) -> Result<u64, postgres::Error> {
+        client.execute(
+            "UPDATE books SET isbn_key = $2, available = $3, published_year = $4, \
+             page_count = $5, language = $6, shelf_location = $7, branch_id = $8, \
+             book = $9::text::jsonb \
+             WHERE id = $1",
+            &self.params(),
+        )
+    }
+}
+
+// An error the database sent displays as just "db error", so its message is used instead
+fn describe(err: &postgres::Error) -> String {
+    match err.as_db_error() {
+        Some(db) => db.to_string(),
+        None => err.to_string(),
+    }
+}
+
+fn failed(err: postgres::Error) -> RepositoryError {
+    RepositoryError::Unavailable(format!("PostgreSQL: {}", describe(&err)))
+}
+
+fn stopped() -> RepositoryError {
+    RepositoryError::Unavailable("a database connection thread stopped".to_string())
+}
+
+fn load(
+    client: &mut Client,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<Vec<Book>, RepositoryError> {
+    client
+        .query(sql, params)
+        .map_err(failed)?
+        .iter()
+        .map(|row| decode(row.get::<_, &str>(0)))
+        .collect()
+}
+
+// What a failed write becomes. A refusal by the ISBN index names the book that holds the
+// ISBN, as the in-memory store's does; it is looked up after rolling back, since a
+// transaction takes no more statements once one has failed.
+fn write_failed(client: &mut Client, err: postgres::Error, isbn_key: &str) -> RepositoryError {
+    let duplicate = err.as_db_error().is_some_and(|db| {
+        *db.code() == SqlState::UNIQUE_VIOLATION && db.constraint() == Some(ISBN_INDEX)
+    });
+    if !duplicate {
+        return failed(err);
+    }
+    // Outside a transaction this only draws a warning
+    let _ = client.batch_execute("ROLLBACK");
+    let sql = format!("{} WHERE isbn_key = $1", SELECT_BOOKS);
+    match load(client, &sql, &[&isbn_key]).map(|mut found| found.pop()) {
+        Ok(Some(holder)) => RepositoryError::DuplicateIsbn {
+            isbn: holder.isbn,
+            existing_id: holder.id,
+        },
+        Ok(None) => RepositoryError::Unavailable(format!(
+            "the ISBN index refused {} but no book holds it",
+            isbn_key
+        )),
+        Err(err) => err,
+    }
+}
+
+// Runs `work` in a transaction opened with `begin`, committing it if `work` succeeds and
+// rolling it back otherwise
+fn in_transaction<T>(
+    client: &mut Client,
+    begin: &str,
+    work: impl FnOnce(&mut Client) -> Result<T, RepositoryError>,
+) -> Result<T, RepositoryError> {
+    client.batch_execute(begin).map_err(failed)?;
+    let result = work(client)
+        .and_then(|value| client.batch_execute("COMMIT").map(|()| value).map_err(failed));
+    if result.is_err() {
+        let _ = client.batch_execute("ROLLBACK");
+    }
+    result
+}
+
+// Brings the schema up to date, and fills the books table with `seed` the first time
+fn migrate(client: &mut Client, seed: Vec<Row>) -> Result<(), RepositoryError> {
+    in_transaction(client, "BEGIN", |client| {
+        client
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .map_err(failed)?;
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version BIGINT PRIMARY KEY,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+            )
+            .map_err(failed)?;
+        let applied: i64 = client
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+            .map_err(failed)?
+            .get(0);
+        if applied > MIGRATIONS.len() as i64 {
+            return Err(RepositoryError::Unavailable(format!(
+                "the database schema is at version {}, but this server only knows up to {}",
+                applied,
+                MIGRATIONS.len()
+            )));
+        }
+        for (version, migration) in (1i64..).zip(MIGRATIONS).skip(applied as usize) {
+            client.batch_execute(migration).map_err(failed)?;
+            client
+                .execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[&version])
+                .map_err(failed)?;
+        }
+        if applied == 0 {
+            for row in &seed {
+                row.insert(client).map_err(|err| write_failed(client, err, &row.isbn_key))?;
+            }
+        }
+        Ok(())
+    })
+}
+
+type Job = Box<dyn FnOnce(&mut Client) + Send>;
+
+// A thread that owns one connection and runs the jobs it is sent, in order. The client
+// blocks on an async runtime of its own, which it can't do on the server's worker threads,
+// so every statement runs on a thread like this one.
+struct Connection {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Connection {
+    fn open(url: &str) -> Result<Self, String> {
+        let url = url.to_string();
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (ready, connected) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut client = match Client::connect(&url, NoTls) {
+                Ok(client) => client,
+                Err(err) => {
+                    let _ = ready.send(Err(describe(&err)));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for job in queue {
+                // A connection the database dropped is replaced before the next job, so
+                // the pool recovers by itself once the database is back
+                if client.is_closed() {
+                    if let Ok(fresh) = Client::connect(&url, NoTls) {
+                        client = fresh;
+                    }
+                }
+                job(&mut client);
+            }
+        });
+        connected
+            .recv()
+            .unwrap_or_else(|_| Err("the connection thread stopped".to_string()))?;
+        Ok(Connection { jobs })
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Client) -> Result<T, RepositoryError> + Send + 'static,
+    ) -> Result<T, RepositoryError> {
+        let (reply, result) = mpsc::channel();
+        let job: Job = Box::new(move |client| {
+            let _ = reply.send(job(client));
+        });
+        self.jobs.send(job).map_err(|_| stopped())?;
+        result.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+// A connection out of the pool until dropped. `open` marks a transaction begun by one
+// job and not yet ended by another; dropping the checkout then rolls it back.
+struct Checkout<'a> {
+    pool: &'a PostgresBooks,
+    connection: Option<Connection>,
+    open: bool,
+}
+
+impl Checkout<'_> {
+    fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Client) -> Result<T, RepositoryError> + Send + 'static,
+    ) -> Result<T, RepositoryError> {
+        self.connection
+            .as_ref()
+            .expect("a checkout keeps its connection until dropped")
+            .run(job)
+    }
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        let Some(connection) = self.connection.take() else {
+            return;
+        };
+        if self.open {
+            // Queued ahead of whatever the next user of the connection sends
+            let rollback: Job = Box::new(|client| {
+                let _ = client.batch_execute("ROLLBACK");
+            });
+            let _ = connection.jobs.send(rollback);
+        }
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.push(connection);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+// Collects the conditions of a query along with the values they compare against
+#[derive(Default)]
+struct Conditions {
+    sql: Vec<String>,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+}
+
+impl Conditions {
+    fn add(&mut self, condition: &str, value: Box<dyn ToSql + Sync + Send>) {
+        self.params.push(value);
+        self.sql.push(format!("{} ${}", condition, self.params.len()));
+    }
+
+    fn from_filter(filter: &BookFilter) -> Self {
+        let mut conditions = Conditions::default();
+        if let Some(available) = filter.available {
+            conditions.add("available =", Box::new(available));
+        }
+        if let Some(from) = filter.year_from {
+            conditions.add("published_year >=", Box::new(i64::from(from)));
+        }
+        if let Some(to) = filter.year_to {
+            conditions.add("published_year <=", Box::new(i64::from(to)));
+        }
+        if let Some(min) = filter.min_pages {
+            conditions.add("page_count >=", Box::new(i64::from(min)));
+        }
+        if let Some(max) = filter.max_pages {
+            conditions.add("page_count <=", Box::new(i64::from(max)));
+        }
+        if let Some(language) = &filter.language {
+            conditions.add("language =", Box::new(language.clone()));
+        }
+        if let Some(shelf) = &filter.shelf {
+            conditions.add("shelf_location =", Box::new(shelf.clone()));
+        }
+        if let Some(branch) = filter.branch {
+            conditions.add("branch_id =", Box::new(i64::from(branch)));
+        }
+        conditions
+    }
+
+    fn where_clause(&self) -> String {
+        if self.sql.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", self.sql.join(" AND "))
+        }
+    }
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params.iter().map(|value| &**value as &(dyn ToSql + Sync)).collect()
+    }
+}
+
+// The catalog in PostgreSQL, reached through a pool of connections. Single-book calls
+// run in one transaction each, with the row locked while it is read and changed.
+// `read_all` holds a SHARE lock on the table and `write_all` an EXCLUSIVE one, so, as
+// with the in-memory store, nothing changes the catalog while either closure runs.
+pub struct PostgresBooks {
+    idle: Mutex<Vec<Connection>>,
+    returned: Condvar,
+}
+
+impl PostgresBooks {
+    // Connects `pool_size` times to the database at `url`, applies any migrations the
+    // schema lacks, and on the first run fills the books table with `seed`
+    pub fn open(url: &str, pool_size: usize, seed: Vec<Book>) -> Result<Self, String> {
+        let connections = (0..pool_size)
+            .map(|_| Connection::open(url))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("could not connect to the database: {}", err))?;
+        let seed = seed
+            .iter()
+            .map(Row::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        connections[0]
+            .run(move |client| migrate(client, seed))
+            .map_err(|err| format!("could not migrate the database: {}", err))?;
+        Ok(PostgresBooks {
+            idle: Mutex::new(connections),
+            returned: Condvar::new(),
+        })
+    }
+
+    // Waits for a free connection
+    fn checkout(&self) -> Result<Checkout<'_>, RepositoryError> {
+        let poisoned = |_| RepositoryError::Unavailable("the connection pool failed".to_string());
+        let idle = self.idle.lock().map_err(poisoned)?;
+        let mut idle = self
+            .returned
+            .wait_while(idle, |idle| idle.is_empty())
+            .map_err(poisoned)?;
+        Ok(Checkout {
+            pool: self,
+            connection: idle.pop(),
+            open: false,
+        })
+    }
+}
+
+impl BookRepository for PostgresBooks {
+    fn list(&self) -> Result<Vec<Book>, RepositoryError> {
+        self.checkout()?.run(|client| {
+            load(client, &format!("{} ORDER BY id", SELECT_BOOKS), &[])
+        })
+    }
+
+    fn get(&self, id: u32) -> Result<Book, RepositoryError> {
+        self.checkout()?
+            .run(move |client| {
+                load(client, &format!("{} WHERE id = $1", SELECT_BOOKS), &[&i64::from(id)])
+            })?
+            .pop()
+            .ok_or(RepositoryError::NotFound(id))
+    }
+
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError> {
+        let key = isbn::canonical(isbn);
+        self.checkout()?
+            .run(move |client| {
+                load(client, &format!("{} WHERE isbn_key = $1", SELECT_BOOKS), &[&key])
+            })?
+            .pop()
+            .ok_or_else(|| RepositoryError::IsbnNotFound(isbn.to_string()))
+    }
+
+    fn insert(&self, book: Book) -> Result<(), RepositoryError> {
+        let row = Row::new(&book)?;
+        self.checkout()?.run(move |client| {
+            row.insert(client)
+                .map(|_| ())
+                .map_err(|err| write_failed(client, err, &row.isbn_key))
+        })
+    }
+
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError> {
+        let mut checkout = self.checkout()?;
+        checkout.open = true;
+        let mut book = checkout
+            .run(move |client| {
+                client.batch_execute("BEGIN").map_err(failed)?;
+                let sql = format!("{} WHERE id = $1 FOR UPDATE", SELECT_BOOKS);
+                load(client, &sql, &[&i64::from(id)])
+            })?
+            .pop()
+            .ok_or(RepositoryError::NotFound(id))?;
+        change(&mut book);
+        let row = Row::new(&book)?;
+        checkout.run(move |client| {
+            row.update(client)
+                .and_then(|_| client.batch_execute("COMMIT"))
+                .map_err(|err| write_failed(client, err, &row.isbn_key))
+        })?;
+        checkout.open = false;
+        Ok(book)
+    }
+
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError> {
+        self.checkout()?.run(move |client| {
+            in_transaction(client, "BEGIN", |client| {
+                let sql = format!("{} WHERE id = $1 FOR UPDATE", SELECT_BOOKS);
+                let book = load(client, &sql, &[&i64::from(id)])?
+                    .pop()
+                    .ok_or(RepositoryError::NotFound(id))?;
+                if book.version != version {
+                    return Err(RepositoryError::Changed {
+                        id,
+                        version: book.version,
+                    });
+                }
+                client
+                    .execute("DELETE FROM books WHERE id = $1", &[&i64::from(id)])
+                    .map_err(failed)?;
+                Ok(book)
+            })
+        })
+    }
+
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.list()?.into_iter().filter(|b| matches(b)).collect())
+    }
+
+    // The filter and the page are both part of the query, so only the page's rows are
+    // read; the count and the page come from the same snapshot
+    fn page(
+        &self,
+        filter: &BookFilter,
+        request: &PageRequest,
+    ) -> Result<BookPage, RepositoryError> {
+        let mut conditions = Conditions::from_filter(filter);
+        let count_sql = format!("SELECT COUNT(*) FROM books{}", conditions.where_clause());
+        let counted = conditions.params.len();
+        if let Some(after) = request.after {
+            conditions.add("id >", Box::new(i64::from(after)));
+        }
+        let limit = request.limit.map_or("ALL".to_string(), |limit| limit.to_string());
+        let page_sql = format!(
+            "{}{} ORDER BY id OFFSET {} LIMIT {}",
+            SELECT_BOOKS,
+            conditions.where_clause(),
+            request.offset,
+            limit
+        );
+        self.checkout()?.run(move |client| {
+            let values = conditions.values();
+            in_transaction(client, "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY", |client| {
+                let total: i64 = client
+                    .query_one(&count_sql, &values[..counted])
+                    .map_err(failed)?
+                    .get(0);
+                let books = load(client, &page_sql, &values)?;
+                Ok(BookPage {
+                    books,
+                    total: total as usize,
+                })
+            })
+        })
+    }
+
+    fn health(&self) -> Option<Result<(), RepositoryError>> {
+        let checked = self.checkout().and_then(|checkout| {
+            checkout.run(|client| client.batch_execute("SELECT 1").map_err(failed))
+        });
+        Some(checked)
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    fn compact(&self) -> Option<Result<Compaction, RepositoryError>> {
+        None
+    }
+
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError> {
+        let mut checkout = self.checkout()?;
+        checkout.open = true;
+        let books = checkout.run(|client| {
+            client
+                .batch_execute("BEGIN; LOCK TABLE books IN SHARE MODE")
+                .map_err(failed)?;
+            load(client, &format!("{} ORDER BY id", SELECT_BOOKS), &[])
+        })?;
+        read(&books);
+        checkout.run(|client| client.batch_execute("COMMIT").map_err(failed))?;
+        checkout.open = false;
+        Ok(())
+    }
+
+    // The closure changes a copy of the catalog, and only the rows that came out different
+    // are written back. Those are deleted before any is inserted again, so books that swap
+    // ISBNs between them don't trip the unique index halfway through.
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError> {
+        let mut checkout = self.checkout()?;
+        checkout.open = true;
+        let mut books = checkout.run(|client| {
+            client
+                .batch_execute("BEGIN; LOCK TABLE books IN EXCLUSIVE MODE")
+                .map_err(failed)?;
+            load(client, &format!("{} ORDER BY id", SELECT_BOOKS), &[])
+        })?;
+        let before = books
+            .iter()
+            .map(|b| encode(b).map(|json| (b.id, json)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        write(&mut books);
+        let mut changed = Vec::new();
+        let mut kept = HashSet::new();
+        for book in &books {
+            kept.insert(book.id);
+            let row = Row::new(book)?;
+            if before.get(&book.id) != Some(&row.json) {
+                changed.push(row);
+            }
+        }
+        let replaced: Vec<i64> = before
+            .keys()
+            .filter(|id| !kept.contains(id))
+            .map(|&id| i64::from(id))
+            .chain(changed.iter().map(|row| row.id))
+            .collect();
+        checkout.run(move |client| {
+            client
+                .execute("DELETE FROM books WHERE id = ANY($1)", &[&replaced])
+                .map_err(failed)?;
+            for row in &changed {
+                row.insert(client).map_err(|err| write_failed(client, err, &row.isbn_key))?;
+            }
+            client.batch_execute("COMMIT").map_err(failed)
+        })?;
+        checkout.open = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{Datelike, NaiveDate};
+use std::sync::Mutex;
+
+// Where the random book gets its randomness from, so tests can fix the sequence
+pub trait Rng: Send + Sync {
+    // A uniformly distributed number from 0 up to, but not including, `bound`, which must
+    // not be 0
+    fn below(&self, bound: usize) -> usize;
+}
+
+// SplitMix64: small, fast, and good enough for picking books; not for anything secret
+pub struct SplitMix {
+    state: Mutex<u64>,
+}
+
+impl SplitMix {
+    pub fn new(seed: u64) -> Self {
+        SplitMix {
+            state: Mutex::new(seed),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Rng for SplitMix {
+    fn below(&self, bound: usize) -> usize {
+        let bound = bound as u64;
+        // Draws past the last whole multiple of `bound` are retried, so every result is
+        // equally likely
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let draw = self.next();
+            if draw < zone {
+                return (draw % bound) as usize;
+            }
+        }
+    }
+}
+
+// The same index for everyone on a given day: the generator is seeded with the date alone
+pub fn pick_for_day(date: NaiveDate, bound: usize) -> usize {
+    SplitMix::new(date.num_days_from_ce() as u64).below(bound)
+}
+
+// `RANDOM_SEED=<u64>` gives the same sequence on every run; otherwise the seed comes from
+// the time of the call
+pub fn from_env() -> Box<dyn Rng> {
+    let seed = std::env::var("RANDOM_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+    Box::new(SplitMix::new(seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(rng: &SplitMix, bound: usize) -> Vec<usize> {
+        (0..20).map(|_| rng.below(bound)).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_sequence() {
+        let sequence = draws(&SplitMix::new(42), 1000);
+        assert_eq!(draws(&SplitMix::new(42), 1000), sequence);
+        assert_ne!(draws(&SplitMix::new(43), 1000), sequence);
+    }
+
+    #[test]
+    fn below_stays_under_its_bound() {
+        let rng = SplitMix::new(7);
+        assert!(draws(&rng, 1).iter().all(|&draw| draw == 0));
+        for bound in [2, 3, 10, usize::MAX] {
+            assert!(
+                draws(&rng, bound).iter().all(|&draw| draw < bound),
+                "{}",
+                bound
+            );
+        }
+    }
+
+    #[test]
+    fn below_is_even_across_its_range() {
+        let rng = SplitMix::new(1);
+        let mut counts = [0; 3];
+        for _ in 0..30_000 {
+            counts[rng.below(3)] += 1;
+        }
+        for count in counts {
+            assert!((9_500..10_500).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn a_day_always_gives_the_same_pick() {
+        let day = NaiveDate::from_ymd_opt(2026, 1, 15).unwrap();
+        let pick = pick_for_day(day, 10);
+        assert_eq!(pick_for_day(day, 10), pick);
+        let month: Vec<usize> = (1..=31)
+            .map(|d| pick_for_day(NaiveDate::from_ymd_opt(2026, 1, d).unwrap(), 10))
+            .collect();
+        assert!(month.iter().any(|&other| other != pick), "{:?}", month);
+    }
+}
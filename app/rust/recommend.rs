@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+
+// What a catalog book needs to be recommended and compared with others
+pub struct Candidate<'a> {
+    pub id: u32,
+    pub authors: &'a [String],
+    pub genre: Option<&'a str>,
+    // Whether it may be recommended; books that may not still count towards the
+    // member's authors and genres
+    pub eligible: bool,
+}
+
+// Why a book was recommended, strongest first. Books borrowed together with the member's
+// always rank above author and genre matches, which only fill up what's left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    BorrowedTogether,
+    SameAuthor,
+    SameGenre,
+    // All a member who has borrowed nothing yet gets
+    Popular,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Recommendation {
+    pub book_id: u32,
+    pub score: f64,
+    pub reason: Reason,
+}
+
+// Up to `limit` of the eligible `candidates` the member hasn't borrowed, best first.
+//
+// `borrows` is every (member, book) pair from the loan history; repeats count once.
+// A candidate borrowed together with the member's books is scored by item-based
+// collaborative filtering: for each of the member's books, the members who borrowed both
+// divided by the geometric mean of the two books' borrower counts (cosine similarity),
+// summed. Places left over go to books by the same authors (scored by the share of
+// the member's books with a shared author), then in the same genre (likewise), then to
+// the most-borrowed books, scored by their borrower counts. That last tier is all a
+// member with no borrows gets. Equal scores keep lower book ids first.
+pub fn recommend(
+    member_id: u32,
+    borrows: &[(u32, u32)],
+    candidates: &[Candidate],
+    limit: usize,
+) -> Vec<Recommendation> {
+    let mut borrowers: HashMap<u32, HashSet<u32>> = HashMap::new();
+    let mut borrowed: HashMap<u32, HashSet<u32>> = HashMap::new();
+    for &(member, book) in borrows {
+        borrowers.entry(book).or_default().insert(member);
+        borrowed.entry(member).or_default().insert(book);
+    }
+    let popularity = |book: u32| borrowers.get(&book).map_or(0, HashSet::len);
+    let none = HashSet::new();
+    let mine = borrowed.get(&member_id).unwrap_or(&none);
+    let unread: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|c| c.eligible && !mine.contains(&c.id))
+        .collect();
+
+    let mut picks = Vec::new();
+    for candidate in &unread {
+        let score: f64 = mine
+            .iter()
+            .map(|&book| {
+                let together = borrowers[&book]
+                    .iter()
+                    .filter(|m| borrowers.get(&candidate.id).is_some_and(|b| b.contains(m)))
+                    .count();
+                if together == 0 {
+                    return 0.0;
+                }
+                together as f64 / ((popularity(book) * popularity(candidate.id)) as f64).sqrt()
+            })
+            .sum();
+        if score > 0.0 {
+            picks.push(Recommendation {
+                book_id: candidate.id,
+                score,
+                reason: Reason::BorrowedTogether,
+            });
+        }
+    }
+
+    // Authors and genres of the member's books that are still in the catalog
+    let profile: Vec<&Candidate> = candidates.iter().filter(|c| mine.contains(&c.id)).collect();
+    let share = |matches: &dyn Fn(&Candidate) -> bool| {
+        profile.iter().filter(|c| matches(c)).count() as f64 / profile.len().max(1) as f64
+    };
+    for candidate in &unread {
+        if picks.iter().any(|p| p.book_id == candidate.id) {
+            continue;
+        }
+        let same_author =
+            share(&|c: &Candidate| c.authors.iter().any(|a| candidate.authors.contains(a)));
+        let same_genre =
+            share(&|c: &Candidate| c.genre.is_some() && c.genre == candidate.genre);
+        if same_author > 0.0 {
+            picks.push(Recommendation {
+                book_id: candidate.id,
+                score: same_author,
+                reason: Reason::SameAuthor,
+            });
+        } else if same_genre > 0.0 {
+            picks.push(Recommendation {
+                book_id: candidate.id,
+                score: same_genre,
+                reason: Reason::SameGenre,
+            });
+        } else if popularity(candidate.id) > 0 {
+            picks.push(Recommendation {
+                book_id: candidate.id,
+                score: popularity(candidate.id) as f64,
+                reason: Reason::Popular,
+            });
+        }
+    }
+    ranked(picks, limit)
+}
+
+fn ranked(mut picks: Vec<Recommendation>, limit: usize) -> Vec<Recommendation> {
+    picks.sort_by(|a, b| {
+        a.reason
+            .cmp(&b.reason)
+            .then(b.score.total_cmp(&a.score))
+            .then(a.book_id.cmp(&b.book_id))
+    });
+    picks.truncate(limit);
+    picks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate<'a>(id: u32, authors: &'a [String], genre: Option<&'a str>) -> Candidate<'a> {
+        Candidate {
+            id,
+            authors,
+            genre,
+            eligible: true,
+        }
+    }
+
+    fn picks(recommendations: &[Recommendation]) -> Vec<(u32, f64, Reason)> {
+        recommendations.iter().map(|r| (r.book_id, r.score, r.reason)).collect()
+    }
+
+    #[test]
+    fn books_borrowed_together_score_by_cosine_similarity() {
+        // Member 2 borrowed book 1 and book 2; member 3, book 2 and book 3
+        let borrows = [(1, 1), (2, 1), (2, 2), (3, 2), (3, 3)];
+        let none = Vec::new();
+        let candidates: Vec<Candidate> = (1..=3).map(|id| candidate(id, &none, None)).collect();
+        let recommended = recommend(1, &borrows, &candidates, 10);
+        // Book 2 shares one borrower with book 1, out of two each: 1 / sqrt(2 * 2). Book 3
+        // shares none, and only fills up as a popular book.
+        assert_eq!(
+            picks(&recommended),
+            [(2, 0.5, Reason::BorrowedTogether), (3, 1.0, Reason::Popular)]
+        );
+    }
+
+    #[test]
+    fn author_and_genre_matches_score_by_their_share_of_the_members_books() {
+        let (a, c, d) = (vec!["A".to_string()], vec!["C".to_string()], vec!["D".to_string()]);
+        let candidates = [
+            candidate(1, &a, Some("SF")),
+            candidate(2, &c, Some("SF")),
+            candidate(3, &a, None),
+            candidate(4, &d, Some("SF")),
+            candidate(5, &d, None),
+        ];
+        let recommended = recommend(1, &[(1, 1), (1, 2)], &candidates, 10);
+        // Book 3 shares an author with one of the two books, book 4 a genre with both; an
+        // author match still ranks first. Book 5 matches nothing and no one borrowed it.
+        assert_eq!(
+            picks(&recommended),
+            [(3, 0.5, Reason::SameAuthor), (4, 1.0, Reason::SameGenre)]
+        );
+    }
+
+    #[test]
+    fn a_member_who_borrowed_nothing_gets_the_most_borrowed_books() {
+        let none = Vec::new();
+        let candidates: Vec<Candidate> = (1..=4).map(|id| candidate(id, &none, None)).collect();
+        // Repeats of the same member and book count once
+        let borrows = [(2, 3), (2, 3), (3, 3), (2, 1), (3, 2)];
+        let recommended = recommend(1, &borrows, &candidates, 2);
+        assert_eq!(picks(&recommended), [(3, 2.0, Reason::Popular), (1, 1.0, Reason::Popular)]);
+    }
+
+    #[test]
+    fn an_ineligible_book_is_never_recommended_but_still_counts_for_the_profile() {
+        let none = Vec::new();
+        let mut gone = candidate(1, &none, Some("SF"));
+        gone.eligible = false;
+        let candidates = [gone, candidate(2, &none, Some("SF"))];
+        let recommended = recommend(1, &[(1, 1), (2, 1)], &candidates, 10);
+        assert_eq!(picks(&recommended), [(2, 1.0, Reason::SameGenre)]);
+        assert!(recommend(2, &[(1, 1), (2, 1)], &candidates[..1], 10).is_empty());
+    }
+}
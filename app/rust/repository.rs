@@ -0,0 +1,293 @@
+use crate::{isbn, Book};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RepositoryError {
+    NotFound(u32),
+    IsbnNotFound(String),
+    // Another book already has the ISBN, or an equivalent form of it
+    DuplicateIsbn { isbn: String, existing_id: u32 },
+    // A conditional change found a newer version of the book than the one expected
+    Changed { id: u32, version: u64 },
+    // The store itself failed; the cause is for the log, not for clients
+    Unavailable(String),
+}
+
+impl std::fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepositoryError::NotFound(id) => write!(f, "no book with id {}", id),
+            RepositoryError::IsbnNotFound(isbn) => write!(f, "no book with ISBN {}", isbn),
+            RepositoryError::DuplicateIsbn { isbn, existing_id } => {
+                write!(f, "ISBN {} already belongs to book {}", isbn, existing_id)
+            }
+            RepositoryError::Changed { id, version } => {
+                write!(f, "book {} is now at version {}", id, version)
+            }
+            RepositoryError::Unavailable(cause) => f.write_str(cause),
+        }
+    }
+}
+
+// Conditions a store can check in its own query instead of handing back every book for
+// the caller to filter; None leaves a field free. As in search, a bound on the year or the
+// page count excludes books without one.
+#[derive(Default)]
+pub struct BookFilter {
+    pub available: Option<bool>,
+    pub year_from: Option<u16>,
+    pub year_to: Option<u16>,
+    pub min_pages: Option<u32>,
+    pub max_pages: Option<u32>,
+    pub language: Option<String>,
+    pub shelf: Option<String>,
+    pub branch: Option<u32>,
+}
+
+impl BookFilter {
+    pub fn matches(&self, book: &Book) -> bool {
+        let in_range = |value: Option<u32>, from: Option<u32>, to: Option<u32>| {
+            (from.is_none() && to.is_none())
+                || value.is_some_and(|value| {
+                    from.is_none_or(|from| value >= from) && to.is_none_or(|to| value <= to)
+                })
+        };
+        self.available.is_none_or(|available| book.available == available)
+            && in_range(
+                book.published_year.map(u32::from),
+                self.year_from.map(u32::from),
+                self.year_to.map(u32::from),
+            )
+            && in_range(book.page_count, self.min_pages, self.max_pages)
+            && (self.language.is_none() || book.language == self.language)
+            && (self.shelf.is_none() || book.shelf_location == self.shelf)
+            && self.branch.is_none_or(|branch| book.branch_id == Some(branch))
+    }
+}
+
+// Which of the matching books, in id order, to return: those with an id past `after`,
+// less the first `offset`, up to `limit`. The default is all of them.
+#[derive(Default)]
+pub struct PageRequest {
+    pub after: Option<u32>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+pub struct BookPage {
+    pub books: Vec<Book>,
+    // Every book the filter accepts, whatever the page
+    pub total: usize,
+}
+
+// `page` for a store that holds the books itself
+pub fn page_of(books: Vec<Book>, filter: &BookFilter, request: &PageRequest) -> BookPage {
+    let matching: Vec<Book> = books.into_iter().filter(|b| filter.matches(b)).collect();
+    let total = matching.len();
+    let books = matching
+        .into_iter()
+        .filter(|b| request.after.is_none_or(|after| b.id > after))
+        .skip(request.offset)
+        .take(request.limit.unwrap_or(usize::MAX))
+        .collect();
+    BookPage { books, total }
+}
+
+// What compacting a store's log did, counted in entries and bytes of the log
+pub struct Compaction {
+    pub entries_before: u64,
+    pub entries_after: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+// Where the catalog lives. Handlers go through this and nothing else, so the storage can
+// change without them noticing, and tests can put in one that fails on demand.
+//
+// The single-book calls are atomic on their own. Work that has to see or change several
+// books at once, or keep other state in step with the catalog, goes through `read_all` and
+// `write_all`, which run the closure with every other caller kept out. Those closures must
+// not call back into the repository.
+pub trait BookRepository: Send + Sync {
+    // Every book, in id order
+    fn list(&self) -> Result<Vec<Book>, RepositoryError>;
+    fn get(&self, id: u32) -> Result<Book, RepositoryError>;
+    // `isbn` is normalized; either form finds the book, whichever one it was stored under
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError>;
+    // Adds a book that already has its id, refusing one whose ISBN is taken
+    fn insert(&self, book: Book) -> Result<(), RepositoryError>;
+    // Applies `change` to the book and returns it as changed
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError>;
+    // Removes the book, but only while it is still at `version`
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError>;
+    // The books `matches` accepts, in id order
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError>;
+    // The books `filter` accepts, in id order, cut down to the requested page
+    fn page(&self, filter: &BookFilter, request: &PageRequest) -> Result<BookPage, RepositoryError>;
+    // Whether the store can be reached, for the health check; None for one in this process
+    fn health(&self) -> Option<Result<(), RepositoryError>>;
+    // The number of the last change recorded, for a store that numbers its changes
+    fn sequence(&self) -> Option<u64>;
+    // Rewrites the store's log down to the books it holds, for a store that keeps one
+    fn compact(&self) -> Option<Result<Compaction, RepositoryError>>;
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError>;
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError>;
+}
+
+impl dyn BookRepository {
+    // `read_all` for a closure that returns something
+    pub fn read_with<T>(&self, read: impl FnOnce(&[Book]) -> T) -> Result<T, RepositoryError> {
+        let mut read = Some(read);
+        let mut result = None;
+        self.read_all(&mut |books| result = read.take().map(|read| read(books)))?;
+        Ok(result.expect("read_all runs its closure once"))
+    }
+
+    // `write_all` for a closure that returns something
+    pub fn write_with<T>(
+        &self,
+        write: impl FnOnce(&mut Vec<Book>) -> T,
+    ) -> Result<T, RepositoryError> {
+        let mut write = Some(write);
+        let mut result = None;
+        self.write_all(&mut |books| result = write.take().map(|write| write(books)))?;
+        Ok(result.expect("write_all runs its closure once"))
+    }
+}
+
+// The steps of the single-book calls for a store that keeps the books in a vector in id
+// order, so that one wrapping such a vector can put its own work between them
+pub fn position(books: &[Book], id: u32) -> Result<usize, RepositoryError> {
+    books
+        .iter()
+        .position(|b| b.id == id)
+        .ok_or(RepositoryError::NotFound(id))
+}
+
+pub fn check_isbn(books: &[Book], isbn: &str) -> Result<(), RepositoryError> {
+    match books.iter().find(|b| isbn::equivalent(&b.isbn, isbn)) {
+        Some(holder) => Err(RepositoryError::DuplicateIsbn {
+            isbn: holder.isbn.clone(),
+            existing_id: holder.id,
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn check_version(book: &Book, version: u64) -> Result<(), RepositoryError> {
+    if book.version != version {
+        return Err(RepositoryError::Changed {
+            id: book.id,
+            version: book.version,
+        });
+    }
+    Ok(())
+}
+
+pub fn insert_sorted(books: &mut Vec<Book>, book: Book) {
+    let index = books.partition_point(|b| b.id < book.id);
+    books.insert(index, book);
+}
+
+// The catalog as a vector behind one lock, kept in id order
+pub struct InMemoryBooks {
+    books: Mutex<Vec<Book>>,
+}
+
+impl InMemoryBooks {
+    pub fn new(mut books: Vec<Book>) -> Self {
+        books.sort_by_key(|b| b.id);
+        InMemoryBooks {
+            books: Mutex::new(books),
+        }
+    }
+
+    // A handler that panicked with the lock held may have left the catalog half changed,
+    // so every later call fails instead of trusting it
+    fn lock(&self) -> Result<MutexGuard<'_, Vec<Book>>, RepositoryError> {
+        self.books.lock().map_err(|_| {
+            RepositoryError::Unavailable(
+                "a request panicked while changing the catalog".to_string(),
+            )
+        })
+    }
+}
+
+impl BookRepository for InMemoryBooks {
+    fn list(&self) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.lock()?.clone())
+    }
+
+    fn get(&self, id: u32) -> Result<Book, RepositoryError> {
+        self.lock()?
+            .iter()
+            .find(|b| b.id == id)
+            .cloned()
+            .ok_or(RepositoryError::NotFound(id))
+    }
+
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError> {
+        self.lock()?
+            .iter()
+            .find(|b| isbn::equivalent(&b.isbn, isbn))
+            .cloned()
+            .ok_or_else(|| RepositoryError::IsbnNotFound(isbn.to_string()))
+    }
+
+    fn insert(&self, book: Book) -> Result<(), RepositoryError> {
+        let mut books = self.lock()?;
+        check_isbn(&books, &book.isbn)?;
+        insert_sorted(&mut books, book);
+        Ok(())
+    }
+
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError> {
+        let mut books = self.lock()?;
+        let index = position(&books, id)?;
+        change(&mut books[index]);
+        Ok(books[index].clone())
+    }
+
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError> {
+        let mut books = self.lock()?;
+        let index = position(&books, id)?;
+        check_version(&books[index], version)?;
+        Ok(books.remove(index))
+    }
+
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.lock()?.iter().filter(|b| matches(b)).cloned().collect())
+    }
+
+    fn page(
+        &self,
+        filter: &BookFilter,
+        request: &PageRequest,
+    ) -> Result<BookPage, RepositoryError> {
+        let books = self.lock()?.iter().filter(|b| filter.matches(b)).cloned().collect();
+        Ok(page_of(books, &BookFilter::default(), request))
+    }
+
+    fn health(&self) -> Option<Result<(), RepositoryError>> {
+        None
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    fn compact(&self) -> Option<Result<Compaction, RepositoryError>> {
+        None
+    }
+
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError> {
+        read(&self.lock()?);
+        Ok(())
+    }
+
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError> {
+        let mut books = self.lock()?;
+        write(&mut books);
+        Ok(())
+    }
+}
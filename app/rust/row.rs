@@ -0,0 +1,66 @@
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::repository::RepositoryError;
+use crate::Book;
+use serde::{Deserialize, Serialize};
+
+// A book as the stores that write it out keep it: its JSON, including the fields the
+// public form leaves out
+#[derive(Serialize)]
+pub struct StoredRow<'a> {
+    #[serde(flatten)]
+    book: &'a Book,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: &'a Option<String>,
+    holds_reserve: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reviews_changed_at: &'a Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct LoadedRow {
+    #[serde(flatten)]
+    book: Book,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    holds_reserve: bool,
+    #[serde(default)]
+    reviews_changed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl<'a> StoredRow<'a> {
+    pub fn new(book: &'a Book) -> Self {
+        StoredRow {
+            book,
+            notes: &book.notes,
+            holds_reserve: book.holds_reserve,
+            reviews_changed_at: &book.reviews_changed_at,
+        }
+    }
+}
+
+impl LoadedRow {
+    pub fn into_book(self) -> Book {
+        Book {
+            notes: self.notes,
+            holds_reserve: self.holds_reserve,
+            reviews_changed_at: self.reviews_changed_at,
+            ..self.book
+        }
+    }
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub fn encode(book: &Book) -> Result<String, RepositoryError> {
+    serde_json::to_string(&StoredRow::new(book)).map_err(|err| {
+        RepositoryError::Unavailable(format!("could not serialize book {}: {}", book.id, err))
+    })
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub fn decode(json: &str) -> Result<Book, RepositoryError> {
+    let row: LoadedRow = serde_json::from_str(json).map_err(|err| {
+        RepositoryError::Unavailable(format!("a row of the books table is not a book: {}", err))
+    })?;
+    Ok(row.into_book())
+}
@@ -0,0 +1,302 @@
+// Levenshtein edit distance over Unicode scalar values
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    
+    previous[b.len()]
+}
+
+// Similarity in [0, 1] where 1 means identical
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(a, b) as f64 / longest as f64
+}
+
+// Share of a fuzzy score that always comes from whole-string similarity. The per-word
+// average ties whenever a title only adds or reorders words around the query's, so this
+// ranks "Programming Rust" above "The Rust Programming Language" for "Progamming Rust".
+const WHOLE_TITLE_WEIGHT: f64 = 0.01;
+
+// Scores how closely a title matches a possibly misspelled query.
+// A case-insensitive substring match scores 1.0; anything else scores the better of
+// whole-string similarity and the average best per-word similarity, with a little of the
+// whole-string similarity mixed in to break ties, capped below 1.0 so exact matches
+// always rank first.
+pub fn fuzzy_score(title: &str, query: &str) -> f64 {
+    let title = title.to_lowercase();
+    let query = query.to_lowercase();
+    if title.contains(&query) {
+        return 1.0;
+    }
+    
+    let title_words: Vec<&str> = title.split_whitespace().collect();
+    let query_words: Vec<&str> = query.split_whitespace().collect();
+    let word_score = if query_words.is_empty() || title_words.is_empty() {
+        0.0
+    } else {
+        query_words
+            .iter()
+            .map(|q| {
+                title_words
+                    .iter()
+                    .map(|t| similarity(q, t))
+                    .fold(0.0, f64::max)
+            })
+            .sum::<f64>()
+            / query_words.len() as f64
+    };
+    
+    let whole = similarity(&title, &query);
+    let best = whole.max(word_score);
+    (best * (1.0 - WHOLE_TITLE_WEIGHT) + whole * WHOLE_TITLE_WEIGHT).min(0.99)
+}
+
+const PREFIX_MULTIPLIER: f64 = 1.5;
+
+// True when `token` occurs in `text` at the start of a word
+fn matches_word_prefix(text: &str, token: &str) -> bool {
+    text.match_indices(token).any(|(index, _)| {
+        text[..index]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !c.is_alphanumeric())
+    })
+}
+
+// Relevance of a full-text match. `fields` pairs each lowercased field value with its
+// weight, and every (token, field) hit adds the field weight, multiplied when the token
+// starts a word. Weights are chosen so a mid-word title hit still beats a prefix hit on a
+// lower-weighted field, and more hits always add up to a higher score.
+pub fn relevance_score(fields: &[(&str, f64)], tokens: &[String]) -> f64 {
+    tokens
+        .iter()
+        .map(|token| {
+            fields
+                .iter()
+                .filter(|(text, _)| text.contains(token.as_str()))
+                .map(|(text, weight)| {
+                    if matches_word_prefix(text, token) {
+                        weight * PREFIX_MULTIPLIER
+                    } else {
+                        *weight
+                    }
+                })
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionKind {
+    Title,
+    Author,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Suggestion {
+    pub value: String,
+    pub kind: SuggestionKind,
+}
+
+// Distinct suggestion strings sorted by their lowercased form, so a prefix lookup is a
+// binary search followed by a short scan instead of a pass over the whole catalog
+pub struct SuggestionIndex {
+    entries: Vec<(String, Suggestion)>,
+}
+
+impl SuggestionIndex {
+    pub fn build<'a>(values: impl Iterator<Item = (&'a str, SuggestionKind)>) -> Self {
+        let mut entries: Vec<(String, Suggestion)> = values
+            .map(|(value, kind)| {
+                (
+                    value.to_lowercase(),
+                    Suggestion {
+                        value: value.to_string(),
+                        kind,
+                    },
+                )
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then(a.1.kind.cmp(&b.1.kind))
+                .then(a.1.value.cmp(&b.1.value))
+        });
+        entries.dedup_by(|a, b| a.1.kind == b.1.kind && a.1.value == b.1.value);
+        SuggestionIndex { entries }
+    }
+    
+    pub fn lookup(&self, prefix: &str, limit: usize) -> Vec<Suggestion> {
+        let prefix = prefix.to_lowercase();
+        let start = self
+            .entries
+            .partition_point(|(key, _)| key.as_str() < prefix.as_str());
+        self.entries[start..]
+            .iter()
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .take(limit)
+            .map(|(_, suggestion)| suggestion.clone())
+            .collect()
+    }
+}
+
+// Byte ranges in `text` where `needle` occurs, compared case-insensitively.
+// Lowercasing can change a character's UTF-8 length (e.g. 'İ'), so matching runs on the
+// lowercased text and each hit is mapped back to the original character boundaries.
+pub fn find_ranges_ci(text: &str, needle: &str) -> Vec<(usize, usize)> {
+    let needle = needle.to_lowercase();
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    
+    let mut lowered = String::new();
+    // (offset in lowered text, original start, original end) per original character
+    let mut spans: Vec<(usize, usize, usize)> = Vec::new();
+    for (start, c) in text.char_indices() {
+        spans.push((lowered.len(), start, start + c.len_utf8()));
+        lowered.extend(c.to_lowercase());
+    }
+    
+    // Index of the original character whose lowered form contains lowered offset `pos`
+    let char_at = |pos: usize| spans.partition_point(|(lowered_start, _, _)| *lowered_start <= pos) - 1;
+    
+    lowered
+        .match_indices(needle.as_str())
+        .map(|(pos, matched)| {
+            let first = char_at(pos);
+            let last = char_at(pos + matched.len() - 1);
+            (spans[first].1, spans[last].2)
+        })
+        .collect()
+}
+
+// Sorts ranges and merges any that overlap or touch
+pub fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+#[derive(serde::Serialize)]
+pub struct Segment {
+    pub text: String,
+    #[serde(rename = "match")]
+    pub matched: bool,
+}
+
+// Splits `text` into alternating unmatched/matched segments; ranges must be merged and
+// fall on character boundaries
+pub fn segments(text: &str, ranges: &[(usize, usize)]) -> Vec<Segment> {
+    let mut result = Vec::new();
+    let mut cursor = 0;
+    for &(start, end) in ranges {
+        if start > cursor {
+            result.push(Segment {
+                text: text[cursor..start].to_string(),
+                matched: false,
+            });
+        }
+        result.push(Segment {
+            text: text[start..end].to_string(),
+            matched: true,
+        });
+        cursor = end;
+    }
+    if cursor < text.len() {
+        result.push(Segment {
+            text: text[cursor..].to_string(),
+            matched: false,
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AUTHOR_WEIGHT, DESCRIPTION_WEIGHT, TITLE_WEIGHT};
+
+    fn tokens(query: &str) -> Vec<String> {
+        query.split_whitespace().map(str::to_string).collect()
+    }
+
+    // The score of a book whose title, author line, and description are given, as the
+    // search handler weighs them
+    fn score(title: &str, author: &str, description: &str, query: &str) -> f64 {
+        let fields = [
+            (title, TITLE_WEIGHT),
+            (author, AUTHOR_WEIGHT),
+            (description, DESCRIPTION_WEIGHT),
+        ];
+        relevance_score(&fields, &tokens(query))
+    }
+
+    #[test]
+    fn a_title_hit_outranks_an_author_hit_which_outranks_a_description_hit() {
+        let in_title = score("dune", "frank herbert", "a desert planet", "dune");
+        let in_author = score("children", "dune smith", "a desert planet", "dune");
+        let in_description = score("children", "frank herbert", "sequel to dune", "dune");
+        assert!(in_title > in_author, "{} <= {}", in_title, in_author);
+        assert!(in_author > in_description, "{} <= {}", in_author, in_description);
+        assert_eq!(score("children", "frank herbert", "a desert planet", "dune"), 0.0);
+    }
+
+    #[test]
+    fn a_mid_word_title_hit_still_outranks_a_word_start_elsewhere() {
+        let mid_word_title = score("the redune saga", "", "", "dune");
+        let word_start_author = score("", "dune smith", "", "dune");
+        assert_eq!(mid_word_title, TITLE_WEIGHT);
+        assert_eq!(word_start_author, AUTHOR_WEIGHT * PREFIX_MULTIPLIER);
+        assert!(mid_word_title > word_start_author);
+    }
+
+    #[test]
+    fn every_hit_adds_to_the_score() {
+        let one = score("dune", "", "", "dune");
+        let two_fields = score("dune", "", "dune again", "dune");
+        let two_tokens = score("dune messiah", "", "", "dune messiah");
+        assert!(two_fields > one);
+        assert_eq!(two_tokens, 2.0 * one);
+    }
+
+    #[test]
+    fn a_fuzzy_tie_on_words_goes_to_the_closer_whole_title() {
+        let closer = fuzzy_score("Programming Rust", "Progamming Rust");
+        let longer = fuzzy_score("The Rust Programming Language", "Progamming Rust");
+        assert!(closer > longer, "{} <= {}", closer, longer);
+        let reordered = fuzzy_score("Rust Programming", "Progamming Rust");
+        assert!(closer > reordered, "{} <= {}", closer, reordered);
+        assert!(closer < 1.0);
+        assert_eq!(fuzzy_score("Programming Rust", "programming"), 1.0);
+    }
+
+    #[test]
+    fn a_word_start_follows_anything_but_a_letter_or_digit() {
+        assert!(matches_word_prefix("dune", "dune"));
+        assert!(matches_word_prefix("herbert, dune-like", "like"));
+        assert!(matches_word_prefix("redune (dune)", "dune"));
+        assert!(!matches_word_prefix("redune", "dune"));
+        assert!(!matches_word_prefix("2dune", "dune"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+
+// How much each kind of overlap counts towards `Scores::total`; they add up to 1
+const AUTHOR_WEIGHT: f64 = 0.5;
+const SUBJECT_WEIGHT: f64 = 0.3;
+const TITLE_WEIGHT: f64 = 0.2;
+
+// Title words too common to make two books alike
+const STOPWORDS: &[&str] = &["a", "an", "and", "for", "in", "of", "on", "the", "to", "with"];
+
+// The parts of a book that similarity looks at
+pub struct BookText<'a> {
+    pub id: u32,
+    pub title: &'a str,
+    pub authors: &'a [String],
+    pub genre: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+// Lowercased sets, worked out once per book when the index is built
+struct Profile {
+    id: u32,
+    authors: HashSet<String>,
+    // Genre and tags together
+    subjects: HashSet<String>,
+    title_tokens: HashSet<String>,
+}
+
+// Each component is the Jaccard similarity of the two books' sets, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Scores {
+    pub author: f64,
+    pub subject: f64,
+    pub title: f64,
+    // Weighted sum of the three
+    pub total: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Similar {
+    pub book_id: u32,
+    pub scores: Scores,
+}
+
+pub struct Index {
+    profiles: Vec<Profile>,
+}
+
+impl Index {
+    pub fn build<'a>(books: impl Iterator<Item = BookText<'a>>) -> Self {
+        let profiles = books
+            .map(|book| Profile {
+                id: book.id,
+                authors: lowercase_set(book.authors.iter().map(String::as_str)),
+                subjects: lowercase_set(
+                    book.genre
+                        .into_iter()
+                        .chain(book.tags.iter().map(String::as_str)),
+                ),
+                title_tokens: book
+                    .title
+                    .to_lowercase()
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|token| !token.is_empty() && !STOPWORDS.contains(token))
+                    .map(str::to_string)
+                    .collect(),
+            })
+            .collect();
+        Index { profiles }
+    }
+
+    // Up to `limit` other books with anything in common with `book_id`, most similar
+    // first, ties in id order. None when the book isn't in the index.
+    pub fn similar(&self, book_id: u32, limit: usize) -> Option<Vec<Similar>> {
+        let book = self.profiles.iter().find(|p| p.id == book_id)?;
+        let mut similar: Vec<Similar> = self
+            .profiles
+            .iter()
+            .filter(|other| other.id != book_id)
+            .map(|other| {
+                let author = jaccard(&book.authors, &other.authors);
+                let subject = jaccard(&book.subjects, &other.subjects);
+                let title = jaccard(&book.title_tokens, &other.title_tokens);
+                Similar {
+                    book_id: other.id,
+                    scores: Scores {
+                        author,
+                        subject,
+                        title,
+                        total: AUTHOR_WEIGHT * author
+                            + SUBJECT_WEIGHT * subject
+                            + TITLE_WEIGHT * title,
+                    },
+                }
+            })
+            .filter(|s| s.scores.total > 0.0)
+            .collect();
+        similar.sort_by(|a, b| {
+            b.scores
+                .total
+                .total_cmp(&a.scores.total)
+                .then(a.book_id.cmp(&b.book_id))
+        });
+        similar.truncate(limit);
+        Some(similar)
+    }
+}
+
+fn lowercase_set<'a>(values: impl Iterator<Item = &'a str>) -> HashSet<String> {
+    values.map(str::to_lowercase).collect()
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let shared = a.intersection(b).count();
+    if shared == 0 {
+        return 0.0;
+    }
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
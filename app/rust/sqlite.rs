@@ -0,0 +1,268 @@
+use crate::repository::{
+    self, BookFilter, BookPage, BookRepository, Compaction, PageRequest, RepositoryError,
+};
+use crate::row::{decode, encode};
+use crate::{isbn, Book};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+// One row per book: the whole book as JSON, with the id and the ISBN pulled out for the
+// primary key and the unique index. `isbn_key` is the ISBN-13 form when the ISBN is
+// valid, so the index also refuses an ISBN-10 and the ISBN-13 of the same book.
+const SCHEMA: &str = "
+    CREATE TABLE books (
+        id INTEGER PRIMARY KEY,
+        isbn_key TEXT NOT NULL,
+        book TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX books_isbn_key ON books (isbn_key);
+";
+
+fn failed(err: rusqlite::Error) -> RepositoryError {
+    RepositoryError::Unavailable(format!("SQLite: {}", err))
+}
+
+fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(cause, _)
+            if cause.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+// Every row, in id order, with the JSON as stored next to the book read from it
+fn load_rows(conn: &Connection) -> Result<Vec<(Book, String)>, RepositoryError> {
+    let mut statement = conn.prepare("SELECT book FROM books ORDER BY id").map_err(failed)?;
+    let rows = statement
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(failed)?;
+    let mut loaded = Vec::new();
+    for json in rows {
+        let json = json.map_err(failed)?;
+        loaded.push((decode(&json)?, json));
+    }
+    Ok(loaded)
+}
+
+fn load_one(
+    conn: &Connection,
+    column: &str,
+    value: &dyn rusqlite::ToSql,
+) -> Result<Option<Book>, RepositoryError> {
+    let sql = format!("SELECT book FROM books WHERE {} = ?1", column);
+    let json: Option<String> = conn
+        .query_row(&sql, [value], |row| row.get(0))
+        .optional()
+        .map_err(failed)?;
+    json.map(|json| decode(&json)).transpose()
+}
+
+fn insert_row(conn: &Connection, book: &Book, json: &str) -> Result<(), RepositoryError> {
+    let key = isbn::canonical(&book.isbn);
+    match conn.execute(
+        "INSERT INTO books (id, isbn_key, book) VALUES (?1, ?2, ?3)",
+        params![book.id, key, json],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) if is_unique_violation(&err) => Err(duplicate(conn, &key)),
+        Err(err) => Err(failed(err)),
+    }
+}
+
+// The unique index turned a write down; the error names the book that holds the ISBN, as
+// the in-memory store's does. The id can't be the clash here: ids come from the server's
+// own counter, so a primary key violation stays a plain failure.
+fn duplicate(conn: &Connection, key: &str) -> RepositoryError {
+    match load_one(conn, "isbn_key", &key) {
+        Ok(Some(holder)) => RepositoryError::DuplicateIsbn {
+            isbn: holder.isbn,
+            existing_id: holder.id,
+        },
+        Ok(None) => RepositoryError::Unavailable(format!(
+            "the ISBN index refused {} but no book holds it",
+            key
+        )),
+        Err(err) => err,
+    }
+}
+
+// The catalog in an SQLite database. One connection behind a lock serves every call,
+// which keeps the in-memory store's semantics: each call runs alone, and `write_all`
+// sees and replaces the whole catalog in one transaction.
+pub struct SqliteBooks {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBooks {
+    // Opens the database at `path`, creating it and the books table on the first run and
+    // filling the table with `seed`. A table that already exists is used as it is.
+    pub fn open(path: &str, seed: Vec<Book>) -> Result<Self, String> {
+        let opened =
+            |err: rusqlite::Error| format!("could not open the database {}: {}", path, err);
+        let mut conn = Connection::open(path).map_err(opened)?;
+        let tx = conn.transaction().map_err(opened)?;
+        let exists: bool = tx
+            .query_row(
+                "SELECT EXISTS \
+                 (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'books')",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(opened)?;
+        if !exists {
+            tx.execute_batch(SCHEMA)
+                .map_err(|err| format!("could not create the books table in {}: {}", path, err))?;
+            for book in &seed {
+                encode(book)
+                    .and_then(|json| insert_row(&tx, book, &json))
+                    .map_err(|err| format!("could not add the seed books to {}: {}", path, err))?;
+            }
+        }
+        tx.commit().map_err(opened)?;
+        // Fail now rather than on the first request if the table holds rows that can't be read
+        load_rows(&conn).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(SqliteBooks {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // As with the in-memory store, a panic with the lock held poisons it for good; the
+    // transaction it was in is rolled back, but the connection isn't trusted again
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>, RepositoryError> {
+        self.conn.lock().map_err(|_| {
+            RepositoryError::Unavailable(
+                "a request panicked while changing the catalog".to_string(),
+            )
+        })
+    }
+}
+
+impl BookRepository for SqliteBooks {
+    fn list(&self) -> Result<Vec<Book>, RepositoryError> {
+        let conn = self.lock()?;
+        Ok(load_rows(&conn)?.into_iter().map(|(book, _)| book).collect())
+    }
+
+    fn get(&self, id: u32) -> Result<Book, RepositoryError> {
+        let conn = self.lock()?;
+        load_one(&conn, "id", &id)?.ok_or(RepositoryError::NotFound(id))
+    }
+
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError> {
+        let conn = self.lock()?;
+        load_one(&conn, "isbn_key", &isbn::canonical(isbn))?
+            .ok_or_else(|| RepositoryError::IsbnNotFound(isbn.to_string()))
+    }
+
+    fn insert(&self, book: Book) -> Result<(), RepositoryError> {
+        let json = encode(&book)?;
+        let conn = self.lock()?;
+        insert_row(&conn, &book, &json)
+    }
+
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(failed)?;
+        let mut book = load_one(&tx, "id", &id)?.ok_or(RepositoryError::NotFound(id))?;
+        change(&mut book);
+        let key = isbn::canonical(&book.isbn);
+        match tx.execute(
+            "UPDATE books SET isbn_key = ?2, book = ?3 WHERE id = ?1",
+            params![id, key, encode(&book)?],
+        ) {
+            Ok(_) => {}
+            Err(err) if is_unique_violation(&err) => return Err(duplicate(&tx, &key)),
+            Err(err) => return Err(failed(err)),
+        }
+        tx.commit().map_err(failed)?;
+        Ok(book)
+    }
+
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(failed)?;
+        let book = load_one(&tx, "id", &id)?.ok_or(RepositoryError::NotFound(id))?;
+        if book.version != version {
+            return Err(RepositoryError::Changed {
+                id,
+                version: book.version,
+            });
+        }
+        tx.execute("DELETE FROM books WHERE id = ?1", [id]).map_err(failed)?;
+        tx.commit().map_err(failed)?;
+        Ok(book)
+    }
+
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError> {
+        Ok(self.list()?.into_iter().filter(|b| matches(b)).collect())
+    }
+
+    // The rows hold only JSON, so the filter runs here rather than in the query
+    fn page(
+        &self,
+        filter: &BookFilter,
+        request: &PageRequest,
+    ) -> Result<BookPage, RepositoryError> {
+        Ok(repository::page_of(self.list()?, filter, request))
+    }
+
+    fn health(&self) -> Option<Result<(), RepositoryError>> {
+        let checked = self.lock().and_then(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(())).map_err(failed)
+        });
+        Some(checked)
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    fn compact(&self) -> Option<Result<Compaction, RepositoryError>> {
+        None
+    }
+
+    // The lock is held until the closure returns, so no write can land between reading
+    // the rows and the closure seeing them
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(failed)?;
+        let books: Vec<Book> = load_rows(&tx)?.into_iter().map(|(book, _)| book).collect();
+        read(&books);
+        tx.commit().map_err(failed)
+    }
+
+    // The closure changes a copy of the catalog, and only the rows that came out different
+    // are written back. Those are deleted before any is inserted again, so books that swap
+    // ISBNs between them don't trip the unique index halfway through.
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction().map_err(failed)?;
+        let (mut books, stored): (Vec<Book>, Vec<String>) = load_rows(&tx)?.into_iter().unzip();
+        let before: HashMap<u32, String> = books.iter().map(|b| b.id).zip(stored).collect();
+        write(&mut books);
+        let mut changed = Vec::new();
+        let mut kept = HashSet::new();
+        for book in &books {
+            let json = encode(book)?;
+            kept.insert(book.id);
+            if before.get(&book.id) != Some(&json) {
+                changed.push((book, json));
+            }
+        }
+        let mut delete = tx.prepare("DELETE FROM books WHERE id = ?1").map_err(failed)?;
+        for id in before.keys() {
+            if !kept.contains(id) {
+                delete.execute([id]).map_err(failed)?;
+            }
+        }
+        for (book, _) in &changed {
+            delete.execute([book.id]).map_err(failed)?;
+        }
+        drop(delete);
+        for (book, json) in &changed {
+            insert_row(&tx, book, json)?;
+        }
+        tx.commit().map_err(failed)
+    }
+}
@@ -0,0 +1,369 @@
+use crate::encryption::{self, Key};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// One step of a document's schema: `migrations[i]` takes a document of version i + 1 to
+// version i + 2, so the current version is one more than the number of steps. A step
+// fills in, renames, or reshapes fields; it never sees `schema_version` change.
+pub type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+// Whether the data file is encrypted. A file is read the way it is written, and one kept
+// the other way is refused rather than guessed at; the two conversions read either kind,
+// so that their first save switches the file over.
+#[derive(Clone)]
+pub enum Encryption {
+    Off,
+    On(Key),
+    Encrypting(Key),
+    Decrypting(Key),
+}
+
+impl Encryption {
+    pub fn is_conversion(&self) -> bool {
+        matches!(self, Encryption::Encrypting(_) | Encryption::Decrypting(_))
+    }
+    
+    // The bytes of the JSON in a file as read from disk
+    fn open(&self, path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let sealed = encryption::is_sealed(&bytes);
+        match self {
+            Encryption::Off if sealed => Err(format!(
+                "{} is encrypted; set DATA_FILE_KEY or DATA_FILE_KEY_FILE to the key it was \
+                 written with, and DATA_FILE_CONVERT=decrypt as well to turn it back into plain \
+                 JSON",
+                path.display()
+            )),
+            Encryption::On(_) if !sealed => Err(format!(
+                "{} is plain JSON, but a data file key is set; start once with \
+                 DATA_FILE_CONVERT=encrypt to encrypt it, or unset the key to keep it as it is",
+                path.display()
+            )),
+            Encryption::On(key) | Encryption::Encrypting(key) | Encryption::Decrypting(key)
+                if sealed =>
+            {
+                key.open(&bytes).map_err(|err| format!("{}: {}", path.display(), err))
+            }
+            _ => Ok(bytes),
+        }
+    }
+    
+    // The bytes to write to disk for a file holding `json`
+    fn seal(&self, json: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Encryption::On(key) | Encryption::Encrypting(key) => key.seal(&json),
+            Encryption::Off | Encryption::Decrypting(_) => Ok(json),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    schema_version: Option<u64>,
+}
+
+// Reads a value saved by `save`. Ok(None) when there is no file yet; every other failure,
+// including a file that was cut short, doesn't decrypt, or isn't JSON of the right shape,
+// is an error naming the file and, for bad content, the line and column. A file of an
+// older schema version is brought up to date by `upgrade` first.
+pub fn load<T: DeserializeOwned>(
+    path: &Path,
+    migrations: &[Migration],
+    encryption: &Encryption,
+) -> Result<Option<T>, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => encryption.open(path, bytes)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("could not read {}: {}", path.display(), err)),
+    };
+    let invalid = |err| format!("{} is not a valid data file: {}", path.display(), err);
+    let version = serde_json::from_slice::<Versioned>(&bytes).map_err(invalid)?.schema_version;
+    // A current file is read straight from the text, so errors keep their line and column
+    if version == Some(current_version(migrations)) {
+        return serde_json::from_slice(&bytes).map(Some).map_err(invalid);
+    }
+    let document = serde_json::from_slice(&bytes).map_err(invalid)?;
+    let upgraded =
+        upgrade(document, migrations).map_err(|err| format!("{}: {}", path.display(), err))?;
+    serde_json::from_value(upgraded).map(Some).map_err(|err| {
+        format!(
+            "{} is not a valid data file once upgraded from schema version {}: {}",
+            path.display(),
+            version.unwrap_or(1),
+            err
+        )
+    })
+}
+
+pub const fn current_version(migrations: &[Migration]) -> u64 {
+    migrations.len() as u64 + 1
+}
+
+// Applies the steps from the document's schema version to the current one, in order, and
+// records the current version in it. A document without `schema_version` is version 1,
+// the shape written before versions were recorded. One from a newer server is refused,
+// since its fields can't be known here.
+pub fn upgrade(document: Value, migrations: &[Migration]) -> Result<Value, String> {
+    let current = current_version(migrations);
+    let Value::Object(mut fields) = document else {
+        return Err("expected a JSON object".to_string());
+    };
+    let version = match fields.get("schema_version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .filter(|&version| version > 0)
+            .ok_or("schema_version must be a whole number of at least 1")?,
+    };
+    if version > current {
+        return Err(format!(
+            "schema version {} was written by a newer server, and this one reads up to version \
+             {}; upgrade the server to load it",
+            version, current
+        ));
+    }
+    for (from, migration) in (version..).zip(&migrations[(version - 1) as usize..]) {
+        migration(&mut fields).map_err(|err| {
+            format!("could not upgrade schema version {} to {}: {}", from, from + 1, err)
+        })?;
+    }
+    fields.insert("schema_version".to_string(), current.into());
+    Ok(Value::Object(fields))
+}
+
+// Replaces the file in one step: the value goes to a temporary file next to it, which is
+// synced and then renamed over the old one, and the directory is synced so the rename
+// itself survives a crash. Until the rename the old file is untouched, so after a crash
+// the path holds either the old or the new value in full, never part of one.
+pub fn save<T: Serialize>(path: &Path, value: &T, encryption: &Encryption) -> Result<(), String> {
+    let bytes = serde_json::to_vec(value)
+        .map_err(|err| format!("could not serialize the data for {}: {}", path.display(), err))
+        .and_then(|json| encryption.seal(json))?;
+    let temp = temp_path(path);
+    let written = write_synced(&temp, &bytes)
+        .map_err(|err| format!("could not write {}: {}", temp.display(), err));
+    let renamed = written.and_then(|()| {
+        std::fs::rename(&temp, path).map_err(|err| {
+            format!("could not replace {} with {}: {}", path.display(), temp.display(), err)
+        })
+    });
+    if renamed.is_err() {
+        // Best effort: a leftover temporary file is harmless, as nothing ever reads it
+        let _ = std::fs::remove_file(&temp);
+        return renamed;
+    }
+    sync_dir(path).map_err(|err| format!("could not sync the directory of {}: {}", path.display(), err))
+}
+
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()
+}
+
+// Elsewhere directories can't be opened to sync; the rename is as durable as it gets
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// A data file that is written when something changed, not on every change: changes mark
+// it dirty and `flush` saves a snapshot only if one is owed
+pub struct FileStore {
+    path: PathBuf,
+    encryption: Encryption,
+    dirty: AtomicBool,
+    // Why the last save failed; cleared by the next one to succeed
+    failure: Mutex<Option<String>>,
+    // When the file last took a save, by this server or, until it saves, an earlier one
+    last_saved_at: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+    // One save at a time, so an older snapshot can't land after a newer one
+    writing: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf, encryption: Encryption) -> Self {
+        let modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        FileStore {
+            path,
+            encryption,
+            dirty: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            last_saved_at: Mutex::new(modified.map(chrono::DateTime::from)),
+            writing: Mutex::new(()),
+        }
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    pub fn encryption(&self) -> &Encryption {
+        &self.encryption
+    }
+    
+    pub fn load<T: DeserializeOwned>(&self, migrations: &[Migration]) -> Result<Option<T>, String> {
+        load(&self.path, migrations, &self.encryption)
+    }
+    
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+    
+    // Whether a change is waiting for the next flush
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+    
+    pub fn last_saved_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        *self.last_saved_at.lock().unwrap()
+    }
+    
+    // The cause while saves are failing
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+    
+    // Saves what `snapshot()` returns if anything changed since the last save. The flag is cleared
+    // before the snapshot is taken, so a change made during the save marks it again; a
+    // failed save marks it again too, and is retried by the next flush.
+    pub fn flush<T: Serialize>(
+        &self,
+        snapshot: impl FnOnce() -> Result<T, String>,
+    ) -> Result<(), String> {
+        let _writing = self.writing.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = snapshot().and_then(|snapshot| save(&self.path, &snapshot, &self.encryption));
+        match result {
+            Ok(()) => *self.last_saved_at.lock().unwrap() = Some(chrono::Utc::now()),
+            Err(_) => self.mark_dirty(),
+        }
+        *self.failure.lock().unwrap() = result.clone().err();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rename_name_to_title(fields: &mut Map<String, Value>) -> Result<(), String> {
+        let name = fields.remove("name").ok_or("no name")?;
+        fields.insert("title".to_string(), name);
+        Ok(())
+    }
+
+    fn add_tags(fields: &mut Map<String, Value>) -> Result<(), String> {
+        fields.insert("tags".to_string(), json!([]));
+        Ok(())
+    }
+
+    const STEPS: &[Migration] = &[rename_name_to_title, add_tags];
+
+    // A directory of its own for one test
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("book-library-storage-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_document_without_a_version_takes_every_step() {
+        let upgraded = upgrade(json!({ "name": "Dune" }), STEPS).unwrap();
+        assert_eq!(upgraded, json!({ "title": "Dune", "tags": [], "schema_version": 3 }));
+    }
+
+    #[test]
+    fn a_document_takes_only_the_steps_after_its_version() {
+        let upgraded = upgrade(json!({ "schema_version": 2, "title": "Dune" }), STEPS).unwrap();
+        assert_eq!(upgraded, json!({ "title": "Dune", "tags": [], "schema_version": 3 }));
+        let current = json!({ "schema_version": 3, "title": "Dune", "tags": ["sf"] });
+        assert_eq!(upgrade(current.clone(), STEPS).unwrap(), current);
+    }
+
+    #[test]
+    fn a_failed_step_names_the_versions() {
+        let err = upgrade(json!({ "title": "Dune" }), STEPS).unwrap_err();
+        assert_eq!(err, "could not upgrade schema version 1 to 2: no name");
+    }
+
+    #[test]
+    fn a_newer_or_invalid_version_is_refused() {
+        let err = upgrade(json!({ "schema_version": 4 }), STEPS).unwrap_err();
+        assert!(err.starts_with("schema version 4 was written by a newer server"), "{}", err);
+        for version in [json!(0), json!(-1), json!("2")] {
+            assert!(upgrade(json!({ "schema_version": version }), STEPS).is_err());
+        }
+    }
+
+    #[test]
+    fn a_failed_save_keeps_the_old_file_and_stays_dirty() {
+        let dir = scratch("failed-save");
+        let path = dir.join("data.json");
+        let store = FileStore::new(path.clone(), Encryption::Off);
+        store.mark_dirty();
+        store.flush(|| Ok(json!({ "next_id": 3 }))).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        let saved_at = store.last_saved_at();
+
+        // A directory where the temporary file goes makes the write fail before the rename
+        std::fs::create_dir(temp_path(&path)).unwrap();
+        store.mark_dirty();
+        let err = store.flush(|| Ok(json!({ "next_id": 4 }))).unwrap_err();
+        assert!(err.starts_with("could not write"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), saved);
+        assert!(store.is_dirty());
+        assert_eq!(store.failure(), Some(err));
+        assert_eq!(store.last_saved_at(), saved_at);
+
+        // The next flush retries without another change being made
+        std::fs::remove_dir(temp_path(&path)).unwrap();
+        store.flush(|| Ok(json!({ "next_id": 4 }))).unwrap();
+        assert!(!store.is_dirty());
+        assert_eq!(store.failure(), None);
+        let reread: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(reread, json!({ "next_id": 4 }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_save_over_a_directory_fails_and_stays_dirty() {
+        let dir = scratch("directory-target");
+        let path = dir.join("data.json");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("keep"), "kept").unwrap();
+        let store = FileStore::new(path.clone(), Encryption::Off);
+        store.mark_dirty();
+        let err = store.flush(|| Ok(json!({ "next_id": 3 }))).unwrap_err();
+        assert!(err.starts_with("could not replace"), "{}", err);
+        assert!(store.is_dirty());
+        assert_eq!(std::fs::read_to_string(path.join("keep")).unwrap(), "kept");
+        // The temporary file isn't left behind
+        assert!(!temp_path(&path).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
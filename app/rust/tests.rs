@@ -0,0 +1,2013 @@
+use super::*;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// When every test library starts; the manual clock stays there until a test moves it
+fn start() -> chrono::DateTime<chrono::Utc> {
+    "2026-01-15T12:00:00Z".parse().unwrap()
+}
+
+// The configuration from the environment, less anything that would keep the library in
+// a file or a database
+fn config() -> Config {
+    let mut config = Config::from_env();
+    config.data_file = None;
+    config.database_url = None;
+    config.operation_log = None;
+    config.seed_file = None;
+    config.seed_force = false;
+    config
+}
+
+// A library around `books` on the manual clock, with the random sequence fixed
+fn library(
+    config: Config,
+    books: Arc<dyn BookRepository>,
+    store: Option<storage::FileStore>,
+) -> web::Data<AppState> {
+    let mut state = new_state(config, books, 3, store, start()).unwrap();
+    state.clock = Box::new(clock::ManualClock::new(start()));
+    state.rng = Box::new(random::SplitMix::new(42));
+    web::Data::new(state)
+}
+
+// The two built-in books, in memory
+fn memory_library() -> web::Data<AppState> {
+    library(config(), Arc::new(InMemoryBooks::new(builtin_books(start()))), None)
+}
+
+// A directory of its own for one test, removed when the test is done
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir()
+            .join(format!("book-library-api-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: actix_web::web::Bytes,
+}
+
+impl Reply {
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|err| {
+            panic!("{} is not JSON ({}): {:?}", self.status, err, self.body)
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+async fn send<S, R>(app: &S, request: R) -> Reply
+where
+    S: Service<R, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let response = app.call(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = test::read_body(response).await;
+    Reply {
+        status,
+        headers,
+        body,
+    }
+}
+
+fn new_book(title: &str, isbn: &str) -> Value {
+    json!({ "title": title, "authors": ["Test Author"], "isbn": isbn })
+}
+
+// A store for the backend suite, with its name for failure messages
+type Backend = (&'static str, Arc<dyn BookRepository>);
+
+// The stores the backend suite runs against, each holding the two built-in books
+fn backends(test: &str) -> (Scratch, Vec<Backend>) {
+    let scratch = Scratch::new(test);
+    let memory: Arc<dyn BookRepository> = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let stores = vec![("memory", memory)];
+    #[cfg(feature = "sqlite")]
+    let stores = {
+        let mut stores = stores;
+        let path = scratch.0.join("books.db");
+        let books = sqlite::SqliteBooks::open(path.to_str().unwrap(), builtin_books(start()));
+        stores.push(("sqlite", Arc::new(books.unwrap()) as _));
+        stores
+    };
+    (scratch, stores)
+}
+
+#[actix_web::test]
+async fn backends_create_read_update_and_delete() {
+    let (_scratch, stores) = backends("crud");
+    for (name, books) in stores {
+        let app = test::init_service(build_app(library(config(), books, None))).await;
+        let created = send(
+            &app,
+            TestRequest::post()
+                .uri("/api/books")
+                .set_json(new_book("Rust in Action", "978-1617294556"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(created.status, StatusCode::CREATED, "{}", name);
+        assert_eq!(created.json()["id"], 3, "{}", name);
+        let location = created.header("location").unwrap().to_string();
+
+        let fetched = send(&app, TestRequest::get().uri(&location).to_request()).await;
+        assert_eq!(fetched.status, StatusCode::OK, "{}", name);
+        assert_eq!(fetched.json(), created.json(), "{}", name);
+
+        let updated = send(
+            &app,
+            TestRequest::patch()
+                .uri(&location)
+                .insert_header(("If-Match", fetched.header("etag").unwrap()))
+                .set_json(json!({ "title": "Rust in Action, 2nd" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(updated.status, StatusCode::OK, "{}", name);
+        assert_eq!(updated.json()["title"], "Rust in Action, 2nd", "{}", name);
+        assert_eq!(updated.json()["version"], 2, "{}", name);
+
+        let deleted = send(&app, TestRequest::delete().uri(&location).to_request()).await;
+        assert_eq!(deleted.status, StatusCode::NO_CONTENT, "{}", name);
+        let gone = send(&app, TestRequest::get().uri(&location).to_request()).await;
+        assert_eq!(gone.status, StatusCode::NOT_FOUND, "{}", name);
+        let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+        assert_eq!(listed.json()["items"].as_array().unwrap().len(), 2, "{}", name);
+    }
+}
+
+#[actix_web::test]
+async fn backends_refuse_a_duplicate_isbn_in_either_form() {
+    let (_scratch, stores) = backends("duplicate-isbn");
+    for (name, books) in stores {
+        let app = test::init_service(build_app(library(config(), books, None))).await;
+        let first = TestRequest::post().uri("/api/books").set_json(new_book("A", "9780306406157"));
+        assert_eq!(send(&app, first.to_request()).await.status, StatusCode::CREATED, "{}", name);
+
+        // The ISBN-10 form of the same book
+        let again = TestRequest::post().uri("/api/books").set_json(new_book("B", "0-306-40615-2"));
+        let again = send(&app, again.to_request()).await;
+        assert_eq!(again.status, StatusCode::CONFLICT, "{}", name);
+        assert_eq!(again.json()["error"], "Book with this ISBN already exists", "{}", name);
+
+        let taken = TestRequest::patch()
+            .uri("/api/books/2")
+            .set_json(json!({ "isbn": "9781718500440" }));
+        assert_eq!(send(&app, taken.to_request()).await.status, StatusCode::CONFLICT, "{}", name);
+
+        let batch = TestRequest::post()
+            .uri("/api/books/batch?atomic=true")
+            .set_json(json!([new_book("C", "9780441172719"), new_book("D", "0306406152")]));
+        let batch = send(&app, batch.to_request()).await;
+        assert_eq!(batch.status, StatusCode::CONFLICT, "{}", name);
+        let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+        assert_eq!(listed.json()["total"], 3, "{}", name);
+    }
+}
+
+#[actix_web::test]
+async fn backends_refuse_a_stale_delete() {
+    let (_scratch, stores) = backends("stale-delete");
+    for (name, books) in stores {
+        let app = test::init_service(build_app(library(config(), books, None))).await;
+        let read = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+        let etag = read.header("etag").unwrap().to_string();
+        let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+        assert_eq!(send(&app, change.to_request()).await.status, StatusCode::OK, "{}", name);
+
+        let delete = TestRequest::delete().uri("/api/books/1").insert_header(("If-Match", etag));
+        let delete = send(&app, delete.to_request()).await;
+        assert_eq!(delete.status, StatusCode::PRECONDITION_FAILED, "{}", name);
+        assert_eq!(delete.json()["current_version"], 2, "{}", name);
+        let kept = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+        assert_eq!(kept.status, StatusCode::OK, "{}", name);
+    }
+}
+
+#[actix_web::test]
+async fn backends_round_trip_a_catalog_replacement() {
+    let (_scratch, stores) = backends("replace-catalog");
+    for (name, books) in stores {
+        let app = test::init_service(build_app(library(config(), books, None))).await;
+        let add = TestRequest::post().uri("/api/books").set_json(new_book("E", "9780553283686"));
+        assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED, "{}", name);
+        let before = send(&app, TestRequest::get().uri("/api/books").to_request()).await.json();
+
+        let replace = TestRequest::put().uri("/api/books").set_json(&before["items"]);
+        let replaced = send(&app, replace.to_request()).await;
+        assert_eq!(replaced.status, StatusCode::OK, "{}", name);
+        assert_eq!(replaced.json(), json!({ "count": 3, "next_id": 4 }), "{}", name);
+        let after = send(&app, TestRequest::get().uri("/api/books").to_request()).await.json();
+        assert_eq!(after["items"], before["items"], "{}", name);
+    }
+}
+
+// A write started while `read_all` runs has to wait for the closure to return
+#[test]
+fn backends_keep_writers_out_while_read_all_runs() {
+    let (_scratch, stores) = backends("read-all");
+    for (name, books) in stores {
+        let mut writer = None;
+        books
+            .read_all(&mut |seen| {
+                let books = books.clone();
+                let write = std::thread::spawn(move || {
+                    books.update(1, &mut |book| book.title = "Changed".to_string())
+                });
+                std::thread::sleep(Duration::from_millis(100));
+                assert!(!write.is_finished(), "{}: a write got in while read_all ran", name);
+                assert_eq!(seen[0].title, "The Rust Programming Language", "{}", name);
+                writer = Some(write);
+            })
+            .unwrap();
+        writer.unwrap().join().unwrap().unwrap();
+        assert_eq!(books.get(1).unwrap().title, "Changed", "{}", name);
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[actix_web::test]
+async fn sqlite_keeps_the_catalog_across_restarts() {
+    let scratch = Scratch::new("sqlite-restart");
+    let path = scratch.0.join("books.db");
+    let open = || -> Arc<dyn BookRepository> {
+        Arc::new(sqlite::SqliteBooks::open(path.to_str().unwrap(), builtin_books(start())).unwrap())
+    };
+    let app = test::init_service(build_app(library(config(), open(), None))).await;
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("F", "9780441569595"));
+    assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED);
+    drop(app);
+
+    let app = test::init_service(build_app(library(config(), open(), None))).await;
+    let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await.json();
+    assert_eq!(listed["total"], 3);
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("G", "9780553283686"));
+    assert_eq!(send(&app, add.to_request()).await.json()["id"], 4);
+}
+
+async fn list_etag<S, R>(app: &S, request: R) -> String
+where
+    S: Service<R, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    send(app, request).await.header("etag").unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn the_collection_etag_changes_with_the_catalog() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let etag = list_etag(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    let unchanged = TestRequest::get().uri("/api/books").insert_header(("If-None-Match", &*etag));
+    let unchanged = send(&app, unchanged.to_request()).await;
+    assert_eq!(unchanged.status, StatusCode::NOT_MODIFIED);
+    assert!(unchanged.body.is_empty());
+
+    let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+    assert_eq!(send(&app, change.to_request()).await.status, StatusCode::OK);
+    let changed = TestRequest::get().uri("/api/books").insert_header(("If-None-Match", &*etag));
+    let changed = send(&app, changed.to_request()).await;
+    assert_eq!(changed.status, StatusCode::OK);
+    assert_ne!(changed.header("etag").unwrap(), etag);
+}
+
+// Each run counts catalog changes from 1 again, so the same count must not give the same tag
+#[actix_web::test]
+async fn a_collection_etag_from_before_a_restart_never_matches() {
+    let before = test::init_service(build_app(memory_library())).await;
+    let etag = list_etag(&before, TestRequest::get().uri("/api/books").to_request()).await;
+
+    let after = test::init_service(build_app(memory_library())).await;
+    let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+    assert_eq!(send(&after, change.to_request()).await.status, StatusCode::OK);
+    let stale = TestRequest::get().uri("/api/books").insert_header(("If-None-Match", &*etag));
+    let stale = send(&after, stale.to_request()).await;
+    assert_eq!(stale.status, StatusCode::OK);
+    assert_ne!(stale.header("etag").unwrap(), etag);
+    let fresh = list_etag(&after, TestRequest::get().uri("/api/books").to_request()).await;
+    let search = TestRequest::get().uri("/api/books/search?q=rust");
+    let search = search.insert_header(("If-None-Match", format!("W/{}", fresh)));
+    assert_eq!(send(&after, search.to_request()).await.status, StatusCode::NOT_MODIFIED);
+}
+
+// The manual clock moves book timestamps as well as loans
+#[actix_web::test]
+async fn book_timestamps_come_from_the_clock() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let created_at = start() + chrono::Duration::days(10);
+    data.clock.set(created_at);
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    let added = send(&app, add.to_request()).await.json();
+    assert_eq!(added["created_at"], json!(created_at));
+    assert_eq!(added["updated_at"], json!(created_at));
+
+    let updated_at = created_at + chrono::Duration::hours(1);
+    data.clock.set(updated_at);
+    let change = TestRequest::patch().uri("/api/books/3").set_json(json!({ "genre": "SF" }));
+    let changed = send(&app, change.to_request()).await.json();
+    assert_eq!(changed["created_at"], json!(created_at));
+    assert_eq!(changed["updated_at"], json!(updated_at));
+
+    let delete = TestRequest::delete().uri("/api/books/3");
+    assert_eq!(send(&app, delete.to_request()).await.status, StatusCode::NO_CONTENT);
+    let trash = send(&app, TestRequest::get().uri("/api/books/trash").to_request()).await.json();
+    assert_eq!(trash["items"][0]["deleted_at"], json!(updated_at));
+}
+
+#[actix_web::test]
+async fn members_and_lists_are_stamped_by_the_clock() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let joined_at = start() + chrono::Duration::days(3);
+    data.clock.set(joined_at);
+    let member = json!({ "name": "Bo Yang", "email": "bo@example.com" });
+    let join = TestRequest::post().uri("/api/members").set_json(member);
+    assert_eq!(send(&app, join.to_request()).await.json()["joined_at"], json!(joined_at));
+
+    let created_at = joined_at + chrono::Duration::hours(2);
+    data.clock.set(created_at);
+    let list = TestRequest::post().uri("/api/members/2/lists").set_json(json!({ "name": "SF" }));
+    let list = send(&app, list.to_request()).await;
+    assert_eq!(list.status, StatusCode::CREATED);
+    assert_eq!(list.json()["created_at"], json!(created_at));
+}
+
+// The `book_id` of every item, in order
+fn ids(items: &Value) -> Vec<u64> {
+    let items = items.as_array().unwrap();
+    items.iter().map(|item| item["book_id"].as_u64().unwrap()).collect()
+}
+
+// The `id` of every book in a list, in order
+fn book_ids(items: &Value) -> Vec<u64> {
+    let items = items.as_array().unwrap();
+    items.iter().map(|item| item["id"].as_u64().unwrap()).collect()
+}
+
+#[actix_web::test]
+async fn the_weeding_report_leaves_out_a_book_exactly_at_the_cutoff() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let created_at = start() + chrono::Duration::days(10);
+    data.clock.set(created_at);
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED);
+    let weeding = || TestRequest::get().uri("/api/reports/weeding?older_than_days=365");
+
+    data.clock.set(created_at + chrono::Duration::days(365));
+    let report = send(&app, weeding().to_request()).await.json();
+    assert_eq!(report["cutoff"], json!(created_at));
+    assert_eq!(ids(&report["items"]), [1, 2]);
+
+    data.clock.set(created_at + chrono::Duration::days(365) + chrono::Duration::seconds(1));
+    let report = send(&app, weeding().to_request()).await.json();
+    assert_eq!(ids(&report["items"]), [1, 2, 3]);
+}
+
+// Books created within RECENT_DAYS of the clock count as recent, the day itself included
+#[actix_web::test]
+async fn stats_count_books_added_recently_by_the_clock() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let created_at = start() + chrono::Duration::days(10);
+    data.clock.set(created_at);
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED);
+    let recent = || async {
+        let stats = send(&app, TestRequest::get().uri("/api/stats").to_request()).await;
+        stats.json()["added_recently"].clone()
+    };
+    assert_eq!(recent().await, 3);
+
+    data.clock.set(created_at + chrono::Duration::days(RECENT_DAYS));
+    assert_eq!(recent().await, 1);
+    data.clock.set(created_at + chrono::Duration::days(RECENT_DAYS) + chrono::Duration::seconds(1));
+    assert_eq!(recent().await, 0);
+}
+
+// The scenario from the test plan: every total after creates, a delete, and a checkout
+#[actix_web::test]
+async fn stats_follow_creates_deletes_and_checkouts() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let stats = || TestRequest::get().uri("/api/stats");
+    let fresh = send(&app, stats().to_request()).await.json();
+    assert_eq!(fresh["total_books"], 2);
+    assert_eq!(fresh["total_copies"], 4);
+    assert_eq!(fresh["distinct_authors"], 5);
+    assert_eq!(fresh["books_per_genre"], json!([{ "genre": "Programming", "count": 2 }]));
+    assert_eq!(fresh["added_recently"], 2);
+    assert_eq!(fresh["loans"], Value::Null);
+
+    let books = [
+        json!({ "title": "H", "authors": ["Steve Klabnik"], "isbn": "9780441172719",
+            "genre": "programming" }),
+        json!({ "title": "Odes", "authors": ["Ada Poet"], "isbn": "9780441569595",
+            "genre": "Poetry" }),
+    ];
+    for book in books {
+        let create = TestRequest::post().uri("/api/books").set_json(book);
+        assert_eq!(send(&app, create.to_request()).await.status, StatusCode::CREATED);
+    }
+    let delete = TestRequest::delete().uri("/api/books/2");
+    assert_eq!(send(&app, delete.to_request()).await.status, StatusCode::NO_CONTENT);
+    assert_eq!(send(&app, checkout(1, 1).to_request()).await.status, StatusCode::OK);
+
+    let after = send(&app, stats().to_request()).await.json();
+    let expected = json!({
+        "total_books": 3,
+        "total_copies": 5,
+        "available_books": 3,
+        "checked_out_books": 1,
+        "available_copies": 3,
+        "checked_out_copies": 1,
+        "distinct_authors": 3,
+        "books_per_genre": [
+            { "genre": "Poetry", "count": 1 },
+            { "genre": "Programming", "count": 2 }
+        ],
+        "added_recently": 3,
+        "loans": { "open_loans": 1, "overdue_loans": 0 }
+    });
+    assert_eq!(after, expected);
+
+    data.clock.set(start() + data.config.loan_period + chrono::Duration::seconds(1));
+    let overdue = send(&app, stats().to_request()).await.json();
+    assert_eq!(overdue["loans"], json!({ "open_loans": 1, "overdue_loans": 1 }));
+    let give_back = TestRequest::post().uri("/api/books/1/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    let returned = send(&app, stats().to_request()).await.json();
+    assert_eq!(returned["loans"], json!({ "open_loans": 0, "overdue_loans": 0 }));
+    assert_eq!(returned["checked_out_copies"], 0);
+}
+
+// Books and loans are counted by the same clock, so a report for a year on the manual
+// clock sees both
+#[actix_web::test]
+async fn the_activity_report_counts_books_and_loans_by_one_clock() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    data.clock.set("2024-05-10T09:00:00Z".parse().unwrap());
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED);
+    let checkout = TestRequest::post()
+        .uri("/api/books/3/checkout")
+        .set_json(json!({ "member_id": 1 }));
+    assert_eq!(send(&app, checkout.to_request()).await.status, StatusCode::OK);
+
+    data.clock.set("2024-06-02T09:00:00Z".parse().unwrap());
+    let give_back = TestRequest::post().uri("/api/books/3/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    let delete = TestRequest::delete().uri("/api/books/3");
+    assert_eq!(send(&app, delete.to_request()).await.status, StatusCode::NO_CONTENT);
+
+    let report = TestRequest::get().uri("/api/reports/activity");
+    let report = send(&app, report.to_request()).await.json();
+    assert_eq!(report["year"], 2024);
+    let month = |index: usize| {
+        let month = &report["months"][index];
+        let count = |field: &str| month[field].as_u64().unwrap();
+        ["books_added", "books_deleted", "loans_started", "loans_returned"].map(count)
+    };
+    assert_eq!(month(4), [1, 0, 1, 0]);
+    assert_eq!(month(5), [0, 1, 0, 1]);
+    let total: u64 = (0..12).map(|index| month(index).iter().sum::<u64>()).sum();
+    assert_eq!(total, 4);
+}
+
+// A data file as the last server before schema versions wrote it: a book with a review
+// and an open loan, and a book in the trash
+const DATA_FILE_V1: &str = include_str!("testdata/data_file_v1.json");
+
+#[actix_web::test]
+async fn a_version_1_data_file_is_upgraded_on_load() {
+    let scratch = Scratch::new("data-file-v1");
+    let path = scratch.0.join("data.json");
+    std::fs::write(&path, DATA_FILE_V1).unwrap();
+    let store = storage::FileStore::new(path.clone(), storage::Encryption::Off);
+    let snapshot: Snapshot = store.load(DATA_FILE_MIGRATIONS).unwrap().unwrap();
+    assert_eq!(snapshot.schema_version, DATA_FILE_VERSION);
+    let data = library(config(), Arc::new(InMemoryBooks::new(Vec::new())), Some(store));
+    restore_snapshot(&data, snapshot, true).unwrap();
+
+    let app = test::init_service(build_app(data.clone())).await;
+    let dune = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await.json();
+    assert_eq!(dune["title"], "Dune");
+    assert_eq!(dune["review_count"], 1);
+    assert_eq!(dune["checked_out_copies"], 1);
+    let trash = send(&app, TestRequest::get().uri("/api/books/trash").to_request()).await.json();
+    assert_eq!(trash["items"][0]["id"], 2);
+    let loans = send(&app, TestRequest::get().uri("/api/loans").to_request()).await.json();
+    assert_eq!(loans[0]["book_id"], 3);
+
+    // The next save writes the current version
+    data.store.as_ref().unwrap().mark_dirty();
+    flush(&data).unwrap();
+    let saved: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(saved["schema_version"], DATA_FILE_VERSION);
+    assert_eq!(saved["books"][1]["title"], "Dune");
+}
+
+#[test]
+fn a_data_file_from_a_newer_server_is_refused() {
+    let scratch = Scratch::new("data-file-future");
+    let path = scratch.0.join("data.json");
+    let mut document: Value = serde_json::from_str(DATA_FILE_V1).unwrap();
+    document["schema_version"] = json!(DATA_FILE_VERSION + 1);
+    let written = serde_json::to_vec(&document).unwrap();
+    std::fs::write(&path, &written).unwrap();
+    let store = storage::FileStore::new(path.clone(), storage::Encryption::Off);
+    let err = store.load::<Snapshot>(DATA_FILE_MIGRATIONS).err().unwrap();
+    assert!(err.contains("written by a newer server"), "{}", err);
+    assert_eq!(std::fs::read(&path).unwrap(), written);
+}
+
+// A library kept in the data file at `path`, as main opens it, with changes left to a
+// flusher that never runs, so only the shutdown flush writes them
+fn file_library(path: &std::path::Path) -> web::Data<AppState> {
+    let mut config = config();
+    config.flush_interval = Duration::from_secs(3600);
+    let store = storage::FileStore::new(path.to_path_buf(), storage::Encryption::Off);
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let data = library(config, books, Some(store));
+    open_data_file(&data).unwrap();
+    data
+}
+
+#[actix_web::test]
+async fn a_change_saved_at_shutdown_is_there_after_a_restart() {
+    let scratch = Scratch::new("restart");
+    let path = scratch.0.join("data.json");
+    let data = file_library(&path);
+    let created = std::fs::read(&path).unwrap();
+    let app = test::init_service(build_app(data.clone())).await;
+    let add = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    let added = send(&app, add.to_request()).await.json();
+    let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+    assert_eq!(send(&app, change.to_request()).await.status, StatusCode::OK);
+    // Not written yet: the flusher's interval hasn't come round
+    assert!(data.store.as_ref().unwrap().is_dirty());
+    assert_eq!(std::fs::read(&path).unwrap(), created);
+
+    // What main does once the server has stopped
+    drop(app);
+    flush(&data).unwrap();
+    assert!(!data.store.as_ref().unwrap().is_dirty());
+
+    let app = test::init_service(build_app(file_library(&path))).await;
+    let fetched = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    assert_eq!(fetched.json(), added);
+    let changed = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(changed.json()["genre"], "Rust");
+    let next = TestRequest::post().uri("/api/books").set_json(new_book("I", "9780441569595"));
+    assert_eq!(send(&app, next.to_request()).await.json()["id"], 4);
+}
+
+// A review dates book 1 an hour after its `updated_at`, and returns the book's Last-Modified
+// and an If-Modified-Since from between the two
+async fn review_book_one(data: &web::Data<AppState>) -> (String, TestRequest) {
+    let app = test::init_service(build_app(data.clone())).await;
+    data.clock.set(start() + chrono::Duration::hours(1));
+    let review = json!({ "rating": 4, "reviewer": "Ann", "comment": "Clear" });
+    let review = TestRequest::post().uri("/api/books/1/reviews").set_json(review);
+    assert_eq!(send(&app, review.to_request()).await.status, StatusCode::CREATED);
+    data.clock.set(start() + chrono::Duration::hours(2));
+    let book = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    let modified = book.header("last-modified").unwrap().to_string();
+    assert_eq!(modified, "Thu, 15 Jan 2026 13:00:00 GMT");
+    let before_the_review = TestRequest::get()
+        .uri("/api/books/1")
+        .insert_header(("If-Modified-Since", "Thu, 15 Jan 2026 12:30:00 GMT"));
+    (modified, before_the_review)
+}
+
+#[actix_web::test]
+async fn a_review_still_dates_the_book_after_a_restart() {
+    let scratch = Scratch::new("review-restart");
+    let path = scratch.0.join("data.json");
+    let data = file_library(&path);
+    let (modified, before_the_review) = review_book_one(&data).await;
+    flush(&data).unwrap();
+
+    let data = file_library(&path);
+    data.clock.set(start() + chrono::Duration::hours(2));
+    let app = test::init_service(build_app(data)).await;
+    let book = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(book.header("last-modified"), Some(modified.as_str()));
+    assert_eq!(send(&app, before_the_review.to_request()).await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn a_review_still_dates_the_book_after_a_backup_is_restored() {
+    let data = memory_library();
+    let (modified, before_the_review) = review_book_one(&data).await;
+    let app = test::init_service(build_app(data)).await;
+    let backup = send(&app, TestRequest::get().uri("/api/admin/backup").to_request()).await;
+    assert_eq!(backup.status, StatusCode::OK);
+    let backup = backup.json();
+
+    let data = memory_library();
+    data.clock.set(start() + chrono::Duration::hours(2));
+    let app = test::init_service(build_app(data)).await;
+    let restore = TestRequest::post().uri("/api/admin/restore").set_json(&backup);
+    assert_eq!(send(&app, restore.to_request()).await.status, StatusCode::OK);
+    let book = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(book.header("last-modified"), Some(modified.as_str()));
+    assert_eq!(send(&app, before_the_review.to_request()).await.status, StatusCode::OK);
+
+    // Merged over book 1, the record keeps the review time the catalog had for it
+    let reviewed_at = json!(start() + chrono::Duration::hours(1));
+    assert_eq!(backup["books"][0]["reviews_changed_at"], reviewed_at);
+    let merge = TestRequest::post().uri("/api/admin/restore?merge=true").set_json(&backup);
+    assert_eq!(send(&app, merge.to_request()).await.json()["books_restored"], 2);
+    let after = send(&app, TestRequest::get().uri("/api/admin/backup").to_request()).await;
+    assert_eq!(after.json()["books"][0]["reviews_changed_at"], reviewed_at);
+    assert_eq!(after.json()["books"][0]["review_count"], 1);
+}
+
+// Ranked by where the query hits, and by id among books that score the same
+#[actix_web::test]
+async fn search_ranks_title_then_author_then_description_and_ties_by_id() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let books = [
+        json!({ "title": "Arrakis", "authors": ["F. H."], "isbn": "9780441172719",
+                "description": "The dune sea" }),
+        json!({ "title": "Arrakis II", "authors": ["Dune Smith"], "isbn": "9780441569595" }),
+        json!({ "title": "Dune", "authors": ["F. H."], "isbn": "9780553283686" }),
+        json!({ "title": "Dune", "authors": ["F. H."], "isbn": "9780306406157" }),
+    ];
+    for book in books {
+        let add = TestRequest::post().uri("/api/books").set_json(book);
+        assert_eq!(send(&app, add.to_request()).await.status, StatusCode::CREATED);
+    }
+    let search = TestRequest::get().uri("/api/books/search?q=dune");
+    let found = send(&app, search.to_request()).await.json();
+    assert_eq!(book_ids(&found["items"]), [5, 6, 4, 3]);
+}
+
+// Two clients read the same version and both write it back; the second gets told what
+// changed under it instead of overwriting the first
+#[actix_web::test]
+async fn the_second_of_two_writes_from_the_same_version_is_refused() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let read = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    let etag = read.header("etag").unwrap().to_string();
+
+    let first = TestRequest::patch()
+        .uri("/api/books/1")
+        .insert_header(("If-Match", &*etag))
+        .set_json(json!({ "genre": "Systems" }));
+    let first = send(&app, first.to_request()).await;
+    assert_eq!(first.status, StatusCode::OK);
+    let current = first.header("etag").unwrap().to_string();
+
+    let mut replacement = read.json();
+    replacement["title"] = json!("Overwritten");
+    let second = TestRequest::put()
+        .uri("/api/books/1")
+        .insert_header(("If-Match", &*etag))
+        .set_json(&replacement);
+    let second = send(&app, second.to_request()).await;
+    assert_eq!(second.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(second.json()["current_version"], 2);
+    assert_eq!(second.json()["current_etag"], current);
+
+    let stale_patch = TestRequest::patch()
+        .uri("/api/books/1")
+        .insert_header(("If-Match", &*etag))
+        .set_json(json!({ "genre": "Overwritten" }));
+    let stale_patch = send(&app, stale_patch.to_request()).await;
+    assert_eq!(stale_patch.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(stale_patch.json()["current_etag"], current);
+
+    let kept = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(kept.json()["genre"], "Systems");
+    assert_eq!(kept.json()["title"], read.json()["title"]);
+    // Redone from the current version, the write goes through
+    let mut replacement = kept.json();
+    replacement["title"] = json!("Overwritten");
+    let retry = TestRequest::put()
+        .uri("/api/books/1")
+        .insert_header(("If-Match", &*current))
+        .set_json(&replacement);
+    let retry = send(&app, retry.to_request()).await;
+    assert_eq!(retry.status, StatusCode::OK);
+    assert_eq!(retry.json()["title"], "Overwritten");
+}
+
+// HTTP dates have whole seconds, so a change is only dated once its second is over: until
+// then a second change in the same second would carry the same date
+#[actix_web::test]
+async fn if_modified_since_is_not_trusted_within_the_second_of_a_change() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let changed_at = start() + chrono::Duration::milliseconds(500);
+    data.clock.set(changed_at);
+    let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+    assert_eq!(send(&app, change.to_request()).await.status, StatusCode::OK);
+    let date = "Thu, 15 Jan 2026 12:00:00 GMT";
+    let since = |date: &str| {
+        let request = TestRequest::get().uri("/api/books/1");
+        request.insert_header(("If-Modified-Since", date)).to_request()
+    };
+
+    let unsettled = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(unsettled.status, StatusCode::OK);
+    assert_eq!(unsettled.header("last-modified"), None);
+    assert_eq!(send(&app, since(date)).await.status, StatusCode::OK);
+
+    // A second change in the same second, which a 304 for `date` would hide
+    data.clock.set(changed_at + chrono::Duration::milliseconds(400));
+    let again = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Systems" }));
+    assert_eq!(send(&app, again.to_request()).await.status, StatusCode::OK);
+    assert_eq!(send(&app, since(date)).await.status, StatusCode::OK);
+
+    data.clock.set(start() + chrono::Duration::seconds(1));
+    let settled = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(settled.header("last-modified"), Some(date));
+    assert_eq!(settled.json()["genre"], "Systems");
+    assert_eq!(send(&app, since(date)).await.status, StatusCode::NOT_MODIFIED);
+    let earlier = send(&app, since("Thu, 15 Jan 2026 11:59:59 GMT")).await;
+    assert_eq!(earlier.status, StatusCode::OK);
+}
+
+fn keyed_create(key: &str, book: Value) -> TestRequest {
+    let request = TestRequest::post().uri("/api/books").set_json(book);
+    request.insert_header(("Idempotency-Key", key))
+}
+
+#[actix_web::test]
+async fn a_create_retried_with_its_idempotency_key_is_replayed() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let book = new_book("H", "9780441172719");
+    let first = send(&app, keyed_create("k-1", book.clone()).to_request()).await;
+    assert_eq!(first.status, StatusCode::CREATED);
+    assert_eq!(first.header("idempotent-replayed"), None);
+
+    let retry = send(&app, keyed_create("k-1", book.clone()).to_request()).await;
+    assert_eq!(retry.status, StatusCode::CREATED);
+    assert_eq!(retry.header("idempotent-replayed"), Some("true"));
+    assert_eq!(retry.header("location"), first.header("location"));
+    assert_eq!(retry.body, first.body);
+    let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    assert_eq!(listed.json()["total"], 3);
+
+    let other = send(&app, keyed_create("k-1", new_book("I", "9780441569595")).to_request()).await;
+    assert_eq!(other.status, StatusCode::CONFLICT);
+    let error = "Idempotency-Key 'k-1' was already used for a different request";
+    assert_eq!(other.json()["error"], error);
+    let bad_key = send(&app, keyed_create("with space", book).to_request()).await;
+    assert_eq!(bad_key.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn an_expired_idempotency_key_is_processed_as_new() {
+    let mut config = config();
+    config.idempotency_ttl = Duration::ZERO;
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let app = test::init_service(build_app(library(config, books, None))).await;
+    let book = new_book("H", "9780441172719");
+    let first = send(&app, keyed_create("k-1", book.clone()).to_request()).await;
+    assert_eq!(first.status, StatusCode::CREATED);
+    // Run again rather than replayed, so it runs into the book the first one created
+    let again = send(&app, keyed_create("k-1", book).to_request()).await;
+    assert_eq!(again.status, StatusCode::CONFLICT);
+    assert_eq!(again.header("idempotent-replayed"), None);
+    assert_eq!(again.json()["error"], "Book with this ISBN already exists");
+}
+
+// Staff notes are only in the staff view of a book; every other response leaves them out
+#[actix_web::test]
+async fn staff_notes_stay_out_of_public_responses() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let note = "Spine damaged, rebind before lending";
+    let set = TestRequest::put()
+        .uri("/api/books/1/notes")
+        .insert_header(("X-Library-Staff", "true"))
+        .set_json(json!({ "notes": note }));
+    assert_eq!(send(&app, set.to_request()).await.status, StatusCode::OK);
+    let staff = TestRequest::get()
+        .uri("/api/books/1?include=notes")
+        .insert_header(("X-Library-Staff", "true"));
+    assert_eq!(send(&app, staff.to_request()).await.json()["notes"], note);
+    let public = TestRequest::get().uri("/api/books/1?include=notes");
+    assert_eq!(send(&app, public.to_request()).await.status, StatusCode::FORBIDDEN);
+
+    let checkout = TestRequest::post().uri("/api/books/1/checkout");
+    let checkout = checkout.set_json(json!({ "member_id": 1 }));
+    let mut bodies = vec![send(&app, checkout.to_request()).await];
+    let change = TestRequest::patch().uri("/api/books/1").set_json(json!({ "genre": "Rust" }));
+    bodies.push(send(&app, change.to_request()).await);
+    for uri in [
+        "/api/books",
+        "/api/books/1",
+        "/api/books/isbn/9781718500440",
+        "/api/books/search?q=rust",
+        "/api/books/random",
+        "/api/books/1/loans",
+        "/api/loans",
+        "/api/members/1/history",
+        "/api/events",
+    ] {
+        bodies.push(send(&app, TestRequest::get().uri(uri).to_request()).await);
+    }
+    bodies.push(send(&app, TestRequest::post().uri("/api/books/1/return").to_request()).await);
+    let delete = TestRequest::delete().uri("/api/books/1");
+    assert_eq!(send(&app, delete.to_request()).await.status, StatusCode::NO_CONTENT);
+    bodies.push(send(&app, TestRequest::get().uri("/api/books/trash").to_request()).await);
+    for reply in bodies {
+        assert_eq!(reply.status, StatusCode::OK, "{:?}", reply.body);
+        let body = String::from_utf8_lossy(&reply.body);
+        assert!(!body.contains("rebind"), "{}", body);
+        assert!(!body.contains("\"notes\""), "{}", body);
+    }
+}
+
+// The in-memory store with a switch: while `failure` holds an error, every call returns it
+struct FailingBooks {
+    books: InMemoryBooks,
+    failure: Mutex<Option<RepositoryError>>,
+}
+
+impl FailingBooks {
+    fn new() -> Arc<Self> {
+        Arc::new(FailingBooks {
+            books: InMemoryBooks::new(builtin_books(start())),
+            failure: Mutex::new(None),
+        })
+    }
+
+    fn fail_with(&self, failure: Option<RepositoryError>) {
+        *self.failure.lock().unwrap() = failure;
+    }
+
+    fn check(&self) -> Result<(), RepositoryError> {
+        self.failure.lock().unwrap().clone().map_or(Ok(()), Err)
+    }
+}
+
+impl BookRepository for FailingBooks {
+    fn list(&self) -> Result<Vec<Book>, RepositoryError> {
+        self.check()?;
+        self.books.list()
+    }
+
+    fn get(&self, id: u32) -> Result<Book, RepositoryError> {
+        self.check()?;
+        self.books.get(id)
+    }
+
+    fn find_by_isbn(&self, isbn: &str) -> Result<Book, RepositoryError> {
+        self.check()?;
+        self.books.find_by_isbn(isbn)
+    }
+
+    fn insert(&self, book: Book) -> Result<(), RepositoryError> {
+        self.check()?;
+        self.books.insert(book)
+    }
+
+    fn update(&self, id: u32, change: &mut dyn FnMut(&mut Book)) -> Result<Book, RepositoryError> {
+        self.check()?;
+        self.books.update(id, change)
+    }
+
+    fn delete(&self, id: u32, version: u64) -> Result<Book, RepositoryError> {
+        self.check()?;
+        self.books.delete(id, version)
+    }
+
+    fn search(&self, matches: &dyn Fn(&Book) -> bool) -> Result<Vec<Book>, RepositoryError> {
+        self.check()?;
+        self.books.search(matches)
+    }
+
+    fn page(
+        &self,
+        filter: &BookFilter,
+        request: &PageRequest,
+    ) -> Result<BookPage, RepositoryError> {
+        self.check()?;
+        self.books.page(filter, request)
+    }
+
+    fn health(&self) -> Option<Result<(), RepositoryError>> {
+        Some(self.check())
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    fn compact(&self) -> Option<Result<repository::Compaction, RepositoryError>> {
+        None
+    }
+
+    fn read_all(&self, read: &mut dyn FnMut(&[Book])) -> Result<(), RepositoryError> {
+        self.check()?;
+        self.books.read_all(read)
+    }
+
+    fn write_all(&self, write: &mut dyn FnMut(&mut Vec<Book>)) -> Result<(), RepositoryError> {
+        self.check()?;
+        self.books.write_all(write)
+    }
+}
+
+#[actix_web::test]
+async fn a_failing_store_makes_catalog_requests_unavailable() {
+    let books = FailingBooks::new();
+    let app = test::init_service(build_app(library(config(), books.clone(), None))).await;
+    let requests = || {
+        let checkout = TestRequest::post().uri("/api/books/1/checkout");
+        [
+            TestRequest::get().uri("/api/books"),
+            TestRequest::get().uri("/api/books/1"),
+            TestRequest::get().uri("/api/books/search?q=rust"),
+            TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719")),
+            checkout.set_json(json!({ "member_id": 1 })),
+        ]
+    };
+
+    books.fail_with(Some(RepositoryError::Unavailable("disk on fire".to_string())));
+    for request in requests() {
+        let reply = send(&app, request.to_request()).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.json(), json!({ "error": "The catalog is unavailable; try again later" }));
+    }
+    let health = send(&app, TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(health.status, StatusCode::SERVICE_UNAVAILABLE);
+
+    books.fail_with(None);
+    for request in requests() {
+        let reply = send(&app, request.to_request()).await;
+        assert!(reply.status.is_success(), "{}: {:?}", reply.status, reply.body);
+    }
+}
+
+#[actix_web::test]
+async fn a_delete_that_loses_a_race_is_a_conflict() {
+    let books = FailingBooks::new();
+    let app = test::init_service(build_app(library(config(), books.clone(), None))).await;
+    books.fail_with(Some(RepositoryError::Changed { id: 1, version: 2 }));
+    let delete = send(&app, TestRequest::delete().uri("/api/books/1").to_request()).await;
+    assert_eq!(delete.status, StatusCode::CONFLICT);
+    books.fail_with(None);
+    let kept = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(kept.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn a_wrong_method_is_a_json_405_listing_the_allowed_ones() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let cases = [
+        (TestRequest::post().uri("/api/books/1"), "GET, HEAD, PUT, PATCH, DELETE"),
+        (TestRequest::delete().uri("/api/books"), "GET, HEAD, POST, PUT"),
+        (TestRequest::post().uri("/api/books/search"), "GET, HEAD"),
+        (TestRequest::put().uri("/api/books/1/reviews"), "GET, POST"),
+        (TestRequest::post().uri("/health"), "GET"),
+    ];
+    for (request, allowed) in cases {
+        let reply = send(&app, request.to_request()).await;
+        assert_eq!(reply.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(reply.header("allow"), Some(allowed));
+        let error = reply.json()["error"].as_str().unwrap().to_string();
+        assert!(error.ends_with(&format!("allowed methods: {}", allowed)), "{}", error);
+    }
+}
+
+#[actix_web::test]
+async fn a_path_that_names_nothing_is_a_json_404() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let unparsed = send(&app, TestRequest::get().uri("/api/books/abc").to_request()).await;
+    assert_eq!(unparsed.status, StatusCode::NOT_FOUND);
+    let error = unparsed.json()["error"].as_str().unwrap().to_string();
+    assert!(error.starts_with("No resource at /api/books/abc: "), "{}", error);
+    let too_big = send(&app, TestRequest::get().uri("/api/books/99999999999").to_request()).await;
+    assert_eq!(too_big.status, StatusCode::NOT_FOUND);
+    assert!(too_big.json()["error"].is_string());
+
+    for uri in ["/api/nothing-here", "/nothing-here", "/api/books/1/nothing"] {
+        let reply = send(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND, "{}", uri);
+        assert_eq!(reply.json(), json!({ "error": format!("No resource at {}", uri) }));
+    }
+    let missing = send(&app, TestRequest::get().uri("/api/books/99").to_request()).await;
+    assert_eq!(missing.json()["error"], "Book with id 99 not found");
+}
+
+// The PUT body for book 1 as it stands, every field in `REPLACE_FIELDS` taken from a GET
+async fn replace_body<S, R>(app: &S, request: R) -> Value
+where
+    S: Service<R, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let current = send(app, request).await.json();
+    let fields = REPLACE_FIELDS.iter().map(|field| (field.to_string(), current[*field].clone()));
+    Value::Object(fields.collect())
+}
+
+#[actix_web::test]
+async fn put_replaces_the_whole_book_and_patch_only_what_it_names() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let before = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await.json();
+    assert!(before["genre"].is_string() && before["publisher"].is_string());
+
+    let mut body = replace_body(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    body["title"] = json!("Replaced");
+    body["genre"] = Value::Null;
+    let put = TestRequest::put().uri("/api/books/1").set_json(&body);
+    let replaced = send(&app, put.to_request()).await;
+    assert_eq!(replaced.status, StatusCode::OK);
+    let replaced = replaced.json();
+    assert_eq!(replaced["id"], 1);
+    assert_eq!(replaced["title"], "Replaced");
+    // A null in a replacement clears the field, which PATCH can't do for genre
+    assert_eq!(replaced["genre"], Value::Null);
+    assert_eq!(replaced["publisher"], before["publisher"]);
+    assert_eq!(replaced["created_at"], before["created_at"]);
+
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "title": "Patched" }));
+    let patched = send(&app, patch.to_request()).await.json();
+    assert_eq!(patched["title"], "Patched");
+    assert_eq!(patched["isbn"], replaced["isbn"]);
+    assert_eq!(patched["publisher"], replaced["publisher"]);
+    assert_eq!(patched["version"], replaced["version"].as_u64().unwrap() + 1);
+}
+
+#[actix_web::test]
+async fn an_empty_patch_changes_nothing() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let before = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({}));
+    let after = send(&app, patch.to_request()).await;
+    assert_eq!(after.status, StatusCode::OK);
+    assert_eq!(after.json(), before.json());
+    assert_eq!(after.header("etag"), before.header("etag"));
+}
+
+#[actix_web::test]
+async fn a_put_missing_fields_is_refused_and_changes_nothing() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let before = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await.json();
+    let put = TestRequest::put().uri("/api/books/1").set_json(json!({ "title": "Only this" }));
+    let reply = send(&app, put.to_request()).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    let error = reply.json()["error"].as_str().unwrap().to_string();
+    assert!(error.contains("use PATCH for partial updates"), "{}", error);
+    assert!(error.contains("missing: authors, isbn, available, "), "{}", error);
+    assert!(!error.contains("title"), "{}", error);
+    let after = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await.json();
+    assert_eq!(after, before);
+
+    // The validations of create apply to both
+    let mut body = replace_body(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    body["title"] = json!("  ");
+    let put = TestRequest::put().uri("/api/books/1").set_json(&body);
+    assert_eq!(send(&app, put.to_request()).await.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "title": "" }));
+    let status = send(&app, patch.to_request()).await.status;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let other = send(&app, TestRequest::get().uri("/api/books/2").to_request()).await.json();
+    let isbn = other["isbn"].clone();
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "isbn": isbn }));
+    assert_eq!(send(&app, patch.to_request()).await.status, StatusCode::CONFLICT);
+    body["title"] = json!("Fine");
+    body["isbn"] = isbn;
+    let put = TestRequest::put().uri("/api/books/1").set_json(&body);
+    assert_eq!(send(&app, put.to_request()).await.status, StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn a_created_book_is_at_its_location() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let create = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    let created = send(&app, create.to_request()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    let location = created.header("location").unwrap().to_string();
+    assert_eq!(location, "/api/books/3");
+    assert_eq!(created.json()["url"], location);
+
+    let fetched = send(&app, TestRequest::get().uri(&location).to_request()).await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(fetched.json(), created.json());
+
+    let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await.json();
+    for book in listed["items"].as_array().unwrap() {
+        assert_eq!(book["url"], format!("/api/books/{}", book["id"]));
+    }
+    let v1 = send(&app, TestRequest::get().uri("/api/books/3?compat=v1").to_request()).await;
+    assert_eq!(v1.json()["url"], location);
+    let fields = TestRequest::get().uri("/api/books/3?fields=id,url");
+    let fields = send(&app, fields.to_request()).await;
+    assert_eq!(fields.json(), json!({ "id": 3, "url": location }));
+}
+
+fn raw_create(content_type: Option<&str>, body: &'static str) -> TestRequest {
+    let request = TestRequest::post().uri("/api/books").set_payload(body);
+    match content_type {
+        Some(content_type) => request.insert_header(("Content-Type", content_type)),
+        None => request,
+    }
+}
+
+#[actix_web::test]
+async fn a_body_that_does_not_parse_is_a_json_400() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let json = Some("application/json");
+
+    let truncated = raw_create(json, r#"{"title": "H", "authors": ["#);
+    let truncated = send(&app, truncated.to_request()).await;
+    assert_eq!(truncated.status, StatusCode::BAD_REQUEST);
+    let error = truncated.json();
+    assert!(error["error"].as_str().unwrap().contains("EOF while parsing"), "{}", error);
+    assert_eq!(error["line"], 1);
+    assert_eq!(error["column"], 27);
+
+    let wrong_type = r#"{"title": "H", "isbn": "9780441172719", "total_copies": "yes"}"#;
+    let wrong_type = send(&app, raw_create(json, wrong_type).to_request()).await;
+    assert_eq!(wrong_type.status, StatusCode::BAD_REQUEST);
+    let error = wrong_type.json();
+    assert!(error["error"].as_str().unwrap().contains("expected u32"), "{}", error);
+    assert!(error["column"].is_u64());
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "available": "yes" }));
+    let error = send(&app, patch.to_request()).await.json();
+    assert!(error["error"].as_str().unwrap().contains("expected a boolean"), "{}", error);
+
+    let missing = raw_create(json, r#"{"title": "H", "authors": ["A"]}"#);
+    let missing = send(&app, missing.to_request()).await;
+    assert_eq!(missing.status, StatusCode::BAD_REQUEST);
+    assert_eq!(missing.json()["field"], "isbn");
+
+    let empty = send(&app, raw_create(json, "").to_request()).await;
+    assert_eq!(empty.status, StatusCode::BAD_REQUEST);
+    let error = "Request body is empty; expected a JSON object";
+    assert_eq!(empty.json(), json!({ "error": error }));
+    for content_type in [Some("text/plain"), None] {
+        let reply = send(&app, raw_create(content_type, "{}").to_request()).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        let error = "Content-Type must be application/json";
+        assert_eq!(reply.json(), json!({ "error": error }));
+    }
+    let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    assert_eq!(listed.json()["total"], 2);
+}
+
+// A create body of exactly `size` bytes, padded out in the description
+fn create_body_of(size: usize) -> String {
+    let book = |description: &str| {
+        let mut book = new_book("H", "9780441172719");
+        book["description"] = json!(description);
+        book.to_string()
+    };
+    let padding = size - book("").len();
+    book(&"x".repeat(padding))
+}
+
+#[actix_web::test]
+async fn a_body_over_the_limit_is_a_json_413() {
+    let mut config = config();
+    config.max_body_bytes = 1000;
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let app = test::init_service(build_app(library(config, books, None))).await;
+    let post = |body: String| {
+        let request = TestRequest::post().uri("/api/books");
+        request.insert_header(("Content-Type", "application/json")).set_payload(body)
+    };
+
+    let over = send(&app, post(create_body_of(1001)).to_request()).await;
+    assert_eq!(over.status, StatusCode::PAYLOAD_TOO_LARGE);
+    let error = "Request body exceeds the limit of 1000 bytes";
+    assert_eq!(over.json(), json!({ "error": error }));
+    let patch = TestRequest::patch()
+        .uri("/api/books/1")
+        .insert_header(("Content-Type", "application/json"))
+        .set_payload(format!(r#"{{"description": "{}"}}"#, "x".repeat(1000)));
+    assert_eq!(send(&app, patch.to_request()).await.status, StatusCode::PAYLOAD_TOO_LARGE);
+    let listed = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    assert_eq!(listed.json()["total"], 2);
+
+    let at = send(&app, post(create_body_of(1000)).to_request()).await;
+    assert_eq!(at.status, StatusCode::CREATED);
+    let health = send(&app, TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(health.json()["limits"]["max_body_bytes"], 1000);
+}
+
+// What a real server sends back for `request` (method and path), read off the socket.
+// The test service hands back whatever body the handler built, and leaving it out of a
+// HEAD reply is the server's job, so HEAD can only be checked this way.
+async fn over_the_wire(data: web::Data<AppState>, requests: &[&str]) -> Vec<(String, String)> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = actix_web::HttpServer::new(move || build_app(data.clone()))
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let mut replies = Vec::new();
+    for request in requests {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let head = format!("{} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", request);
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).await.unwrap();
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        replies.push((head.to_lowercase(), body.to_string()));
+    }
+    handle.stop(true).await;
+    replies
+}
+
+// The value of header `name` in a lowercased response head
+fn head_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+    head.lines().find_map(|line| line.strip_prefix(&prefix))
+}
+
+#[actix_web::test]
+async fn head_sends_the_get_headers_without_a_body() {
+    let paths = ["/api/books/1", "/api/books", "/api/books/search?author=Klabnik"];
+    let mut requests = Vec::new();
+    for path in paths {
+        requests.push(format!("GET {}", path));
+        requests.push(format!("HEAD {}", path));
+    }
+    requests.push("HEAD /api/books/999".to_string());
+    requests.push("HEAD /api/books?limit=abc".to_string());
+    let requests: Vec<&str> = requests.iter().map(String::as_str).collect();
+    let replies = over_the_wire(memory_library(), &requests).await;
+
+    for (path, pair) in paths.iter().zip(replies.chunks(2)) {
+        let ((get, get_body), (head, head_body)) = (&pair[0], &pair[1]);
+        assert!(head.starts_with("http/1.1 200"), "{}: {}", path, head);
+        assert_eq!(head_body, "", "{}", path);
+        assert!(!get_body.is_empty(), "{}", path);
+        let length = get_body.len().to_string();
+        assert_eq!(head_value(head, "content-length"), Some(length.as_str()), "{}", path);
+        for name in ["content-type", "etag", "x-total-count"] {
+            assert_eq!(head_value(head, name), head_value(get, name), "{}: {}", path, name);
+        }
+    }
+    assert!(head_value(&replies[1].0, "etag").is_some());
+    assert!(head_value(&replies[3].0, "x-total-count").is_some());
+    let (missing, missing_body) = &replies[6];
+    assert!(missing.starts_with("http/1.1 404"), "{}", missing);
+    assert_eq!(missing_body, "");
+    let (invalid, invalid_body) = &replies[7];
+    assert!(invalid.starts_with("http/1.1 400"), "{}", invalid);
+    assert_eq!(invalid_body, "");
+}
+
+#[actix_web::test]
+async fn a_catalog_put_back_as_it_was_read_comes_back_the_same() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let review = json!({ "rating": 4, "reviewer": "Ann", "comment": "Clear" });
+    let review = TestRequest::post().uri("/api/books/1/reviews").set_json(review);
+    assert_eq!(send(&app, review.to_request()).await.status, StatusCode::CREATED);
+    let first = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+
+    let put = TestRequest::put().uri("/api/books").set_json(&first.json()["items"]);
+    let replaced = send(&app, put.to_request()).await;
+    assert_eq!(replaced.status, StatusCode::OK, "{:?}", replaced.body);
+    let second = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    assert_eq!(second.body, first.body);
+    assert_eq!(second.json()["items"][0]["review_count"], 1);
+}
+
+#[actix_web::test]
+async fn a_catalog_with_bad_records_lists_each_one_and_changes_nothing() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let before = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    let mut records = before.json()["items"].as_array().unwrap().clone();
+    records[0]["title"] = json!("");
+    let mut repeated_id = new_book("Repeated id", "9780441172719");
+    repeated_id["id"] = records[1]["id"].clone();
+    // Book 2's ISBN as an ISBN-10 is still book 2's ISBN
+    let repeated_isbn = new_book("Repeated ISBN", "1492052590");
+    records.extend([repeated_id, repeated_isbn, json!("not a book")]);
+    let put = TestRequest::put().uri("/api/books").set_json(&records);
+    let reply = send(&app, put.to_request()).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+    let reply = reply.json();
+    let error = "Catalog rejected: 4 of 5 books are invalid; nothing was changed";
+    assert_eq!(reply["error"], error);
+    let errors = reply["errors"].as_array().unwrap();
+    let indexes: Vec<u64> = errors.iter().map(|e| e["index"].as_u64().unwrap()).collect();
+    assert_eq!(indexes, [0, 2, 3, 4]);
+    assert!(errors.iter().all(|e| e["status"] == 400 && e["error"].is_string()));
+    assert_eq!(errors[1]["error"], "Duplicate id within the catalog (same as index 1)");
+    assert_eq!(errors[2]["error"], "Duplicate ISBN within the catalog (same as index 1)");
+
+    let after = send(&app, TestRequest::get().uri("/api/books").to_request()).await;
+    assert_eq!(after.body, before.body);
+    let create = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    assert_eq!(send(&app, create.to_request()).await.json()["id"], 3);
+}
+
+fn checkout(book: u32, member: u32) -> TestRequest {
+    let request = TestRequest::post().uri(&format!("/api/books/{}/checkout", book));
+    request.set_json(json!({ "member_id": member }))
+}
+
+fn renew(loan: &Value) -> TestRequest {
+    TestRequest::post().uri(&format!("/api/loans/{}/renew", loan["id"]))
+}
+
+#[actix_web::test]
+async fn a_loan_renews_twice_and_then_hits_the_limit() {
+    let mut config = config();
+    config.max_renewals = 2;
+    let period = config.loan_period;
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let app = test::init_service(build_app(library(config, books, None))).await;
+    let lent = send(&app, checkout(1, 1).to_request()).await;
+    let loan = TestRequest::get().uri(lent.header("location").unwrap());
+    let loan = send(&app, loan.to_request()).await.json();
+    assert_eq!(loan["renewal_count"], 0);
+    let due: chrono::DateTime<chrono::Utc> = loan["due_at"].as_str().unwrap().parse().unwrap();
+
+    for count in 1..=2 {
+        let renewed = send(&app, renew(&loan).to_request()).await;
+        assert_eq!(renewed.status, StatusCode::OK);
+        let renewed = renewed.json();
+        assert_eq!(renewed["renewal_count"], count);
+        let due_at: chrono::DateTime<chrono::Utc> =
+            renewed["due_at"].as_str().unwrap().parse().unwrap();
+        assert_eq!(due_at, due + period * count);
+    }
+    let before = send(&app, TestRequest::get().uri("/api/loans").to_request()).await;
+    let third = send(&app, renew(&loan).to_request()).await;
+    assert_eq!(third.status, StatusCode::CONFLICT);
+    let error = format!("Loan with id {} has reached the renewal limit (2 of 2)", loan["id"]);
+    assert_eq!(third.json()["error"], error);
+    let after = send(&app, TestRequest::get().uri("/api/loans").to_request()).await;
+    assert_eq!(after.body, before.body);
+}
+
+#[actix_web::test]
+async fn returned_and_overdue_loans_do_not_renew() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let lent = send(&app, checkout(1, 1).to_request()).await;
+    let returned = TestRequest::get().uri(lent.header("location").unwrap());
+    let returned = send(&app, returned.to_request()).await.json();
+    let give_back = TestRequest::post().uri("/api/books/1/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    let reply = send(&app, renew(&returned).to_request()).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    let error = format!("Loan with id {} has already been returned", returned["id"]);
+    assert_eq!(reply.json()["error"], error);
+
+    let lent = send(&app, checkout(2, 1).to_request()).await;
+    let overdue = TestRequest::get().uri(lent.header("location").unwrap());
+    let overdue = send(&app, overdue.to_request()).await.json();
+    data.clock.set(start() + data.config.loan_period + chrono::Duration::days(1));
+    let reply = send(&app, renew(&overdue).to_request()).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    let error = reply.json()["error"].as_str().unwrap().to_string();
+    assert!(error.contains("is overdue"), "{}", error);
+}
+
+#[actix_web::test]
+async fn a_member_at_the_loan_limit_is_told_what_they_hold() {
+    let mut config = config();
+    config.max_open_loans = 2;
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let app = test::init_service(build_app(library(config, books, None))).await;
+    let create = TestRequest::post().uri("/api/books").set_json(new_book("H", "9780441172719"));
+    assert_eq!(send(&app, create.to_request()).await.status, StatusCode::CREATED);
+    for book in [1, 2] {
+        assert_eq!(send(&app, checkout(book, 1).to_request()).await.status, StatusCode::OK);
+    }
+    let book_before = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+
+    let refused = send(&app, checkout(3, 1).to_request()).await;
+    assert_eq!(refused.status, StatusCode::CONFLICT);
+    let refused = refused.json();
+    let error = "Member with id 1 already has 2 books checked out, the most allowed";
+    assert_eq!(refused["error"], error);
+    let held = refused["loans"].as_array().unwrap();
+    let held_books: Vec<&Value> = held.iter().map(|loan| &loan["book_id"]).collect();
+    assert_eq!(held_books, [1, 2]);
+    assert_eq!(held[0]["title"], "The Rust Programming Language");
+    assert_eq!(held[1]["title"], "Programming Rust");
+    assert!(held.iter().all(|loan| loan["loan_id"].is_u64() && loan["due_at"].is_string()));
+    let book_after = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    assert_eq!(book_after.body, book_before.body);
+    let loans = send(&app, TestRequest::get().uri("/api/loans").to_request()).await.json();
+    assert_eq!(loans.as_array().unwrap().len(), 2);
+
+    let member = json!({ "name": "Bo Park", "email": "bo.park@example.com" });
+    let member = TestRequest::post().uri("/api/members").set_json(member);
+    let member = send(&app, member.to_request()).await.json();
+    let other = checkout(3, member["id"].as_u64().unwrap() as u32);
+    assert_eq!(send(&app, other.to_request()).await.status, StatusCode::OK);
+    let give_back = TestRequest::post().uri("/api/books/3/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    let give_back = TestRequest::post().uri("/api/books/1/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    assert_eq!(send(&app, checkout(3, 1).to_request()).await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn turning_history_off_scrubs_the_member_from_returned_loans() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    for book in [1, 2] {
+        assert_eq!(send(&app, checkout(book, 1).to_request()).await.status, StatusCode::OK);
+        let give_back = TestRequest::post().uri(&format!("/api/books/{}/return", book));
+        assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    }
+    assert_eq!(send(&app, checkout(1, 1).to_request()).await.status, StatusCode::OK);
+    let history = TestRequest::get().uri("/api/members/1/history");
+    let history = send(&app, history.to_request()).await.json();
+    assert_eq!(history["items"].as_array().unwrap().len(), 2, "{}", history);
+
+    let member = json!({
+        "name": "Ann Lee",
+        "email": "ann.lee@example.com",
+        "retain_history": false
+    });
+    let update = TestRequest::put().uri("/api/members/1").set_json(member);
+    assert_eq!(send(&app, update.to_request()).await.status, StatusCode::OK);
+
+    let history = TestRequest::get().uri("/api/members/1/history");
+    let history = send(&app, history.to_request()).await.json();
+    assert_eq!(history["items"], json!([]));
+    // Gone from the store itself, not just hidden from the member's views
+    let stored: Vec<(bool, Option<u32>)> =
+        data.loans.lock().unwrap().iter().map(|l| (l.is_open(), l.member_id)).collect();
+    assert_eq!(stored, [(false, None), (false, None), (true, Some(1))]);
+    let by_member = TestRequest::get().uri("/api/loans?member_id=1");
+    let by_member = send(&app, by_member.to_request()).await.json();
+    assert_eq!(by_member.as_array().unwrap().len(), 1);
+    assert_eq!(by_member[0]["returned_at"], Value::Null);
+    let book_loans = send(&app, TestRequest::get().uri("/api/books/2/loans").to_request()).await;
+    assert_eq!(book_loans.json()["items"][0]["member_id"], Value::Null);
+}
+
+#[actix_web::test]
+async fn an_uncollected_hold_expires_when_the_clock_passes_its_window() {
+    let mut config = config();
+    config.hold_pickup_window = chrono::Duration::days(7);
+    let books = Arc::new(InMemoryBooks::new(builtin_books(start())));
+    let data = library(config, books, None);
+    let app = test::init_service(build_app(data.clone())).await;
+    for name in ["Bo Park", "Cy Dunn"] {
+        let email = format!("{}@example.com", name.to_lowercase().replace(' ', "."));
+        let member = TestRequest::post().uri("/api/members");
+        let member = member.set_json(json!({ "name": name, "email": email }));
+        assert_eq!(send(&app, member.to_request()).await.status, StatusCode::CREATED);
+    }
+    assert_eq!(send(&app, checkout(2, 1).to_request()).await.status, StatusCode::OK);
+    for member in [2, 3] {
+        let hold = TestRequest::post().uri("/api/books/2/holds");
+        let hold = send(&app, hold.set_json(json!({ "member_id": member })).to_request()).await;
+        assert_eq!(hold.status, StatusCode::CREATED, "{:?}", hold.body);
+    }
+    let give_back = TestRequest::post().uri("/api/books/2/return");
+    assert_eq!(send(&app, give_back.to_request()).await.status, StatusCode::OK);
+    let holds = send(&app, TestRequest::get().uri("/api/books/2/holds").to_request()).await;
+    let holds = holds.json();
+    assert_eq!(holds[0]["member_id"], 2);
+    assert_eq!(holds[0]["pickup_by"], "2026-01-22T12:00:00Z");
+    assert_eq!(holds[1]["pickup_by"], Value::Null);
+
+    let window = chrono::Duration::days(7);
+    data.clock.set(start() + window - chrono::Duration::seconds(1));
+    assert_eq!(expire_holds(&data), 0);
+    data.clock.set(start() + window);
+    assert_eq!(expire_holds(&data), 1);
+    let holds = send(&app, TestRequest::get().uri("/api/books/2/holds").to_request()).await;
+    let holds = holds.json();
+    assert_eq!(holds.as_array().unwrap().len(), 1);
+    assert_eq!(holds[0]["member_id"], 3);
+    assert_eq!(holds[0]["pickup_by"], "2026-01-29T12:00:00Z");
+    let book = send(&app, TestRequest::get().uri("/api/books/2").to_request()).await;
+    assert_eq!(book.json()["hold_count"], 1);
+
+    let events = send(&app, TestRequest::get().uri("/api/events").to_request()).await.json();
+    let expired: Vec<&Value> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["kind"] == "hold_expired")
+        .collect();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0]["book_id"], 2);
+    assert_eq!(expired[0]["member_id"], 2);
+    assert_eq!(expired[0]["at"], "2026-01-22T12:00:00Z");
+    // Member 3 collects in time, so nothing is left to expire
+    assert_eq!(send(&app, checkout(2, 3).to_request()).await.status, StatusCode::OK);
+    data.clock.set(start() + window * 3);
+    assert_eq!(expire_holds(&data), 0);
+}
+
+#[actix_web::test]
+async fn the_random_book_follows_the_injected_sequence() {
+    let app = test::init_service(build_app(memory_library())).await;
+    // The same generator the test library was given, so it predicts every pick
+    let expected = random::SplitMix::new(42);
+    let mut seen = Vec::new();
+    for _ in 0..10 {
+        let reply = send(&app, TestRequest::get().uri("/api/books/random").to_request()).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let id = reply.json()["id"].as_u64().unwrap();
+        assert_eq!(id, [1, 2][random::Rng::below(&expected, 2)]);
+        seen.push(id);
+    }
+    assert!(seen.contains(&1) && seen.contains(&2), "{:?}", seen);
+
+    let filtered = TestRequest::get().uri("/api/books/random?genre=Programming&compat=v1");
+    let filtered = send(&app, filtered.to_request()).await.json();
+    assert!(filtered["author"].is_string(), "{}", filtered);
+    let bad = TestRequest::get().uri("/api/books/random?available=maybe");
+    assert_eq!(send(&app, bad.to_request()).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn the_book_of_the_day_holds_for_the_day() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let featured = || TestRequest::get().uri("/api/books/featured").to_request();
+    let first: chrono::DateTime<chrono::Utc> = "2026-01-15T00:00:00Z".parse().unwrap();
+    let mut picks = Vec::new();
+    for day in 0..10 {
+        let midnight = first + chrono::Duration::days(day);
+        data.clock.set(midnight);
+        let pick = send(&app, featured()).await.json()["id"].clone();
+        let expected = 1 + random::pick_for_day(midnight.date_naive(), 2);
+        assert_eq!(pick, expected);
+        // Later the same day, and from the same book however many times it's asked for
+        data.clock.set(midnight + chrono::Duration::hours(23));
+        for _ in 0..3 {
+            assert_eq!(send(&app, featured()).await.json()["id"], pick);
+        }
+        picks.push(pick);
+    }
+    assert!(picks.iter().any(|pick| *pick != picks[0]), "{:?}", picks);
+}
+
+#[actix_web::test]
+async fn no_book_to_pick_is_a_friendly_404() {
+    let app = test::init_service(build_app(memory_library())).await;
+    for book in [1, 2] {
+        let patch = TestRequest::patch().uri(&format!("/api/books/{}", book));
+        let patch = patch.set_json(json!({ "available": false }));
+        assert_eq!(send(&app, patch.to_request()).await.status, StatusCode::OK);
+    }
+    let error = json!({ "error": "No books match these filters; try fewer of them" });
+    for endpoint in ["random", "featured"] {
+        for filter in ["genre=Poetry", "available=true"] {
+            let uri = format!("/api/books/{}?{}", endpoint, filter);
+            let reply = send(&app, TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{}", uri);
+            assert_eq!(reply.json(), error, "{}", uri);
+        }
+    }
+}
+
+// A tag depends on nothing but the record, so it is the same on every build and every run
+#[test]
+fn a_book_etag_is_a_fixed_digest_of_the_record() {
+    let mut book = builtin_books(start()).remove(0);
+    assert_eq!(book_etag(&book), "\"1-062dd80fd62204a2\"");
+    book.review_count = 1;
+    let reviewed = book_etag(&book);
+    assert!(reviewed.starts_with("\"1-"), "{}", reviewed);
+    assert_ne!(reviewed, "\"1-062dd80fd62204a2\"");
+    assert_eq!(book_etag(&book), reviewed);
+}
+
+#[actix_web::test]
+async fn stored_text_is_trimmed_collapsed_and_composed() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let mut book = new_book("  The   Book  ", "9780441172719");
+    book["authors"] = json!(["  Ann   Author "]);
+    book["description"] = json!("  First line\nsecond  line  ");
+    let create = TestRequest::post().uri("/api/books").set_json(book);
+    let created = send(&app, create.to_request()).await.json();
+    assert_eq!(created["title"], "The Book");
+    assert_eq!(created["authors"], json!(["Ann Author"]));
+    assert_eq!(created["description"], "First line\nsecond  line");
+    let fetched = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    assert_eq!(fetched.json(), created);
+
+    let patch = |title: &str| {
+        let request = TestRequest::patch().uri("/api/books/3");
+        request.set_json(json!({ "title": title })).to_request()
+    };
+    let nfd = send(&app, patch("Cafe\u{301}   Society ")).await.json();
+    assert_eq!(nfd["title"], "Caf\u{e9} Society");
+    // Already the stored form once cleaned, so nothing changes
+    let same = send(&app, patch("Caf\u{e9} Society")).await.json();
+    assert_eq!(same["version"], nfd["version"]);
+    let blank = send(&app, patch("    ")).await;
+    assert_eq!(blank.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn control_characters_are_refused_and_zero_width_ones_dropped() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let create = |book: Value| TestRequest::post().uri("/api/books").set_json(book).to_request();
+
+    let refused = send(&app, create(new_book("Two\nLines", "9780441172719"))).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let error = "Title cannot contain control characters (found U+000A)";
+    assert_eq!(refused.json()["error"], error);
+    let mut book = new_book("Fine", "9780441172719");
+    book["authors"] = json!(["Next\u{85}Line"]);
+    let refused = send(&app, create(book)).await.json();
+    assert!(refused["error"].as_str().unwrap().starts_with("Author cannot"), "{}", refused);
+    let refused = send(&app, create(new_book("Fine", "978044117\t2719"))).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(refused.json()["errors"][0]["field"], "isbn");
+
+    let created = send(&app, create(new_book("Zero\u{200b}Width", "97804411\u{200b}72719"))).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    let created = created.json();
+    assert_eq!(created["title"], "ZeroWidth");
+    assert_eq!(created["isbn"], "9780441172719");
+
+    let before = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    let patch = TestRequest::patch().uri("/api/books/3").set_json(json!({ "title": "A\tB" }));
+    let refused = send(&app, patch.to_request()).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let after = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    assert_eq!(after.body, before.body);
+}
+
+// The fields a 422 names, in the order it lists them
+fn error_fields(reply: &Value) -> Vec<&Value> {
+    reply["errors"].as_array().unwrap().iter().map(|e| &e["field"]).collect()
+}
+
+#[actix_web::test]
+async fn every_invalid_field_is_reported_together() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let book = json!({ "title": "", "author": "", "isbn": "978" });
+    let create = TestRequest::post().uri("/api/books").set_json(book);
+    let reply = send(&app, create.to_request()).await;
+    assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let reply = reply.json();
+    assert_eq!(error_fields(&reply), ["title", "author", "isbn"]);
+    let errors = reply["errors"].as_array().unwrap();
+    assert_eq!(reply["error"], errors[0]["message"]);
+    assert!(errors.iter().all(|e| e["message"].is_string()));
+
+    let book = json!({ "title": "", "authors": [""], "isbn": "978" });
+    let create = TestRequest::post().uri("/api/books").set_json(book);
+    let reply = send(&app, create.to_request()).await.json();
+    assert_eq!(reply["errors"][1]["field"], "authors");
+
+    let single = TestRequest::post().uri("/api/books").set_json(new_book("", "9780441172719"));
+    let single = send(&app, single.to_request()).await.json();
+    assert_eq!(single["errors"].as_array().unwrap().len(), 1);
+
+    let before = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    let patch = json!({ "title": "", "available": true, "available_copies": 0 });
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(patch);
+    let reply = send(&app, patch.to_request()).await;
+    assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_fields(&reply.json()), ["title", "available"]);
+    let after = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(after.body, before.body);
+}
+
+#[actix_web::test]
+async fn search_by_title_alone_with_an_author_and_with_no_match() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let book = new_book("Über Bücher", "9780441172719");
+    let create = TestRequest::post().uri("/api/books").set_json(book);
+    assert_eq!(send(&app, create.to_request()).await.status, StatusCode::CREATED);
+    let search = |query: &str| TestRequest::get().uri(&format!("/api/books/search?{}", query));
+
+    let title = send(&app, search("title=rust").to_request()).await.json();
+    assert_eq!(book_ids(&title["items"]), [1, 2]);
+    let unicode = send(&app, search("title=%C3%9CBER").to_request()).await.json();
+    assert_eq!(book_ids(&unicode["items"]), [3]);
+    let both = send(&app, search("title=rust&author=klabnik").to_request()).await.json();
+    assert_eq!(book_ids(&both["items"]), [1]);
+    let combined = send(&app, search("title=rust&available=true").to_request()).await.json();
+    assert_eq!(book_ids(&combined["items"]), [1, 2]);
+
+    let none = send(&app, search("title=cobol").to_request()).await;
+    assert_eq!(none.status, StatusCode::OK);
+    assert_eq!(none.json()["items"], json!([]));
+    assert_eq!(none.json()["total"], 0);
+    let crossed = send(&app, search("title=b%C3%BCcher&author=klabnik").to_request()).await.json();
+    assert_eq!(crossed["items"], json!([]));
+}
+
+#[actix_web::test]
+async fn every_q_token_must_match_the_same_book() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let search = |query: &str| TestRequest::get().uri(&format!("/api/books/search?{}", query));
+
+    // "language" is only in book 1's title and "klabnik" only in its authors
+    let across_fields = send(&app, search("q=Language+KLABNIK").to_request()).await.json();
+    assert_eq!(book_ids(&across_fields["items"]), [1]);
+    let with_isbn = send(&app, search("q=klabnik+9781718500440").to_request()).await.json();
+    assert_eq!(book_ids(&with_isbn["items"]), [1]);
+
+    // "klabnik" is only in book 1 and "blandy" only in book 2, so no book has both
+    let split = send(&app, search("q=klabnik+blandy").to_request()).await;
+    assert_eq!(split.status, StatusCode::OK);
+    assert_eq!(split.json()["items"], json!([]));
+
+    let filtered = send(&app, search("q=rust&author=blandy&available=true").to_request()).await;
+    assert_eq!(book_ids(&filtered.json()["items"]), [2]);
+}
+
+#[actix_web::test]
+async fn the_last_page_is_partial_and_pages_past_it_are_empty() {
+    let app = test::init_service(build_app(memory_library())).await;
+    for (title, isbn) in [("Dune", "9780441172719"), ("Neuromancer", "9780441569595")] {
+        let create = TestRequest::post().uri("/api/books").set_json(new_book(title, isbn));
+        assert_eq!(send(&app, create.to_request()).await.status, StatusCode::CREATED);
+    }
+    for base in ["/api/books?", "/api/books/search?"] {
+        let page = |query: &str| TestRequest::get().uri(&format!("{}{}", base, query));
+        let last = send(&app, page("page=2&limit=3").to_request()).await.json();
+        assert_eq!(book_ids(&last["items"]), [4], "{}", base);
+        assert_eq!(last["page"], 2);
+        assert_eq!(last["limit"], 3);
+        assert_eq!(last["total"], 4);
+
+        for query in ["page=3&limit=3", "page=1000&limit=3"] {
+            let past = send(&app, page(query).to_request()).await;
+            assert_eq!(past.status, StatusCode::OK, "{}{}", base, query);
+            let past = past.json();
+            assert_eq!(past["items"], json!([]), "{}{}", base, query);
+            assert_eq!(past["total"], 4);
+        }
+        for query in ["limit=0", "limit=-1", "limit=abc", "page=0", "page=-2"] {
+            let bad = send(&app, page(query).to_request()).await;
+            assert_eq!(bad.status, StatusCode::BAD_REQUEST, "{}{}", base, query);
+            assert!(bad.json()["error"].is_string());
+        }
+    }
+}
+
+#[actix_web::test]
+async fn unknown_and_misspelled_search_parameters_are_refused() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let search = |query: &str| TestRequest::get().uri(&format!("/api/books/search?{}", query));
+
+    for (query, name) in [("colour=blue", "colour"), ("auther=klabnik", "auther")] {
+        let reply = send(&app, search(query).to_request()).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", query);
+        let error = reply.json()["error"].as_str().unwrap().to_string();
+        assert!(error.starts_with("Invalid query parameter: "), "{}", error);
+        assert!(error.contains(&format!("unknown field `{}`", name)), "{}", error);
+    }
+    let valid = send(&app, search("title=rust&author=klabnik&available=true").to_request()).await;
+    assert_eq!(valid.status, StatusCode::OK);
+    assert_eq!(book_ids(&valid.json()["items"]), [1]);
+}
+
+#[actix_web::test]
+async fn the_available_filter_parses_only_true_and_false() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let patch = TestRequest::patch().uri("/api/books/2").set_json(json!({ "available": false }));
+    assert_eq!(send(&app, patch.to_request()).await.status, StatusCode::OK);
+
+    let cases: [(&str, StatusCode, &[u64]); 7] = [
+        ("true", StatusCode::OK, &[1]),
+        ("false", StatusCode::OK, &[2]),
+        ("TRUE", StatusCode::OK, &[1]),
+        ("1", StatusCode::OK, &[1]),
+        ("0", StatusCode::OK, &[2]),
+        ("yes", StatusCode::BAD_REQUEST, &[]),
+        ("", StatusCode::BAD_REQUEST, &[]),
+    ];
+    for (value, status, expected) in cases {
+        let uri = format!("/api/books/search?available={}", value);
+        let reply = send(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(reply.status, status, "{:?}", value);
+        if status == StatusCode::OK {
+            assert_eq!(book_ids(&reply.json()["items"]), expected, "{:?}", value);
+        } else {
+            let error = format!(
+                "Invalid query parameter: available must be 'true' or 'false' \
+                 (case-insensitive, '1' and '0' also accepted), got '{}'",
+                value
+            );
+            assert_eq!(reply.json()["error"], error);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn exclusions_apply_alone_after_inclusions_and_can_empty_the_result() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let create = TestRequest::post().uri("/api/books").set_json(new_book("Dune", "9780441172719"));
+    assert_eq!(send(&app, create.to_request()).await.status, StatusCode::CREATED);
+    let search = |query: &str| TestRequest::get().uri(&format!("/api/books/search?{}", query));
+
+    let alone = send(&app, search("author_not=KLABNIK").to_request()).await.json();
+    assert_eq!(book_ids(&alone["items"]), [2, 3]);
+    let title_not = send(&app, search("title_not=rust").to_request()).await.json();
+    assert_eq!(book_ids(&title_not["items"]), [3]);
+    let mixed = send(&app, search("title=rust&author_not=Klabnik").to_request()).await.json();
+    assert_eq!(book_ids(&mixed["items"]), [2]);
+
+    let contradictory = send(&app, search("author=klabnik&author_not=klabnik").to_request()).await;
+    assert_eq!(contradictory.status, StatusCode::OK);
+    assert_eq!(contradictory.json()["items"], json!([]));
+    assert_eq!(contradictory.json()["total"], 0);
+}
+
+#[actix_web::test]
+async fn the_total_count_header_is_the_filtered_total_even_when_zero() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let cases = [
+        ("/api/books?limit=1", "2"),
+        ("/api/books/search?title=rust&limit=1", "2"),
+        ("/api/books/search?author=klabnik", "1"),
+        ("/api/books/search?title=cobol", "0"),
+        ("/api/books?page=5", "2"),
+    ];
+    for (uri, total) in cases {
+        let reply = send(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", uri);
+        assert_eq!(reply.header("x-total-count"), Some(total), "{}", uri);
+    }
+    let count = TestRequest::get().uri("/api/books/count?title=cobol");
+    assert_eq!(send(&app, count.to_request()).await.json(), json!({ "count": 0 }));
+}
+
+#[actix_web::test]
+async fn total_copies_cannot_drop_below_the_copies_on_loan() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let member = json!({ "name": "Bo Yang", "email": "bo@example.com" });
+    let join = TestRequest::post().uri("/api/members").set_json(member);
+    assert_eq!(send(&app, join.to_request()).await.status, StatusCode::CREATED);
+    for member in [1, 2] {
+        assert_eq!(send(&app, checkout(1, member).to_request()).await.status, StatusCode::OK);
+    }
+    let before = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(before.json()["available_copies"], 0);
+    assert_eq!(before.json()["checked_out_copies"], 2);
+
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "total_copies": 1 }));
+    let refused = send(&app, patch.to_request()).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let refused = refused.json();
+    assert_eq!(error_fields(&refused), ["available_copies"]);
+    let message = "plus the 2 copies checked out cannot exceed total_copies (1)";
+    assert_eq!(refused["error"], format!("available_copies (0) {}", message));
+    let after = send(&app, TestRequest::get().uri("/api/books/1").to_request()).await;
+    assert_eq!(after.body, before.body);
+
+    let patch = TestRequest::patch().uri("/api/books/1").set_json(json!({ "total_copies": 2 }));
+    let lowered = send(&app, patch.to_request()).await;
+    assert_eq!(lowered.status, StatusCode::OK);
+    assert_eq!(lowered.json()["total_copies"], 2);
+}
+
+#[actix_web::test]
+async fn cover_urls_must_use_http_or_https() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let mut book = new_book("Dune", "9780441172719");
+    book["cover_url"] = json!("https://covers.example.com/a.png");
+    let create = TestRequest::post().uri("/api/books").set_json(&book);
+    let created = send(&app, create.to_request()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    assert_eq!(created.json()["cover_url"], "https://covers.example.com/a.png");
+
+    let refused_urls = [
+        ("ftp://covers.example.com/a.png", "ftp"),
+        ("javascript:alert(1)", "javascript"),
+    ];
+    for (url, scheme) in refused_urls {
+        let message = format!("cover_url must use http or https, got '{}'", scheme);
+        let mut book = new_book("Neuromancer", "9780441569595");
+        book["cover_url"] = json!(url);
+        let create = TestRequest::post().uri("/api/books").set_json(&book);
+        let refused = send(&app, create.to_request()).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+        assert_eq!(error_fields(&refused.json()), ["cover_url"]);
+        assert_eq!(refused.json()["error"], message);
+
+        let before = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+        let patch = TestRequest::patch().uri("/api/books/3").set_json(json!({ "cover_url": url }));
+        let refused = send(&app, patch.to_request()).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+        assert_eq!(refused.json()["error"], message);
+        let after = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+        assert_eq!(after.body, before.body);
+    }
+    let count = send(&app, TestRequest::get().uri("/api/books/count").to_request()).await;
+    assert_eq!(count.json(), json!({ "count": 3 }));
+}
+
+#[actix_web::test]
+async fn length_limits_accept_the_limit_and_refuse_one_more() {
+    let app = test::init_service(build_app(memory_library())).await;
+    let create = |book: &Value| TestRequest::post().uri("/api/books").set_json(book);
+    let at_limit = "a".repeat(500);
+    let over = "a".repeat(501);
+
+    let mut book = new_book(&over, "9780441172719");
+    let refused = send(&app, create(&book).to_request()).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(refused.json()["error"], "Title cannot be longer than 500 characters");
+    book["title"] = json!("é".repeat(500));
+    book["authors"] = json!([over]);
+    let refused = send(&app, create(&book).to_request()).await;
+    assert_eq!(refused.json()["error"], "Author cannot be longer than 500 characters");
+    book["authors"] = json!([at_limit]);
+    let created = send(&app, create(&book).to_request()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    assert_eq!(created.json()["authors"][0], at_limit.as_str());
+
+    let patch = |title: &str| {
+        let patch = TestRequest::patch().uri("/api/books/3");
+        patch.set_json(json!({ "title": title }))
+    };
+    let updated = send(&app, patch(&at_limit).to_request()).await;
+    assert_eq!(updated.status, StatusCode::OK);
+    assert_eq!(updated.json()["title"], at_limit.as_str());
+    let before = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    let refused = send(&app, patch(&over).to_request()).await;
+    assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let after = send(&app, TestRequest::get().uri("/api/books/3").to_request()).await;
+    assert_eq!(after.body, before.body);
+
+    // Twenty characters get past the length limit to the ISBN check itself
+    let isbn_errors = [
+        ("1".repeat(20), "ISBN must have 10 or 13 characters after removing hyphens and \
+            spaces, got 20"),
+        ("1".repeat(21), "ISBN cannot be longer than 20 characters"),
+    ];
+    for (isbn, error) in isbn_errors {
+        let refused = send(&app, create(&new_book("Neuromancer", &isbn)).to_request()).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", isbn);
+        assert_eq!(refused.json()["error"], error);
+    }
+    let spaced = "978 - 0 - 441 - 56959 - 5";
+    let created = send(&app, create(&new_book("Neuromancer", spaced)).to_request()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    assert_eq!(created.json()["isbn"], "9780441569595");
+}
+
+#[actix_web::test]
+async fn the_same_upsert_twice_creates_then_changes_nothing() {
+    let data = memory_library();
+    let app = test::init_service(build_app(data.clone())).await;
+    let payload = json!({
+        "title": "Zero to Production in Rust",
+        "authors": ["Luca Palmieri"],
+        "published_year": 2022,
+        "total_copies": 2
+    });
+    let uri = "/api/books/isbn/978-3-16-148410-0";
+    let upsert = || TestRequest::put().uri(uri).set_json(&payload);
+
+    let created = send(&app, upsert().to_request()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    assert_eq!(created.header("location"), Some("/api/books/3"));
+    let first = created.json();
+    assert_eq!(first["id"], 3);
+    assert_eq!(first["isbn"], "9783161484100");
+    assert_eq!(first["total_copies"], 2);
+
+    data.clock.set(start() + chrono::Duration::hours(1));
+    let again = send(&app, upsert().to_request()).await;
+    assert_eq!(again.status, StatusCode::OK);
+    assert_eq!(again.header("etag"), created.header("etag"));
+    let second = again.json();
+    for field in ["id", "version", "updated_at", "title", "total_copies"] {
+        assert_eq!(second[field], first[field], "{}", field);
+    }
+    let count = send(&app, TestRequest::get().uri("/api/books/count").to_request()).await;
+    assert_eq!(count.json(), json!({ "count": 3 }));
+}
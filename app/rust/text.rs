@@ -0,0 +1,119 @@
+use unicode_normalization::UnicodeNormalization;
+
+// Invisible characters that turn up in pasted text; they are dropped rather than rejected
+const ZERO_WIDTH: &[char] = &[
+    '\u{200B}', // zero width space
+    '\u{200C}', // zero width non-joiner
+    '\u{200D}', // zero width joiner
+    '\u{2060}', // word joiner
+    '\u{FEFF}', // byte order mark / zero width no-break space
+];
+
+// Drops zero-width characters and rejects C0/C1 control characters (tabs and line
+// breaks included), naming `field` in the error. Every client-supplied string goes
+// through here before any other validation.
+pub fn sanitize(field: &str, raw: &str) -> Result<String, String> {
+    reject_control(field, raw, false)?;
+    Ok(raw.chars().filter(|c| !ZERO_WIDTH.contains(c)).collect())
+}
+
+fn reject_control(field: &str, raw: &str, allow_breaks: bool) -> Result<(), String> {
+    let allowed = |c: char| allow_breaks && matches!(c, '\n' | '\r' | '\t');
+    match raw.chars().find(|&c| c.is_control() && !allowed(c)) {
+        Some(c) => Err(format!(
+            "{} cannot contain control characters (found U+{:04X})",
+            field, c as u32
+        )),
+        None => Ok(()),
+    }
+}
+
+// Canonical form for single-line text: zero-width characters dropped, NFC, trimmed, and
+// every run of whitespace collapsed to one space, so "  Cafe\u{301}   Society " and
+// "Café Society" are the same string. Used as-is for query values, which are compared
+// rather than stored.
+pub fn normalize(raw: &str) -> String {
+    let composed: String = raw
+        .chars()
+        .filter(|c| !ZERO_WIDTH.contains(c))
+        .nfc()
+        .collect();
+    composed.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Sanitizes and normalizes a single-line field for storing
+pub fn clean(field: &str, raw: &str) -> Result<String, String> {
+    reject_control(field, raw, false)?;
+    Ok(normalize(raw))
+}
+
+// Like `clean`, but free-form text keeps its line breaks, tabs, and internal spacing
+pub fn clean_multiline(field: &str, raw: &str) -> Result<String, String> {
+    reject_control(field, raw, true)?;
+    let composed: String = raw
+        .chars()
+        .filter(|c| !ZERO_WIDTH.contains(c))
+        .nfc()
+        .collect();
+    Ok(composed.trim().to_string())
+}
+
+// URL-safe identifier for a name: lowercase, with each run of non-alphanumeric characters
+// turned into a single hyphen, so "Steve Klabnik" and "steve  klabnik." share "steve-klabnik"
+pub fn slug(name: &str) -> String {
+    normalize(name)
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_trims_and_collapses_whitespace() {
+        assert_eq!(normalize("  The   Book  "), "The Book");
+        assert_eq!(normalize("The\u{a0}\u{3000}Book"), "The Book");
+        assert_eq!(normalize("   "), "");
+    }
+
+    #[test]
+    fn normalize_composes_to_nfc() {
+        assert_eq!(normalize("  Cafe\u{301}   Society "), "Caf\u{e9} Society");
+        assert_eq!(normalize("Caf\u{e9} Society"), "Caf\u{e9} Society");
+    }
+
+    #[test]
+    fn multiline_text_keeps_its_breaks_and_inner_spacing() {
+        let cleaned = clean_multiline("Description", "\n  One  line\n\tand\r\nanother  \n");
+        assert_eq!(cleaned.unwrap(), "One  line\n\tand\r\nanother");
+        assert_eq!(clean("Title", "  The   Book  ").unwrap(), "The Book");
+    }
+
+    #[test]
+    fn zero_width_characters_are_dropped() {
+        assert_eq!(sanitize("Title", "Zero\u{200b}Width").unwrap(), "ZeroWidth");
+        assert_eq!(
+            sanitize("ISBN", "\u{feff}978\u{2060}0306406157").unwrap(),
+            "9780306406157"
+        );
+        assert_eq!(normalize("Zero\u{200c}\u{200d}Width"), "ZeroWidth");
+    }
+
+    #[test]
+    fn control_characters_are_refused_by_name_and_code_point() {
+        let error = "Title cannot contain control characters (found U+000A)";
+        assert_eq!(sanitize("Title", "Two\nLines").unwrap_err(), error);
+        assert_eq!(
+            clean("Title", "A\tB").unwrap_err(),
+            "Title cannot contain control characters (found U+0009)"
+        );
+        let error = "Author cannot contain control characters (found U+0085)";
+        assert_eq!(clean("Author", "Next\u{85}Line").unwrap_err(), error);
+        assert!(clean_multiline("Description", "Line\n\tindented\r\n").is_ok());
+        assert!(clean_multiline("Description", "Bell\u{7}").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use serde::Serialize;
+use std::fmt;
+
+// One failed check on one field of a request body
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// Every failed check of a request, in the order the checks ran; never empty
+#[derive(Debug)]
+pub struct Errors(Vec<FieldError>);
+
+impl Errors {
+    pub fn new(field: &str, message: String) -> Self {
+        Errors(vec![FieldError {
+            field: field.to_string(),
+            message,
+        }])
+    }
+
+    pub fn into_vec(self) -> Vec<FieldError> {
+        self.0
+    }
+}
+
+// The first message, for clients (and callers) that handle a single error string
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.first() {
+            Some(error) => f.write_str(&error.message),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Errors> for String {
+    fn from(errors: Errors) -> String {
+        errors.to_string()
+    }
+}
+
+// Runs a request's checks without stopping at the first failure, so a client can fix
+// everything in one round trip. Checks should run in a fixed order: the first error is
+// then the one a single-error response would have given.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    // The value of a passed check, or None once the failure is recorded under `field`
+    pub fn check<T>(&mut self, field: &str, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.fail(field, message);
+                None
+            }
+        }
+    }
+
+    pub fn fail(&mut self, field: &str, message: String) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message,
+        });
+    }
+
+    pub fn finish(self) -> Result<(), Errors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Errors(self.errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(errors: Errors) -> Vec<String> {
+        errors.into_vec().into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn every_failed_check_is_kept_in_order() {
+        let mut validator = Validator::new();
+        let empty = Err::<(), _>("Title is empty".to_string());
+        assert_eq!(validator.check("title", empty), None);
+        assert_eq!(validator.check("pages", Ok(12)), Some(12));
+        validator.check::<()>("isbn", Err("ISBN is too short".to_string()));
+        validator.fail("author", "Author is empty".to_string());
+        let errors = validator.finish().unwrap_err();
+        assert_eq!(errors.to_string(), "Title is empty");
+        assert_eq!(fields(errors), ["title", "isbn", "author"]);
+    }
+
+    #[test]
+    fn no_failures_is_ok() {
+        let mut validator = Validator::new();
+        validator.check("title", Ok("Dune"));
+        assert!(validator.finish().is_ok());
+    }
+
+    #[test]
+    fn a_single_error_reads_as_its_message() {
+        let errors = Errors::new("title", "Title is empty".to_string());
+        assert_eq!(String::from(errors), "Title is empty");
+    }
+}